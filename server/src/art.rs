@@ -0,0 +1,127 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+use symphonia::{
+    core::{
+        formats::FormatOptions,
+        io::MediaSourceStream,
+        meta::{MetadataOptions, StandardVisualKey, Visual},
+        probe::Hint,
+    },
+    default::get_probe,
+};
+
+/// Picture files taken as an album's cover when they sit next to its
+/// tracks, in order of preference.
+const COVER_NAMES: &[&str] = &["cover", "folder", "front", "album", "albumart"];
+const COVER_TYPES: &[(&str, &str)] = &[
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("webp", "image/webp"),
+];
+
+/// A track's cover image, encoded as it was found.
+pub struct Artwork {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Where a track's art is served from by the gateway.
+pub fn art_url(id: u64) -> String {
+    format!("/art?id={id:x}")
+}
+
+pub fn is_cover_file(path: &Path) -> bool {
+    cover_rank(path).is_some()
+}
+
+fn cover_rank(path: &Path) -> Option<usize> {
+    let stem = path.file_stem()?.to_str()?.to_lowercase();
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    COVER_TYPES.iter().find(|(ext, _)| *ext == extension)?;
+    COVER_NAMES.iter().position(|name| *name == stem)
+}
+
+/// The preferred cover file in `dir`, if it has one.
+pub fn cover_file(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| cover_rank(&entry.path()).map(|rank| (rank, entry.path())))
+        .min()
+        .map(|(_, path)| path)
+}
+
+/// The front cover if it's marked, otherwise whatever picture came first.
+pub fn embedded(visuals: &[Visual]) -> Option<&Visual> {
+    visuals
+        .iter()
+        .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+        .or_else(|| visuals.first())
+}
+
+/// The art of the audio file at `path`: a picture embedded in its tags,
+/// or failing that a cover file in its folder.
+pub fn read(path: &Path) -> Result<Option<Artwork>, String> {
+    let source_file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("error opening audio file: {e}")),
+    };
+
+    let media = MediaSourceStream::new(Box::new(source_file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut probed = match get_probe().format(
+        &hint,
+        media,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) {
+        Ok(probed) => probed,
+        Err(e) => return Err(format!("error probing audio format: {e}")),
+    };
+
+    // the container's pictures win, the same as its tags do
+    let mut artwork = None;
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        artwork = embedded(revision.visuals()).map(to_artwork);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        artwork = embedded(revision.visuals()).map(to_artwork).or(artwork);
+    }
+    if artwork.is_some() {
+        return Ok(artwork);
+    }
+
+    let cover = match path.parent().and_then(cover_file) {
+        Some(cover) => cover,
+        None => return Ok(None),
+    };
+    let extension = cover
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let media_type = COVER_TYPES
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map_or("application/octet-stream", |(_, media_type)| media_type);
+
+    return match fs::read(&cover) {
+        Ok(data) => Ok(Some(Artwork {
+            media_type: media_type.to_string(),
+            data,
+        })),
+        Err(e) => Err(format!("error reading {}: {e}", cover.display())),
+    };
+}
+
+fn to_artwork(visual: &Visual) -> Artwork {
+    Artwork {
+        media_type: visual.media_type.clone(),
+        data: visual.data.to_vec(),
+    }
+}
@@ -0,0 +1,551 @@
+use crate::{library::Track, search::AlbumKey};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+/// One part of an item's place in a listing. Keys are compared part by
+/// part, and a listing's keys always have the same kind of value in the
+/// same place.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortValue {
+    Number(i64),
+    Text(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+/// Where in a listing to start and how much of it to return.
+#[derive(Clone, Debug)]
+pub struct PageRequest {
+    /// The `next_cursor` of the page before, None for the first page.
+    pub cursor: Option<String>,
+    pub limit: usize,
+    pub order: Order,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Set if there's more after this page.
+    pub next_cursor: Option<String>,
+}
+
+/// Sorts `items` by `key` and returns the page after the cursor.
+///
+/// The cursor is the key of the last item returned rather than a position,
+/// so items added or removed while paging don't shift the next page and
+/// have it skip or repeat any. `key` needs to be unique to each item.
+pub fn paginate<T, F>(items: Vec<T>, key: F, request: &PageRequest) -> Result<Page<T>, String>
+where
+    F: Fn(&T) -> Vec<SortValue>,
+{
+    let after = match request.cursor.as_deref() {
+        Some(cursor) => Some(decode_cursor(cursor)?),
+        None => None,
+    };
+
+    let mut keyed: Vec<(Vec<SortValue>, T)> = items
+        .into_iter()
+        .map(|item| (key(&item), item))
+        .filter(|(key, _)| match (after.as_ref(), request.order) {
+            (None, _) => true,
+            (Some(after), Order::Ascending) => key > after,
+            (Some(after), Order::Descending) => key < after,
+        })
+        .collect();
+    keyed.sort_by(|a, b| match request.order {
+        Order::Ascending => a.0.cmp(&b.0),
+        Order::Descending => b.0.cmp(&a.0),
+    });
+
+    let more = keyed.len() > request.limit;
+    keyed.truncate(request.limit);
+    let next_cursor = match more {
+        true => keyed.last().map(|(key, _)| encode_cursor(key)),
+        false => None,
+    };
+
+    Ok(Page {
+        items: keyed.into_iter().map(|(_, item)| item).collect(),
+        next_cursor,
+    })
+}
+
+/// Cursors are opaque to clients, hex keeps them safe to put in a query
+/// string as they are.
+fn encode_cursor(key: &[SortValue]) -> String {
+    serde_json::to_vec(key)
+        .unwrap_or_default()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn decode_cursor(cursor: &str) -> Result<Vec<SortValue>, String> {
+    let bytes = match (0..cursor.len())
+        .step_by(2)
+        .map(|i| {
+            cursor
+                .get(i..i + 2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+    {
+        Some(bytes) => bytes,
+        None => return Err("malformed cursor".to_string()),
+    };
+
+    return match serde_json::from_slice(&bytes) {
+        Ok(key) => Ok(key),
+        Err(_) => Err("malformed cursor".to_string()),
+    };
+}
+
+fn text(value: &str) -> SortValue {
+    SortValue::Text(value.to_lowercase())
+}
+
+/// Sorts case-insensitively, the exact text is only there to tell apart
+/// names that differ in case alone.
+fn name_key(value: &str) -> [SortValue; 2] {
+    [text(value), SortValue::Text(value.to_string())]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArtistSort {
+    Name,
+    TrackCount,
+    AlbumCount,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ArtistSummary {
+    pub name: String,
+    pub album_count: usize,
+    pub track_count: usize,
+    pub art_url: Option<String>,
+}
+
+impl ArtistSummary {
+    pub fn sort_key(&self, sort: ArtistSort) -> Vec<SortValue> {
+        let mut key = match sort {
+            ArtistSort::Name => vec![],
+            ArtistSort::TrackCount => vec![SortValue::Number(self.track_count as i64)],
+            ArtistSort::AlbumCount => vec![SortValue::Number(self.album_count as i64)],
+        };
+        key.extend(name_key(&self.name));
+        key
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlbumSort {
+    Name,
+    Artist,
+    Year,
+    Added,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AlbumSummary {
+    #[serde(flatten)]
+    pub key: AlbumKey,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub track_count: usize,
+    pub duration_ms: u64,
+    /// When the album's most recently added track was, in unix
+    /// milliseconds.
+    pub added_at: Option<u64>,
+    pub art_url: Option<String>,
+}
+
+impl AlbumSummary {
+    pub fn sort_key(&self, sort: AlbumSort) -> Vec<SortValue> {
+        let artist = self.key.artist.as_deref().unwrap_or_default();
+        let year = SortValue::Number(self.year.unwrap_or(0) as i64);
+        let mut key = match sort {
+            AlbumSort::Name => vec![text(&self.key.album), text(artist)],
+            AlbumSort::Artist => vec![text(artist), year, text(&self.key.album)],
+            AlbumSort::Year => vec![year, text(artist), text(&self.key.album)],
+            AlbumSort::Added => vec![
+                SortValue::Number(self.added_at.unwrap_or(0) as i64),
+                text(artist),
+                text(&self.key.album),
+            ],
+        };
+        key.extend([
+            SortValue::Text(self.key.album.clone()),
+            SortValue::Text(artist.to_string()),
+        ]);
+        key
+    }
+}
+
+/// An album with its tracks in disc and track order.
+#[derive(Clone, Debug, Serialize)]
+pub struct Album {
+    #[serde(flatten)]
+    pub summary: AlbumSummary,
+    pub tracks: Vec<Track>,
+}
+
+/// Narrows down a listing of albums, unset fields match anything.
+#[derive(Clone, Debug, Default)]
+pub struct AlbumFilter {
+    /// Albums by this artist or with any track by them.
+    pub artist: Option<String>,
+    /// Albums with any track of this genre.
+    pub genre: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenreSort {
+    Name,
+    TrackCount,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GenreSummary {
+    pub name: String,
+    pub album_count: usize,
+    pub track_count: usize,
+}
+
+impl GenreSummary {
+    pub fn sort_key(&self, sort: GenreSort) -> Vec<SortValue> {
+        let mut key = match sort {
+            GenreSort::Name => vec![],
+            GenreSort::TrackCount => vec![SortValue::Number(self.track_count as i64)],
+        };
+        key.extend(name_key(&self.name));
+        key
+    }
+}
+
+/// Recently added first, when sorted descending.
+pub fn recent_key(track: &Track) -> Vec<SortValue> {
+    vec![
+        SortValue::Number(track.added_at.unwrap_or(0) as i64),
+        SortValue::Text(format!("{:016x}", track.id)),
+    ]
+}
+
+/// Who an artist listing files a track under, the album artist so a
+/// compilation doesn't scatter across everyone on it.
+fn artist_of(track: &Track) -> Option<&String> {
+    track.album_artist.as_ref().or(track.artist.as_ref())
+}
+
+fn matches(value: Option<&String>, wanted: &str) -> bool {
+    value.is_some_and(|value| value.to_lowercase() == wanted.to_lowercase())
+}
+
+/// The order tracks play in on an album.
+fn album_order(a: &Track, b: &Track) -> Ordering {
+    a.disc_number
+        .cmp(&b.disc_number)
+        .then(a.track_number.cmp(&b.track_number))
+        .then_with(|| a.title.to_lowercase().cmp(&b.title.to_lowercase()))
+        .then(a.id.cmp(&b.id))
+}
+
+/// Tracks by album, each album's in play order, so the first art found for
+/// an album or artist is always from the same track.
+fn by_album<'a>(tracks: impl Iterator<Item = &'a Track>) -> Vec<(AlbumKey, Vec<&'a Track>)> {
+    let mut albums: HashMap<AlbumKey, Vec<&Track>> = HashMap::new();
+    for track in tracks {
+        if let Some(key) = AlbumKey::of(track) {
+            albums.entry(key).or_default().push(track);
+        }
+    }
+
+    let mut albums: Vec<(AlbumKey, Vec<&Track>)> = albums.into_iter().collect();
+    albums.sort_by(|a, b| a.0.cmp(&b.0));
+    for (_, tracks) in albums.iter_mut() {
+        tracks.sort_by(|a, b| album_order(a, b));
+    }
+    albums
+}
+
+fn summarize(key: AlbumKey, tracks: &[&Track]) -> AlbumSummary {
+    AlbumSummary {
+        key,
+        year: tracks.iter().filter_map(|track| track.year).min(),
+        genre: tracks.iter().find_map(|track| track.genre.clone()),
+        track_count: tracks.len(),
+        duration_ms: tracks.iter().filter_map(|track| track.duration_ms).sum(),
+        added_at: tracks.iter().filter_map(|track| track.added_at).max(),
+        art_url: tracks.iter().find_map(|track| track.art_url.clone()),
+    }
+}
+
+pub fn artists<'a>(tracks: impl Iterator<Item = &'a Track>) -> Vec<ArtistSummary> {
+    let tracks: Vec<&Track> = tracks.collect();
+    let mut artists: HashMap<&String, ArtistSummary> = HashMap::new();
+    let mut albums: HashSet<(&String, &String)> = HashSet::new();
+
+    // albums first, so an artist's art comes from one of their albums
+    let mut ordered: Vec<&Track> = by_album(tracks.iter().copied())
+        .into_iter()
+        .flat_map(|(_, tracks)| tracks)
+        .collect();
+    ordered.extend(tracks.iter().filter(|track| track.album.is_none()));
+
+    for track in ordered {
+        let name = match artist_of(track) {
+            Some(name) => name,
+            None => continue,
+        };
+        let artist = artists.entry(name).or_insert_with(|| ArtistSummary {
+            name: name.clone(),
+            album_count: 0,
+            track_count: 0,
+            art_url: None,
+        });
+        artist.track_count += 1;
+        if artist.art_url.is_none() {
+            artist.art_url = track.art_url.clone();
+        }
+        if let Some(album) = track.album.as_ref() {
+            if albums.insert((name, album)) {
+                artist.album_count += 1;
+            }
+        }
+    }
+
+    artists.into_values().collect()
+}
+
+pub fn albums<'a>(
+    tracks: impl Iterator<Item = &'a Track>,
+    filter: &AlbumFilter,
+) -> Vec<AlbumSummary> {
+    by_album(tracks)
+        .into_iter()
+        .filter(|(key, tracks)| match filter.artist.as_deref() {
+            Some(artist) => {
+                matches(key.artist.as_ref(), artist)
+                    || tracks
+                        .iter()
+                        .any(|track| matches(track.artist.as_ref(), artist))
+            }
+            None => true,
+        })
+        .filter(|(_, tracks)| match filter.genre.as_deref() {
+            Some(genre) => tracks
+                .iter()
+                .any(|track| matches(track.genre.as_ref(), genre)),
+            None => true,
+        })
+        .map(|(key, tracks)| summarize(key, &tracks))
+        .collect()
+}
+
+pub fn album<'a>(tracks: impl Iterator<Item = &'a Track>, key: &AlbumKey) -> Option<Album> {
+    let mut tracks: Vec<&Track> = tracks
+        .filter(|track| AlbumKey::of(track).as_ref() == Some(key))
+        .collect();
+    if tracks.is_empty() {
+        return None;
+    }
+
+    tracks.sort_by(|a, b| album_order(a, b));
+    Some(Album {
+        summary: summarize(key.clone(), &tracks),
+        tracks: tracks.into_iter().cloned().collect(),
+    })
+}
+
+pub fn genres<'a>(tracks: impl Iterator<Item = &'a Track>) -> Vec<GenreSummary> {
+    let mut genres: HashMap<&String, (HashSet<AlbumKey>, usize)> = HashMap::new();
+    for track in tracks {
+        let genre = match track.genre.as_ref() {
+            Some(genre) => genre,
+            None => continue,
+        };
+        let (albums, track_count) = genres.entry(genre).or_default();
+        *track_count += 1;
+        albums.extend(AlbumKey::of(track));
+    }
+
+    genres
+        .into_iter()
+        .map(|(name, (albums, track_count))| GenreSummary {
+            name: name.clone(),
+            album_count: albums.len(),
+            track_count,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A library with plenty of ties, in artists, album counts, years and
+    /// added times, and names that differ only in case.
+    fn library() -> Vec<Track> {
+        (0..60u64)
+            .map(|id| Track {
+                id,
+                title: format!("track {id}"),
+                artist: Some(
+                    ["Alpha", "alpha", "Beta", "Gamma", "delta"][id as usize % 5].to_string(),
+                ),
+                album: Some(format!("Album {}", id % 13)),
+                track_number: Some((id / 13) as u32 + 1),
+                genre: Some(["Rock", "rock", "Jazz"][id as usize % 3].to_string()),
+                year: Some(1990 + (id % 4) as u32),
+                added_at: Some(1_000 * (id % 7)),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Every page of a listing, `limit` items at a time.
+    fn pages<T: Clone, F>(items: &[T], key: F, limit: usize, order: Order) -> Vec<Vec<T>>
+    where
+        F: Fn(&T) -> Vec<SortValue> + Copy,
+    {
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let request = PageRequest {
+                cursor,
+                limit,
+                order,
+            };
+            let page = paginate(items.to_vec(), key, &request).unwrap();
+            assert!(page.items.len() <= limit);
+            pages.push(page.items);
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                return pages;
+            }
+        }
+    }
+
+    /// Paging through gives every item once, in the same order as taking
+    /// the whole listing in one go.
+    fn assert_pages_cover<T: Clone, F>(items: &[T], key: F)
+    where
+        F: Fn(&T) -> Vec<SortValue> + Copy,
+    {
+        for order in [Order::Ascending, Order::Descending] {
+            let whole: Vec<Vec<SortValue>> = pages(items, key, usize::MAX, order)
+                .concat()
+                .iter()
+                .map(key)
+                .collect();
+            assert_eq!(whole.len(), items.len());
+            let mut sorted = whole.clone();
+            sorted.sort();
+            if order == Order::Descending {
+                sorted.reverse();
+            }
+            assert_eq!(whole, sorted);
+
+            for limit in [1, 2, 3, 7] {
+                let paged: Vec<Vec<SortValue>> = pages(items, key, limit, order)
+                    .concat()
+                    .iter()
+                    .map(key)
+                    .collect();
+                assert_eq!(paged, whole, "limit {limit} {order:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn artist_pages_cover_every_artist_once() {
+        let artists = artists(library().iter());
+        assert_eq!(artists.len(), 5);
+        for sort in [
+            ArtistSort::Name,
+            ArtistSort::TrackCount,
+            ArtistSort::AlbumCount,
+        ] {
+            assert_pages_cover(&artists, |artist| artist.sort_key(sort));
+        }
+    }
+
+    #[test]
+    fn album_pages_cover_every_album_once() {
+        let albums = albums(library().iter(), &AlbumFilter::default());
+        assert!(albums.len() > 13);
+        for sort in [
+            AlbumSort::Name,
+            AlbumSort::Artist,
+            AlbumSort::Year,
+            AlbumSort::Added,
+        ] {
+            assert_pages_cover(&albums, |album| album.sort_key(sort));
+        }
+    }
+
+    #[test]
+    fn genre_and_recent_pages_cover_everything_once() {
+        let genres = genres(library().iter());
+        assert_eq!(genres.len(), 3);
+        for sort in [GenreSort::Name, GenreSort::TrackCount] {
+            assert_pages_cover(&genres, |genre| genre.sort_key(sort));
+        }
+        assert_pages_cover(&library(), recent_key);
+    }
+
+    #[test]
+    fn changes_between_pages_dont_shift_the_next_one() {
+        let mut tracks = library();
+        let request = PageRequest {
+            cursor: None,
+            limit: 10,
+            order: Order::Ascending,
+        };
+        let first = paginate(tracks.clone(), recent_key, &request).unwrap();
+        let last = first.items.last().unwrap().id;
+
+        // one from the first page goes, and one lands before the cursor
+        let gone = first.items[0].id;
+        tracks.retain(|track| track.id != gone);
+        tracks.push(Track {
+            id: 1_000,
+            added_at: Some(0),
+            ..Default::default()
+        });
+
+        let request = PageRequest {
+            cursor: first.next_cursor,
+            ..request
+        };
+        let second = paginate(tracks.clone(), recent_key, &request).unwrap();
+        let mut expected: Vec<Track> = tracks;
+        expected.sort_by_key(recent_key);
+        let after = expected.iter().position(|track| track.id == last).unwrap();
+        let expected: Vec<u64> = expected[after + 1..after + 11]
+            .iter()
+            .map(|track| track.id)
+            .collect();
+        let second: Vec<u64> = second.items.iter().map(|track| track.id).collect();
+        assert_eq!(second, expected);
+    }
+
+    #[test]
+    fn malformed_cursors_are_refused() {
+        for cursor in ["zz", "7", "7b22"] {
+            let request = PageRequest {
+                cursor: Some(cursor.to_string()),
+                limit: 10,
+                order: Order::Ascending,
+            };
+            assert!(paginate(library(), recent_key, &request).is_err());
+        }
+    }
+}
@@ -1,11 +1,22 @@
-use std::io;
-use axum::extract::BodyStream;
-use symphonia::core::{
-    audio::{AudioBufferRef, SampleBuffer, SignalSpec},
-    codecs::{CodecParameters, CodecRegistry, Decoder, DecoderOptions},
-    conv::ConvertibleSample,
-    errors::Error,
-    formats::FormatReader,
+use std::{
+    fs::File,
+    io::{self, Cursor},
+    path::Path,
+    time::Duration,
+};
+use symphonia::{
+    core::{
+        audio::{AudioBufferRef, SampleBuffer, SignalSpec},
+        codecs::{CodecParameters, CodecRegistry, Decoder, DecoderOptions},
+        conv::ConvertibleSample,
+        errors::Error,
+        formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+        io::MediaSourceStream,
+        meta::MetadataOptions,
+        probe::Hint,
+        units::Time,
+    },
+    default::{get_codecs, get_probe},
 };
 
 use super::provider::ProviderObject;
@@ -41,6 +52,38 @@ impl AudioReader {
         })
     }
 
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let source_file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("error opening audio file: {e}")),
+        };
+
+        let media = MediaSourceStream::new(Box::new(source_file), Default::default());
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        Self::probe(media, &hint)
+    }
+
+    /// Audio held in memory, like an upload, in whatever format it probes as.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, String> {
+        let media = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+        Self::probe(media, &Hint::new())
+    }
+
+    fn probe(media: MediaSourceStream, hint: &Hint) -> Result<Self, String> {
+        let meta_opts: MetadataOptions = Default::default();
+        let fmt_opts: FormatOptions = Default::default();
+        let format_reader = match get_probe().format(hint, media, &fmt_opts, &meta_opts) {
+            Ok(result) => result.format,
+            Err(e) => return Err(format!("error probing audio format: {e}")),
+        };
+
+        Self::new(format_reader, get_codecs(), &Default::default())
+    }
+
     pub fn codec_params(&self) -> &CodecParameters {
         self.decoder.codec_params()
     }
@@ -53,6 +96,28 @@ impl AudioReader {
         }
     }
 
+    /// Total frames in the track, if the container says.
+    pub fn n_frames(&self) -> Option<u64> {
+        self.codec_params().n_frames
+    }
+
+    /// Seeks to `position`. Seeks land at or before the requested position,
+    /// so this returns how many decoded frames to discard to be sample
+    /// accurate.
+    pub fn seek(&mut self, position: Duration) -> Result<u64, String> {
+        let seek_to = SeekTo::Time {
+            time: Time::from(position.as_secs_f64()),
+            track_id: Some(self.track_id),
+        };
+        let seeked_to = match self.format_reader.seek(SeekMode::Accurate, seek_to) {
+            Ok(seeked_to) => seeked_to,
+            Err(e) => return Err(format!("error seeking: {e}")),
+        };
+        self.decoder.reset();
+
+        Ok(seeked_to.required_ts.saturating_sub(seeked_to.actual_ts))
+    }
+
     pub fn consume_next<F>(&mut self, mut callback: F) -> Result<(), String>
     where
         F: FnMut(AudioBufferRef) -> Result<(), String>,
@@ -97,11 +162,3 @@ impl AudioReader {
 }
 
 impl ProviderObject for AudioReader {}
-
-impl From<BodyStream> for AudioReader {
-    fn from(value: BodyStream) -> Self {
-        
-
-        todo!()
-    }
-}
\ No newline at end of file
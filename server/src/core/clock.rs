@@ -0,0 +1,213 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Tracks playback position in frames rather than wall time. The decoder
+/// counts frames it queues into the pipeline and the drain counts frames it
+/// hands to the output, so the position only moves while audio is actually
+/// heard and pausing can't make it drift.
+pub struct PlaybackClock {
+    sample_rate: AtomicU32,
+    start_frame: AtomicU64,
+    queued: AtomicU64,
+    played: AtomicU64,
+    duration: AtomicU64,
+    paused: AtomicBool,
+    /// Queued frames after which the next track starts, once the drain gets
+    /// there.
+    boundary: AtomicU64,
+    next_duration: AtomicU64,
+}
+
+impl Default for PlaybackClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackClock {
+    const UNKNOWN_DURATION: u64 = u64::MAX;
+    const NO_BOUNDARY: u64 = u64::MAX;
+
+    pub fn new() -> Self {
+        Self {
+            sample_rate: AtomicU32::new(44100),
+            start_frame: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            played: AtomicU64::new(0),
+            duration: AtomicU64::new(Self::UNKNOWN_DURATION),
+            paused: AtomicBool::new(true),
+            boundary: AtomicU64::new(Self::NO_BOUNDARY),
+            next_duration: AtomicU64::new(Self::UNKNOWN_DURATION),
+        }
+    }
+
+    /// Resets the clock for a new track, starting `start_frame` frames in.
+    pub fn start_track(&self, sample_rate: u32, start_frame: u64, duration: Option<u64>) {
+        self.boundary.store(Self::NO_BOUNDARY, Ordering::Relaxed);
+        self.sample_rate
+            .store(sample_rate.max(1), Ordering::Relaxed);
+        self.start_frame.store(start_frame, Ordering::Relaxed);
+        self.queued.store(0, Ordering::Relaxed);
+        self.played.store(0, Ordering::Relaxed);
+        self.duration.store(
+            duration.unwrap_or(Self::UNKNOWN_DURATION),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Starts a track that follows on from the current one without a flush.
+    /// What's queued so far is the current track's tail, still on its way
+    /// through the pipeline, so the clock only moves on to the new track
+    /// once the drain has played past it.
+    /// Pipelines run at one rate, so `sample_rate` is the same as the current
+    /// track's unless there isn't one.
+    pub fn queue_track(&self, sample_rate: u32, duration: Option<u64>) {
+        self.sample_rate
+            .store(sample_rate.max(1), Ordering::Relaxed);
+        self.next_duration.store(
+            duration.unwrap_or(Self::UNKNOWN_DURATION),
+            Ordering::Relaxed,
+        );
+        self.boundary
+            .store(self.queued.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.cross_boundary();
+    }
+
+    /// Called by the decoder for frames it has sent into the pipeline.
+    pub fn queue(&self, frames: u64) {
+        self.queued.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    /// Called by the drain for frames it has handed to the output.
+    pub fn advance(&self, frames: u64) {
+        self.played.fetch_add(frames, Ordering::Relaxed);
+        self.cross_boundary();
+    }
+
+    /// Switches to the queued track if the drain has reached it, carrying
+    /// over whatever of it has been queued and played already.
+    fn cross_boundary(&self) {
+        let boundary = self.boundary.load(Ordering::Relaxed);
+        if boundary == Self::NO_BOUNDARY || self.played.load(Ordering::Relaxed) < boundary {
+            return;
+        }
+        // the decoder and drain can both get here, only one of them moves on
+        if self
+            .boundary
+            .compare_exchange(
+                boundary,
+                Self::NO_BOUNDARY,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return;
+        }
+
+        self.played.fetch_sub(boundary, Ordering::Relaxed);
+        self.queued.fetch_sub(boundary, Ordering::Relaxed);
+        self.start_frame.store(0, Ordering::Relaxed);
+        self.duration.store(
+            self.next_duration.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn position_frames(&self) -> u64 {
+        self.start_frame.load(Ordering::Relaxed) + self.played.load(Ordering::Relaxed)
+    }
+
+    pub fn position(&self) -> Duration {
+        self.frames_to_duration(self.position_frames())
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        return match self.duration.load(Ordering::Relaxed) {
+            Self::UNKNOWN_DURATION => None,
+            frames => Some(self.frames_to_duration(frames)),
+        };
+    }
+
+    /// Audio decoded that hasn't been heard yet, including any of a queued
+    /// track.
+    pub fn buffered(&self) -> Duration {
+        let queued = self.queued.load(Ordering::Relaxed);
+        let played = self.played.load(Ordering::Relaxed);
+        self.frames_to_duration(queued.saturating_sub(played))
+    }
+
+    pub fn duration_to_frames(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * self.sample_rate() as u128 / 1_000_000_000) as u64
+    }
+
+    fn frames_to_duration(&self, frames: u64) -> Duration {
+        let nanos = frames as u128 * 1_000_000_000 / self.sample_rate() as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queued_track_starts_when_its_audio_is_heard() {
+        let clock = PlaybackClock::new();
+        clock.start_track(1000, 0, Some(3000));
+        clock.queue(3000);
+        clock.advance(2500);
+
+        // the last 500 frames are still on their way to the output
+        clock.queue_track(1000, Some(2000));
+        clock.queue(1000);
+        assert_eq!(clock.position_frames(), 2500);
+        assert_eq!(clock.duration(), Some(Duration::from_secs(3)));
+        assert_eq!(clock.buffered(), Duration::from_millis(1500));
+
+        clock.advance(700);
+        assert_eq!(clock.position_frames(), 200);
+        assert_eq!(clock.duration(), Some(Duration::from_secs(2)));
+        assert_eq!(clock.buffered(), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn queued_track_starts_at_once_after_the_tail_has_played() {
+        let clock = PlaybackClock::new();
+        clock.start_track(1000, 0, Some(3000));
+        clock.queue(3000);
+        clock.advance(3000);
+
+        clock.queue_track(1000, None);
+        assert_eq!(clock.position_frames(), 0);
+        assert_eq!(clock.duration(), None);
+    }
+
+    #[test]
+    fn starting_a_track_drops_a_queued_one() {
+        let clock = PlaybackClock::new();
+        clock.start_track(1000, 0, Some(3000));
+        clock.queue(3000);
+        clock.queue_track(1000, Some(2000));
+
+        clock.start_track(1000, 500, Some(4000));
+        clock.queue(1000);
+        clock.advance(1000);
+        assert_eq!(clock.position_frames(), 1500);
+        assert_eq!(clock.duration(), Some(Duration::from_secs(4)));
+    }
+}
@@ -0,0 +1,244 @@
+use super::pipeline::Sample;
+
+/// Brings decoded audio to the pipeline's sample rate and channel count, a
+/// packet at a time.
+///
+/// Channels are mapped before resampling. Mono goes to every output
+/// channel, anything going to mono is averaged, and otherwise each output
+/// channel takes the input channel in the same place, with extra inputs
+/// folded in by averaging and extra outputs repeating the inputs.
+///
+/// Resampling interpolates between frames with a Catmull-Rom spline, which
+/// is clean enough for music going between the usual rates. There's no
+/// low pass ahead of it, so going down a long way lets anything above the
+/// new Nyquist frequency fold back.
+pub struct FormatConverter {
+    from_channels: usize,
+    to_channels: usize,
+    /// The input and output rates, None if they're the same.
+    rates: Option<(u64, u64)>,
+    /// Input frames taken and output frames made since the start, which
+    /// between them say exactly where the next output frame falls.
+    frames_in: u64,
+    frames_out: u64,
+    /// The last four input frames after mapping, oldest first, once there's
+    /// been one.
+    window: Vec<Sample>,
+    frame: Vec<Sample>,
+    output: Vec<Sample>,
+}
+
+impl FormatConverter {
+    pub fn new(from_rate: u32, from_channels: u16, to_rate: u32, to_channels: u16) -> Self {
+        let to_channels = to_channels.max(1) as usize;
+        Self {
+            from_channels: from_channels.max(1) as usize,
+            to_channels,
+            rates: match from_rate == to_rate {
+                true => None,
+                false => Some((from_rate.max(1) as u64, to_rate.max(1) as u64)),
+            },
+            frames_in: 0,
+            frames_out: 0,
+            window: Vec::with_capacity(4 * to_channels),
+            frame: vec![0.0; to_channels],
+            output: Vec::new(),
+        }
+    }
+
+    pub fn from_channels(&self) -> usize {
+        self.from_channels
+    }
+
+    /// How many frames `frames` input frames come out as.
+    pub fn output_frames(&self, frames: u64) -> u64 {
+        match self.rates {
+            Some((from, to)) => (frames * to).div_ceil(from),
+            None => frames,
+        }
+    }
+
+    /// Starts over, as for a seek.
+    pub fn reset(&mut self) {
+        self.frames_in = 0;
+        self.frames_out = 0;
+        self.window.clear();
+    }
+
+    /// Converts interleaved input samples, returning whatever output they
+    /// complete. Resampling holds the last couple of frames back until
+    /// what follows them is known, see `finish`.
+    pub fn convert(&mut self, samples: &[Sample]) -> &[Sample] {
+        self.output.clear();
+        for input in samples.chunks_exact(self.from_channels) {
+            self.map_channels(input);
+            match self.rates {
+                Some(rates) => self.resample_frame(rates),
+                None => self.output.extend_from_slice(&self.frame),
+            }
+        }
+        &self.output
+    }
+
+    /// The output held back at the end of the input.
+    pub fn finish(&mut self) -> &[Sample] {
+        self.output.clear();
+        let rates = match self.rates {
+            Some(rates) if !self.window.is_empty() => rates,
+            _ => return &self.output,
+        };
+
+        // carrying the last frame on takes the spline up to it
+        let last = self.window.len() - self.to_channels;
+        self.frame.copy_from_slice(&self.window[last..]);
+        for _ in 0..2 {
+            self.resample_frame(rates);
+        }
+        self.reset();
+        &self.output
+    }
+
+    fn map_channels(&mut self, input: &[Sample]) {
+        let (from, to) = (self.from_channels, self.to_channels);
+        if from == to {
+            self.frame.copy_from_slice(input);
+        } else if from == 1 {
+            self.frame.fill(input[0]);
+        } else if to == 1 {
+            self.frame[0] = input.iter().sum::<Sample>() / from as Sample;
+        } else if to > from {
+            for (channel, sample) in self.frame.iter_mut().enumerate() {
+                *sample = input[channel % from];
+            }
+        } else {
+            for (channel, sample) in self.frame.iter_mut().enumerate() {
+                let folded = input.iter().skip(channel).step_by(to);
+                let count = folded.clone().count();
+                *sample = folded.sum::<Sample>() / count as Sample;
+            }
+        }
+    }
+
+    /// Takes `frame` as the next input frame and outputs every frame that
+    /// falls between the middle two of the window.
+    fn resample_frame(&mut self, (from, to): (u64, u64)) {
+        let channels = self.to_channels;
+        if self.window.is_empty() {
+            // before the first frame is taken to be more of it
+            for _ in 0..3 {
+                self.window.extend_from_slice(&self.frame);
+            }
+        } else {
+            self.window.drain(..channels);
+        }
+        self.window.extend_from_slice(&self.frame);
+        self.frames_in += 1;
+
+        // the middle two are frames_in - 3 and frames_in - 2, counting from
+        // 0, so outputs before the later of them are ready
+        while self.frames_in >= 2 && self.frames_out * from < (self.frames_in - 2) * to {
+            let offset = self.frames_out * from + 3 * to - self.frames_in * to;
+            let t = (offset as f64 / to as f64) as Sample;
+            for channel in 0..channels {
+                let p = |i: usize| self.window[i * channels + channel];
+                let (p0, p1, p2, p3) = (p(0), p(1), p(2), p(3));
+                self.output.push(
+                    p1 + 0.5
+                        * t
+                        * (p2 - p0
+                            + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3
+                                + t * (3.0 * (p1 - p2) + p3 - p0))),
+                );
+            }
+            self.frames_out += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn convert_all(converter: &mut FormatConverter, input: &[Sample]) -> Vec<Sample> {
+        let mut output = Vec::new();
+        // in uneven packets, as a decoder hands them over
+        for packet in input.chunks(3 * converter.from_channels()) {
+            output.extend_from_slice(converter.convert(packet));
+        }
+        output.extend_from_slice(converter.finish());
+        output
+    }
+
+    fn sine(frequency: f32, rate: u32, frames: usize) -> Vec<Sample> {
+        (0..frames)
+            .map(|n| (2.0 * PI * frequency * n as f32 / rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn same_format_passes_through() {
+        let input: Vec<Sample> = (0..100).map(|i| i as Sample / 100.0).collect();
+        let mut converter = FormatConverter::new(44100, 2, 44100, 2);
+        assert_eq!(convert_all(&mut converter, &input), input);
+        assert_eq!(converter.output_frames(50), 50);
+    }
+
+    #[test]
+    fn channels_are_mapped() {
+        let mut mono_to_stereo = FormatConverter::new(8000, 1, 8000, 2);
+        assert_eq!(mono_to_stereo.convert(&[0.1, 0.2]), &[0.1, 0.1, 0.2, 0.2]);
+
+        let mut stereo_to_mono = FormatConverter::new(8000, 2, 8000, 1);
+        assert_eq!(stereo_to_mono.convert(&[0.2, 0.4, -1.0, 1.0]), &[0.3, 0.0]);
+
+        // front left, front right, centre, lfe, surround left and right
+        let mut surround = FormatConverter::new(8000, 6, 8000, 2);
+        let folded = surround.convert(&[0.3, 0.6, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(folded, &[0.1, 0.2]);
+
+        let mut widened = FormatConverter::new(8000, 2, 8000, 3);
+        assert_eq!(widened.convert(&[0.1, 0.2]), &[0.1, 0.2, 0.1]);
+    }
+
+    #[test]
+    fn resampling_keeps_the_length_and_pitch() {
+        for (from, to) in [(48000, 44100), (22050, 44100), (44100, 48000)] {
+            let input = sine(1000.0, from, from as usize / 10);
+            let mut converter = FormatConverter::new(from, 1, to, 1);
+            let output = convert_all(&mut converter, &input);
+            assert_eq!(
+                output.len() as u64,
+                converter.output_frames(input.len() as u64),
+                "{from} to {to}"
+            );
+
+            // past the last input frame there's nothing to go on but it
+            let expected = sine(1000.0, to, output.len());
+            let interior = output.len() - (to / from + 1) as usize;
+            let error = output[..interior]
+                .iter()
+                .zip(expected.iter())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, Sample::max);
+            assert!(error < 0.02, "{from} to {to} off by {error}");
+        }
+    }
+
+    #[test]
+    fn resampling_lands_on_input_frames() {
+        // halving the rate keeps every other frame as it was
+        let input: Vec<Sample> = (0..20).map(|i| (i as Sample * 0.7).sin()).collect();
+        let mut converter = FormatConverter::new(16000, 1, 8000, 1);
+        let output = convert_all(&mut converter, &input);
+        let kept: Vec<Sample> = input.iter().step_by(2).copied().collect();
+        assert_eq!(output, kept);
+
+        // and a reset starts over from the next frame
+        converter.convert(&input[..5]);
+        converter.reset();
+        let output = convert_all(&mut converter, &input[1..]);
+        let kept: Vec<Sample> = input[1..].iter().step_by(2).copied().collect();
+        assert_eq!(output, kept);
+    }
+}
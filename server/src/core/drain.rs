@@ -0,0 +1,84 @@
+use super::{
+    clock::PlaybackClock,
+    pipeline::{Sample, TransformCtx},
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Receiver,
+        Arc,
+    },
+    time::Duration,
+};
+
+/// The end of a pipeline: somewhere samples leave the process, be it a sound
+/// device, a file, or nowhere at all.
+pub trait Drain: Send + Sync {
+    fn init(&self, ctx: &TransformCtx) -> Result<(), String>;
+
+    /// Consumes samples until `from` disconnects, advancing `clock` as
+    /// frames reach the output. Returns once everything recieved has been
+    /// written out.
+    fn drain(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        clock: Arc<PlaybackClock>,
+    ) -> Result<(), String>;
+
+    fn purge(&self) -> Result<(), String>;
+
+    fn stats(&self) -> DrainStats;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrainStats {
+    /// Frames handed to the output so far.
+    pub frames: u64,
+    /// Times the output asked for samples the pipeline hadn't produced yet.
+    pub underruns: u64,
+    /// Time between a sample reaching the drain and it being heard.
+    pub latency: Duration,
+}
+
+/// Counters behind `Drain::stats`, safe to update from an output callback
+/// while the gateway reads them.
+#[derive(Default)]
+pub struct DrainMetrics {
+    frames: AtomicU64,
+    underruns: AtomicU64,
+    latency_us: AtomicU64,
+}
+
+impl DrainMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_frames(&self, frames: u64) {
+        self.frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn add_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.latency_us
+            .store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.frames.store(0, Ordering::Relaxed);
+        self.underruns.store(0, Ordering::Relaxed);
+        self.latency_us.store(0, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> DrainStats {
+        DrainStats {
+            frames: self.frames.load(Ordering::Relaxed),
+            underruns: self.underruns.load(Ordering::Relaxed),
+            latency: Duration::from_micros(self.latency_us.load(Ordering::Relaxed)),
+        }
+    }
+}
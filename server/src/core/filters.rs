@@ -0,0 +1,95 @@
+use super::{
+    params::SmoothedParam,
+    pipeline::{AudioPipe, Sample, TransformCtx},
+    volume::db_to_amplitude,
+};
+use std::{
+    f32::consts::TAU,
+    sync::{
+        mpsc::{Receiver, SyncSender},
+        Mutex,
+    },
+};
+
+/// Fixed gain in dB, adjustable while running.
+pub struct Gain {
+    gain_db: Mutex<SmoothedParam>,
+}
+
+impl Gain {
+    pub fn new(gain_db: SmoothedParam) -> Self {
+        Self {
+            gain_db: Mutex::new(gain_db),
+        }
+    }
+}
+
+impl AudioPipe for Gain {
+    fn pipe(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        to: SyncSender<Sample>,
+    ) -> Result<(), String> {
+        let channels = ctx.channels.max(1) as usize;
+        // only this thread ever takes the lock, it's here to make the stage Sync
+        let mut gain_db = self.gain_db.lock().unwrap();
+
+        let mut gain = 1.0;
+        let mut channel = 0;
+        for sample in from {
+            if channel == 0 {
+                gain = db_to_amplitude(gain_db.next_value());
+            }
+            channel = (channel + 1) % channels;
+
+            if let Err(err) = to.send(sample * gain) {
+                return Err(format!("error sending sample: {err}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// One-pole low pass with an adjustable cutoff.
+pub struct LowPass {
+    cutoff_hz: Mutex<SmoothedParam>,
+}
+
+impl LowPass {
+    pub fn new(cutoff_hz: SmoothedParam) -> Self {
+        Self {
+            cutoff_hz: Mutex::new(cutoff_hz),
+        }
+    }
+}
+
+impl AudioPipe for LowPass {
+    fn pipe(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        to: SyncSender<Sample>,
+    ) -> Result<(), String> {
+        let channels = ctx.channels.max(1) as usize;
+        let mut cutoff_hz = self.cutoff_hz.lock().unwrap();
+
+        let mut state = vec![0.0; channels];
+        let mut coefficient = 1.0;
+        let mut channel = 0;
+        for sample in from {
+            if channel == 0 {
+                coefficient = 1.0 - (-TAU * cutoff_hz.next_value() / ctx.sample_rate as f32).exp();
+            }
+            state[channel] += (sample - state[channel]) * coefficient;
+
+            if let Err(err) = to.send(state[channel]) {
+                return Err(format!("error sending sample: {err}"));
+            }
+            channel = (channel + 1) % channels;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+use super::{audio::AudioReader, pipeline::Sample};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{f32::consts::PI, sync::Arc};
+use symphonia::core::audio::SampleBuffer;
+
+/// An acoustic fingerprint along the lines of Chromaprint's: the audio's
+/// chroma, how much of each of the twelve pitch classes there is, over
+/// time, boiled down to one 32 bit word per frame. Encodings of the same
+/// recording come out with most bits the same, 80% or more, different
+/// recordings with around half of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    items: Vec<u32>,
+}
+
+impl Fingerprint {
+    /// How alike two fingerprints have to be, see `similarity`, to be
+    /// taken for the same recording.
+    pub const DUPLICATE_SIMILARITY: f32 = 0.75;
+
+    /// How far two fingerprints are slid against each other looking for
+    /// the best match, about three seconds either way, for encoders adding
+    /// or trimming silence at the start.
+    const MAX_OFFSET: isize = 24;
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.items
+            .iter()
+            .flat_map(|item| item.to_le_bytes())
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.len().is_multiple_of(4) {
+            return Err(format!(
+                "fingerprint of {} bytes isn't whole items",
+                bytes.len()
+            ));
+        }
+
+        Ok(Self {
+            items: bytes
+                .chunks_exact(4)
+                .map(|item| u32::from_le_bytes([item[0], item[1], item[2], item[3]]))
+                .collect(),
+        })
+    }
+
+    /// The share of bits the same, from 0 to 1, where the two line up best.
+    /// Only the overlap counts, and it has to be at least half the shorter
+    /// of them, so a snippet doesn't match whatever it happens to resemble.
+    /// Silence, which is all zeros, is left out, or every quiet intro would
+    /// match every other.
+    pub fn similarity(&self, other: &Fingerprint) -> f32 {
+        let min_overlap = (self.len().min(other.len()) / 2).max(1);
+        let mut best = 0.0;
+        for offset in -Self::MAX_OFFSET..=Self::MAX_OFFSET {
+            let (a, b) = match offset < 0 {
+                true => (&self.items[..], other.items.get(-offset as usize..)),
+                false => (
+                    self.items.get(offset as usize..).unwrap_or_default(),
+                    Some(&other.items[..]),
+                ),
+            };
+            let (overlap, errors) = a
+                .iter()
+                .zip(b.unwrap_or_default().iter())
+                .filter(|(a, b)| **a != 0 || **b != 0)
+                .fold((0, 0), |(overlap, errors), (a, b)| {
+                    (overlap + 1, errors + (a ^ b).count_ones() as usize)
+                });
+            if overlap < min_overlap {
+                continue;
+            }
+
+            let similarity = 1.0 - errors as f32 / (overlap * 32) as f32;
+            if similarity > best {
+                best = similarity;
+            }
+        }
+
+        best
+    }
+}
+
+/// Works a fingerprint out from a stream of interleaved samples, like
+/// `WaveformBuilder`. Only the first two minutes are used, which is plenty
+/// to tell recordings apart, so decoding can stop once `is_full`.
+pub struct FingerprintBuilder {
+    channels: usize,
+    // averaged down by a whole factor first, then interpolated to exactly
+    // the target rate, so frames line up across sample rates
+    decimation: usize,
+    step: f64,
+    position: f64,
+    last: f32,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    // the mono signal at the lower rate, not yet made into a frame
+    signal: Vec<f32>,
+    frame_sum: f32,
+    frame_count: usize,
+    channel: usize,
+    resampled: usize,
+    chroma: Vec<[f32; 12]>,
+}
+
+impl FingerprintBuilder {
+    const TARGET_RATE: u32 = 11025;
+    const FFT_SIZE: usize = 4096;
+    const HOP: usize = Self::FFT_SIZE / 3;
+    const MAX_SECONDS: usize = 120;
+    const MIN_FREQUENCY: f32 = 28.0;
+    const MAX_FREQUENCY: f32 = 3520.0;
+
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let decimation = (sample_rate / Self::TARGET_RATE).max(1) as usize;
+        let decimated_rate = sample_rate as f64 / decimation as f64;
+        let window = (0..Self::FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / Self::FFT_SIZE as f32).cos())
+            .collect();
+
+        Self {
+            channels: channels.max(1) as usize,
+            decimation,
+            step: decimated_rate / Self::TARGET_RATE as f64,
+            position: 0.0,
+            last: 0.0,
+            fft: FftPlanner::new().plan_fft_forward(Self::FFT_SIZE),
+            window,
+            buffer: vec![Complex::default(); Self::FFT_SIZE],
+            signal: Vec::with_capacity(Self::FFT_SIZE),
+            frame_sum: 0.0,
+            frame_count: 0,
+            channel: 0,
+            resampled: 0,
+            chroma: Vec::new(),
+        }
+    }
+
+    /// Whether it's had all the audio it's going to use.
+    pub fn is_full(&self) -> bool {
+        self.resampled >= Self::MAX_SECONDS * Self::TARGET_RATE as usize
+    }
+
+    pub fn add(&mut self, samples: &[Sample]) {
+        for sample in samples {
+            if self.is_full() {
+                return;
+            }
+
+            self.frame_sum += *sample;
+            self.channel += 1;
+            if self.channel < self.channels {
+                continue;
+            }
+            self.channel = 0;
+            self.frame_count += 1;
+            if self.frame_count < self.decimation {
+                continue;
+            }
+
+            let mono = self.frame_sum / (self.frame_count * self.channels) as f32;
+            self.frame_sum = 0.0;
+            self.frame_count = 0;
+            self.resample(mono);
+        }
+    }
+
+    /// Takes the next decimated sample, `position` being how far past the
+    /// previous one the next resampled one falls.
+    fn resample(&mut self, sample: f32) {
+        while self.position <= 1.0 {
+            let t = self.position as f32;
+            self.signal.push(self.last + (sample - self.last) * t);
+            self.resampled += 1;
+            self.position += self.step;
+            if self.signal.len() == Self::FFT_SIZE {
+                self.analyze_frame();
+                self.signal.drain(..Self::HOP);
+            }
+        }
+        self.position -= 1.0;
+        self.last = sample;
+    }
+
+    fn analyze_frame(&mut self) {
+        for (bin, (sample, window)) in self
+            .buffer
+            .iter_mut()
+            .zip(self.signal.iter().zip(self.window.iter()))
+        {
+            *bin = Complex::new(sample * window, 0.0);
+        }
+        self.fft.process(&mut self.buffer);
+
+        let mut chroma = [0.0f32; 12];
+        for (bin, value) in self.buffer[..Self::FFT_SIZE / 2].iter().enumerate() {
+            let frequency = bin as f32 * Self::TARGET_RATE as f32 / Self::FFT_SIZE as f32;
+            if !(Self::MIN_FREQUENCY..=Self::MAX_FREQUENCY).contains(&frequency) {
+                continue;
+            }
+            let note = 12.0 * (frequency / 440.0).log2() + 69.0;
+            chroma[(note.round() as i32).rem_euclid(12) as usize] += value.norm();
+        }
+
+        // loudness shouldn't matter, only the balance between pitch classes
+        let norm = chroma.iter().map(|c| c * c).sum::<f32>().sqrt();
+        if norm > 0.01 {
+            chroma.iter_mut().for_each(|c| *c /= norm);
+        } else {
+            chroma = [0.0; 12];
+        }
+        self.chroma.push(chroma);
+    }
+
+    pub fn finish(self) -> Fingerprint {
+        // smoothed over three frames, as one encoding's transients smear
+        // into the next frame in another
+        let smoothed: Vec<[f32; 12]> = (0..self.chroma.len())
+            .map(|i| {
+                let frames = &self.chroma[i.saturating_sub(1)..(i + 2).min(self.chroma.len())];
+                let mut chroma = [0.0; 12];
+                for frame in frames {
+                    for (c, value) in chroma.iter_mut().zip(frame.iter()) {
+                        *c += value / frames.len() as f32;
+                    }
+                }
+                chroma
+            })
+            .collect();
+
+        Fingerprint {
+            items: smoothed
+                .windows(2)
+                .map(|frames| sub_fingerprint(&frames[0], &frames[1]))
+                .collect(),
+        }
+    }
+
+    /// Decodes as much of what's left in `reader` as is used.
+    pub fn read(reader: &mut AudioReader) -> Result<Fingerprint, String> {
+        let spec = reader.signal_spec();
+        let mut builder = Self::new(spec.rate, spec.channels.count() as u16);
+
+        let mut sample_buffer = SampleBuffer::new(64 * 1024, spec);
+        while !builder.is_full() {
+            match reader.read_next_as_samples::<Sample>(&mut sample_buffer) {
+                Ok(_) => builder.add(sample_buffer.samples()),
+                Err(e) if e == "EOF" => break,
+                Err(e) => return Err(format!("error reading samples: {e}")),
+            }
+        }
+
+        Ok(builder.finish())
+    }
+}
+
+/// Twelve bits for which pitch classes are stronger than the next one up,
+/// twelve for which got stronger since the last frame, and eight comparing
+/// neighbouring pairs with the pair a third above.
+fn sub_fingerprint(previous: &[f32; 12], chroma: &[f32; 12]) -> u32 {
+    let mut bits = 0u32;
+    let mut bit = 0;
+    let mut push = |set: bool| {
+        bits |= (set as u32) << bit;
+        bit += 1;
+    };
+
+    for b in 0..12 {
+        push(chroma[b] > chroma[(b + 1) % 12]);
+    }
+    for b in 0..12 {
+        push(chroma[b] > previous[b]);
+    }
+    for b in 0..8 {
+        push(chroma[b] + chroma[b + 1] > chroma[(b + 4) % 12] + chroma[(b + 5) % 12]);
+    }
+
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tune of `notes` half second notes, each a root with a fifth, picked
+    /// by `seed`.
+    fn tune(seed: u64, notes: usize, sample_rate: u32, channels: u16) -> Vec<Sample> {
+        let mut state = seed;
+        let note_frames = sample_rate as usize / 2;
+        let mut samples = Vec::with_capacity(notes * note_frames * channels as usize);
+        for _ in 0..notes {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let midi = 48 + (state >> 33) % 24;
+            let frequency = 440.0 * 2f32.powf((midi as f32 - 69.0) / 12.0);
+            for i in 0..note_frames {
+                let t = i as f32 / sample_rate as f32;
+                let sample = 0.3 * (2.0 * PI * frequency * t).sin()
+                    + 0.2 * (2.0 * PI * frequency * 1.5 * t).sin();
+                for _ in 0..channels {
+                    samples.push(sample);
+                }
+            }
+        }
+        samples
+    }
+
+    fn fingerprint(samples: &[Sample], sample_rate: u32, channels: u16) -> Fingerprint {
+        let mut builder = FingerprintBuilder::new(sample_rate, channels);
+        builder.add(samples);
+        builder.finish()
+    }
+
+    #[test]
+    fn another_encoding_is_a_duplicate() {
+        let original = fingerprint(&tune(1, 60, 44100, 2), 44100, 2);
+
+        // resampled, in mono, with a little noise as a lossy encoder might add
+        let mut noise = 7u32;
+        let reencoded: Vec<Sample> = tune(1, 60, 22050, 1)
+            .into_iter()
+            .map(|sample| {
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                sample * 0.8 + (noise as f32 / u32::MAX as f32 - 0.5) * 0.01
+            })
+            .collect();
+        let reencoded = fingerprint(&reencoded, 22050, 1);
+
+        assert!(!original.is_empty());
+        assert!(original.similarity(&reencoded) >= Fingerprint::DUPLICATE_SIMILARITY);
+    }
+
+    #[test]
+    fn an_offset_copy_is_a_duplicate() {
+        let samples = tune(2, 60, 22050, 1);
+        let mut offset = vec![0.0; 22050 * 3 / 2];
+        offset.extend_from_slice(&samples);
+
+        let similarity =
+            fingerprint(&samples, 22050, 1).similarity(&fingerprint(&offset, 22050, 1));
+        assert!(similarity >= Fingerprint::DUPLICATE_SIMILARITY);
+    }
+
+    #[test]
+    fn unrelated_audio_and_silence_are_not_duplicates() {
+        let a = fingerprint(&tune(3, 60, 22050, 1), 22050, 1);
+        let b = fingerprint(&tune(4, 60, 22050, 1), 22050, 1);
+        let silence = fingerprint(&vec![0.0; 22050 * 30], 22050, 1);
+
+        assert!(a.similarity(&b) < Fingerprint::DUPLICATE_SIMILARITY - 0.1);
+        assert!(a.similarity(&silence) < Fingerprint::DUPLICATE_SIMILARITY - 0.1);
+        assert!(silence.similarity(&silence) < Fingerprint::DUPLICATE_SIMILARITY - 0.1);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let original = fingerprint(&tune(5, 20, 22050, 1), 22050, 1);
+        assert_eq!(
+            Fingerprint::from_bytes(&original.to_bytes()).unwrap(),
+            original
+        );
+        assert!(Fingerprint::from_bytes(&[0, 1, 2]).is_err());
+    }
+}
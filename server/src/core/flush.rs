@@ -0,0 +1,421 @@
+use super::{
+    drain::Drain,
+    pipeline::{AudioPipe, PipeMessage, Sample, TransformCtx},
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Coordinates flushing a running pipeline, e.g. on a seek or skip.
+///
+/// `begin` tells every stage that what it's holding is stale, and the caller
+/// then sends `PipeMessage::Flush` with the returned token after the last
+/// stale sample. Each stage discards samples until the token reaches it,
+/// resets itself and passes the token on, so once it comes out the other end
+/// every channel and every stage is clean.
+pub struct FlushControl {
+    requested: AtomicU64,
+    completed: Mutex<u64>,
+    done: Condvar,
+}
+
+impl Default for FlushControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlushControl {
+    pub fn new() -> Self {
+        Self {
+            requested: AtomicU64::new(0),
+            completed: Mutex::new(0),
+            done: Condvar::new(),
+        }
+    }
+
+    pub fn begin(&self) -> u64 {
+        self.requested.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// The most recent token handed out by `begin`.
+    pub fn requested(&self) -> u64 {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Called at the end of the pipeline once `token` has made it through.
+    pub fn complete(&self, token: u64) {
+        let mut completed = self.completed.lock().unwrap();
+        *completed = (*completed).max(token);
+        self.done.notify_all();
+    }
+
+    /// Waits for `token` to make it through. Returns false on timeout.
+    pub fn wait(&self, token: u64, timeout: Duration) -> bool {
+        let completed = self.completed.lock().unwrap();
+        let (completed, _) = self
+            .done
+            .wait_timeout_while(completed, timeout, |completed| *completed < token)
+            .unwrap();
+        *completed >= token
+    }
+}
+
+/// Runs `pipe` between two message channels. On a flush barrier the stage's
+/// input is closed so it finishes whatever it's holding, its output up to
+/// that point is thrown away, it's purged and then started again fresh.
+///
+/// The stage and what forwards its output each keep a thread for as long as
+/// the stage runs, and are handed new channels after every flush.
+pub fn run_stage(
+    pipe: Arc<dyn AudioPipe>,
+    ctx: Arc<TransformCtx>,
+    flush: Arc<FlushControl>,
+    from: Receiver<PipeMessage>,
+    to: SyncSender<PipeMessage>,
+) -> Result<(), String> {
+    // the last token to pass this stage, anything that arrives while a newer
+    // one has been requested is stale
+    let seen = Arc::new(AtomicU64::new(flush.requested()));
+
+    let stage_pipe = pipe.clone();
+    let stage_ctx = ctx.clone();
+    let stage = Worker::spawn(move |(from, to)| stage_pipe.pipe(&stage_ctx, from, to));
+
+    let out_to = to.clone();
+    let out_flush = flush.clone();
+    let out_seen = seen.clone();
+    let output = Worker::spawn(move |out_reciever: Receiver<Sample>| {
+        for sample in out_reciever {
+            if out_flush.requested() > out_seen.load(Ordering::SeqCst) {
+                continue;
+            }
+            if let Err(err) = out_to.send(PipeMessage::Sample(sample)) {
+                return Err(format!("error sending sample: {err}"));
+            }
+        }
+        Ok(())
+    });
+
+    let result = loop {
+        let (stage_sender, stage_reciever) = sync_channel(ctx.fitting_buffer);
+        let (out_sender, out_reciever) = sync_channel::<Sample>(ctx.fitting_buffer);
+        stage.start((stage_reciever, out_sender));
+        output.start(out_reciever);
+
+        let mut barrier = None;
+        for message in from.iter() {
+            match message {
+                PipeMessage::Sample(sample) => {
+                    if flush.requested() > seen.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    // a stage that stopped early reports why once finished
+                    if stage_sender.send(sample).is_err() {
+                        break;
+                    }
+                }
+                PipeMessage::Flush(token) => {
+                    barrier = Some(token);
+                    break;
+                }
+            }
+        }
+
+        drop(stage_sender);
+        let run_result = stage.finished().and(output.finished());
+
+        let token = match barrier {
+            Some(token) => token,
+            None => break run_result,
+        };
+        if let Err(err) = run_result {
+            break Err(format!("error flushing stage: {err}"));
+        }
+
+        pipe.purge();
+        seen.store(token, Ordering::SeqCst);
+        if let Err(err) = to.send(PipeMessage::Flush(token)) {
+            break Err(format!("error sending flush barrier: {err}"));
+        }
+    };
+
+    stage.close();
+    output.close();
+    result
+}
+
+/// A thread running a job for each set of channels it's handed, so a stage
+/// can start over after a flush without a new thread.
+struct Worker<T> {
+    jobs: Sender<T>,
+    results: Receiver<Result<(), String>>,
+    handle: JoinHandle<()>,
+}
+
+impl<T: Send + 'static> Worker<T> {
+    fn spawn<F>(run: F) -> Self
+    where
+        F: Fn(T) -> Result<(), String> + Send + 'static,
+    {
+        let (jobs, job_reciever) = channel::<T>();
+        let (result_sender, results) = channel();
+        let handle = thread::spawn(move || {
+            for job in job_reciever {
+                if result_sender.send(run(job)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            jobs,
+            results,
+            handle,
+        }
+    }
+
+    fn start(&self, job: T) {
+        // if the thread has gone, `finished` says so
+        let _ = self.jobs.send(job);
+    }
+
+    /// Waits for the job last started to finish.
+    fn finished(&self) -> Result<(), String> {
+        return match self.results.recv() {
+            Ok(result) => result,
+            Err(_) => Err("stage panicked".to_string()),
+        };
+    }
+
+    fn close(self) {
+        drop(self.jobs);
+        let _ = self.handle.join();
+    }
+}
+
+/// Passes messages straight through, for a pipeline without any stages.
+pub fn forward(
+    flush: Arc<FlushControl>,
+    from: Receiver<PipeMessage>,
+    to: SyncSender<PipeMessage>,
+) -> Result<(), String> {
+    let mut seen = flush.requested();
+    for message in from {
+        match message {
+            PipeMessage::Sample(_) if flush.requested() > seen => continue,
+            PipeMessage::Flush(token) => seen = token,
+            _ => (),
+        }
+        if let Err(err) = to.send(message) {
+            return Err(format!("error forwarding message: {err}"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Feeds the end of a pipeline into a drain. Barriers stop here: the drain is
+/// purged of anything it has queued and the flush is marked complete.
+pub fn feed_drain(
+    drain: Arc<dyn Drain>,
+    flush: Arc<FlushControl>,
+    from: Receiver<PipeMessage>,
+    to: SyncSender<Sample>,
+) -> Result<(), String> {
+    let mut seen = flush.requested();
+    for message in from {
+        match message {
+            PipeMessage::Sample(_) if flush.requested() > seen => (),
+            PipeMessage::Sample(sample) => {
+                if let Err(err) = to.send(sample) {
+                    return Err(format!("error sending sample: {err}"));
+                }
+            }
+            PipeMessage::Flush(token) => {
+                seen = token;
+                if let Err(err) = drain.purge() {
+                    return Err(format!("error purging drain: {err}"));
+                }
+                flush.complete(token);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{clock::PlaybackClock, drain::DrainStats};
+    use std::{sync::atomic::AtomicUsize, thread::ThreadId, time::Instant};
+
+    /// Passes samples through, counting purges and noting which thread
+    /// each run is on. With a gate it waits to be let go after its first
+    /// sample, holding on to what it's sent.
+    struct Stage {
+        gate: Mutex<Option<Receiver<()>>>,
+        purges: AtomicUsize,
+        runs: Mutex<Vec<ThreadId>>,
+    }
+
+    impl Stage {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                gate: Mutex::new(None),
+                purges: AtomicUsize::new(0),
+                runs: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn gated() -> (Arc<Self>, Sender<()>) {
+            let (sender, reciever) = channel();
+            let stage = Self::new();
+            *stage.gate.lock().unwrap() = Some(reciever);
+            (stage, sender)
+        }
+    }
+
+    impl AudioPipe for Stage {
+        fn pipe(
+            &self,
+            _ctx: &TransformCtx,
+            from: Receiver<Sample>,
+            to: SyncSender<Sample>,
+        ) -> Result<(), String> {
+            self.runs.lock().unwrap().push(thread::current().id());
+            let mut gate = self.gate.lock().unwrap().take();
+            for sample in from {
+                if to.send(sample).is_err() {
+                    return Err("output closed".to_string());
+                }
+                if let Some(gate) = gate.take() {
+                    let _ = gate.recv();
+                }
+            }
+            Ok(())
+        }
+
+        fn purge(&self) {
+            self.purges.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[derive(Default)]
+    struct PurgeCounter(AtomicUsize);
+
+    impl Drain for PurgeCounter {
+        fn init(&self, _ctx: &TransformCtx) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn drain(
+            &self,
+            _ctx: &TransformCtx,
+            _from: Receiver<Sample>,
+            _clock: Arc<PlaybackClock>,
+        ) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn purge(&self) -> Result<(), String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn stats(&self) -> DrainStats {
+            DrainStats::default()
+        }
+    }
+
+    #[test]
+    fn flush_discards_stale_samples_and_purges_every_stage() {
+        let ctx = Arc::new(TransformCtx {
+            window_size: 16,
+            fitting_buffer: 64,
+            sample_rate: 44100,
+            channels: 1,
+        });
+        let flush = Arc::new(FlushControl::new());
+        let (first, gate) = Stage::gated();
+        let second = Stage::new();
+        let drain = Arc::new(PurgeCounter::default());
+
+        let (sender, first_reciever) = sync_channel(ctx.fitting_buffer);
+        let (first_sender, second_reciever) = sync_channel(ctx.fitting_buffer);
+        let (second_sender, drain_reciever) = sync_channel(ctx.fitting_buffer);
+        let (out_sender, out_reciever) = sync_channel(ctx.fitting_buffer);
+        let handles = vec![
+            {
+                let (pipe, ctx, flush) = (first.clone(), ctx.clone(), flush.clone());
+                thread::spawn(move || run_stage(pipe, ctx, flush, first_reciever, first_sender))
+            },
+            {
+                let (pipe, ctx, flush) = (second.clone(), ctx.clone(), flush.clone());
+                thread::spawn(move || run_stage(pipe, ctx, flush, second_reciever, second_sender))
+            },
+            {
+                let (drain, flush) = (drain.clone(), flush.clone());
+                thread::spawn(move || feed_drain(drain, flush, drain_reciever, out_sender))
+            },
+        ];
+
+        // once one sample is through every stage is running, having seen
+        // there's no flush yet
+        sender.send(PipeMessage::Sample(0.0)).unwrap();
+        assert_eq!(out_reciever.recv().unwrap(), 0.0);
+
+        // all still held up in the first stage when the flush begins
+        for i in 1..=20 {
+            sender.send(PipeMessage::Sample(i as Sample)).unwrap();
+        }
+        let token = flush.begin();
+        sender.send(PipeMessage::Flush(token)).unwrap();
+        for i in 101..=105 {
+            sender.send(PipeMessage::Sample(i as Sample)).unwrap();
+        }
+        gate.send(()).unwrap();
+
+        assert!(flush.wait(token, Duration::from_secs(5)));
+        assert_eq!(first.purges.load(Ordering::SeqCst), 1);
+        assert_eq!(second.purges.load(Ordering::SeqCst), 1);
+        assert_eq!(drain.0.load(Ordering::SeqCst), 1);
+
+        drop(sender);
+        let output: Vec<Sample> = out_reciever.iter().collect();
+        assert_eq!(output, vec![101.0, 102.0, 103.0, 104.0, 105.0]);
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        // started over after the flush on the thread it started on
+        for stage in [first, second] {
+            let runs = stage.runs.lock().unwrap();
+            assert_eq!(runs.len(), 2);
+            assert_eq!(runs[0], runs[1]);
+        }
+    }
+
+    #[test]
+    fn wait_times_out_until_the_token_completes() {
+        let flush = FlushControl::new();
+        let first = flush.begin();
+        let second = flush.begin();
+
+        let started = Instant::now();
+        assert!(!flush.wait(first, Duration::from_millis(20)));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        // a later token completing covers the earlier ones
+        flush.complete(second);
+        assert!(flush.wait(first, Duration::from_millis(20)));
+        assert!(flush.wait(second, Duration::from_millis(20)));
+    }
+}
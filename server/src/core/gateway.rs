@@ -1,31 +1,102 @@
-use crate::fs_provider::FsAudioProvider;
+use super::{
+    audio::AudioReader,
+    fingerprint::Fingerprint,
+    loudness::LoudnessSummary,
+    params::{ParamError, ParamStatus, PipelineControls, StageStatus},
+    playback::{ObservablePlaybackState, PlaybackEvent, PlaybackStatus},
+    playlist::{self, Playlist, PlaylistChanges, PlaylistFormat, PlaylistImport},
+    provider::{ProviderError, ReadableProvider, WriteableProvider},
+    volume::{ReplayGainMode, VolumeStatus},
+};
+use crate::{
+    art,
+    browse::{
+        self, AlbumFilter, AlbumSort, AlbumSummary, ArtistSort, ArtistSummary, GenreSort,
+        GenreSummary, Order, Page, PageRequest,
+    },
+    duplicates::{self, DuplicateGroup, DuplicateMatch},
+    fs_provider::FsAudioProvider,
+    history::{ArtistCount, Period, Play, PlaySource, PlaySummary, TrackCount},
+    library::{Library, LibraryError, LibraryEvent, Track, TrackQuery},
+    search::{AlbumKey, SearchResults},
+};
 use axum::{
+    body::Bytes,
     extract::{Query, State, BodyStream},
-    routing::{get, put},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post, put},
     Json, Router, Server,
 };
-use hyper::StatusCode;
-use symphonia::core::io::ReadOnlySource;
+use hyper::{header, StatusCode};
 use std::{
     collections::HashMap,
+    convert::Infallible,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
 
-struct HttpGateway {
+pub struct HttpGateway {
     provider: Arc<FsAudioProvider>,
+    library: Arc<RwLock<Library>>,
+    playback: Arc<Mutex<ObservablePlaybackState>>,
+    playback_events: broadcast::Sender<PlaybackEvent>,
+    library_events: broadcast::Sender<LibraryEvent>,
+    pipeline_controls: Arc<PipelineControls>,
 }
 
 struct GatewayHandlerState {
     provider: Arc<FsAudioProvider>,
+    library: Arc<RwLock<Library>>,
+    playback: Arc<Mutex<ObservablePlaybackState>>,
+    playback_events: broadcast::Sender<PlaybackEvent>,
+    library_events: broadcast::Sender<LibraryEvent>,
+    pipeline_controls: Arc<PipelineControls>,
 }
 
 type SharedGatewayHandlerState = State<Arc<GatewayHandlerState>>;
 
 impl HttpGateway {
-    pub fn new(provider: FsAudioProvider) -> Self {
+    const EVENT_BUFFER: usize = 256;
+    const PAGE_LIMIT: usize = 50;
+    const MAX_PAGE_LIMIT: usize = 500;
+    const MAX_UPLOAD_BYTES: usize = 1024 * 1024 * 1024;
+
+    pub fn new(
+        provider: Arc<FsAudioProvider>,
+        library: Arc<RwLock<Library>>,
+        playback: Arc<Mutex<ObservablePlaybackState>>,
+        pipeline_controls: Arc<PipelineControls>,
+    ) -> Self {
+        let (playback_events, _) = broadcast::channel(Self::EVENT_BUFFER);
+        let (library_events, _) = broadcast::channel(Self::EVENT_BUFFER);
+        let event_sender = playback_events.clone();
+        playback.lock().unwrap().on_event(move |event| {
+            // nobody listening is fine, events aren't buffered for later
+            let _ = event_sender.send(event);
+        });
+
         Self {
-            provider: Arc::new(provider),
+            provider,
+            library,
+            playback,
+            playback_events,
+            library_events,
+            pipeline_controls,
+        }
+    }
+
+    /// For whatever keeps the library up to date to report changes through.
+    pub fn library_listener(&self) -> impl Fn(LibraryEvent) + Send + 'static {
+        let sender = self.library_events.clone();
+        move |event| {
+            // nobody listening is fine, events aren't buffered for later
+            let _ = sender.send(event);
         }
     }
 
@@ -33,10 +104,51 @@ impl HttpGateway {
         let address = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port);
         let handler_ctx = Arc::new(GatewayHandlerState {
             provider: self.provider.clone(),
+            library: self.library.clone(),
+            playback: self.playback.clone(),
+            playback_events: self.playback_events.clone(),
+            library_events: self.library_events.clone(),
+            pipeline_controls: self.pipeline_controls.clone(),
         });
         let service = Router::new()
             .route("/audio", get(http_get_audio))
-            .route("/audio", put(http_get_track_stream))
+            .route("/audio", post(http_upload_audio))
+            .route("/audio", delete(http_delete_audio))
+            .route("/audio/loudness", get(http_get_loudness))
+            .route("/duplicates", get(http_get_duplicates))
+            .route("/playback", get(http_get_playback))
+            .route("/playback/events", get(http_get_playback_events))
+            .route("/playback/playing", put(http_set_playing))
+            .route("/playback/seek", put(http_seek))
+            .route("/playback/volume", put(http_set_volume))
+            .route("/playback/skip", put(http_skip))
+            .route("/history", get(http_get_history))
+            .route("/stats/tracks", get(http_get_top_tracks))
+            .route("/stats/artists", get(http_get_top_artists))
+            .route("/stats/summary", get(http_get_play_summary))
+            .route("/library/events", get(http_get_library_events))
+            .route("/track", get(http_get_track))
+            .route("/track/stream", get(http_get_track_stream))
+            .route("/tracks", get(http_get_tracks))
+            .route("/tracks/recent", get(http_get_recent_tracks))
+            .route("/artists", get(http_get_artists))
+            .route("/albums", get(http_get_albums))
+            .route("/album", get(http_get_album))
+            .route("/genres", get(http_get_genres))
+            .route("/art", get(http_get_art))
+            .route("/search", get(http_search))
+            .route("/playlists", get(http_get_playlists))
+            .route("/playlists", post(http_create_playlist))
+            .route("/playlist", get(http_get_playlist))
+            .route("/playlist", put(http_update_playlist))
+            .route("/playlist", delete(http_delete_playlist))
+            .route("/playlist/tracks", get(http_get_playlist_tracks))
+            .route("/playlist/export", get(http_export_playlist))
+            .route("/playlist/import", post(http_import_playlist))
+            .route("/playlist/enqueue", put(http_enqueue_playlist))
+            .route("/pipeline", get(http_get_pipeline))
+            .route("/pipeline/param", put(http_set_pipeline_param))
+            .route("/waveform", get(http_get_waveform))
             .with_state(handler_ctx)
             .into_make_service();
 
@@ -45,31 +157,191 @@ impl HttpGateway {
     }
 }
 
+/// Ids audio is stored under. Kept to what's safe in a file name, for
+/// anything still naming files after them.
+fn audio_id_param(params: &HashMap<String, String>) -> Result<String, StatusCode> {
+    return match params.get("id") {
+        Some(id)
+            if !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            Ok(id.clone())
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    };
+}
+
+/// Why an upload was turned away.
+enum UploadError {
+    Status(StatusCode),
+    Duplicate(Vec<DuplicateMatch>),
+}
+
+impl From<StatusCode> for UploadError {
+    fn from(status: StatusCode) -> Self {
+        UploadError::Status(status)
+    }
+}
+
+impl From<ProviderError> for UploadError {
+    fn from(e: ProviderError) -> Self {
+        UploadError::Status(provider_status(e))
+    }
+}
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        match self {
+            UploadError::Status(status) => status.into_response(),
+            UploadError::Duplicate(matches) => {
+                (StatusCode::CONFLICT, Json(matches)).into_response()
+            }
+        }
+    }
+}
+
+/// Stores the audio in the body under `id`, in any format symphonia reads,
+/// with 201 if it's new and 200 if it replaced what was there. With
+/// `reject_duplicates=true`, audio that sounds like a library track or audio
+/// already stored is turned away with 409 and what it duplicates.
 async fn http_upload_audio(
     State(state): SharedGatewayHandlerState,
     Query(params): Query<HashMap<String, String>>,
-    mut stream: BodyStream
+    mut stream: BodyStream,
+) -> Result<StatusCode, UploadError> {
+    let id = audio_id_param(&params)?;
+    let reject_duplicates = match params.get("reject_duplicates").map(|r| r.parse::<bool>()) {
+        Some(Ok(reject)) => reject,
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+        None => false,
+    };
+
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if body.len() + chunk.len() > HttpGateway::MAX_UPLOAD_BYTES {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    // decoding a whole track is too long to hold up the runtime for
+    let result = tokio::task::spawn_blocking(move || {
+        let reader = match AudioReader::from_bytes(body) {
+            Ok(reader) => reader,
+            Err(_) => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into()),
+        };
+        let duration_ms = reader
+            .n_frames()
+            .map(|frames| frames * 1000 / reader.signal_spec().rate.max(1) as u64);
+        let replacing = state.provider.contains_audio(&id);
+        state
+            .provider
+            .set_checked(&id, reader, |fingerprint| match reject_duplicates {
+                true => check_duplicates(&state, &id, fingerprint, duration_ms),
+                false => Ok(()),
+            })?;
+
+        return match replacing {
+            true => Ok(StatusCode::OK),
+            false => Ok(StatusCode::CREATED),
+        };
+    })
+    .await;
+
+    return match result {
+        Ok(result) => result,
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+    };
+}
+
+/// Stops `id` referring to its audio. The audio itself is only deleted if no
+/// other id refers to the same.
+async fn http_delete_audio(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
 ) -> Result<(), StatusCode> {
-    let id = match params.get("id") {
-        Some(id) => id,
-        None => return Err(StatusCode::BAD_REQUEST),
+    let id = audio_id_param(&params)?;
+    state.provider.delete_audio(&id).map_err(provider_status)
+}
+
+/// How loud uploaded audio measured when it was stored, along with the
+/// ReplayGain to play it back at.
+async fn http_get_loudness(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<LoudnessSummary>, StatusCode> {
+    let id = audio_id_param(&params)?;
+    return match state.provider.loudness(&id) {
+        Ok(Some(analysis)) => Ok(Json(analysis.summary())),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => Err(provider_status(e)),
     };
+}
 
-    
+/// Turns uploaded audio away if it's a near duplicate of anything but what
+/// it's replacing.
+fn check_duplicates(
+    state: &GatewayHandlerState,
+    id: &str,
+    fingerprint: &Fingerprint,
+    duration_ms: Option<u64>,
+) -> Result<(), UploadError> {
+    let mut stored = state.provider.fingerprints().map_err(provider_status)?;
+    stored.retain(|(stored_id, _)| stored_id != id);
+    let matches = duplicates::matches(
+        fingerprint,
+        duration_ms,
+        &state.library.read().unwrap(),
+        &stored,
+        Fingerprint::DUPLICATE_SIMILARITY,
+    )
+    .map_err(library_status)?;
 
-    Ok(())
+    return match matches.is_empty() {
+        true => Ok(()),
+        false => Err(UploadError::Duplicate(matches)),
+    };
 }
 
-async fn http_get_audio(
+/// Library tracks that sound like the same recording. `threshold` is how
+/// alike, from 0 to 1, they have to be.
+async fn http_get_duplicates(
     State(state): SharedGatewayHandlerState,
     Query(params): Query<HashMap<String, String>>,
-) -> Result<Vec<u8>, StatusCode> {
-    let id = match params.get("id") {
-        Some(id) => id,
-        None => return Err(StatusCode::BAD_REQUEST),
+) -> Result<Json<Vec<DuplicateGroup>>, StatusCode> {
+    let threshold = match params.get("threshold").map(|t| t.parse::<f32>()) {
+        Some(Ok(threshold)) if (0.0..=1.0).contains(&threshold) => threshold,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => Fingerprint::DUPLICATE_SIMILARITY,
     };
 
-    todo!()
+    let library = state.library.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        duplicates::groups(&library.read().unwrap(), threshold)
+    })
+    .await;
+    return match result {
+        Ok(groups) => groups.map(Json).map_err(library_status),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+}
+
+/// The audio stored under `id`, as the wav it was stored as.
+async fn http_get_audio(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, StatusCode> {
+    let id = audio_id_param(&params)?;
+
+    let result = tokio::task::spawn_blocking(move || state.provider.audio_bytes(&id)).await;
+    return match result {
+        Ok(Ok(contents)) => Ok(([(header::CONTENT_TYPE, "audio/wav")], contents).into_response()),
+        Ok(Err(e)) => Err(provider_status(e)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
 }
 
 async fn http_get_track(
@@ -86,12 +358,587 @@ async fn http_get_track(
         Err(_) => return Err(StatusCode::BAD_REQUEST),
     };
 
-    return match state.pipeline.library.read().unwrap().get_track(id) {
+    return match state.library.read().unwrap().get_track(id) {
         Ok(track) => Ok(Json((*track).clone())),
         Err(_) => Err(StatusCode::NOT_FOUND),
     };
 }
 
+async fn http_get_tracks(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<Track>>, StatusCode> {
+    let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => Some(limit),
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+
+    let offset = match params.get("offset").map(|o| o.parse::<usize>()) {
+        Some(Ok(offset)) => offset,
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        None => 0,
+    };
+
+    let query = TrackQuery {
+        artist: params.get("artist").cloned(),
+        album: params.get("album").cloned(),
+        genre: params.get("genre").cloned(),
+        limit,
+        offset,
+    };
+    return match state.library.read().unwrap().query(&query) {
+        Ok(tracks) => Ok(Json(tracks.iter().map(|track| (**track).clone()).collect())),
+        Err(e) => {
+            eprintln!("{e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+}
+
+/// The `cursor`, `limit` and `order` parameters every listing takes.
+fn page_request(
+    params: &HashMap<String, String>,
+    default_order: Order,
+) -> Result<PageRequest, StatusCode> {
+    let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) if (1..=HttpGateway::MAX_PAGE_LIMIT).contains(&limit) => limit,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => HttpGateway::PAGE_LIMIT,
+    };
+
+    let order = match params.get("order").map(|o| o.as_str()) {
+        Some("asc") => Order::Ascending,
+        Some("desc") => Order::Descending,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => default_order,
+    };
+
+    Ok(PageRequest {
+        cursor: params.get("cursor").cloned(),
+        limit,
+        order,
+    })
+}
+
+async fn http_get_recent_tracks(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Page<Track>>, StatusCode> {
+    let request = page_request(&params, Order::Descending)?;
+
+    let tracks = state
+        .library
+        .read()
+        .unwrap()
+        .tracks()
+        .map(|track| (**track).clone())
+        .collect();
+    return match browse::paginate(tracks, browse::recent_key, &request) {
+        Ok(page) => Ok(Json(page)),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    };
+}
+
+async fn http_get_artists(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Page<ArtistSummary>>, StatusCode> {
+    let (sort, order) = match params.get("sort").map(|s| s.as_str()) {
+        None | Some("name") => (ArtistSort::Name, Order::Ascending),
+        Some("track_count") => (ArtistSort::TrackCount, Order::Descending),
+        Some("album_count") => (ArtistSort::AlbumCount, Order::Descending),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let request = page_request(&params, order)?;
+
+    let library = state.library.read().unwrap();
+    let artists = browse::artists(library.tracks().map(|track| &**track));
+    return match browse::paginate(artists, |artist| artist.sort_key(sort), &request) {
+        Ok(page) => Ok(Json(page)),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    };
+}
+
+async fn http_get_albums(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Page<AlbumSummary>>, StatusCode> {
+    let (sort, order) = match params.get("sort").map(|s| s.as_str()) {
+        None | Some("name") => (AlbumSort::Name, Order::Ascending),
+        Some("artist") => (AlbumSort::Artist, Order::Ascending),
+        Some("year") => (AlbumSort::Year, Order::Descending),
+        Some("added") => (AlbumSort::Added, Order::Descending),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let request = page_request(&params, order)?;
+
+    let filter = AlbumFilter {
+        artist: params.get("artist").cloned(),
+        genre: params.get("genre").cloned(),
+    };
+    let library = state.library.read().unwrap();
+    let albums = browse::albums(library.tracks().map(|track| &**track), &filter);
+    return match browse::paginate(albums, |album| album.sort_key(sort), &request) {
+        Ok(page) => Ok(Json(page)),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    };
+}
+
+async fn http_get_album(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<browse::Album>, StatusCode> {
+    let album = match params.get("album") {
+        Some(album) => album,
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let key = AlbumKey {
+        artist: params.get("artist").cloned(),
+        album: album.clone(),
+    };
+    let library = state.library.read().unwrap();
+    return match browse::album(library.tracks().map(|track| &**track), &key) {
+        Some(album) => Ok(Json(album)),
+        None => Err(StatusCode::NOT_FOUND),
+    };
+}
+
+async fn http_get_genres(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Page<GenreSummary>>, StatusCode> {
+    let (sort, order) = match params.get("sort").map(|s| s.as_str()) {
+        None | Some("name") => (GenreSort::Name, Order::Ascending),
+        Some("track_count") => (GenreSort::TrackCount, Order::Descending),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let request = page_request(&params, order)?;
+
+    let library = state.library.read().unwrap();
+    let genres = browse::genres(library.tracks().map(|track| &**track));
+    return match browse::paginate(genres, |genre| genre.sort_key(sort), &request) {
+        Ok(page) => Ok(Json(page)),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    };
+}
+
+async fn http_get_art(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, StatusCode> {
+    let id_str = match params.get("id") {
+        Some(id) => id,
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let id = match u64::from_str_radix(id_str, 16) {
+        Ok(id) => id,
+        Err(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let path = match state.library.read().unwrap().get_track(id) {
+        Ok(track) => track.path.clone(),
+        Err(_) => return Err(StatusCode::NOT_FOUND),
+    };
+    // embedded art means probing the file, which blocks
+    let artwork = tokio::task::spawn_blocking(move || art::read(&path));
+    return match artwork.await {
+        Ok(Ok(Some(artwork))) => {
+            Ok(([(header::CONTENT_TYPE, artwork.media_type)], artwork.data).into_response())
+        }
+        Ok(Ok(None)) => Err(StatusCode::NOT_FOUND),
+        Ok(Err(e)) => {
+            eprintln!("{e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+}
+
+fn hex_id_param(params: &HashMap<String, String>) -> Result<u64, StatusCode> {
+    let id_str = match params.get("id") {
+        Some(id) => id,
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    return match u64::from_str_radix(id_str, 16) {
+        Ok(id) => Ok(id),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    };
+}
+
+fn provider_status(e: ProviderError) -> StatusCode {
+    return match e {
+        ProviderError::NotFound => StatusCode::NOT_FOUND,
+        ProviderError::Other(message) => {
+            eprintln!("{message}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+}
+
+/// Smart playlists come with their tracks as of now, in case the refresher
+/// hasn't got to them yet.
+fn get_playlist(state: &GatewayHandlerState, id: u64) -> Result<Playlist, StatusCode> {
+    let mut playlist = ReadableProvider::<Playlist>::get(&*state.provider, &format!("{id:x}"))
+        .map_err(provider_status)?;
+    playlist.refresh(&state.library.read().unwrap());
+    Ok(playlist)
+}
+
+fn set_playlist(
+    state: &GatewayHandlerState,
+    mut playlist: Playlist,
+) -> Result<Playlist, StatusCode> {
+    playlist.refresh(&state.library.read().unwrap());
+    let id = format!("{:x}", playlist.id);
+    return match state.provider.set(&id, playlist.clone()) {
+        Ok(_) => Ok(playlist),
+        Err(e) => Err(provider_status(e)),
+    };
+}
+
+/// Playlists can only be made of what's in the library, and smart ones
+/// only of what their rules find.
+fn check_changes(
+    state: &GatewayHandlerState,
+    changes: &PlaylistChanges,
+    smart: bool,
+) -> Result<(), StatusCode> {
+    if let Some(Some(rules)) = changes.rules.as_ref() {
+        if let Err(e) = rules.validate() {
+            eprintln!("not saving smart playlist: {e}");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    let tracks = match changes.tracks.as_ref() {
+        Some(_) if smart => return Err(StatusCode::BAD_REQUEST),
+        Some(tracks) => tracks,
+        None => return Ok(()),
+    };
+    let library = state.library.read().unwrap();
+    return match tracks.iter().all(|id| library.get_track(*id).is_ok()) {
+        true => Ok(()),
+        false => Err(StatusCode::BAD_REQUEST),
+    };
+}
+
+async fn http_get_playlists(
+    State(state): SharedGatewayHandlerState,
+) -> Result<Json<Vec<Playlist>>, StatusCode> {
+    let mut playlists = state.provider.playlists().map_err(provider_status)?;
+    let library = state.library.read().unwrap();
+    for playlist in playlists.iter_mut() {
+        playlist.refresh(&library);
+    }
+    playlists.sort_by_key(|playlist| playlist.name.to_lowercase());
+    Ok(Json(playlists))
+}
+
+async fn http_create_playlist(
+    State(state): SharedGatewayHandlerState,
+    Json(changes): Json<PlaylistChanges>,
+) -> Result<Json<Playlist>, StatusCode> {
+    let mut playlist = match changes.name.clone() {
+        Some(name) => Playlist::new(name),
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+    let smart = matches!(changes.rules, Some(Some(_)));
+    check_changes(&state, &changes, smart)?;
+
+    changes.apply(&mut playlist);
+    Ok(Json(set_playlist(&state, playlist)?))
+}
+
+async fn http_get_playlist(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Playlist>, StatusCode> {
+    let id = hex_id_param(&params)?;
+    Ok(Json(get_playlist(&state, id)?))
+}
+
+async fn http_update_playlist(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+    Json(changes): Json<PlaylistChanges>,
+) -> Result<Json<Playlist>, StatusCode> {
+    let id = hex_id_param(&params)?;
+    let mut playlist = get_playlist(&state, id)?;
+    let smart = match changes.rules.as_ref() {
+        Some(rules) => rules.is_some(),
+        None => playlist.rules.is_some(),
+    };
+    check_changes(&state, &changes, smart)?;
+
+    changes.apply(&mut playlist);
+    Ok(Json(set_playlist(&state, playlist)?))
+}
+
+async fn http_delete_playlist(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<(), StatusCode> {
+    let id = hex_id_param(&params)?;
+    state
+        .provider
+        .delete_playlist(&format!("{id:x}"))
+        .map_err(provider_status)
+}
+
+async fn http_get_playlist_tracks(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<Track>>, StatusCode> {
+    let id = hex_id_param(&params)?;
+    let playlist = get_playlist(&state, id)?;
+
+    let tracks = playlist.resolve(&state.library.read().unwrap());
+    Ok(Json(tracks.iter().map(|track| (**track).clone()).collect()))
+}
+
+async fn http_export_playlist(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, StatusCode> {
+    let id = hex_id_param(&params)?;
+    let (format, extension) = match params.get("format").map(|f| f.as_str()) {
+        None | Some("m3u8") => (PlaylistFormat::M3u8, "m3u8"),
+        Some("m3u") => (PlaylistFormat::M3u, "m3u"),
+        Some("xspf") => (PlaylistFormat::Xspf, "xspf"),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let playlist = get_playlist(&state, id)?;
+
+    let tracks = playlist.resolve(&state.library.read().unwrap());
+    // quotes and anything outside ASCII don't belong in a header
+    let file_name: String = playlist
+        .name
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || " -_.()".contains(c) {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.media_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}.{extension}\""),
+            ),
+        ],
+        playlist::export(&playlist, &tracks, format),
+    )
+        .into_response())
+}
+
+async fn http_import_playlist(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> Result<Json<PlaylistImport>, StatusCode> {
+    let format = match params.get("format").map(|f| f.as_str()) {
+        None => PlaylistFormat::guess(&body),
+        Some("m3u") => PlaylistFormat::M3u,
+        Some("m3u8") => PlaylistFormat::M3u8,
+        Some("xspf") => PlaylistFormat::Xspf,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let parsed = match playlist::parse(&body, format) {
+        Ok(parsed) => parsed,
+        Err(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let (tracks, unmatched) =
+        playlist::resolve_entries(&parsed.entries, &state.library.read().unwrap());
+
+    let name = params
+        .get("name")
+        .cloned()
+        .or(parsed.name)
+        .unwrap_or_else(|| "Imported playlist".to_string());
+    let mut playlist = Playlist::new(name);
+    playlist.description = parsed.description;
+    playlist.tracks = tracks;
+
+    let playlist = set_playlist(&state, playlist)?;
+    Ok(Json(PlaylistImport {
+        playlist,
+        unmatched,
+    }))
+}
+
+async fn http_enqueue_playlist(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<PlaybackStatus>, StatusCode> {
+    let id = hex_id_param(&params)?;
+    let next = match params.get("position").map(|p| p.as_str()) {
+        None | Some("end") => false,
+        Some("next") => true,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let playlist = get_playlist(&state, id)?;
+
+    let tracks = playlist.resolve(&state.library.read().unwrap());
+    let mut playback = state.playback.lock().unwrap();
+    let offset = match next {
+        true => 0,
+        false => playback.queue().len(),
+    };
+    playback.enqueue_all(&tracks, offset, PlaySource::Playlist { id });
+    Ok(Json(playback.status()))
+}
+
+/// Plays or pauses, as `playing` says.
+async fn http_set_playing(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<PlaybackStatus>, StatusCode> {
+    let playing = match params.get("playing").map(|p| p.parse::<bool>()) {
+        Some(Ok(playing)) => playing,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let mut playback = state.playback.lock().unwrap();
+    playback.set_playing(playing);
+    Ok(Json(playback.status()))
+}
+
+/// Seeks within the current track, to `position_ms` from the start.
+async fn http_seek(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<PlaybackStatus>, StatusCode> {
+    let position = match params.get("position_ms").map(|p| p.parse::<u64>()) {
+        Some(Ok(position)) => Duration::from_millis(position),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let mut playback = state.playback.lock().unwrap();
+    if playback.current_track().is_none() {
+        return Err(StatusCode::CONFLICT);
+    }
+    if let Some(duration) = playback.duration() {
+        if position > duration {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+    playback.seek(position);
+    Ok(Json(playback.status()))
+}
+
+/// Moves `by` tracks through the session, back for negative, counting as
+/// a skip of the current track.
+async fn http_skip(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<PlaybackStatus>, StatusCode> {
+    let by = match params.get("by").map(|by| by.parse::<i32>()) {
+        Some(Ok(by)) => by,
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        None => 1,
+    };
+
+    let mut playback = state.playback.lock().unwrap();
+    playback.skip(by);
+    Ok(Json(playback.status()))
+}
+
+fn library_status(e: LibraryError) -> StatusCode {
+    eprintln!("{e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// The `period` and `limit` parameters the stats take, a month's worth
+/// by default.
+fn stats_params(params: &HashMap<String, String>) -> Result<(Period, usize), StatusCode> {
+    let period = match params.get("period").map(|period| Period::parse(period)) {
+        Some(Some(period)) => period,
+        Some(None) => return Err(StatusCode::BAD_REQUEST),
+        None => Period::Month,
+    };
+    let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit.min(HttpGateway::MAX_PAGE_LIMIT),
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        None => 20,
+    };
+
+    Ok((period, limit))
+}
+
+/// Plays newest first. `before` is the `played_at` of the last play of the
+/// previous page.
+async fn http_get_history(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<Play>>, StatusCode> {
+    let before = match params.get("before").map(|b| b.parse::<u64>()) {
+        Some(Ok(before)) => Some(before),
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+    let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit.min(HttpGateway::MAX_PAGE_LIMIT),
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        None => HttpGateway::PAGE_LIMIT,
+    };
+
+    let plays = state.library.read().unwrap().plays(before, limit);
+    plays.map(Json).map_err(library_status)
+}
+
+async fn http_get_top_tracks(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<TrackCount>>, StatusCode> {
+    let (period, limit) = stats_params(&params)?;
+    let tracks = state.library.read().unwrap().top_tracks(period, limit);
+    tracks.map(Json).map_err(library_status)
+}
+
+async fn http_get_top_artists(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<ArtistCount>>, StatusCode> {
+    let (period, limit) = stats_params(&params)?;
+    let artists = state.library.read().unwrap().top_artists(period, limit);
+    artists.map(Json).map_err(library_status)
+}
+
+async fn http_get_play_summary(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<PlaySummary>, StatusCode> {
+    let (period, _) = stats_params(&params)?;
+    let summary = state.library.read().unwrap().play_summary(period);
+    summary.map(Json).map_err(library_status)
+}
+
+async fn http_search(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<SearchResults>, StatusCode> {
+    let query = match params.get("q") {
+        Some(query) => query,
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit.min(HttpGateway::MAX_PAGE_LIMIT),
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        None => 20,
+    };
+
+    Ok(Json(state.library.read().unwrap().search(query, limit)))
+}
+
 async fn http_get_track_stream(
     State(state): SharedGatewayHandlerState,
     Query(params): Query<HashMap<String, String>>,
@@ -106,8 +953,239 @@ async fn http_get_track_stream(
         Err(_) => return Err(StatusCode::BAD_REQUEST),
     };
 
-    return match state.pipeline.library.read().unwrap().get_track_source(id) {
+    return match state.library.read().unwrap().get_track_source(id) {
         Ok(stream) => Ok(stream),
         Err(_) => Err(StatusCode::NOT_FOUND),
     };
 }
+
+async fn http_get_playback(
+    State(state): SharedGatewayHandlerState,
+) -> Result<Json<PlaybackStatus>, StatusCode> {
+    Ok(Json(state.playback.lock().unwrap().status()))
+}
+
+async fn http_get_playback_events(
+    State(state): SharedGatewayHandlerState,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = BroadcastStream::new(state.playback_events.subscribe()).filter_map(|event| {
+        // a lagging client just misses the events it fell behind on
+        match event {
+            Ok(event) => Event::default().json_data(event).ok().map(Ok),
+            Err(_) => None,
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn http_get_library_events(
+    State(state): SharedGatewayHandlerState,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = BroadcastStream::new(state.library_events.subscribe()).filter_map(|event| {
+        // a client that lagged should reload the library rather than trust
+        // the events it did get
+        match event {
+            Ok(event) => Event::default().json_data(event).ok().map(Ok),
+            Err(_) => Some(Ok(Event::default().event("lagged").data(""))),
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn http_set_volume(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<VolumeStatus>, StatusCode> {
+    let volume = match params.get("volume").map(|v| v.parse::<f32>()) {
+        Some(Ok(volume)) if (0.0..=1.0).contains(&volume) => Some(volume),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+    let muted = match params.get("muted").map(|m| m.parse::<bool>()) {
+        Some(Ok(muted)) => Some(muted),
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+    let replay_gain = match params.get("replay_gain").map(|m| m.as_str()) {
+        Some("off") => Some(ReplayGainMode::Off),
+        Some("track") => Some(ReplayGainMode::Track),
+        Some("album") => Some(ReplayGainMode::Album),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+
+    let mut playback = state.playback.lock().unwrap();
+    if let Some(volume) = volume {
+        playback.set_volume(volume);
+    }
+    if let Some(muted) = muted {
+        playback.set_muted(muted);
+    }
+    if let Some(replay_gain) = replay_gain {
+        playback.set_replay_gain_mode(replay_gain);
+    }
+
+    Ok(Json(playback.volume_status()))
+}
+
+async fn http_get_pipeline(State(state): SharedGatewayHandlerState) -> Json<Vec<StageStatus>> {
+    Json(state.pipeline_controls.status())
+}
+
+async fn http_set_pipeline_param(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ParamStatus>, StatusCode> {
+    let (stage, param) = match (params.get("stage"), params.get("param")) {
+        (Some(stage), Some(param)) => (stage, param),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    let value = match params.get("value").map(|v| v.parse::<f32>()) {
+        Some(Ok(value)) => value,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    return match state.pipeline_controls.set(stage, param, value) {
+        Ok(status) => Ok(Json(status)),
+        Err(ParamError::UnknownStage(_) | ParamError::UnknownParam(_)) => {
+            Err(StatusCode::NOT_FOUND)
+        }
+        Err(ParamError::OutOfRange { .. }) => Err(StatusCode::BAD_REQUEST),
+    };
+}
+
+async fn http_get_waveform(
+    State(state): SharedGatewayHandlerState,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, StatusCode> {
+    // library tracks first, then audio stored with the provider
+    let track = match params.get("id").map(|id| u64::from_str_radix(id, 16)) {
+        Some(Ok(id)) => state.library.read().unwrap().get_track(id).ok(),
+        _ => None,
+    };
+
+    let points = match params.get("points").map(|p| p.parse::<usize>()) {
+        Some(Ok(points)) if points > 0 => Some(points),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+
+    let json = match params.get("format").map(|f| f.as_str()) {
+        None | Some("binary") => false,
+        Some("json") => true,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    // audio without a cached waveform gets decoded here, which shouldn't
+    // hold up the runtime
+    let provider = state.provider.clone();
+    let waveform = match track {
+        Some(track) => {
+            tokio::task::spawn_blocking(move || provider.track_waveform(track.id, &track.path))
+        }
+        None => {
+            let id = audio_id_param(&params)?;
+            tokio::task::spawn_blocking(move || provider.waveform(&id))
+        }
+    };
+    let waveform = match waveform.await {
+        Ok(Ok(waveform)) => waveform,
+        Ok(Err(e)) => return Err(provider_status(e)),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let waveform = match points {
+        Some(points) => waveform.resample(points),
+        None => waveform,
+    };
+
+    return match json {
+        true => Ok(Json(waveform).into_response()),
+        false => Ok((
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            waveform.to_bytes(),
+        )
+            .into_response()),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn handler_state() -> Arc<GatewayHandlerState> {
+        let (playback_events, _) = broadcast::channel(HttpGateway::EVENT_BUFFER);
+        let (library_events, _) = broadcast::channel(HttpGateway::EVENT_BUFFER);
+        Arc::new(GatewayHandlerState {
+            provider: Arc::new(FsAudioProvider::new("./public")),
+            library: Arc::new(RwLock::new(Library::open_in_memory().unwrap())),
+            playback: Arc::new(Mutex::new(ObservablePlaybackState::new())),
+            playback_events,
+            library_events,
+            pipeline_controls: Arc::new(PipelineControls::new()),
+        })
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Query<HashMap<String, String>> {
+        Query(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn play_and_seek_reach_the_player() {
+        let state = handler_state();
+        let (sender, reciever) = channel();
+        {
+            let mut playback = state.playback.lock().unwrap();
+            let playing_sender = sender.clone();
+            playback.on_is_playing_changed(move |playing| {
+                playing_sender.send(format!("playing {playing}")).unwrap()
+            });
+            playback.on_seek(move |position| {
+                sender
+                    .send(format!("seek {}", position.as_millis()))
+                    .unwrap()
+            });
+        }
+
+        // nothing to seek in yet
+        let result = http_seek(State(state.clone()), params(&[("position_ms", "1000")])).await;
+        assert_eq!(result.err(), Some(StatusCode::CONFLICT));
+
+        let track = Track {
+            id: 1,
+            title: "one".to_string(),
+            ..Default::default()
+        };
+        state.playback.lock().unwrap().enqueue(Arc::new(track), 0);
+
+        let status = http_set_playing(State(state.clone()), params(&[("playing", "true")]))
+            .await
+            .unwrap();
+        assert!(status.is_playing);
+        let status = http_seek(State(state.clone()), params(&[("position_ms", "1500")]))
+            .await
+            .unwrap();
+        assert_eq!(status.current_track.as_ref().map(|track| track.id), Some(1));
+        let status = http_set_playing(State(state.clone()), params(&[("playing", "false")]))
+            .await
+            .unwrap();
+        assert!(!status.is_playing);
+
+        let result = http_set_playing(State(state.clone()), params(&[("playing", "yes")])).await;
+        assert_eq!(result.err(), Some(StatusCode::BAD_REQUEST));
+        let result = http_seek(State(state.clone()), params(&[])).await;
+        assert_eq!(result.err(), Some(StatusCode::BAD_REQUEST));
+
+        assert_eq!(
+            reciever.try_iter().collect::<Vec<_>>(),
+            vec!["playing true", "seek 1500", "playing false"]
+        );
+    }
+}
@@ -0,0 +1,900 @@
+use super::{
+    clock::PlaybackClock,
+    drain::Drain,
+    pipeline::{AudioPipe, PipeHandle, Sample, TransformCtx},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+/// What happens to a branch when one of its stages fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// The branch ends at the failed stage. Sibling branches and the rest of
+    /// the graph keep running.
+    #[default]
+    Stop,
+    /// The failed stage is taken out and its input passed through untouched.
+    Bypass,
+}
+
+enum NodeKind {
+    Input,
+    Pipe(Arc<dyn AudioPipe>, ErrorPolicy),
+    Bus,
+    Drain(Arc<dyn Drain>, Arc<PlaybackClock>),
+    Output,
+}
+
+struct Node {
+    name: String,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+pub enum GraphError {
+    UnknownNode(String),
+    DuplicateName(String),
+    Cycle(Vec<String>),
+    Topology(String),
+    MissingInput(String),
+    MissingOutput(String),
+    Init { node: String, message: String },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::UnknownNode(node) => write!(f, "no node named \"{node}\""),
+            GraphError::DuplicateName(node) => write!(f, "more than one node named \"{node}\""),
+            GraphError::Cycle(nodes) => write!(f, "cycle through {}", nodes.join(", ")),
+            GraphError::Topology(message) => write!(f, "{message}"),
+            GraphError::MissingInput(node) => write!(f, "no source given for input \"{node}\""),
+            GraphError::MissingOutput(node) => {
+                write!(f, "nowhere given for output \"{node}\"")
+            }
+            GraphError::Init { node, message } => {
+                write!(f, "error initializing \"{node}\": {message}")
+            }
+        }
+    }
+}
+
+/// A failure reported by one node of a running graph.
+#[derive(Debug)]
+pub struct NodeError {
+    pub node: String,
+    pub message: String,
+}
+
+/// An audio graph: inputs fan out to any number of branches, buses mix
+/// branches back together and drains consume them, e.g. one decoder feeding
+/// the speakers, a stream encoder and a level meter at once.
+///
+/// Stages report their delay through `AudioPipe::latency`, and buses delay
+/// their faster inputs to match the slowest so merged branches stay aligned.
+pub struct PipelineGraph {
+    ctx: Arc<TransformCtx>,
+    nodes: Vec<Node>,
+    edges: Vec<(NodeId, NodeId)>,
+}
+
+impl PipelineGraph {
+    pub fn new(ctx: TransformCtx) -> Self {
+        Self {
+            ctx: Arc::new(ctx),
+            nodes: Vec::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    pub fn ctx(&self) -> &TransformCtx {
+        &self.ctx
+    }
+
+    /// An entry point, fed by the receiver of the same name given to `run`.
+    pub fn add_input(&mut self, name: &str) -> NodeId {
+        self.add_node(name, NodeKind::Input)
+    }
+
+    pub fn add_pipe(
+        &mut self,
+        name: &str,
+        pipe: Arc<dyn AudioPipe>,
+        policy: ErrorPolicy,
+    ) -> NodeId {
+        self.add_node(name, NodeKind::Pipe(pipe, policy))
+    }
+
+    /// Sums everything connected into it.
+    pub fn add_bus(&mut self, name: &str) -> NodeId {
+        self.add_node(name, NodeKind::Bus)
+    }
+
+    /// `clock` is advanced by whatever `drain` plays, so only the drain the
+    /// listener hears should get the playback clock.
+    pub fn add_drain(
+        &mut self,
+        name: &str,
+        drain: Arc<dyn Drain>,
+        clock: Arc<PlaybackClock>,
+    ) -> NodeId {
+        self.add_node(name, NodeKind::Drain(drain, clock))
+    }
+
+    /// An exit point, sending to the sender of the same name given to `run`.
+    pub fn add_output(&mut self, name: &str) -> NodeId {
+        self.add_node(name, NodeKind::Output)
+    }
+
+    pub fn connect(&mut self, from: NodeId, to: NodeId) {
+        self.edges.push((from, to));
+    }
+
+    pub fn node(&self, name: &str) -> Result<NodeId, GraphError> {
+        return match self.nodes.iter().position(|node| node.name == name) {
+            Some(index) => Ok(NodeId(index)),
+            None => Err(GraphError::UnknownNode(name.to_string())),
+        };
+    }
+
+    fn add_node(&mut self, name: &str, kind: NodeKind) -> NodeId {
+        self.nodes.push(Node {
+            name: name.to_string(),
+            kind,
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    fn incoming(&self, id: NodeId) -> Vec<NodeId> {
+        self.edges
+            .iter()
+            .filter(|(_, to)| *to == id)
+            .map(|(from, _)| *from)
+            .collect()
+    }
+
+    fn outgoing_count(&self, id: NodeId) -> usize {
+        self.edges.iter().filter(|(from, _)| *from == id).count()
+    }
+
+    /// Checks that every node is wired the way its kind needs and that there
+    /// are no cycles, returning the nodes in an order where each comes after
+    /// everything feeding it.
+    pub fn validate(&self) -> Result<Vec<NodeId>, GraphError> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            if self.nodes[..index].iter().any(|n| n.name == node.name) {
+                return Err(GraphError::DuplicateName(node.name.clone()));
+            }
+
+            let id = NodeId(index);
+            let inputs = self.incoming(id).len();
+            let outputs = self.outgoing_count(id);
+            let problem = match node.kind {
+                NodeKind::Input if inputs > 0 => Some("an input can't be connected into"),
+                NodeKind::Pipe(..) if inputs != 1 => Some("a pipe needs exactly one input"),
+                NodeKind::Bus if inputs == 0 => Some("a bus needs at least one input"),
+                NodeKind::Drain(..) | NodeKind::Output if inputs != 1 => {
+                    Some("a drain or output needs exactly one input")
+                }
+                NodeKind::Drain(..) | NodeKind::Output if outputs > 0 => {
+                    Some("a drain or output can't be connected from")
+                }
+                NodeKind::Input | NodeKind::Pipe(..) | NodeKind::Bus if outputs == 0 => {
+                    Some("output isn't connected to anything")
+                }
+                _ => None,
+            };
+            if let Some(problem) = problem {
+                return Err(GraphError::Topology(format!(
+                    "\"{}\": {problem}",
+                    node.name
+                )));
+            }
+        }
+
+        for (from, to) in self.edges.iter() {
+            if from.0 >= self.nodes.len() || to.0 >= self.nodes.len() {
+                return Err(GraphError::Topology(
+                    "edge to a node of another graph".to_string(),
+                ));
+            }
+        }
+
+        // Kahn's algorithm, whatever is left over sits on a cycle
+        let mut remaining: Vec<usize> = (0..self.nodes.len())
+            .map(|index| self.incoming(NodeId(index)).len())
+            .collect();
+        let mut ready: VecDeque<NodeId> = (0..self.nodes.len())
+            .filter(|index| remaining[*index] == 0)
+            .map(NodeId)
+            .collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(id) = ready.pop_front() {
+            order.push(id);
+            for (_, to) in self.edges.iter().filter(|(from, _)| *from == id) {
+                remaining[to.0] -= 1;
+                if remaining[to.0] == 0 {
+                    ready.push_back(*to);
+                }
+            }
+        }
+
+        if order.len() < self.nodes.len() {
+            let cycle = (0..self.nodes.len())
+                .filter(|index| remaining[*index] > 0)
+                .map(|index| self.nodes[index].name.clone())
+                .collect();
+            return Err(GraphError::Cycle(cycle));
+        }
+
+        Ok(order)
+    }
+
+    /// Frames of delay between the inputs and each node's output.
+    fn latencies(&self, order: &[NodeId]) -> Vec<usize> {
+        let mut latencies = vec![0; self.nodes.len()];
+        for id in order.iter() {
+            let upstream = self
+                .incoming(*id)
+                .iter()
+                .map(|from| latencies[from.0])
+                .max()
+                .unwrap_or(0);
+            latencies[id.0] = match &self.nodes[id.0].kind {
+                NodeKind::Pipe(pipe, _) => upstream + pipe.latency(&self.ctx),
+                _ => upstream,
+            };
+        }
+
+        latencies
+    }
+
+    /// Frames of delay between the inputs and the slowest output.
+    pub fn latency(&self) -> usize {
+        let order = match self.validate() {
+            Ok(order) => order,
+            Err(_) => return 0,
+        };
+        let latencies = self.latencies(&order);
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| matches!(node.kind, NodeKind::Output))
+            .map(|(index, _)| latencies[index])
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Forgets the state of every stage, see `AudioPipe::purge`.
+    pub fn purge(&self) {
+        for node in self.nodes.iter() {
+            if let NodeKind::Pipe(pipe, _) = &node.kind {
+                pipe.purge();
+            }
+        }
+    }
+
+    /// Validates the graph and initializes its stages and drains, so one
+    /// that can't start fails the whole graph rather than leaving a branch
+    /// dangling.
+    pub fn init(&self) -> Result<(), GraphError> {
+        self.validate()?;
+
+        for node in self.nodes.iter() {
+            match &node.kind {
+                NodeKind::Pipe(pipe, _) => {
+                    if let Err(message) = pipe.init(&self.ctx) {
+                        return Err(GraphError::Init {
+                            node: node.name.clone(),
+                            message,
+                        });
+                    }
+                }
+                NodeKind::Drain(drain, _) => {
+                    if let Err(message) = drain.init(&self.ctx) {
+                        return Err(GraphError::Init {
+                            node: node.name.clone(),
+                            message,
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Initializes and starts every node, with `inputs` feeding the input
+    /// nodes by name and `outputs` taking what reaches the output nodes.
+    pub fn run(
+        &self,
+        inputs: HashMap<String, Receiver<Sample>>,
+        outputs: HashMap<String, SyncSender<Sample>>,
+    ) -> Result<GraphHandles, GraphError> {
+        self.init()?;
+        self.start(inputs, outputs)
+    }
+
+    /// Starts every node of a graph that's already been through `init`.
+    pub fn start(
+        &self,
+        mut inputs: HashMap<String, Receiver<Sample>>,
+        mut outputs: HashMap<String, SyncSender<Sample>>,
+    ) -> Result<GraphHandles, GraphError> {
+        let order = self.validate()?;
+        let latencies = self.latencies(&order);
+
+        for node in self.nodes.iter() {
+            match &node.kind {
+                NodeKind::Input if !inputs.contains_key(&node.name) => {
+                    return Err(GraphError::MissingInput(node.name.clone()))
+                }
+                NodeKind::Output if !outputs.contains_key(&node.name) => {
+                    return Err(GraphError::MissingOutput(node.name.clone()))
+                }
+                _ => (),
+            }
+        }
+
+        let mut senders: Vec<Vec<SyncSender<Sample>>> =
+            self.nodes.iter().map(|_| Vec::new()).collect();
+        let mut recievers: Vec<Vec<(Receiver<Sample>, usize)>> =
+            self.nodes.iter().map(|_| Vec::new()).collect();
+        for (from, to) in self.edges.iter() {
+            let (sender, reciever) = sync_channel(self.ctx.fitting_buffer);
+            senders[from.0].push(sender);
+            // only buses merge branches, so only their inputs need lining up
+            let delay = match self.nodes[to.0].kind {
+                NodeKind::Bus => latencies[to.0] - latencies[from.0],
+                _ => 0,
+            };
+            recievers[to.0].push((reciever, delay));
+        }
+
+        let mut handles = Vec::new();
+        for id in order {
+            let node = &self.nodes[id.0];
+            let ctx = self.ctx.clone();
+            let to = self.output(&mut handles, node, senders[id.0].drain(..).collect());
+            let mut from = recievers[id.0].drain(..);
+
+            let handle: PipeHandle = match &node.kind {
+                NodeKind::Input => {
+                    let from = inputs.remove(&node.name).unwrap();
+                    thread::spawn(move || forward(from, to.unwrap()))
+                }
+                NodeKind::Pipe(pipe, policy) => {
+                    let (from, _) = from.next().unwrap();
+                    let pipe = pipe.clone();
+                    match policy {
+                        ErrorPolicy::Stop => {
+                            thread::spawn(move || pipe.pipe(&ctx, from, to.unwrap()))
+                        }
+                        ErrorPolicy::Bypass => {
+                            let to = to.unwrap();
+                            thread::spawn(move || run_bypassable(pipe, &ctx, from, to))
+                        }
+                    }
+                }
+                NodeKind::Bus => {
+                    let from = from.collect();
+                    thread::spawn(move || mix(&ctx, from, to.unwrap()))
+                }
+                NodeKind::Drain(drain, clock) => {
+                    let (from, _) = from.next().unwrap();
+                    let drain = drain.clone();
+                    let clock = clock.clone();
+                    thread::spawn(move || drain.drain(&ctx, from, clock))
+                }
+                NodeKind::Output => {
+                    let (from, _) = from.next().unwrap();
+                    let to = outputs.remove(&node.name).unwrap();
+                    thread::spawn(move || forward(from, to))
+                }
+            };
+            handles.push((node.name.clone(), handle));
+        }
+
+        Ok(GraphHandles { handles })
+    }
+
+    /// Where a node should send its output, with a splitter in between when
+    /// it fans out to more than one branch.
+    fn output(
+        &self,
+        handles: &mut Vec<(String, PipeHandle)>,
+        node: &Node,
+        mut senders: Vec<SyncSender<Sample>>,
+    ) -> Option<SyncSender<Sample>> {
+        if senders.len() <= 1 {
+            return senders.pop();
+        }
+
+        let (sender, reciever) = sync_channel(self.ctx.fitting_buffer);
+        handles.push((
+            format!("{} (split)", node.name),
+            thread::spawn(move || split(reciever, senders)),
+        ));
+
+        Some(sender)
+    }
+}
+
+/// Handles for every thread of a running graph.
+pub struct GraphHandles {
+    handles: Vec<(String, PipeHandle)>,
+}
+
+impl GraphHandles {
+    /// Waits for the graph to run to the end, returning what went wrong
+    /// along the way.
+    pub fn join(self) -> Vec<NodeError> {
+        let mut errors = Vec::new();
+        for (node, handle) in self.handles {
+            let message = match handle.join() {
+                Ok(Ok(())) => continue,
+                Ok(Err(message)) => message,
+                Err(_) => "panicked".to_string(),
+            };
+            errors.push(NodeError { node, message });
+        }
+
+        errors
+    }
+}
+
+fn forward(from: Receiver<Sample>, to: SyncSender<Sample>) -> Result<(), String> {
+    for sample in from {
+        if let Err(err) = to.send(sample) {
+            return Err(format!("error forwarding sample: {err}"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies every sample to each branch. A branch that has gone away is
+/// dropped without holding up the others.
+fn split(from: Receiver<Sample>, mut to: Vec<SyncSender<Sample>>) -> Result<(), String> {
+    for sample in from {
+        to.retain(|branch| branch.send(sample).is_ok());
+        if to.is_empty() {
+            return Err("every branch has stopped".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `pipe` behind a forwarder, so that if it fails the forwarder can
+/// carry on sending its input straight to the output.
+fn run_bypassable(
+    pipe: Arc<dyn AudioPipe>,
+    ctx: &TransformCtx,
+    from: Receiver<Sample>,
+    to: SyncSender<Sample>,
+) -> Result<(), String> {
+    let (pipe_sender, pipe_reciever) = sync_channel(ctx.fitting_buffer);
+    let pipe_to = to.clone();
+    let pipe_ctx = ctx.clone();
+    let handle = thread::spawn(move || pipe.pipe(&pipe_ctx, pipe_reciever, pipe_to));
+
+    let mut bypassed = false;
+    for sample in from.iter() {
+        if !bypassed {
+            if pipe_sender.send(sample).is_ok() {
+                continue;
+            }
+            // the stage dropped its input, which it only does on the way out
+            eprintln!("pipeline stage failed, bypassing it");
+            bypassed = true;
+        }
+        if let Err(err) = to.send(sample) {
+            return Err(format!("error sending sample: {err}"));
+        }
+    }
+
+    drop(pipe_sender);
+    return match handle.join() {
+        Ok(Err(message)) if bypassed => Err(format!("bypassed after: {message}")),
+        Ok(result) => result,
+        Err(_) => Err("panicked".to_string()),
+    };
+}
+
+/// What each input of a bus has delivered that it hasn't mixed yet, filled
+/// by a thread reading each input.
+struct MixQueues {
+    state: Mutex<MixState>,
+    /// Signalled whenever a queue grows, shrinks or ends.
+    changed: Condvar,
+    /// Most samples an input can be held ahead of the others by.
+    capacity: usize,
+}
+
+struct MixState {
+    queues: Vec<VecDeque<Sample>>,
+    /// Silence still to come from each input before what it sends.
+    delays: Vec<usize>,
+    open: Vec<bool>,
+    /// The bus has stopped, so nothing more will be taken.
+    closed: bool,
+}
+
+impl MixQueues {
+    /// Samples an input takes whatever of that has arrived in before
+    /// queueing it, rather than locking for every one.
+    const CHUNK: usize = 256;
+
+    /// Reads `reciever` into queue `index` until it ends or the bus stops,
+    /// waiting while the queue is full.
+    fn feed(&self, index: usize, reciever: Receiver<Sample>) {
+        let mut chunk = Vec::with_capacity(Self::CHUNK);
+        while let Ok(sample) = reciever.recv() {
+            chunk.push(sample);
+            while chunk.len() < Self::CHUNK {
+                match reciever.try_recv() {
+                    Ok(sample) => chunk.push(sample),
+                    Err(_) => break,
+                }
+            }
+
+            let mut state = self.state.lock().unwrap();
+            while !state.closed && state.queues[index].len() + chunk.len() > self.capacity {
+                state = self.changed.wait(state).unwrap();
+            }
+            if state.closed {
+                return;
+            }
+            state.queues[index].extend(chunk.drain(..));
+            drop(state);
+            self.changed.notify_all();
+        }
+
+        self.state.lock().unwrap().open[index] = false;
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+impl MixState {
+    /// Whether input `index` has a sample, or silence, for the next frame.
+    fn ready(&self, index: usize) -> bool {
+        self.delays[index] > 0 || !self.queues[index].is_empty() || !self.open[index]
+    }
+
+    fn finished(&self, index: usize) -> bool {
+        self.delays[index] == 0 && self.queues[index].is_empty() && !self.open[index]
+    }
+
+    fn next(&mut self, index: usize) -> Sample {
+        if self.delays[index] > 0 {
+            self.delays[index] -= 1;
+            return 0.0;
+        }
+        self.queues[index].pop_front().unwrap_or(0.0)
+    }
+}
+
+/// Sums its inputs, each delayed by its compensation in frames. An input
+/// that ends counts as silence until they all have.
+///
+/// Inputs are read as far as they've got rather than in lockstep, and held
+/// until the others catch up. Branches that buffer more than their reported
+/// latency, or than fits in a channel, would otherwise hold up the splitter
+/// feeding them all, and with it the branch being waited on. How far ahead
+/// an input is held is bounded though, to a second of audio, past which it
+/// isn't read until the others catch up.
+fn mix(
+    ctx: &TransformCtx,
+    from: Vec<(Receiver<Sample>, usize)>,
+    to: SyncSender<Sample>,
+) -> Result<(), String> {
+    let channels = ctx.channels.max(1) as usize;
+    let capacity = (ctx.sample_rate as usize * channels).max(MixQueues::CHUNK);
+    mix_within(capacity, channels, from, to)
+}
+
+/// `mix`, holding at most `capacity` samples of any input ahead.
+fn mix_within(
+    capacity: usize,
+    channels: usize,
+    from: Vec<(Receiver<Sample>, usize)>,
+    to: SyncSender<Sample>,
+) -> Result<(), String> {
+    let inputs = from.len();
+    let queues = Arc::new(MixQueues {
+        state: Mutex::new(MixState {
+            queues: vec![VecDeque::new(); inputs],
+            delays: from.iter().map(|(_, delay)| delay * channels).collect(),
+            open: vec![true; inputs],
+            closed: false,
+        }),
+        changed: Condvar::new(),
+        capacity,
+    });
+    // each ends with its input, or once the bus has stopped and it's sent
+    // another sample, so there's no need to wait for them
+    for (index, (reciever, _)) in from.into_iter().enumerate() {
+        let queues = queues.clone();
+        thread::spawn(move || queues.feed(index, reciever));
+    }
+
+    let result = mix_queued(&queues, to);
+    queues.close();
+    result
+}
+
+fn mix_queued(queues: &MixQueues, to: SyncSender<Sample>) -> Result<(), String> {
+    let mut sums = Vec::new();
+    loop {
+        let mut state = queues.state.lock().unwrap();
+        let inputs = state.queues.len();
+        while !(0..inputs).all(|index| state.ready(index)) {
+            state = queues.changed.wait(state).unwrap();
+        }
+
+        let mut finished = false;
+        while (0..inputs).all(|index| state.ready(index)) {
+            if (0..inputs).all(|index| state.finished(index)) {
+                finished = true;
+                break;
+            }
+            sums.push((0..inputs).map(|index| state.next(index)).sum());
+        }
+        drop(state);
+        // there's room in the queues again
+        queues.changed.notify_all();
+
+        for sum in sums.drain(..) {
+            if let Err(err) = to.send(sum) {
+                return Err(format!("error sending sample: {err}"));
+            }
+        }
+        if finished {
+            return Ok(());
+        }
+    }
+}
+
+/// A graph with an input named `in` and an output named `out`, run as a
+/// single stage of a linear pipeline, e.g. parallel branches mixed back
+/// together.
+pub struct GraphPipe {
+    graph: PipelineGraph,
+}
+
+impl GraphPipe {
+    pub const INPUT: &str = "in";
+    pub const OUTPUT: &str = "out";
+
+    pub fn new(graph: PipelineGraph) -> Result<Self, GraphError> {
+        graph.node(Self::INPUT)?;
+        graph.node(Self::OUTPUT)?;
+        graph.validate()?;
+        Ok(Self { graph })
+    }
+}
+
+impl AudioPipe for GraphPipe {
+    fn init(&self, _ctx: &TransformCtx) -> Result<(), String> {
+        self.graph.init().map_err(|e| e.to_string())
+    }
+
+    fn pipe(
+        &self,
+        _ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        to: SyncSender<Sample>,
+    ) -> Result<(), String> {
+        let inputs = HashMap::from([(Self::INPUT.to_string(), from)]);
+        let outputs = HashMap::from([(Self::OUTPUT.to_string(), to)]);
+        let handles = match self.graph.start(inputs, outputs) {
+            Ok(handles) => handles,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        return match handles.join().first() {
+            Some(error) => Err(format!("{}: {}", error.node, error.message)),
+            None => Ok(()),
+        };
+    }
+
+    fn latency(&self, _ctx: &TransformCtx) -> usize {
+        self.graph.latency()
+    }
+
+    fn purge(&self) {
+        self.graph.purge();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::RecvTimeoutError,
+        },
+        time::Duration,
+    };
+
+    fn ctx() -> TransformCtx {
+        TransformCtx {
+            window_size: 64,
+            fitting_buffer: 64,
+            sample_rate: 44100,
+            channels: 1,
+        }
+    }
+
+    /// Delays by `frames`, but like an STFT stage holds on to `hold`
+    /// samples before it lets any out.
+    struct Delay {
+        frames: usize,
+        hold: usize,
+    }
+
+    impl AudioPipe for Delay {
+        fn pipe(
+            &self,
+            _ctx: &TransformCtx,
+            from: Receiver<Sample>,
+            to: SyncSender<Sample>,
+        ) -> Result<(), String> {
+            let mut queue: VecDeque<Sample> = vec![0.0; self.frames].into();
+            for sample in from {
+                queue.push_back(sample);
+                if queue.len() > self.hold {
+                    for sample in queue.drain(..) {
+                        to.send(sample).map_err(|e| e.to_string())?;
+                    }
+                }
+            }
+            for sample in queue {
+                to.send(sample).map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        }
+
+        fn latency(&self, _ctx: &TransformCtx) -> usize {
+            self.frames
+        }
+    }
+
+    /// Runs `pipe` over `input`, failing rather than hanging if it stalls.
+    fn run_pipe(pipe: Arc<dyn AudioPipe>, ctx: TransformCtx, input: Vec<Sample>) -> Vec<Sample> {
+        pipe.init(&ctx).unwrap();
+        let (sender, reciever) = sync_channel(ctx.fitting_buffer);
+        let (out_sender, out_reciever) = sync_channel(ctx.fitting_buffer);
+        let feeder = thread::spawn(move || {
+            for sample in input {
+                sender.send(sample).unwrap();
+            }
+        });
+        let stage = thread::spawn(move || pipe.pipe(&ctx, reciever, out_sender));
+
+        let mut output = Vec::new();
+        loop {
+            match out_reciever.recv_timeout(Duration::from_secs(5)) {
+                Ok(sample) => output.push(sample),
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => panic!("stalled after {} samples", output.len()),
+            }
+        }
+        feeder.join().unwrap();
+        stage.join().unwrap().unwrap();
+
+        output
+    }
+
+    #[test]
+    fn bus_waits_out_a_branch_holding_more_than_a_channel() {
+        let mut graph = PipelineGraph::new(ctx());
+        let input = graph.add_input(GraphPipe::INPUT);
+        let delay = graph.add_pipe(
+            "delay",
+            Arc::new(Delay {
+                frames: 16,
+                hold: 4096,
+            }),
+            ErrorPolicy::Stop,
+        );
+        let bus = graph.add_bus("mix");
+        let output = graph.add_output(GraphPipe::OUTPUT);
+        graph.connect(input, delay);
+        graph.connect(delay, bus);
+        graph.connect(input, bus);
+        graph.connect(bus, output);
+        let pipe = GraphPipe::new(graph).unwrap();
+        assert_eq!(pipe.latency(&ctx()), 16);
+
+        let input: Vec<Sample> = (1..=10000).map(|i| i as Sample).collect();
+        let output = run_pipe(Arc::new(pipe), ctx(), input.clone());
+
+        assert_eq!(output.len(), input.len() + 16);
+        assert!(output[..16].iter().all(|sample| *sample == 0.0));
+        for (sample, expected) in output[16..].iter().zip(input.iter()) {
+            assert_eq!(*sample, expected * 2.0);
+        }
+    }
+
+    #[test]
+    fn bus_holds_up_an_input_that_gets_too_far_ahead() {
+        let capacity = 4 * MixQueues::CHUNK;
+        let (ahead, ahead_reciever) = sync_channel(0);
+        let (behind, behind_reciever) = sync_channel(0);
+        let (to, output) = sync_channel(0);
+        let bus = thread::spawn(move || {
+            mix_within(
+                capacity,
+                1,
+                vec![(ahead_reciever, 0), (behind_reciever, 2)],
+                to,
+            )
+        });
+
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counted = sent.clone();
+        let feeder = thread::spawn(move || {
+            for i in 0..10 * capacity {
+                ahead.send(i as Sample).unwrap();
+                counted.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        // with nothing from the other input, no more than fits is taken
+        thread::sleep(Duration::from_millis(200));
+        let taken = sent.load(Ordering::Relaxed);
+        assert!(
+            taken >= capacity && taken <= capacity + MixQueues::CHUNK + 1,
+            "{taken}"
+        );
+
+        let behind = thread::spawn(move || {
+            for _ in 0..10 * capacity - 2 {
+                behind.send(1.0).unwrap();
+            }
+        });
+        let mixed: Vec<Sample> = output.iter().collect();
+        feeder.join().unwrap();
+        behind.join().unwrap();
+        bus.join().unwrap().unwrap();
+
+        // the delayed input comes in two samples late, silent until then
+        assert_eq!(mixed.len(), 10 * capacity);
+        assert_eq!(&mixed[..3], &[0.0, 1.0, 3.0]);
+        for (i, sample) in mixed.iter().enumerate().skip(2) {
+            assert_eq!(*sample, i as Sample + 1.0);
+        }
+    }
+
+    #[test]
+    fn graph_pipe_needs_its_input_and_output() {
+        let mut graph = PipelineGraph::new(ctx());
+        let input = graph.add_input("source");
+        let output = graph.add_output(GraphPipe::OUTPUT);
+        graph.connect(input, output);
+
+        assert!(matches!(
+            GraphPipe::new(graph),
+            Err(GraphError::UnknownNode(name)) if name == GraphPipe::INPUT
+        ));
+    }
+}
@@ -0,0 +1,513 @@
+use super::{audio::AudioReader, pipeline::Sample};
+use crate::library::ReplayGain;
+use serde::{Deserialize, Serialize};
+use std::{f64::consts::PI, path::Path};
+use symphonia::core::audio::SampleBuffer;
+
+/// ReplayGain 2.0 plays everything back as if it measured this loud.
+pub const REPLAY_GAIN_REFERENCE_LUFS: f32 = -18.0;
+
+/// Reported as the true peak of silence rather than negative infinity,
+/// which JSON can't hold.
+const TRUE_PEAK_FLOOR_DBTP: f64 = -120.0;
+
+/// Summary of a loudness measurement, per EBU R128 / ITU-R BS.1770.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Loudness {
+    pub integrated_lufs: f32,
+    pub range_lu: f32,
+    pub true_peak_dbtp: f32,
+}
+
+/// Everything measured for a track. The gating block energies are kept so
+/// album loudness can be gated over all of an album's blocks together,
+/// which averaging track values wouldn't get right.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoudnessAnalysis {
+    pub loudness: Loudness,
+    pub true_peak: f32,
+    pub blocks: Vec<f64>,
+}
+
+/// A measurement as clients see it, without the gating blocks.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct LoudnessSummary {
+    #[serde(flatten)]
+    pub loudness: Loudness,
+    pub replay_gain: ReplayGain,
+}
+
+impl LoudnessAnalysis {
+    pub fn summary(&self) -> LoudnessSummary {
+        LoudnessSummary {
+            loudness: self.loudness,
+            replay_gain: self.replay_gain(),
+        }
+    }
+
+    pub fn replay_gain(&self) -> ReplayGain {
+        ReplayGain {
+            track_gain: REPLAY_GAIN_REFERENCE_LUFS - self.loudness.integrated_lufs,
+            track_peak: self.true_peak,
+            album_gain: None,
+            album_peak: None,
+        }
+    }
+
+    /// ReplayGain album gain and peak over every track of an album.
+    pub fn album_gain(tracks: &[&LoudnessAnalysis]) -> (f32, f32) {
+        let blocks: Vec<f64> = tracks
+            .iter()
+            .flat_map(|track| track.blocks.iter().copied())
+            .collect();
+        let peak = tracks
+            .iter()
+            .map(|track| track.true_peak)
+            .fold(0.0, f32::max);
+
+        (
+            REPLAY_GAIN_REFERENCE_LUFS - gated_loudness(&blocks, -10.0) as f32,
+            peak,
+        )
+    }
+}
+
+/// Two cascaded biquads approximating how loud frequencies sound: a high
+/// shelf for the head's acoustic effect and a high pass below bass.
+#[derive(Clone)]
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+impl KWeighting {
+    /// Filter coefficients worked out for any sample rate rather than the
+    /// 48 kHz tables in the spec.
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        };
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        };
+
+        Self {
+            stages: [shelf, high_pass],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let x = self.stages[0].process(x);
+        self.stages[1].process(x)
+    }
+}
+
+/// Estimates the peak between samples by oversampling 4x with a windowed
+/// sinc, since that's where a DAC's reconstruction overshoots.
+struct TruePeak {
+    phases: Vec<[f64; TruePeak::TAPS]>,
+    history: [f64; TruePeak::TAPS],
+    peak: f64,
+}
+
+impl TruePeak {
+    const OVERSAMPLING: usize = 4;
+    const TAPS: usize = 12;
+
+    fn new() -> Self {
+        let length = Self::OVERSAMPLING * Self::TAPS;
+        let center = (length - 1) as f64 / 2.0;
+        let phases = (0..Self::OVERSAMPLING)
+            .map(|phase| {
+                let mut taps = [0.0; Self::TAPS];
+                for (tap, value) in taps.iter_mut().enumerate() {
+                    let n = (tap * Self::OVERSAMPLING + phase) as f64;
+                    let x = (n - center) / Self::OVERSAMPLING as f64;
+                    let sinc = match x == 0.0 {
+                        true => 1.0,
+                        false => (PI * x).sin() / (PI * x),
+                    };
+                    let window = 0.5 - 0.5 * (2.0 * PI * n / (length - 1) as f64).cos();
+                    *value = sinc * window;
+                }
+                // unity gain at DC for every phase
+                let sum: f64 = taps.iter().sum();
+                taps.iter_mut().for_each(|tap| *tap /= sum);
+                taps
+            })
+            .collect();
+
+        Self {
+            phases,
+            history: [0.0; Self::TAPS],
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f64) {
+        self.history.copy_within(1.., 0);
+        self.history[Self::TAPS - 1] = x;
+        self.peak = self.peak.max(x.abs());
+
+        for taps in self.phases.iter() {
+            let y: f64 = taps
+                .iter()
+                .rev()
+                .zip(self.history.iter())
+                .map(|(tap, x)| tap * x)
+                .sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+}
+
+/// Measures a stream of interleaved samples as they go by, e.g. while a
+/// track is being written out at ingest.
+pub struct LoudnessAnalyzer {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<KWeighting>,
+    peaks: Vec<TruePeak>,
+    sub_block_frames: usize,
+    // K-weighted energy of 100 ms sub-blocks, four of which make a gating
+    // block and thirty a short-term block
+    sub_blocks: Vec<f64>,
+    energy: f64,
+    frames: usize,
+    channel: usize,
+}
+
+impl LoudnessAnalyzer {
+    const SUB_BLOCKS_PER_BLOCK: usize = 4;
+    const SUB_BLOCKS_PER_SHORT_TERM: usize = 30;
+
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        // BS.1770 weights the surround channels of 5.1 up and ignores the LFE
+        let weights = (0..channels)
+            .map(|channel| match (channels, channel) {
+                (6, 3) => 0.0,
+                (6, 4) | (6, 5) => 1.41,
+                _ => 1.0,
+            })
+            .collect();
+
+        Self {
+            channels,
+            weights,
+            filters: vec![KWeighting::new(sample_rate); channels],
+            peaks: (0..channels).map(|_| TruePeak::new()).collect(),
+            sub_block_frames: (sample_rate as usize / 10).max(1),
+            sub_blocks: Vec::new(),
+            energy: 0.0,
+            frames: 0,
+            channel: 0,
+        }
+    }
+
+    pub fn add(&mut self, samples: &[Sample]) {
+        for sample in samples {
+            let x = *sample as f64;
+            let weighted = self.filters[self.channel].process(x);
+            self.energy += self.weights[self.channel] * weighted * weighted;
+            self.peaks[self.channel].process(x);
+
+            self.channel += 1;
+            if self.channel == self.channels {
+                self.channel = 0;
+                self.frames += 1;
+                if self.frames == self.sub_block_frames {
+                    self.sub_blocks.push(self.energy / self.frames as f64);
+                    self.energy = 0.0;
+                    self.frames = 0;
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> LoudnessAnalysis {
+        let blocks = mean_windows(&self.sub_blocks, Self::SUB_BLOCKS_PER_BLOCK);
+        let short_term = mean_windows(&self.sub_blocks, Self::SUB_BLOCKS_PER_SHORT_TERM);
+        let true_peak = self.peaks.iter().map(|peak| peak.peak).fold(0.0, f64::max);
+
+        LoudnessAnalysis {
+            loudness: Loudness {
+                integrated_lufs: gated_loudness(&blocks, -10.0) as f32,
+                range_lu: loudness_range(&short_term) as f32,
+                true_peak_dbtp: (20.0 * true_peak.log10()).max(TRUE_PEAK_FLOOR_DBTP) as f32,
+            },
+            true_peak: true_peak as f32,
+            blocks,
+        }
+    }
+
+    /// Decodes and measures a whole file.
+    pub fn analyze_file(path: &Path) -> Result<LoudnessAnalysis, String> {
+        let mut reader = AudioReader::open(path)?;
+        let spec = reader.signal_spec();
+        let mut analyzer = Self::new(spec.rate, spec.channels.count() as u16);
+
+        let mut sample_buffer: Option<SampleBuffer<Sample>> = None;
+        loop {
+            let result = reader.consume_next(|buffer| {
+                let spec = *buffer.spec();
+                let samples = buffer.capacity() * spec.channels.count();
+                if sample_buffer
+                    .as_ref()
+                    .is_none_or(|b| b.capacity() < samples)
+                {
+                    sample_buffer = Some(SampleBuffer::new(buffer.capacity() as u64, spec));
+                }
+                sample_buffer.as_mut().unwrap().copy_interleaved_ref(buffer);
+                Ok(())
+            });
+            match result {
+                Ok(_) => analyzer.add(sample_buffer.as_ref().unwrap().samples()),
+                Err(e) if e == "EOF" => break,
+                Err(e) => return Err(format!("error decoding: {e}")),
+            }
+        }
+
+        Ok(analyzer.finish())
+    }
+}
+
+/// Averages every run of `length` consecutive sub-blocks, stepping one
+/// sub-block (100 ms) at a time.
+fn mean_windows(sub_blocks: &[f64], length: usize) -> Vec<f64> {
+    sub_blocks
+        .windows(length)
+        .map(|window| window.iter().sum::<f64>() / length as f64)
+        .collect()
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+/// Loudness of the blocks above the absolute gate and above the mean of
+/// those by `relative_gate` LU. Silence measures as the absolute gate.
+fn gated_loudness(blocks: &[f64], relative_gate: f64) -> f64 {
+    let gated: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|energy| energy_to_lufs(*energy) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    if gated.is_empty() {
+        return ABSOLUTE_GATE_LUFS;
+    }
+
+    let mean = gated.iter().sum::<f64>() / gated.len() as f64;
+    let threshold = energy_to_lufs(mean) + relative_gate;
+    let gated: Vec<f64> = gated
+        .into_iter()
+        .filter(|energy| energy_to_lufs(*energy) > threshold)
+        .collect();
+    if gated.is_empty() {
+        return energy_to_lufs(mean);
+    }
+
+    energy_to_lufs(gated.iter().sum::<f64>() / gated.len() as f64)
+}
+
+/// EBU Tech 3342: the spread between the 10th and 95th percentile of gated
+/// short-term loudness.
+fn loudness_range(short_term: &[f64]) -> f64 {
+    let gated: Vec<f64> = short_term
+        .iter()
+        .copied()
+        .filter(|energy| energy_to_lufs(*energy) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    if gated.is_empty() {
+        return 0.0;
+    }
+
+    let mean = gated.iter().sum::<f64>() / gated.len() as f64;
+    let threshold = energy_to_lufs(mean) - 20.0;
+    let mut loudness: Vec<f64> = gated
+        .into_iter()
+        .map(energy_to_lufs)
+        .filter(|lufs| *lufs > threshold)
+        .collect();
+    if loudness.is_empty() {
+        return 0.0;
+    }
+    loudness.sort_by(f64::total_cmp);
+
+    let percentile = |p: f64| loudness[((loudness.len() - 1) as f64 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::volume::ReplayGainMode;
+
+    #[test]
+    fn silence_survives_a_round_trip() {
+        let mut analyzer = LoudnessAnalyzer::new(44100, 2);
+        analyzer.add(&vec![0.0; 44100 * 2]);
+        let analysis = analyzer.finish();
+        assert_eq!(
+            analysis.loudness.true_peak_dbtp,
+            TRUE_PEAK_FLOOR_DBTP as f32
+        );
+
+        let json = serde_json::to_vec(&analysis).unwrap();
+        let parsed: LoudnessAnalysis = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            parsed.loudness.true_peak_dbtp,
+            analysis.loudness.true_peak_dbtp
+        );
+        assert_eq!(parsed.loudness.integrated_lufs, ABSOLUTE_GATE_LUFS as f32);
+    }
+
+    /// A sine on every channel, `seconds` long, with its peak at `dbfs` and
+    /// starting `phase` radians in.
+    fn sine(
+        frequency: f64,
+        dbfs: f64,
+        phase: f64,
+        seconds: f64,
+        rate: u32,
+        channels: usize,
+    ) -> Vec<Sample> {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        (0..(seconds * rate as f64) as usize)
+            .flat_map(|n| {
+                let x = amplitude * (2.0 * PI * frequency * n as f64 / rate as f64 + phase).sin();
+                std::iter::repeat_n(x as Sample, channels)
+            })
+            .collect()
+    }
+
+    fn measure(rate: u32, channels: u16, parts: &[Vec<Sample>]) -> LoudnessAnalysis {
+        let mut analyzer = LoudnessAnalyzer::new(rate, channels);
+        for part in parts {
+            analyzer.add(part);
+        }
+        analyzer.finish()
+    }
+
+    fn assert_near(measured: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (measured - expected).abs() <= tolerance,
+            "{measured} rather than {expected}"
+        );
+    }
+
+    #[test]
+    fn sine_on_one_channel_measures_to_bs_1770() {
+        // a 0 dBFS 1 kHz sine on a single channel is -3.01 LKFS
+        for rate in [44100, 48000] {
+            let analysis = measure(rate, 1, &[sine(1000.0, -20.0, 0.0, 5.0, rate, 1)]);
+            assert_near(analysis.loudness.integrated_lufs, -23.01, 0.1);
+            assert_near(analysis.loudness.range_lu, 0.0, 0.1);
+            assert_near(analysis.loudness.true_peak_dbtp, -20.0, 0.1);
+        }
+    }
+
+    #[test]
+    fn ebu_tech_3341_levels() {
+        // cases 1 and 2, stereo sines read as their level
+        for level in [-23.0, -33.0] {
+            let analysis = measure(48000, 2, &[sine(1000.0, level, 0.0, 5.0, 48000, 2)]);
+            assert_near(analysis.loudness.integrated_lufs, level as f32, 0.1);
+        }
+
+        // cases 3 to 5, where quieter stretches fall under the gates, a
+        // quarter as long
+        let tone = |level: f64, seconds: f64| sine(1000.0, level, 0.0, seconds, 48000, 2);
+        for parts in [
+            [tone(-36.0, 2.5), tone(-23.0, 15.0), tone(-36.0, 2.5)],
+            [tone(-72.0, 2.5), tone(-36.0, 2.5), tone(-23.0, 15.0)],
+            [tone(-26.0, 5.0), tone(-20.0, 5.025), tone(-26.0, 5.0)],
+        ] {
+            let analysis = measure(48000, 2, &parts);
+            assert_near(analysis.loudness.integrated_lufs, -23.0, 0.1);
+        }
+    }
+
+    #[test]
+    fn true_peak_is_found_between_samples() {
+        // a quarter of the sample rate, sampled 45 degrees off its peaks, so
+        // every sample is 3 dB under it; EBU Tech 3341 allows +0.2 / -0.4 dB
+        let samples = sine(12000.0, -6.0, PI / 4.0, 1.0, 48000, 1);
+        let sample_peak = samples.iter().fold(0.0, |peak: f32, x| peak.max(x.abs()));
+        assert_near(20.0 * sample_peak.log10(), -9.01, 0.05);
+
+        let analysis = measure(48000, 1, &[samples]);
+        let true_peak = analysis.loudness.true_peak_dbtp;
+        assert!((-6.4..=-5.8).contains(&true_peak), "{true_peak} dBTP");
+    }
+
+    #[test]
+    fn gain_derived_from_reference_levels() {
+        let loud = measure(48000, 1, &[sine(1000.0, -20.0, 0.0, 5.0, 48000, 1)]);
+        let replay_gain = loud.replay_gain();
+        // up from -23 to the -18 LUFS reference
+        assert_near(replay_gain.track_gain, 5.01, 0.1);
+        assert_near(replay_gain.track_peak, 0.1, 0.002);
+        assert!(replay_gain.album_gain.is_none());
+        assert_near(
+            ReplayGainMode::Track.gain_db(Some(&replay_gain)),
+            replay_gain.track_gain,
+            0.0,
+        );
+
+        // 10 dB quieter for as long, so the album's mean energy is 0.55 of
+        // the louder track's and neither is gated out
+        let quiet = measure(48000, 1, &[sine(1000.0, -30.0, 0.0, 5.0, 48000, 1)]);
+        assert_near(quiet.replay_gain().track_gain, 15.01, 0.1);
+        let (album_gain, album_peak) = LoudnessAnalysis::album_gain(&[&loud, &quiet]);
+        let album_lufs = -23.01 + 10.0 * 0.55f32.log10();
+        assert_near(album_gain, REPLAY_GAIN_REFERENCE_LUFS - album_lufs, 0.1);
+        assert_eq!(album_peak, loud.true_peak);
+
+        // a gain that would clip is held to the peak
+        let hot = measure(48000, 1, &[sine(1000.0, -3.0, 0.0, 5.0, 48000, 1)]);
+        let hot_gain = hot.replay_gain();
+        assert!(hot_gain.track_gain < 0.0);
+        let quiet_gain = ReplayGain {
+            track_gain: 6.0,
+            ..hot_gain
+        };
+        assert_near(ReplayGainMode::Track.gain_db(Some(&quiet_gain)), 3.0, 0.1);
+    }
+}
@@ -0,0 +1,265 @@
+use super::{
+    pipeline::{AudioPipe, Sample, TransformCtx},
+    volume::amplitude_to_db,
+};
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::Serialize;
+use std::{
+    f32::consts::PI,
+    sync::mpsc::{Receiver, SyncSender},
+};
+
+/// Levels and spectrum of the audio since the previous reading.
+#[derive(Clone, Debug, Serialize)]
+pub struct MeterReading {
+    pub peak_db: Vec<f32>,
+    pub rms_db: Vec<f32>,
+    /// Band levels of all channels mixed down, log spaced from `min_hz` to
+    /// `max_hz`.
+    pub spectrum_db: Vec<f32>,
+    pub min_hz: f32,
+    pub max_hz: f32,
+}
+
+type MeterCallback = Box<dyn Fn(MeterReading) + Send + Sync>;
+
+/// Passes samples through untouched while measuring them, handing a
+/// `MeterReading` to `on_reading` `rate` times a second of audio.
+///
+/// Readings are made on the audio thread, so `on_reading` should hand them
+/// off rather than wait on anything.
+pub struct MeterTap {
+    rate: f32,
+    bands: usize,
+    on_reading: MeterCallback,
+}
+
+impl MeterTap {
+    const FFT_SIZE: usize = 2048;
+    const MIN_HZ: f32 = 20.0;
+    const MAX_HZ: f32 = 20_000.0;
+    /// Reported for silence rather than negative infinity.
+    const FLOOR_DB: f32 = -120.0;
+
+    pub fn new<F>(rate: f32, bands: usize, on_reading: F) -> Self
+    where
+        F: Fn(MeterReading) + Send + Sync + 'static,
+    {
+        Self {
+            rate,
+            bands: bands.max(1),
+            on_reading: Box::new(on_reading),
+        }
+    }
+
+    fn to_db(amplitude: f32) -> f32 {
+        amplitude_to_db(amplitude).max(Self::FLOOR_DB)
+    }
+}
+
+impl AudioPipe for MeterTap {
+    fn pipe(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        to: SyncSender<Sample>,
+    ) -> Result<(), String> {
+        let channels = ctx.channels.max(1) as usize;
+        let interval = (ctx.sample_rate as f32 / self.rate).max(1.0) as usize;
+
+        let fft = FftPlanner::new().plan_fft_forward(Self::FFT_SIZE);
+        let window: Vec<f32> = (0..Self::FFT_SIZE)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / Self::FFT_SIZE as f32).cos())
+            .collect();
+        // by Parseval, a full scale sine's bins in the positive half add up
+        // to this, so it lands on 0 dB in its band
+        let full_scale = Self::FFT_SIZE as f32 * window.iter().map(|w| w * w).sum::<f32>() / 4.0;
+
+        let max_hz = Self::MAX_HZ.min(ctx.sample_rate as f32 / 2.0);
+        let bin_hz = ctx.sample_rate as f32 / Self::FFT_SIZE as f32;
+        let band_edges: Vec<usize> = (0..=self.bands)
+            .map(|band| {
+                let hz =
+                    Self::MIN_HZ * (max_hz / Self::MIN_HZ).powf(band as f32 / self.bands as f32);
+                ((hz / bin_hz).round() as usize).min(Self::FFT_SIZE / 2)
+            })
+            .collect();
+
+        let mut peaks = vec![0f32; channels];
+        let mut squares = vec![0f32; channels];
+        // the most recent FFT_SIZE frames mixed down, oldest at `history_pos`
+        let mut history = vec![0f32; Self::FFT_SIZE];
+        let mut history_pos = 0;
+        let mut buffer = vec![Complex::default(); Self::FFT_SIZE];
+        let mut mix = 0.0;
+        let mut channel = 0;
+        let mut frames = 0;
+
+        for sample in from {
+            if let Err(err) = to.send(sample) {
+                return Err(format!("error sending sample: {err}"));
+            }
+
+            peaks[channel] = peaks[channel].max(sample.abs());
+            squares[channel] += sample * sample;
+            mix += sample;
+
+            channel += 1;
+            if channel < channels {
+                continue;
+            }
+            channel = 0;
+            history[history_pos] = mix / channels as f32;
+            history_pos = (history_pos + 1) % Self::FFT_SIZE;
+            mix = 0.0;
+
+            frames += 1;
+            if frames < interval {
+                continue;
+            }
+
+            for (n, value) in buffer.iter_mut().enumerate() {
+                let sample = history[(history_pos + n) % Self::FFT_SIZE];
+                *value = Complex::new(sample * window[n], 0.0);
+            }
+            fft.process(&mut buffer);
+
+            // a band too narrow to have a bin of its own uses the nearest one
+            let spectrum_db = band_edges
+                .windows(2)
+                .map(|edges| {
+                    let end = edges[1].max(edges[0] + 1).min(Self::FFT_SIZE / 2 + 1);
+                    let start = edges[0].min(end - 1);
+                    let power: f32 = buffer[start..end].iter().map(|bin| bin.norm_sqr()).sum();
+                    Self::to_db((power / full_scale).sqrt())
+                })
+                .collect();
+
+            (self.on_reading)(MeterReading {
+                peak_db: peaks.iter().map(|peak| Self::to_db(*peak)).collect(),
+                rms_db: squares
+                    .iter()
+                    .map(|sum| Self::to_db((sum / frames as f32).sqrt()))
+                    .collect(),
+                spectrum_db,
+                min_hz: Self::MIN_HZ,
+                max_hz,
+            });
+
+            peaks.fill(0.0);
+            squares.fill(0.0);
+            frames = 0;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{mpsc::sync_channel, Arc, Mutex},
+        thread,
+    };
+
+    const RATE: u32 = 48_000;
+
+    /// Runs `input` through a tap making `readings_per_second` readings in
+    /// `bands` bands, returning what came out and the readings.
+    fn meter(
+        input: Vec<Sample>,
+        readings_per_second: f32,
+        bands: usize,
+    ) -> (Vec<Sample>, Vec<MeterReading>) {
+        let readings = Arc::new(Mutex::new(Vec::new()));
+        let collected = readings.clone();
+        let tap = MeterTap::new(readings_per_second, bands, move |reading| {
+            collected.lock().unwrap().push(reading)
+        });
+        let ctx = TransformCtx {
+            window_size: 1024,
+            fitting_buffer: 1024,
+            sample_rate: RATE,
+            channels: 2,
+        };
+
+        let (sender, from) = sync_channel(input.len());
+        let (to, receiver) = sync_channel(input.len());
+        for sample in input {
+            sender.send(sample).unwrap();
+        }
+        drop(sender);
+        thread::spawn(move || tap.pipe(&ctx, from, to))
+            .join()
+            .unwrap()
+            .unwrap();
+
+        let output = receiver.iter().collect();
+        let readings = readings.lock().unwrap().clone();
+        (output, readings)
+    }
+
+    /// A 1 kHz sine, half scale on the left and quarter on the right.
+    fn sine(frames: usize) -> Vec<Sample> {
+        (0..frames)
+            .flat_map(|n| {
+                let value = (2.0 * PI * 1000.0 * n as f32 / RATE as f32).sin();
+                [0.5 * value, 0.25 * value]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sine_reads_at_its_level_and_frequency() {
+        let input = sine(RATE as usize);
+        let (output, readings) = meter(input.clone(), 10.0, 10);
+        assert_eq!(output, input);
+        assert_eq!(readings.len(), 10);
+
+        for reading in readings {
+            let expected = [
+                (reading.peak_db[0], 20.0 * 0.5f32.log10()),
+                (reading.peak_db[1], 20.0 * 0.25f32.log10()),
+                (reading.rms_db[0], 20.0 * (0.5 / 2f32.sqrt()).log10()),
+                (reading.rms_db[1], 20.0 * (0.25 / 2f32.sqrt()).log10()),
+            ];
+            for (level, expected) in expected {
+                assert!((level - expected).abs() < 0.05, "{level} vs {expected}");
+            }
+
+            // 1 kHz falls between 632 Hz and 1262 Hz, the sixth of ten bands
+            // doubling from 20 Hz
+            assert_eq!(reading.spectrum_db.len(), 10);
+            assert_eq!(reading.max_hz, 20_000.0);
+            let mixed = 20.0 * 0.375f32.log10();
+            for (band, level) in reading.spectrum_db.iter().enumerate() {
+                match band {
+                    5 => assert!((level - mixed).abs() < 1.0, "{level} vs {mixed}"),
+                    _ => assert!(*level < mixed - 30.0, "band {band} at {level}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn readings_come_at_the_rate_asked_for() {
+        // the half interval left over at the end isn't read
+        let (_, readings) = meter(sine(RATE as usize * 2 + RATE as usize / 50), 25.0, 4);
+        assert_eq!(readings.len(), 50);
+
+        let (_, readings) = meter(sine(RATE as usize / 2), 4.0, 4);
+        assert_eq!(readings.len(), 2);
+    }
+
+    #[test]
+    fn silence_reads_at_the_floor() {
+        let (_, readings) = meter(vec![0.0; RATE as usize * 2], 2.0, 8);
+        assert_eq!(readings.len(), 2);
+        for reading in readings {
+            assert_eq!(reading.peak_db, vec![MeterTap::FLOOR_DB; 2]);
+            assert_eq!(reading.rms_db, vec![MeterTap::FLOOR_DB; 2]);
+            assert_eq!(reading.spectrum_db, vec![MeterTap::FLOOR_DB; 8]);
+        }
+    }
+}
@@ -1,3 +1,24 @@
 pub mod provider;
 pub mod audio;
-pub mod gateway;
\ No newline at end of file
+pub mod gateway;
+pub mod playback;
+pub mod session;
+pub mod pipeline;
+pub mod drain;
+pub mod clock;
+pub mod player;
+pub mod volume;
+pub mod quantize;
+pub mod pipeline_config;
+pub mod params;
+pub mod filters;
+pub mod graph;
+pub mod flush;
+pub mod spectral;
+pub mod loudness;
+pub mod waveform;
+pub mod meter;
+pub mod playlist;
+pub mod smart_playlist;
+pub mod fingerprint;
+pub mod convert;
@@ -0,0 +1,379 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+/// A stage parameter that can be changed from any thread while the stage is
+/// running. The audio thread only ever does an atomic load, so it never waits
+/// on whoever is setting it.
+pub struct StageParam {
+    value: AtomicU32,
+    initial: f32,
+    min: f32,
+    max: f32,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ParamStatus {
+    pub value: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+#[derive(Debug)]
+pub enum ParamError {
+    UnknownStage(String),
+    UnknownParam(String),
+    OutOfRange { value: f32, min: f32, max: f32 },
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::UnknownStage(stage) => write!(f, "no stage named \"{stage}\""),
+            ParamError::UnknownParam(param) => write!(f, "no parameter named \"{param}\""),
+            ParamError::OutOfRange { value, min, max } => {
+                write!(f, "{value} is outside of {min}..={max}")
+            }
+        }
+    }
+}
+
+impl StageParam {
+    pub fn new(value: f32, min: f32, max: f32) -> Result<Self, ParamError> {
+        let param = Self {
+            value: AtomicU32::new(0f32.to_bits()),
+            initial: value,
+            min,
+            max,
+        };
+        param.set(value)?;
+
+        Ok(param)
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.value.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, value: f32) -> Result<(), ParamError> {
+        if !(self.min..=self.max).contains(&value) {
+            return Err(ParamError::OutOfRange {
+                value,
+                min: self.min,
+                max: self.max,
+            });
+        }
+
+        self.value.store(value.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether it was set since being created from the config.
+    pub fn is_changed(&self) -> bool {
+        self.get() != self.initial
+    }
+
+    pub fn status(&self) -> ParamStatus {
+        ParamStatus {
+            value: self.get(),
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+/// The audio thread's view of a `StageParam`, eased towards the latest value
+/// over `ramp` so a jump in the setting doesn't click.
+pub struct SmoothedParam {
+    param: Arc<StageParam>,
+    coefficient: f32,
+    // unset until the first frame, so a value carried over from a previous
+    // pipeline is picked up without ramping from the configured one
+    current: Option<f32>,
+}
+
+impl SmoothedParam {
+    const SNAP_THRESHOLD: f32 = 1e-5;
+
+    pub fn new(param: Arc<StageParam>, ramp: Duration, sample_rate: u32) -> Self {
+        let ramp_frames = (ramp.as_secs_f32() * sample_rate as f32).max(1.0);
+        Self {
+            param,
+            coefficient: 1.0 - (-1.0 / ramp_frames).exp(),
+            current: None,
+        }
+    }
+
+    /// Advances by one frame and returns the value to use for it.
+    pub fn next_value(&mut self) -> f32 {
+        let target = self.param.get();
+        let mut current = match self.current {
+            Some(current) => current + (target - current) * self.coefficient,
+            None => target,
+        };
+        if (target - current).abs() < Self::SNAP_THRESHOLD {
+            current = target;
+        }
+
+        self.current = Some(current);
+        current
+    }
+}
+
+/// The live parameters of one stage, addressed by the stage's name.
+pub struct StageControls {
+    name: String,
+    stage_type: String,
+    params: BTreeMap<String, Arc<StageParam>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StageStatus {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub stage_type: String,
+    pub params: BTreeMap<String, ParamStatus>,
+}
+
+impl StageControls {
+    pub fn new(name: &str, stage_type: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            stage_type: stage_type.to_string(),
+            params: BTreeMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn insert(&mut self, name: &str, param: Arc<StageParam>) {
+        self.params.insert(name.to_string(), param);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<StageParam>> {
+        self.params.get(name)
+    }
+
+    pub fn status(&self) -> StageStatus {
+        StageStatus {
+            name: self.name.clone(),
+            stage_type: self.stage_type.clone(),
+            params: self
+                .params
+                .iter()
+                .map(|(name, param)| (name.clone(), param.status()))
+                .collect(),
+        }
+    }
+}
+
+/// Controls for every stage of the running pipeline, shared with the gateway.
+pub struct PipelineControls {
+    stages: RwLock<Vec<StageControls>>,
+}
+
+impl Default for PipelineControls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PipelineControls {
+    pub fn new() -> Self {
+        Self {
+            stages: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Sets values that were changed live on the running pipeline on the
+    /// controls of a newly built one, for stages and parameters of the same
+    /// name, so a config reload doesn't undo them. Done before the new
+    /// pipeline runs, so they're there from its first frame.
+    pub fn carry_over(&self, stages: &[StageControls]) {
+        Self::carry_over_from(&self.stages.read().unwrap(), stages);
+    }
+
+    fn carry_over_from(current: &[StageControls], stages: &[StageControls]) {
+        for stage in stages.iter() {
+            let previous = match current.iter().find(|s| s.name == stage.name) {
+                Some(previous) if previous.stage_type == stage.stage_type => previous,
+                _ => continue,
+            };
+            for (name, param) in stage.params.iter() {
+                if let Some(previous) = previous.get(name).filter(|p| p.is_changed()) {
+                    // out of range means the config narrowed it, keep the new value
+                    let _ = param.set(previous.get());
+                }
+            }
+        }
+    }
+
+    /// Swaps in the controls of a newly built pipeline, once it's running.
+    /// Values carry over as with `carry_over`, again, in case any changed
+    /// since.
+    pub fn replace(&self, stages: Vec<StageControls>) {
+        let mut current = self.stages.write().unwrap();
+        Self::carry_over_from(&current, &stages);
+        *current = stages;
+    }
+
+    pub fn status(&self) -> Vec<StageStatus> {
+        self.stages
+            .read()
+            .unwrap()
+            .iter()
+            .map(StageControls::status)
+            .collect()
+    }
+
+    pub fn set(&self, stage: &str, param: &str, value: f32) -> Result<ParamStatus, ParamError> {
+        let stages = self.stages.read().unwrap();
+        let stage = match stages.iter().find(|s| s.name == stage) {
+            Some(stage) => stage,
+            None => return Err(ParamError::UnknownStage(stage.to_string())),
+        };
+        let param = match stage.get(param) {
+            Some(param) => param,
+            None => return Err(ParamError::UnknownParam(param.to_string())),
+        };
+
+        param.set(value)?;
+        Ok(param.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(name: &str, stage_type: &str, params: &[(&str, f32, f32, f32)]) -> StageControls {
+        let mut controls = StageControls::new(name, stage_type);
+        for (param, value, min, max) in params {
+            controls.insert(
+                param,
+                Arc::new(StageParam::new(*value, *min, *max).unwrap()),
+            );
+        }
+        controls
+    }
+
+    fn value(stages: &[StageControls], stage: &str, param: &str) -> f32 {
+        stages
+            .iter()
+            .find(|s| s.name() == stage)
+            .and_then(|s| s.get(param))
+            .unwrap()
+            .get()
+    }
+
+    #[test]
+    fn smoothed_param_ramps_without_a_step() {
+        let param = Arc::new(StageParam::new(0.0, 0.0, 1.0).unwrap());
+        // a 10 frame ramp
+        let mut smoothed = SmoothedParam::new(param.clone(), Duration::from_millis(10), 1000);
+        assert_eq!(smoothed.next_value(), 0.0);
+
+        param.set(1.0).unwrap();
+        let mut previous = 0.0;
+        for _ in 0..10 {
+            let value = smoothed.next_value();
+            assert!(value > previous);
+            assert!(value - previous < 0.1);
+            previous = value;
+        }
+        // most of the way there after the ramp, and all the way soon after
+        assert!(previous > 0.6 && previous < 1.0);
+        for _ in 0..200 {
+            smoothed.next_value();
+        }
+        assert_eq!(smoothed.next_value(), 1.0);
+    }
+
+    #[test]
+    fn smoothed_param_starts_at_the_current_value() {
+        let param = Arc::new(StageParam::new(0.0, 0.0, 1.0).unwrap());
+        param.set(0.5).unwrap();
+        let mut smoothed = SmoothedParam::new(param, Duration::from_millis(10), 1000);
+        assert_eq!(smoothed.next_value(), 0.5);
+    }
+
+    #[test]
+    fn out_of_range_values_are_refused() {
+        let param = StageParam::new(0.5, 0.0, 1.0).unwrap();
+        assert!(matches!(param.set(1.5), Err(ParamError::OutOfRange { .. })));
+        assert_eq!(param.get(), 0.5);
+        assert!(StageParam::new(2.0, 0.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn carry_over_keeps_live_changes() {
+        let controls = PipelineControls::new();
+        controls.replace(vec![
+            stage("crush", "bit_crusher", &[("mix", 1.0, 0.0, 1.0)]),
+            stage("trim", "gain", &[("gain_db", 0.0, -60.0, 24.0)]),
+            stage("eq", "gain", &[("gain_db", 0.0, -60.0, 24.0)]),
+        ]);
+        controls.set("crush", "mix", 0.25).unwrap();
+        controls.set("trim", "gain_db", 12.0).unwrap();
+        controls.set("eq", "gain_db", -3.0).unwrap();
+
+        let reloaded = vec![
+            // changed live, so it stays at 0.25 over the configured 0.8
+            stage("crush", "bit_crusher", &[("mix", 0.8, 0.0, 1.0)]),
+            // the config narrowed the range past the live value
+            stage("trim", "gain", &[("gain_db", 0.0, -6.0, 6.0)]),
+            // a different kind of stage under the same name
+            stage("eq", "low_pass", &[("gain_db", 1.0, -60.0, 24.0)]),
+            stage("new", "gain", &[("gain_db", 2.0, -60.0, 24.0)]),
+        ];
+        controls.carry_over(&reloaded);
+        assert_eq!(value(&reloaded, "crush", "mix"), 0.25);
+        assert_eq!(value(&reloaded, "trim", "gain_db"), 0.0);
+        assert_eq!(value(&reloaded, "eq", "gain_db"), 1.0);
+        assert_eq!(value(&reloaded, "new", "gain_db"), 2.0);
+    }
+
+    #[test]
+    fn replace_carries_over_changes_made_meanwhile() {
+        let controls = PipelineControls::new();
+        controls.replace(vec![stage(
+            "trim",
+            "gain",
+            &[("gain_db", 0.0, -60.0, 24.0)],
+        )]);
+        let reloaded = vec![stage("trim", "gain", &[("gain_db", 0.0, -60.0, 24.0)])];
+        controls.carry_over(&reloaded);
+
+        // set while the new pipeline was starting
+        controls.set("trim", "gain_db", 6.0).unwrap();
+        controls.replace(reloaded);
+        assert_eq!(controls.status()[0].params["gain_db"].value, 6.0);
+    }
+
+    #[test]
+    fn unknown_stages_and_params_are_errors() {
+        let controls = PipelineControls::new();
+        controls.replace(vec![stage(
+            "trim",
+            "gain",
+            &[("gain_db", 0.0, -60.0, 24.0)],
+        )]);
+        assert!(matches!(
+            controls.set("nope", "gain_db", 0.0),
+            Err(ParamError::UnknownStage(_))
+        ));
+        assert!(matches!(
+            controls.set("trim", "nope", 0.0),
+            Err(ParamError::UnknownParam(_))
+        ));
+    }
+}
@@ -1,83 +1,172 @@
+use super::{
+    clock::PlaybackClock,
+    drain::Drain,
+    flush::{self, FlushControl},
+};
 use std::{
     sync::{
-        mpsc::{sync_channel, Receiver, SendError, SyncSender, TryRecvError},
+        mpsc::{sync_channel, Receiver, SyncSender},
         Arc,
     },
-    thread::JoinHandle,
+    thread::{self, JoinHandle},
 };
 
-trait AudioPipe: Sync {
+pub type Sample = f32;
+
+/// What travels between the stages of an `AudioPipeline`: samples, and the
+/// barriers that mark the end of stale audio during a flush.
+#[derive(Clone, Copy, Debug)]
+pub enum PipeMessage {
+    Sample(Sample),
+    Flush(u64),
+}
+
+pub trait AudioPipe: Send + Sync {
+    /// Called once before the stage first runs.
+    fn init(&self, _ctx: &TransformCtx) -> Result<(), String> {
+        Ok(())
+    }
+
     fn pipe(
         &self,
         ctx: &TransformCtx,
         from: Receiver<Sample>,
         to: SyncSender<Sample>,
     ) -> Result<(), String>;
+
+    /// Frames of delay the stage adds between input and output, so parallel
+    /// branches can be lined up where they merge.
+    fn latency(&self, _ctx: &TransformCtx) -> usize {
+        0
+    }
+
+    /// Forgets any state carried between samples, like filter history or a
+    /// reverb tail. Called during a flush, between one run of `pipe` and the
+    /// next.
+    fn purge(&self) {}
 }
 
-trait AudioFilter: Sync {
+pub trait AudioFilter: Send + Sync {
+    /// Transforms one window of interleaved samples in place. Only the last
+    /// `hop_size` samples are new and only they are passed on, anything
+    /// before them is the input that came before, there as context. The last
+    /// window of a stream can be shorter.
     fn transform(&self, ctx: &TransformCtx, data: &mut [Sample]) -> Result<(), String>;
+
+    /// Samples the window advances by, defaulting to the whole window. Less
+    /// than `ctx.window_size` makes windows overlap, as STFT-style filters
+    /// need. Has to be a whole number of frames.
+    fn hop_size(&self, ctx: &TransformCtx) -> usize {
+        ctx.window_size
+    }
+
+    /// See `AudioPipe::latency`. That many frames of silence are run
+    /// through after the end of a stream, to push out what the filter is
+    /// still holding.
+    fn latency(&self, _ctx: &TransformCtx) -> usize {
+        0
+    }
+
+    /// See `AudioPipe::purge`.
+    fn purge(&self) {}
 }
 
 impl AudioPipe for dyn AudioFilter {
+    fn init(&self, ctx: &TransformCtx) -> Result<(), String> {
+        let hop_size = self.hop_size(ctx);
+        if hop_size == 0 || hop_size > ctx.window_size {
+            return Err(format!(
+                "hop size {hop_size} doesn't fit a window of {}",
+                ctx.window_size
+            ));
+        }
+        if !hop_size.is_multiple_of(ctx.channels.max(1) as usize) {
+            return Err(format!(
+                "hop size {hop_size} isn't a whole number of {} channel frames",
+                ctx.channels
+            ));
+        }
+
+        Ok(())
+    }
+
     fn pipe(
         &self,
         ctx: &TransformCtx,
         from: Receiver<Sample>,
         to: SyncSender<Sample>,
     ) -> Result<(), String> {
-        let mut recv_result: Result<Sample, TryRecvError>;
-        let mut send_result: Result<(), SendError<Sample>>;
-        let mut sample_window = Vec::<Sample>::with_capacity(ctx.window_size);
-        let mut transform_result: Result<(), String>;
-
-        loop {
-            let mut i = 0;
-            while i < ctx.window_size {
-                recv_result = from.try_recv();
-                if let Err(err) = recv_result {
-                    match err {
-                        TryRecvError::Empty => break,
-                        TryRecvError::Disconnected => {
-                            return Err(format!("error recieving sample: {err}"))
-                        }
+        let hop_size = self.hop_size(ctx).clamp(1, ctx.window_size.max(1));
+        let context = ctx.window_size.max(1) - hop_size;
+
+        // the input history ahead of the new samples starts out silent, and
+        // is kept separately since the filter overwrites its window
+        let mut input = vec![0.0; context];
+        input.reserve(hop_size);
+        let mut window = Vec::with_capacity(context + hop_size);
+
+        let mut tail = AudioFilter::latency(self, ctx) * ctx.channels.max(1) as usize;
+        let mut open = true;
+        while open || tail > 0 {
+            while input.len() < context + hop_size {
+                if !open {
+                    if tail == 0 {
+                        break;
                     }
+                    tail -= 1;
+                    input.push(0.0);
+                    continue;
                 }
+                match from.recv() {
+                    Ok(sample) => input.push(sample),
+                    Err(_) => open = false,
+                }
+            }
 
-                sample_window.push(recv_result.unwrap());
-                i += 1;
+            let new_samples = input.len() - context;
+            if new_samples == 0 {
+                break;
             }
 
-            transform_result = self.transform(ctx, &mut sample_window[0..(i - 1)]);
-            if let Err(err) = transform_result {
+            window.clear();
+            window.extend_from_slice(&input);
+            if let Err(err) = self.transform(ctx, &mut window) {
                 return Err(format!("error transforming sample window: {err}"));
             }
 
-            for sample in sample_window.iter() {
-                send_result = to.send(*sample);
-                if let Err(err) = send_result {
+            for sample in window[context..].iter() {
+                if let Err(err) = to.send(*sample) {
                     return Err(format!("error sending result: {err}"));
                 }
             }
 
-            sample_window.truncate(0);
+            input.drain(..new_samples);
         }
+
+        Ok(())
+    }
+
+    fn latency(&self, ctx: &TransformCtx) -> usize {
+        AudioFilter::latency(self, ctx)
+    }
+
+    fn purge(&self) {
+        AudioFilter::purge(self);
     }
 }
 
 impl AudioFilter for dyn AudioPipe {
-    fn transform(&self, ctx: &TransformCtx, data: &mut [Sample]) -> Result<(), String> {
-        let sample_count = data.len();
-        let (sender, reciever) = sync_channel(sample_count);
+    fn transform(&self, _ctx: &TransformCtx, data: &mut [Sample]) -> Result<(), String> {
+        let (sender, reciever) = sync_channel(data.len());
         for sample in data.iter() {
-            if let Err(err) = sender.send(sample.clone()) {
+            if let Err(err) = sender.send(*sample) {
                 return Err(format!("error sending sample: {err}"));
             }
         }
 
-        for i in 0..sample_count {
+        for sample in data.iter_mut() {
             match reciever.recv() {
-                Ok(transformed) => data[i] = transformed,
+                Ok(transformed) => *sample = transformed,
                 Err(err) => return Err(format!("error recieving sample {err}")),
             }
         }
@@ -86,80 +175,323 @@ impl AudioFilter for dyn AudioPipe {
     }
 }
 
-struct TransformCtx {
-    window_size: usize,
-    fitting_buffer: usize,
-}
+/// Runs an `AudioFilter` as a pipeline stage.
+pub struct FilterPipe(pub Arc<dyn AudioFilter>);
 
-struct AudioTransformation<const Len: usize> {
-    ctx: TransformCtx,
-    transformations: Vec<Arc<dyn AudioFilter>>,
-}
+impl AudioPipe for FilterPipe {
+    fn pipe(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        to: SyncSender<Sample>,
+    ) -> Result<(), String> {
+        self.0.pipe(ctx, from, to)
+    }
 
-impl<const Len: usize> AudioTransformation<Len> {
-    fn new(
-        ctx: TransformCtx,
-        transformations: &[&Arc<dyn AudioFilter>],
-    ) -> Self {
-        Self {
-            ctx,
-            transformations: transformations
-                .into_iter()
-                .map(|arc| (*arc).clone())
-                .collect(),
-        }
+    fn init(&self, ctx: &TransformCtx) -> Result<(), String> {
+        AudioPipe::init(&*self.0, ctx)
     }
 
-    fn transform(&mut self, source: &mut Audio) -> Result<(), String> {
-        for transformation in self.transformations.iter() {
-            if let Err(err) = transformation.transform(&self.ctx, &mut source.samples) {
-                return Err(format!("error transforming source: {err}"));
-            }
-        }
+    fn latency(&self, ctx: &TransformCtx) -> usize {
+        AudioFilter::latency(&*self.0, ctx)
+    }
 
-        Ok(())
+    fn purge(&self) {
+        AudioFilter::purge(&*self.0);
     }
 }
 
-struct AudioPipeline {
+#[derive(Clone, Debug)]
+pub struct TransformCtx {
+    pub window_size: usize,
+    pub fitting_buffer: usize,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+pub struct AudioPipeline {
     ctx: Arc<TransformCtx>,
     pipes: Vec<Arc<dyn AudioPipe>>,
 }
 
+pub type PipeHandle = JoinHandle<Result<(), String>>;
+
 impl AudioPipeline {
-    fn new(ctx: TransformCtx, pipes: &[&Arc<dyn AudioPipe>]) -> Self {
+    pub fn new(ctx: TransformCtx, pipes: &[&Arc<dyn AudioPipe>]) -> Self {
         Self {
             ctx: Arc::new(ctx),
-            pipes: pipes.into_iter().map(|arc| (*arc).clone()).collect(),
+            pipes: pipes.iter().map(|arc| (*arc).clone()).collect(),
         }
     }
 
-    fn pipe(
+    pub fn ctx(&self) -> &TransformCtx {
+        &self.ctx
+    }
+
+    /// Starts every stage, each on its own thread, with `flush` used to
+    /// coordinate flushes sent down `from`.
+    pub fn pipe(
         &self,
-        from: Receiver<Sample>,
-        to: SyncSender<Sample>,
-    ) -> Result<Vec<JoinHandle<()>>, String> {
-        let mut next_reciever = from;
-        let mut reciever: Receiver<Sample>;
-        let mut sender: SyncSender<Sample>;
+        from: Receiver<PipeMessage>,
+        to: SyncSender<PipeMessage>,
+        flush: Arc<FlushControl>,
+    ) -> Result<Vec<PipeHandle>, String> {
+        if self.pipes.is_empty() {
+            return Ok(vec![thread::spawn(move || flush::forward(flush, from, to))]);
+        }
 
-        let pipe_count = self.pipes.len();
-        let mut handles = Vec::with_capacity(pipe_count);
+        for (i, pipe) in self.pipes.iter().enumerate() {
+            if let Err(err) = pipe.init(&self.ctx) {
+                return Err(format!("error initializing stage {i}: {err}"));
+            }
+        }
 
-        let mut ctx_arc: Arc<TransformCtx>;
-        let mut pipe_arc: Arc<dyn AudioPipe>;
+        let mut handles = Vec::with_capacity(self.pipes.len());
+        let mut next_reciever = Some(from);
+        let mut last_sender = Some(to);
         for (i, pipe) in self.pipes.iter().enumerate() {
-            ctx_arc = self.ctx.clone();
-            pipe_arc = (*pipe).clone();
+            let reciever = next_reciever.take().unwrap();
+            let sender = match i + 1 == self.pipes.len() {
+                true => last_sender.take().unwrap(),
+                false => {
+                    let (pipe_sender, pipe_reciever) = sync_channel(self.ctx.fitting_buffer);
+                    next_reciever = Some(pipe_reciever);
+                    pipe_sender
+                }
+            };
 
-            reciever = next_reciever;
-            (sender, next_reciever) = sync_channel(ctx_arc.fitting_buffer);
+            let (pipe, ctx, flush) = (pipe.clone(), self.ctx.clone(), flush.clone());
+            handles.push(thread::spawn(move || {
+                flush::run_stage(pipe, ctx, flush, reciever, sender)
+            }));
+        }
+
+        Ok(handles)
+    }
 
-            // handles.push(thread::spawn(move || {
-            //     pipe_arc.pipe(&(*ctx_arc), from, to);
-            // }));
+    /// Runs the pipeline with its output consumed by `drain`. The returned
+    /// handles include the drain's, which finishes once `from` disconnects
+    /// and everything before it has been played out.
+    pub fn drain(
+        &self,
+        from: Receiver<PipeMessage>,
+        drain: Arc<dyn Drain>,
+        clock: Arc<PlaybackClock>,
+        flush: Arc<FlushControl>,
+    ) -> Result<Vec<PipeHandle>, String> {
+        if let Err(err) = drain.init(&self.ctx) {
+            return Err(format!("error initializing drain: {err}"));
         }
 
+        let (sender, reciever) = sync_channel(self.ctx.fitting_buffer);
+        let mut handles = self.pipe(from, sender, flush.clone())?;
+
+        // kept short, whatever is in here when a flush reaches the drain
+        // still gets played
+        let (drain_sender, drain_reciever) = sync_channel(self.ctx.window_size);
+        let feed_drain = drain.clone();
+        handles.push(thread::spawn(move || {
+            flush::feed_drain(feed_drain, flush, reciever, drain_sender)
+        }));
+
+        let ctx_arc = self.ctx.clone();
+        handles.push(thread::spawn(move || {
+            drain.drain(&ctx_arc, drain_reciever, clock)
+        }));
+
         Ok(handles)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Passes windows through untouched, keeping a copy of each.
+    struct Recorder {
+        hop_size: usize,
+        latency: usize,
+        windows: Mutex<Vec<Vec<Sample>>>,
+    }
+
+    impl AudioFilter for Recorder {
+        fn transform(&self, _ctx: &TransformCtx, data: &mut [Sample]) -> Result<(), String> {
+            self.windows.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+
+        fn hop_size(&self, _ctx: &TransformCtx) -> usize {
+            self.hop_size
+        }
+
+        fn latency(&self, _ctx: &TransformCtx) -> usize {
+            self.latency
+        }
+    }
+
+    fn ctx(window_size: usize, channels: u16) -> TransformCtx {
+        TransformCtx {
+            window_size,
+            fitting_buffer: 64,
+            sample_rate: 44100,
+            channels,
+        }
+    }
+
+    fn recorder(hop_size: usize, latency: usize) -> Arc<Recorder> {
+        Arc::new(Recorder {
+            hop_size,
+            latency,
+            windows: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn run(filter: Arc<Recorder>, ctx: &TransformCtx, input: &[Sample]) -> Vec<Sample> {
+        let pipe = FilterPipe(filter);
+        pipe.init(ctx).unwrap();
+        let (sender, reciever) = sync_channel(input.len());
+        for sample in input {
+            sender.send(*sample).unwrap();
+        }
+        drop(sender);
+
+        let (out_sender, out_reciever) = sync_channel(input.len() + ctx.window_size);
+        pipe.pipe(ctx, reciever, out_sender).unwrap();
+        out_reciever.iter().collect()
+    }
+
+    #[test]
+    fn windows_overlap_by_the_context() {
+        let filter = recorder(2, 0);
+        let output = run(filter.clone(), &ctx(4, 1), &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        assert_eq!(output, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(
+            *filter.windows.lock().unwrap(),
+            vec![
+                vec![0.0, 0.0, 1.0, 2.0],
+                vec![1.0, 2.0, 3.0, 4.0],
+                vec![3.0, 4.0, 5.0, 6.0],
+            ]
+        );
+    }
+
+    #[test]
+    fn last_window_can_be_short() {
+        let filter = recorder(4, 0);
+        let output = run(filter.clone(), &ctx(8, 2), &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        assert_eq!(output, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(
+            *filter.windows.lock().unwrap(),
+            vec![
+                vec![0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 4.0],
+                vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+            ]
+        );
+    }
+
+    #[test]
+    fn latency_and_hop_come_from_the_filter() {
+        let ctx = ctx(8, 2);
+        assert_eq!(FilterPipe(recorder(4, 2)).latency(&ctx), 2);
+        assert!(FilterPipe(recorder(3, 0)).init(&ctx).is_err());
+        assert!(FilterPipe(recorder(10, 0)).init(&ctx).is_err());
+    }
+
+    #[test]
+    fn latency_is_flushed_with_silence() {
+        let filter = recorder(4, 1);
+        let output = run(filter.clone(), &ctx(4, 2), &[1.0, 2.0, 3.0, 4.0]);
+
+        assert_eq!(output, vec![1.0, 2.0, 3.0, 4.0, 0.0, 0.0]);
+        assert_eq!(filter.windows.lock().unwrap().len(), 2);
+    }
+
+    /// Outputs the samples `latency` frames behind the new ones, taken from
+    /// the window's context.
+    struct Delay {
+        hop_size: usize,
+        latency: usize,
+    }
+
+    impl AudioFilter for Delay {
+        fn transform(&self, ctx: &TransformCtx, data: &mut [Sample]) -> Result<(), String> {
+            let shift = self.latency * ctx.channels as usize;
+            let context = ctx.window_size - self.hop_size;
+            data.copy_within(context - shift..data.len() - shift, context);
+            Ok(())
+        }
+
+        fn hop_size(&self, _ctx: &TransformCtx) -> usize {
+            self.hop_size
+        }
+
+        fn latency(&self, _ctx: &TransformCtx) -> usize {
+            self.latency
+        }
+    }
+
+    /// xorshift, enough to pick test cases without pulling in a crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn every_sample_passes_through_once_in_order() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for case in 0..300 {
+            let channels = 1 + rng.below(3) as u16;
+            let ch = channels as usize;
+            let hop_size = ch * (1 + rng.below(6));
+            let window_size = hop_size + rng.below(12);
+            let latency = rng.below((window_size - hop_size) / ch + 1);
+            let fitting_buffer = 1 + rng.below(16);
+            let frames = rng.below(40);
+            let ctx = TransformCtx {
+                window_size,
+                fitting_buffer,
+                sample_rate: 44100,
+                channels,
+            };
+
+            // distinct and non-zero, so a repeat or a lost sample can't hide
+            // behind the silence the filter starts with
+            let input: Vec<Sample> = (1..=frames * ch).map(|i| i as Sample).collect();
+            let pipe: Arc<dyn AudioPipe> =
+                Arc::new(FilterPipe(Arc::new(Delay { hop_size, latency })));
+            pipe.init(&ctx).unwrap();
+            assert_eq!(pipe.latency(&ctx), latency);
+
+            let (sender, reciever) = sync_channel(fitting_buffer);
+            let (out_sender, out_reciever) = sync_channel(fitting_buffer);
+            let (stage, stage_ctx) = (pipe.clone(), ctx.clone());
+            let handle = thread::spawn(move || stage.pipe(&stage_ctx, reciever, out_sender));
+            let feeder_input = input.clone();
+            let feeder = thread::spawn(move || {
+                for sample in feeder_input {
+                    sender.send(sample).unwrap();
+                }
+            });
+            let output: Vec<Sample> = out_reciever.iter().collect();
+            feeder.join().unwrap();
+            handle.join().unwrap().unwrap();
+
+            let mut expected = vec![0.0; latency * ch];
+            expected.extend_from_slice(&input);
+            assert_eq!(
+                output, expected,
+                "case {case}: window {window_size}, hop {hop_size}, {channels} channels, \
+                 latency {latency}, buffer {fitting_buffer}, {frames} frames"
+            );
+        }
+    }
+}
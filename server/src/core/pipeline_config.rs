@@ -0,0 +1,694 @@
+use super::{
+    filters::{Gain, LowPass},
+    graph::{ErrorPolicy, GraphPipe, PipelineGraph},
+    params::{SmoothedParam, StageControls, StageParam},
+    pipeline::{AudioPipe, AudioPipeline, FilterPipe, TransformCtx},
+    quantize::BitCrusher,
+    spectral::{SpectralEq, SpectralGate, StftFilter},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+/// A filter chain as written in a TOML or JSON file:
+///
+/// ```toml
+/// window_size = 1024
+/// fitting_buffer = 16384
+///
+/// [[stages]]
+/// type = "bit_crusher"
+/// name = "crusher"
+/// bits = 8
+/// ```
+///
+/// A `branches` stage runs the audio through each of its branches side by
+/// side and sums what comes out, lined up by their latencies. A branch with
+/// no stages passes the input through, e.g. for a dry signal under a wet one:
+///
+/// ```toml
+/// [[stages]]
+/// type = "branches"
+/// name = "denoise"
+///
+/// [[stages.branches]]
+/// [[stages.branches.stages]]
+/// type = "spectral_gate"
+///
+/// [[stages.branches]]
+/// [[stages.branches.stages]]
+/// type = "gain"
+/// gain_db = -12.0
+/// ```
+///
+/// Stages without a `name` are named after their type and position, e.g.
+/// `bit_crusher-0`, or `denoise.1.gain-0` inside a branch. Names address the
+/// stage's live parameters.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub window_size: usize,
+    pub fitting_buffer: usize,
+    #[serde(default = "PipelineConfig::default_sample_rate")]
+    pub sample_rate: u32,
+    #[serde(default = "PipelineConfig::default_channels")]
+    pub channels: u16,
+    #[serde(default)]
+    pub stages: Vec<StageConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StageConfig {
+    #[serde(rename = "type")]
+    pub stage_type: String,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub branches: Vec<BranchConfig>,
+    #[serde(flatten)]
+    pub params: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BranchConfig {
+    #[serde(default)]
+    pub stages: Vec<StageConfig>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String),
+    Parse(String),
+    Invalid(String),
+    UnknownStage {
+        index: usize,
+        stage_type: String,
+    },
+    InvalidStage {
+        index: usize,
+        stage_type: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(message) => write!(f, "error reading pipeline config: {message}"),
+            ConfigError::Parse(message) => write!(f, "error parsing pipeline config: {message}"),
+            ConfigError::Invalid(message) => write!(f, "invalid pipeline config: {message}"),
+            ConfigError::UnknownStage { index, stage_type } => {
+                write!(f, "stage {index}: unknown stage type \"{stage_type}\"")
+            }
+            ConfigError::InvalidStage {
+                index,
+                stage_type,
+                message,
+            } => write!(f, "stage {index} ({stage_type}): {message}"),
+        }
+    }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            window_size: 1024,
+            fitting_buffer: 16 * 1024,
+            sample_rate: Self::default_sample_rate(),
+            channels: Self::default_channels(),
+            stages: Vec::new(),
+        }
+    }
+}
+
+impl PipelineConfig {
+    fn default_sample_rate() -> u32 {
+        44100
+    }
+
+    fn default_channels() -> u16 {
+        2
+    }
+
+    /// Parses `path` as TOML, or as JSON when it has a `.json` extension.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => return Err(ConfigError::Io(format!("{}: {e}", path.display()))),
+        };
+
+        let config: Self = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => {
+                serde_json::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?
+            }
+            _ => toml::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.window_size == 0 {
+            return Err(ConfigError::Invalid(
+                "window_size must be positive".to_string(),
+            ));
+        }
+        if self.fitting_buffer == 0 {
+            return Err(ConfigError::Invalid(
+                "fitting_buffer must be positive".to_string(),
+            ));
+        }
+        if self.sample_rate == 0 || self.channels == 0 {
+            return Err(ConfigError::Invalid(
+                "sample_rate and channels must be positive".to_string(),
+            ));
+        }
+
+        check_names(&self.stages, "", &mut HashSet::new())
+    }
+
+    pub fn transform_ctx(&self) -> TransformCtx {
+        TransformCtx {
+            window_size: self.window_size,
+            fitting_buffer: self.fitting_buffer,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+        }
+    }
+}
+
+/// Names have to be unique across branches too, since controls are looked
+/// up by name alone.
+fn check_names(
+    stages: &[StageConfig],
+    prefix: &str,
+    names: &mut HashSet<String>,
+) -> Result<(), ConfigError> {
+    for (index, stage) in stages.iter().enumerate() {
+        let name = stage.name_within(prefix, index);
+        if !names.insert(name.clone()) {
+            return Err(ConfigError::Invalid(format!(
+                "more than one stage named \"{name}\""
+            )));
+        }
+        for (branch_index, branch) in stage.branches.iter().enumerate() {
+            check_names(&branch.stages, &format!("{name}.{branch_index}."), names)?;
+        }
+    }
+
+    Ok(())
+}
+
+impl StageConfig {
+    pub fn name(&self, index: usize) -> String {
+        self.name_within("", index)
+    }
+
+    /// The name of a stage inside a branch, where `prefix` is the branch's.
+    fn name_within(&self, prefix: &str, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{prefix}{}-{index}", self.stage_type),
+        }
+    }
+}
+
+/// A stage's parameters, handed to its constructor.
+pub struct StageParams<'a> {
+    ctx: &'a TransformCtx,
+    params: &'a Map<String, Value>,
+    controls: RefCell<StageControls>,
+}
+
+impl<'a> StageParams<'a> {
+    pub fn ctx(&self) -> &TransformCtx {
+        self.ctx
+    }
+
+    /// Deserializes the parameters into the stage's own settings type.
+    /// Mark it `deny_unknown_fields` so typos are reported rather than
+    /// silently ignored.
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_json::from_value(Value::Object(self.params.clone())).map_err(|e| e.to_string())
+    }
+
+    /// Exposes a parameter that can be changed while the stage runs,
+    /// starting from `initial`.
+    pub fn param(
+        &self,
+        name: &str,
+        initial: f32,
+        min: f32,
+        max: f32,
+    ) -> Result<Arc<StageParam>, String> {
+        let param = match StageParam::new(initial, min, max) {
+            Ok(param) => Arc::new(param),
+            Err(e) => return Err(format!("{name}: {e}")),
+        };
+        self.controls.borrow_mut().insert(name, param.clone());
+
+        Ok(param)
+    }
+
+    /// Like `param`, for stages that read it per sample through the
+    /// returned smoother.
+    pub fn control(
+        &self,
+        name: &str,
+        initial: f32,
+        min: f32,
+        max: f32,
+    ) -> Result<SmoothedParam, String> {
+        let param = self.param(name, initial, min, max)?;
+        Ok(SmoothedParam::new(param, PARAM_RAMP, self.ctx.sample_rate))
+    }
+}
+
+/// Time live parameter changes are smoothed over.
+const PARAM_RAMP: Duration = Duration::from_millis(20);
+
+/// A constructed stage along with the controls it exposed, and those of any
+/// stages inside it.
+pub struct Stage {
+    pub pipe: Arc<dyn AudioPipe>,
+    pub controls: Vec<StageControls>,
+}
+
+type StageConstructor =
+    Box<dyn Fn(&StageParams) -> Result<Arc<dyn AudioPipe>, String> + Send + Sync>;
+
+/// Named stage constructors that configs can refer to by `type`.
+pub struct StageRegistry {
+    constructors: HashMap<String, StageConstructor>,
+}
+
+impl Default for StageRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StageRegistry {
+    /// Built here rather than registered, since its branches are built from
+    /// the registry itself.
+    const BRANCHES: &str = "branches";
+
+    pub fn new() -> Self {
+        let mut registry = Self {
+            constructors: HashMap::new(),
+        };
+        registry.register("bit_crusher", |params| {
+            let settings: BitCrusherSettings = params.parse()?;
+            if !(1..=24).contains(&settings.bits) {
+                return Err("bits must be between 1 and 24".to_string());
+            }
+            let mix = params.control("mix", settings.mix, 0.0, 1.0)?;
+            let bit_crusher =
+                BitCrusher::new(settings.bits, settings.reduced_rate, settings.dither)
+                    .with_mix(mix);
+            Ok(Arc::new(FilterPipe(Arc::new(bit_crusher))))
+        });
+        registry.register("gain", |params| {
+            let settings: GainSettings = params.parse()?;
+            let gain_db = params.control("gain_db", settings.gain_db, -60.0, 24.0)?;
+            Ok(Arc::new(Gain::new(gain_db)))
+        });
+        registry.register("low_pass", |params| {
+            let settings: LowPassSettings = params.parse()?;
+            let nyquist = params.ctx().sample_rate as f32 / 2.0;
+            let cutoff_hz = params.control(
+                "cutoff_hz",
+                settings.cutoff_hz.unwrap_or(nyquist),
+                20.0,
+                nyquist,
+            )?;
+            Ok(Arc::new(LowPass::new(cutoff_hz)))
+        });
+        registry.register("spectral_gate", |params| {
+            let settings: SpectralGateSettings = params.parse()?;
+            check_overlap(params.ctx(), settings.overlap)?;
+            let threshold_db = params.param("threshold_db", settings.threshold_db, -120.0, 0.0)?;
+            let reduction_db = params.param("reduction_db", settings.reduction_db, -120.0, 0.0)?;
+            let gate = SpectralGate::new(threshold_db, reduction_db);
+            Ok(Arc::new(FilterPipe(Arc::new(StftFilter::new(
+                gate,
+                settings.overlap,
+            )))))
+        });
+        registry.register("spectral_eq", |params| {
+            let settings: SpectralEqSettings = params.parse()?;
+            check_overlap(params.ctx(), settings.overlap)?;
+            let mut bands = Vec::with_capacity(settings.bands.len());
+            for (i, band) in settings.bands.iter().enumerate() {
+                if band.frequency <= 0.0 {
+                    return Err(format!("band {i}: frequency must be positive"));
+                }
+                let gain_db =
+                    params.param(&format!("band{i}_gain_db"), band.gain_db, -60.0, 24.0)?;
+                bands.push((band.frequency, gain_db));
+            }
+            let eq = SpectralEq::new(bands);
+            Ok(Arc::new(FilterPipe(Arc::new(StftFilter::new(
+                eq,
+                settings.overlap,
+            )))))
+        });
+
+        registry
+    }
+
+    pub fn register<F>(&mut self, stage_type: &str, constructor: F)
+    where
+        F: Fn(&StageParams) -> Result<Arc<dyn AudioPipe>, String> + Send + Sync + 'static,
+    {
+        self.constructors
+            .insert(stage_type.to_string(), Box::new(constructor));
+    }
+
+    pub fn build_stages(&self, config: &PipelineConfig) -> Result<Vec<Stage>, ConfigError> {
+        self.build_list(&config.transform_ctx(), &config.stages, "")
+    }
+
+    fn build_list(
+        &self,
+        ctx: &TransformCtx,
+        stages: &[StageConfig],
+        prefix: &str,
+    ) -> Result<Vec<Stage>, ConfigError> {
+        let mut built = Vec::with_capacity(stages.len());
+
+        for (index, stage) in stages.iter().enumerate() {
+            let name = stage.name_within(prefix, index);
+            let result = match stage.stage_type.as_str() {
+                Self::BRANCHES => self.build_branches(ctx, stage, &name),
+                _ if !stage.branches.is_empty() => {
+                    Err("only a branches stage can have branches".to_string())
+                }
+                _ => match self.constructors.get(&stage.stage_type) {
+                    Some(constructor) => {
+                        let params = StageParams {
+                            ctx,
+                            params: &stage.params,
+                            controls: RefCell::new(StageControls::new(&name, &stage.stage_type)),
+                        };
+                        constructor(&params).map(|pipe| Stage {
+                            pipe,
+                            controls: vec![params.controls.into_inner()],
+                        })
+                    }
+                    None => {
+                        return Err(ConfigError::UnknownStage {
+                            index,
+                            stage_type: stage.stage_type.clone(),
+                        })
+                    }
+                },
+            };
+
+            match result {
+                Ok(stage) => built.push(stage),
+                Err(message) => {
+                    return Err(ConfigError::InvalidStage {
+                        index,
+                        stage_type: stage.stage_type.clone(),
+                        message,
+                    })
+                }
+            }
+        }
+
+        Ok(built)
+    }
+
+    /// Each branch's stages in a chain from the shared input to a bus
+    /// summing them, run as one stage.
+    fn build_branches(
+        &self,
+        ctx: &TransformCtx,
+        stage: &StageConfig,
+        name: &str,
+    ) -> Result<Stage, String> {
+        if !stage.params.is_empty() {
+            return Err("only takes branches".to_string());
+        }
+        if stage.branches.is_empty() {
+            return Err("needs at least one branch".to_string());
+        }
+
+        let mut graph = PipelineGraph::new(ctx.clone());
+        let input = graph.add_input(GraphPipe::INPUT);
+        let bus = graph.add_bus("mix");
+        let output = graph.add_output(GraphPipe::OUTPUT);
+        graph.connect(bus, output);
+
+        let mut controls = Vec::new();
+        for (branch_index, branch) in stage.branches.iter().enumerate() {
+            let prefix = format!("{name}.{branch_index}.");
+            let stages = match self.build_list(ctx, &branch.stages, &prefix) {
+                Ok(stages) => stages,
+                Err(e) => return Err(format!("branch {branch_index}, {e}")),
+            };
+
+            let mut last = input;
+            for (index, nested) in stages.into_iter().enumerate() {
+                let node =
+                    graph.add_pipe(&format!("{prefix}{index}"), nested.pipe, ErrorPolicy::Stop);
+                graph.connect(last, node);
+                last = node;
+                controls.extend(nested.controls);
+            }
+            graph.connect(last, bus);
+        }
+
+        let pipe = GraphPipe::new(graph).map_err(|e| e.to_string())?;
+        Ok(Stage {
+            pipe: Arc::new(pipe),
+            controls,
+        })
+    }
+
+    pub fn build(&self, config: &PipelineConfig) -> Result<AudioPipeline, ConfigError> {
+        let stages = self.build_stages(config)?;
+        let stage_refs: Vec<_> = stages.iter().map(|stage| &stage.pipe).collect();
+        Ok(AudioPipeline::new(config.transform_ctx(), &stage_refs))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BitCrusherSettings {
+    bits: u32,
+    reduced_rate: Option<u32>,
+    #[serde(default)]
+    dither: bool,
+    #[serde(default = "BitCrusherSettings::default_mix")]
+    mix: f32,
+}
+
+impl BitCrusherSettings {
+    fn default_mix() -> f32 {
+        1.0
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GainSettings {
+    #[serde(default)]
+    gain_db: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LowPassSettings {
+    cutoff_hz: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpectralGateSettings {
+    #[serde(default = "SpectralGateSettings::default_threshold_db")]
+    threshold_db: f32,
+    #[serde(default = "SpectralGateSettings::default_reduction_db")]
+    reduction_db: f32,
+    #[serde(default = "default_overlap")]
+    overlap: usize,
+}
+
+impl SpectralGateSettings {
+    fn default_threshold_db() -> f32 {
+        -60.0
+    }
+
+    fn default_reduction_db() -> f32 {
+        -30.0
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpectralEqSettings {
+    bands: Vec<SpectralEqBand>,
+    #[serde(default = "default_overlap")]
+    overlap: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpectralEqBand {
+    frequency: f32,
+    gain_db: f32,
+}
+
+fn default_overlap() -> usize {
+    4
+}
+
+/// The STFT hop has to divide the window evenly for the overlapping windows
+/// to add back up to the input.
+fn check_overlap(ctx: &TransformCtx, overlap: usize) -> Result<(), String> {
+    let fft_size = ctx.window_size / ctx.channels.max(1) as usize;
+    if !matches!(overlap, 2 | 4 | 8) || !fft_size.is_multiple_of(overlap) {
+        return Err(format!(
+            "overlap must be 2, 4 or 8 and divide the {fft_size} frame window"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Polls `path` and calls `on_change` with the new config whenever the file
+/// is modified. Configs that fail to load are reported and skipped, leaving
+/// whatever was running in place.
+pub fn watch_config<F>(path: PathBuf, interval: Duration, on_change: F) -> JoinHandle<()>
+where
+    F: Fn(PipelineConfig) + Send + 'static,
+{
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+
+    thread::spawn(move || {
+        let mut last_modified: Option<SystemTime> = modified(&path);
+        loop {
+            thread::sleep(interval);
+
+            let current = modified(&path);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+            if current.is_none() {
+                continue;
+            }
+
+            match PipelineConfig::load(&path) {
+                Ok(config) => on_change(config),
+                Err(e) => eprintln!("not reloading pipeline: {e}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    const BRANCHES: &str = r#"
+        window_size = 64
+        fitting_buffer = 64
+        channels = 1
+
+        [[stages]]
+        type = "branches"
+        name = "parallel"
+
+        [[stages.branches]]
+        [[stages.branches.stages]]
+        type = "gain"
+        gain_db = -6.0206
+
+        [[stages.branches]]
+    "#;
+
+    fn parse(toml: &str) -> Result<PipelineConfig, ConfigError> {
+        let config: PipelineConfig =
+            toml::from_str(toml).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn branches_are_summed() {
+        let config = parse(BRANCHES).unwrap();
+        let stages = StageRegistry::new().build_stages(&config).unwrap();
+        assert_eq!(stages.len(), 1);
+        let names: Vec<_> = stages[0].controls.iter().map(|c| c.name()).collect();
+        assert_eq!(names, vec!["parallel.0.gain-0"]);
+
+        let ctx = config.transform_ctx();
+        let pipe = stages[0].pipe.clone();
+        pipe.init(&ctx).unwrap();
+        let (sender, reciever) = sync_channel(ctx.fitting_buffer);
+        let (out_sender, out_reciever) = sync_channel(ctx.fitting_buffer);
+        let stage = thread::spawn(move || pipe.pipe(&ctx, reciever, out_sender));
+        let feeder = thread::spawn(move || {
+            for _ in 0..1000 {
+                sender.send(0.5).unwrap();
+            }
+        });
+
+        let output: Vec<_> = out_reciever.iter().collect();
+        feeder.join().unwrap();
+        stage.join().unwrap().unwrap();
+        assert_eq!(output.len(), 1000);
+        assert!(output.iter().all(|sample| (sample - 0.75).abs() < 1e-3));
+    }
+
+    #[test]
+    fn names_are_unique_across_branches() {
+        let toml = r#"
+            window_size = 64
+            fitting_buffer = 64
+
+            [[stages]]
+            type = "gain"
+            name = "level"
+
+            [[stages]]
+            type = "branches"
+
+            [[stages.branches]]
+            [[stages.branches.stages]]
+            type = "gain"
+            name = "level"
+        "#;
+        assert!(matches!(parse(toml), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn only_branches_stages_have_branches() {
+        let toml = r#"
+            window_size = 64
+            fitting_buffer = 64
+
+            [[stages]]
+            type = "gain"
+
+            [[stages.branches]]
+        "#;
+        let config = parse(toml).unwrap();
+        assert!(matches!(
+            StageRegistry::new().build_stages(&config),
+            Err(ConfigError::InvalidStage { index: 0, .. })
+        ));
+    }
+}
@@ -1,127 +1,526 @@
-use crate::library::Track;
+use super::{
+    clock::PlaybackClock,
+    meter::MeterReading,
+    session::{PlaybackSession, RepeatMode},
+    volume::{ReplayGainMode, VolumeControl, VolumeStatus},
+};
+use crate::{
+    history::{Play, PlaySource},
+    library::{Library, Track},
+};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-struct PlaybackState<'a> {
+struct PlaybackState {
     pub is_playing: bool,
     pub current_track: usize,
-    pub session: Vec<&'a Track>,
+    pub session: Vec<Arc<Track>>,
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
+    pub replay_gain: ReplayGainMode,
+    /// What each track was last queued from.
+    pub sources: HashMap<u64, PlaySource>,
 }
 
-impl<'a> PlaybackState<'a> {
+impl PlaybackState {
     pub fn new() -> Self {
         Self {
             is_playing: false,
             current_track: 0,
             session: Vec::new(),
+            repeat: RepeatMode::Off,
+            shuffle: false,
+            replay_gain: ReplayGainMode::Off,
+            sources: HashMap::new(),
         }
     }
+
+    /// Forgets where tracks that have left the session were queued from.
+    fn prune_sources(&mut self) {
+        let session = &self.session;
+        self.sources
+            .retain(|id, _| session.iter().any(|track| track.id == *id));
+    }
 }
 
-type Listeners<P> = Vec<Box<dyn Fn(&P) -> ()>>;
+type Listeners<P> = Vec<Box<dyn Fn(&P) -> () + Send>>;
 
-pub struct ObservablePlaybackState<'a> {
-    state: PlaybackState<'a>,
+#[derive(Clone, Debug, Serialize)]
+pub struct PlaybackStatus {
+    pub is_playing: bool,
+    pub current_track: Option<Track>,
+    pub position_ms: u64,
+    pub duration_ms: Option<u64>,
+    pub buffered_ms: u64,
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
+    pub volume: VolumeStatus,
+}
+
+/// Every change to the playback state, as one stream for clients that want
+/// to follow along.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlaybackEvent {
+    IsPlaying { is_playing: bool },
+    CurrentTrack { track: Option<Track> },
+    Enqueue { track: Track, index: usize },
+    Dequeue { track: Track, index: usize },
+    Seek { position_ms: u64 },
+    Volume(VolumeStatus),
+    Meter(MeterReading),
+}
+
+pub struct ObservablePlaybackState {
+    state: PlaybackState,
+    clock: Arc<PlaybackClock>,
+    volume: Arc<VolumeControl>,
 
     is_playing_listeners: Listeners<bool>,
-    current_track_listeners: Listeners<Option<&'a Track>>,
-    enqueue_listeners: Listeners<(&'a Track, usize)>,
-    dequeue_listeners: Listeners<(&'a Track, usize)>,
+    current_track_listeners: Listeners<Option<Arc<Track>>>,
+    enqueue_listeners: Listeners<(Arc<Track>, usize)>,
+    dequeue_listeners: Listeners<(Arc<Track>, usize)>,
+    seek_listeners: Listeners<Duration>,
+    volume_listeners: Listeners<VolumeStatus>,
+    meter_listeners: Listeners<MeterReading>,
+    session_listeners: Listeners<PlaybackSession>,
+    play_listeners: Listeners<Play>,
 }
 
-impl<'a> ObservablePlaybackState<'a> {
+impl Default for ObservablePlaybackState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ObservablePlaybackState {
     pub fn new() -> Self {
         Self {
             state: PlaybackState::new(),
+            clock: Arc::new(PlaybackClock::new()),
+            volume: Arc::new(VolumeControl::new(1.0)),
             is_playing_listeners: Vec::new(),
             current_track_listeners: Vec::new(),
             enqueue_listeners: Vec::new(),
             dequeue_listeners: Vec::new(),
+            seek_listeners: Vec::new(),
+            volume_listeners: Vec::new(),
+            meter_listeners: Vec::new(),
+            session_listeners: Vec::new(),
+            play_listeners: Vec::new(),
         }
     }
 
     pub fn on_is_playing_changed<F>(&mut self, callback: F)
     where
-        F: Fn(&bool) -> () + 'static,
+        F: Fn(&bool) -> () + Send + 'static,
     {
         self.is_playing_listeners.push(Box::new(callback));
     }
 
     pub fn on_current_track_changed<F>(&mut self, callback: F)
     where
-        F: Fn(&Option<&'a Track>) -> () + 'static,
+        F: Fn(&Option<Arc<Track>>) -> () + Send + 'static,
     {
         self.current_track_listeners.push(Box::new(callback));
     }
 
     pub fn on_enqueue<F>(&mut self, callback: F)
     where
-        F: Fn(&(&'a Track, usize)) -> () + 'static,
+        F: Fn(&(Arc<Track>, usize)) -> () + Send + 'static,
     {
         self.enqueue_listeners.push(Box::new(callback))
     }
 
     pub fn on_dequeue<F>(&mut self, callback: F)
     where
-        F: Fn(&(&'a Track, usize)) -> () + 'static,
+        F: Fn(&(Arc<Track>, usize)) -> () + Send + 'static,
     {
         self.dequeue_listeners.push(Box::new(callback))
     }
 
+    pub fn on_seek<F>(&mut self, callback: F)
+    where
+        F: Fn(&Duration) -> () + Send + 'static,
+    {
+        self.seek_listeners.push(Box::new(callback))
+    }
+
+    pub fn on_volume_changed<F>(&mut self, callback: F)
+    where
+        F: Fn(&VolumeStatus) -> () + Send + 'static,
+    {
+        self.volume_listeners.push(Box::new(callback))
+    }
+
+    pub fn on_meter<F>(&mut self, callback: F)
+    where
+        F: Fn(&MeterReading) -> () + Send + 'static,
+    {
+        self.meter_listeners.push(Box::new(callback))
+    }
+
+    pub fn on_session_changed<F>(&mut self, callback: F)
+    where
+        F: Fn(&PlaybackSession) -> () + Send + 'static,
+    {
+        self.session_listeners.push(Box::new(callback))
+    }
+
+    /// Called when a track stops being the current one, whether it played to
+    /// the end or was skipped.
+    pub fn on_play_ended<F>(&mut self, callback: F)
+    where
+        F: Fn(&Play) -> () + Send + 'static,
+    {
+        self.play_listeners.push(Box::new(callback))
+    }
+
+    /// Subscribes `callback` to every listener at once.
+    pub fn on_event<F>(&mut self, callback: F)
+    where
+        F: Fn(PlaybackEvent) -> () + Send + Sync + 'static,
+    {
+        let callback = Arc::new(callback);
+
+        let is_playing_callback = callback.clone();
+        self.on_is_playing_changed(move |is_playing| {
+            is_playing_callback(PlaybackEvent::IsPlaying {
+                is_playing: *is_playing,
+            })
+        });
+        let current_track_callback = callback.clone();
+        self.on_current_track_changed(move |track| {
+            current_track_callback(PlaybackEvent::CurrentTrack {
+                track: track.as_ref().map(|track| (**track).clone()),
+            })
+        });
+        let enqueue_callback = callback.clone();
+        self.on_enqueue(move |(track, index)| {
+            enqueue_callback(PlaybackEvent::Enqueue {
+                track: (**track).clone(),
+                index: *index,
+            })
+        });
+        let dequeue_callback = callback.clone();
+        self.on_dequeue(move |(track, index)| {
+            dequeue_callback(PlaybackEvent::Dequeue {
+                track: (**track).clone(),
+                index: *index,
+            })
+        });
+        let seek_callback = callback.clone();
+        self.on_seek(move |position| {
+            seek_callback(PlaybackEvent::Seek {
+                position_ms: position.as_millis() as u64,
+            })
+        });
+        let volume_callback = callback.clone();
+        self.on_volume_changed(move |volume| volume_callback(PlaybackEvent::Volume(*volume)));
+        self.on_meter(move |reading| callback(PlaybackEvent::Meter(reading.clone())));
+    }
+
     fn notify_listeners<P>(listeners: &Listeners<P>, args: P) {
         for callback in listeners.iter() {
             (*callback)(&args)
         }
     }
 
+    fn update_normalization(&self) {
+        let current_track = self.current_track();
+        let replay_gain = current_track
+            .as_ref()
+            .and_then(|track| track.replay_gain.as_ref());
+        self.volume
+            .set_normalization_db(self.state.replay_gain.gain_db(replay_gain));
+    }
+
+    fn notify_current_track_changed(&self) {
+        self.update_normalization();
+        Self::notify_listeners(&self.current_track_listeners, self.current_track());
+    }
+
+    /// Skipping past a track that never started, paused, isn't a play.
+    fn notify_play_ended(&self, skipped: bool) {
+        let track = match self.current_track() {
+            Some(track) => track,
+            None => return,
+        };
+        // the decoder finishes a track ahead of it being heard, so one that
+        // played out goes by its length rather than the clock
+        let played = match skipped {
+            true => self.position(),
+            false => track
+                .duration_ms
+                .map(Duration::from_millis)
+                .or(self.duration())
+                .unwrap_or(self.position()),
+        };
+        if skipped && played.is_zero() && !self.state.is_playing {
+            return;
+        }
+
+        let source = self
+            .state
+            .sources
+            .get(&track.id)
+            .cloned()
+            .unwrap_or_default();
+        let mut play = Play::new(&track, played, skipped, source);
+        // and its tail is still on the way out, so it started that much later
+        if !skipped {
+            play.played_at += self.buffered().as_millis() as u64;
+        }
+        Self::notify_listeners(&self.play_listeners, play);
+    }
+
+    fn notify_volume_changed(&self) {
+        Self::notify_listeners(&self.volume_listeners, self.volume_status());
+        self.notify_session_changed();
+    }
+
+    fn notify_session_changed(&self) {
+        if self.session_listeners.is_empty() {
+            return;
+        }
+        Self::notify_listeners(&self.session_listeners, self.snapshot());
+    }
+
     pub fn is_playing(&self) -> bool {
         self.state.is_playing
     }
 
     pub fn set_playing(&mut self, playing: bool) {
-        if self.state.is_playing {
+        if self.state.is_playing == playing {
             return;
         }
         self.state.is_playing = playing;
         Self::notify_listeners(&self.is_playing_listeners, playing)
     }
 
-    pub fn queue(&self) -> &[&'a Track] {
+    /// Shared with the player and drain, which advance it as audio is
+    /// decoded and heard.
+    pub fn clock(&self) -> Arc<PlaybackClock> {
+        self.clock.clone()
+    }
+
+    pub fn position(&self) -> Duration {
+        self.clock.position()
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.clock.duration()
+    }
+
+    pub fn buffered(&self) -> Duration {
+        self.clock.buffered()
+    }
+
+    /// Passes a reading from the meter tap on to whoever's listening.
+    pub fn publish_meter(&self, reading: MeterReading) {
+        Self::notify_listeners(&self.meter_listeners, reading);
+    }
+
+    /// Shared with the mixer stage, which applies it to the audio.
+    pub fn volume_control(&self) -> Arc<VolumeControl> {
+        self.volume.clone()
+    }
+
+    pub fn volume_status(&self) -> VolumeStatus {
+        VolumeStatus {
+            volume: self.volume.volume(),
+            muted: self.volume.muted(),
+            replay_gain: self.state.replay_gain,
+            normalization_db: self.volume.normalization_db(),
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume.set_volume(volume);
+        self.notify_volume_changed();
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.volume.set_muted(muted);
+        self.notify_volume_changed();
+    }
+
+    pub fn set_replay_gain_mode(&mut self, mode: ReplayGainMode) {
+        self.state.replay_gain = mode;
+        self.update_normalization();
+        self.notify_volume_changed();
+    }
+
+    pub fn seek(&mut self, position: Duration) {
+        Self::notify_listeners(&self.seek_listeners, position);
+        self.notify_session_changed();
+    }
+
+    pub fn status(&self) -> PlaybackStatus {
+        PlaybackStatus {
+            is_playing: self.state.is_playing,
+            current_track: self.current_track().map(|track| (*track).clone()),
+            position_ms: self.position().as_millis() as u64,
+            duration_ms: self.duration().map(|duration| duration.as_millis() as u64),
+            buffered_ms: self.buffered().as_millis() as u64,
+            repeat: self.state.repeat,
+            shuffle: self.state.shuffle,
+            volume: self.volume_status(),
+        }
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.state.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.state.repeat = repeat;
+        self.notify_session_changed();
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.state.shuffle
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.state.shuffle = shuffle;
+        self.notify_session_changed();
+    }
+
+    pub fn queue(&self) -> &[Arc<Track>] {
         let i = (self.state.current_track + 1).min(self.state.session.len());
         &self.state.session[i..]
     }
 
-    pub fn current_track(&self) -> Option<&'a Track> {
-        match self.state.session.get(self.state.current_track) {
-            Some(track) => Some(*track),
-            _ => None,
-        }
+    pub fn current_track(&self) -> Option<Arc<Track>> {
+        self.state.session.get(self.state.current_track).cloned()
     }
 
-    pub fn history(&self) -> &[&'a Track] {
+    pub fn history(&self) -> &[Arc<Track>] {
         let i = self.state.current_track.min(self.state.session.len() - 1);
         &self.state.session[..i]
     }
 
-    pub fn session(&self) -> &[&'a Track] {
+    pub fn session(&self) -> &[Arc<Track>] {
         &self.state.session
     }
 
-    pub fn play_now(&mut self, track: &'a Track) {
+    pub fn snapshot(&self) -> PlaybackSession {
+        PlaybackSession {
+            track_ids: self.state.session.iter().map(|track| track.id).collect(),
+            current_track: self.state.current_track,
+            position_ms: self.position().as_millis() as u64,
+            repeat: self.state.repeat,
+            shuffle: self.state.shuffle,
+            volume: self.volume.volume(),
+            muted: self.volume.muted(),
+            replay_gain: self.state.replay_gain,
+        }
+    }
+
+    /// Replaces the session with a persisted one. Tracks that no longer
+    /// resolve in the library are dropped; if the current track is among
+    /// them, playback resumes from the start of the next surviving track.
+    pub fn restore(&mut self, session: &PlaybackSession, library: &Library) {
+        let mut current_track = 0;
+        let mut current_dropped = false;
+        let mut tracks = Vec::with_capacity(session.track_ids.len());
+
+        for (i, id) in session.track_ids.iter().enumerate() {
+            match library.get_track(*id) {
+                Ok(track) => {
+                    if i < session.current_track {
+                        current_track += 1;
+                    }
+                    tracks.push(track);
+                }
+                Err(_) if i == session.current_track => current_dropped = true,
+                Err(_) => (),
+            }
+        }
+
+        self.state.current_track = current_track.min(tracks.len());
+        self.state.session = tracks;
+        self.state.prune_sources();
+        self.state.repeat = session.repeat;
+        self.state.shuffle = session.shuffle;
+        self.state.replay_gain = session.replay_gain;
+        self.volume.set_volume(session.volume);
+        self.volume.set_muted(session.muted);
+
+        self.notify_current_track_changed();
+        Self::notify_listeners(&self.volume_listeners, self.volume_status());
+        if !current_dropped && session.position_ms > 0 {
+            self.seek(Duration::from_millis(session.position_ms));
+        } else {
+            self.notify_session_changed();
+        }
+    }
+
+    pub fn play_now(&mut self, track: Arc<Track>) {
         self.enqueue(track, 0);
         self.skip(1);
     }
 
     pub fn skip(&mut self, n: i32) {
+        self.notify_play_ended(true);
+        self.move_by(n);
+    }
+
+    fn move_by(&mut self, n: i32) {
         self.state.current_track = (self.state.current_track as i32 + n)
             .min(self.state.session.len() as i32)
             .max(0) as usize;
 
-        Self::notify_listeners(&self.current_track_listeners, self.current_track());
+        self.notify_current_track_changed();
+        self.notify_session_changed();
     }
 
-    pub fn enqueue(&mut self, track: &'a Track, offset: usize) {
+    /// Moves on once the current track has played to the end, honouring the
+    /// repeat mode.
+    pub fn track_finished(&mut self) {
+        self.notify_play_ended(false);
+        match self.state.repeat {
+            RepeatMode::One => self.notify_current_track_changed(),
+            RepeatMode::All if self.state.current_track + 1 >= self.state.session.len() => {
+                self.move_by(-(self.state.current_track as i32))
+            }
+            _ => self.move_by(1),
+        }
+    }
+
+    /// Moves past a track that couldn't be played, without it counting as
+    /// a play. Repeat modes are left out so a session of nothing playable
+    /// comes to an end rather than going round forever.
+    pub fn track_failed(&mut self) {
+        self.move_by(1);
+    }
+
+    pub fn enqueue(&mut self, track: Arc<Track>, offset: usize) {
         let i = (self.state.current_track + offset + 1).min(self.state.session.len());
-        self.state.session.insert(i, track);
+        self.state.sources.insert(track.id, PlaySource::Queue);
+        self.state.session.insert(i, track.clone());
         Self::notify_listeners(&self.enqueue_listeners, (track, i));
+        // an empty or finished session has nothing current until now
+        if i == self.state.current_track {
+            self.notify_current_track_changed();
+        }
+        self.notify_session_changed();
+    }
+
+    /// Enqueues `tracks` in order, the first of them `offset` after the
+    /// current track.
+    pub fn enqueue_all(&mut self, tracks: &[Arc<Track>], offset: usize, source: PlaySource) {
+        let start = (self.state.current_track + offset + 1).min(self.state.session.len());
+        for (i, track) in tracks.iter().enumerate() {
+            self.state.sources.insert(track.id, source.clone());
+            self.state.session.insert(start + i, track.clone());
+            Self::notify_listeners(&self.enqueue_listeners, (track.clone(), start + i));
+        }
+        if start == self.state.current_track && !tracks.is_empty() {
+            self.notify_current_track_changed();
+        }
+        self.notify_session_changed();
     }
 
     pub fn dequeue(&mut self, offset: usize) {
@@ -131,6 +530,121 @@ impl<'a> ObservablePlaybackState<'a> {
         }
         
         let track = self.state.session.remove(i);
-        Self::notify_listeners(&self.dequeue_listeners, (track, i))
+        self.state.prune_sources();
+        Self::notify_listeners(&self.dequeue_listeners, (track, i));
+        self.notify_session_changed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn track(id: u64) -> Arc<Track> {
+        Arc::new(Track {
+            id,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn dequeued_tracks_lose_their_source() {
+        let mut playback = ObservablePlaybackState::new();
+        let source = PlaySource::Playlist { id: 7 };
+        playback.enqueue_all(&[track(1), track(2), track(2)], 0, source.clone());
+
+        // one of the two copies of track 2
+        playback.dequeue(0);
+        assert_eq!(playback.state.sources.get(&2), Some(&source));
+        playback.dequeue(0);
+        assert_eq!(playback.state.sources.get(&2), None);
+        assert_eq!(playback.state.sources.get(&1), Some(&source));
+    }
+
+    #[test]
+    fn failed_tracks_move_on_without_a_play() {
+        let mut playback = ObservablePlaybackState::new();
+        let plays = Arc::new(Mutex::new(Vec::new()));
+        let recorded = plays.clone();
+        playback.on_play_ended(move |play| recorded.lock().unwrap().push(play.clone()));
+        playback.enqueue_all(&[track(1), track(2)], 0, PlaySource::Queue);
+        playback.set_playing(true);
+
+        playback.track_failed();
+        assert_eq!(playback.current_track().map(|track| track.id), Some(2));
+        assert!(plays.lock().unwrap().is_empty());
+
+        playback.track_finished();
+        assert_eq!(plays.lock().unwrap().len(), 1);
+        assert!(!plays.lock().unwrap()[0].skipped);
+    }
+
+    /// Restores a session of `session_ids`, 90 s into the `current` one,
+    /// against a library holding only `ids`. Also gives back where it was
+    /// sought to.
+    fn restore(
+        ids: &[u64],
+        session_ids: &[u64],
+        current: usize,
+    ) -> (ObservablePlaybackState, Vec<Duration>) {
+        let mut library = Library::open_in_memory().unwrap();
+        for id in ids {
+            library.insert((*track(*id)).clone()).unwrap();
+        }
+        let session = PlaybackSession {
+            track_ids: session_ids.to_vec(),
+            current_track: current,
+            position_ms: 90_000,
+            repeat: RepeatMode::All,
+            volume: 0.5,
+            ..Default::default()
+        };
+
+        let mut playback = ObservablePlaybackState::new();
+        let seeks = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seeks.clone();
+        playback.on_seek(move |position| recorded.lock().unwrap().push(*position));
+        playback.restore(&session, &library);
+        let seeks = seeks.lock().unwrap().clone();
+        (playback, seeks)
+    }
+
+    fn ids(tracks: &[Arc<Track>]) -> Vec<u64> {
+        tracks.iter().map(|track| track.id).collect()
+    }
+
+    #[test]
+    fn restore_drops_tracks_gone_from_the_library() {
+        let (playback, seeks) = restore(&[1, 3, 5], &[1, 2, 3, 4, 5], 0);
+        assert_eq!(ids(playback.session()), vec![1, 3, 5]);
+        assert_eq!(playback.current_track().map(|track| track.id), Some(1));
+        assert_eq!(seeks, vec![Duration::from_secs(90)]);
+        assert_eq!(playback.repeat(), RepeatMode::All);
+        assert_eq!(playback.volume_status().volume, 0.5);
+    }
+
+    #[test]
+    fn restore_keeps_the_current_track_when_ones_before_it_go() {
+        let (playback, seeks) = restore(&[3, 4, 5], &[1, 2, 3, 4, 5], 3);
+        assert_eq!(ids(playback.session()), vec![3, 4, 5]);
+        assert_eq!(playback.snapshot().current_track, 1);
+        assert_eq!(playback.current_track().map(|track| track.id), Some(4));
+        assert_eq!(seeks, vec![Duration::from_secs(90)]);
+    }
+
+    #[test]
+    fn restore_starts_the_next_track_over_when_the_current_one_goes() {
+        let (playback, seeks) = restore(&[1, 4], &[1, 2, 3, 4], 2);
+        assert_eq!(ids(playback.session()), vec![1, 4]);
+        assert_eq!(playback.current_track().map(|track| track.id), Some(4));
+        assert!(seeks.is_empty());
+        assert_eq!(playback.snapshot().position_ms, 0);
+
+        // and past the end when nothing after it survives
+        let (playback, seeks) = restore(&[1], &[1, 2], 1);
+        assert!(playback.current_track().is_none());
+        assert_eq!(playback.snapshot().current_track, 1);
+        assert!(seeks.is_empty());
     }
 }
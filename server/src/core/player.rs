@@ -0,0 +1,459 @@
+use super::{
+    audio::AudioReader,
+    clock::PlaybackClock,
+    convert::FormatConverter,
+    drain::Drain,
+    flush::{self, FlushControl},
+    pipeline::{AudioPipeline, PipeHandle, PipeMessage, Sample},
+};
+use crate::library::Track;
+use std::{
+    mem,
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use symphonia::core::audio::SampleBuffer;
+
+enum PlayerCommand {
+    Load(Arc<Track>),
+    SetPipeline(AudioPipeline, Sender<Result<(), String>>),
+    Seek(Duration),
+    Play,
+    Pause,
+    Stop,
+}
+
+/// Why the decoder moved off a track.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackEnd {
+    /// Decoded to the end.
+    Finished,
+    /// Couldn't be opened, so none of it was played.
+    Failed,
+}
+
+/// Decodes the current track into a running pipeline. Cheap to clone; every
+/// clone controls the same decoder thread.
+#[derive(Clone)]
+pub struct Player {
+    commands: Sender<PlayerCommand>,
+}
+
+impl Player {
+    /// Starts `pipeline` into `drain` along with a decoder thread feeding it.
+    /// Tracks are converted to the pipeline's sample rate and channels as
+    /// they're decoded.
+    ///
+    /// `on_track_end` is called from the decoder thread whenever a track has
+    /// been decoded to the end, which is ahead of its tail being heard so
+    /// the next track can follow without a gap. The clock stays on the
+    /// finished track until the drain reaches the next one. It's also called
+    /// for a track that couldn't be loaded, so playback can move past it.
+    pub fn spawn<F>(
+        pipeline: AudioPipeline,
+        drain: Arc<dyn Drain>,
+        clock: Arc<PlaybackClock>,
+        on_track_end: F,
+    ) -> Result<(Self, Vec<PipeHandle>), String>
+    where
+        F: Fn(TrackEnd) + Send + 'static,
+    {
+        let ctx = pipeline.ctx().clone();
+        if let Err(err) = drain.init(&ctx) {
+            return Err(format!("error initializing drain: {err}"));
+        }
+
+        // the drain outlives any one pipeline, so pipelines can be swapped
+        // underneath it without reopening the output
+        let flush = Arc::new(FlushControl::new());
+        let (drain_sender, feed_reciever) = sync_channel(ctx.fitting_buffer);
+        // kept short, whatever is in here when a flush reaches the drain
+        // still gets played
+        let (feed_sender, drain_reciever) = sync_channel(ctx.window_size);
+        let (feed_drain, feed_flush) = (drain.clone(), flush.clone());
+        let feed_handle = thread::spawn(move || {
+            flush::feed_drain(feed_drain, feed_flush, feed_reciever, feed_sender)
+        });
+        let drain_ctx = ctx.clone();
+        let drain_clock = clock.clone();
+        let drain_handle =
+            thread::spawn(move || drain.drain(&drain_ctx, drain_reciever, drain_clock));
+
+        let (sender, reciever) = sync_channel(ctx.fitting_buffer);
+        let stage_handles = pipeline.pipe(reciever, drain_sender.clone(), flush.clone())?;
+
+        let (commands, command_reciever) = channel();
+        let decoder = PlayerDecoder {
+            channels: ctx.channels,
+            sample_rate: ctx.sample_rate,
+            clock,
+            to: sender,
+            drain_sender,
+            flush,
+            stage_handles,
+            reader: None,
+            converter: FormatConverter::new(
+                ctx.sample_rate,
+                ctx.channels,
+                ctx.sample_rate,
+                ctx.channels,
+            ),
+            sample_buffer: None,
+            playing: false,
+            discard_frames: 0,
+        };
+        let decoder_handle = thread::spawn(move || decoder.run(command_reciever, on_track_end));
+
+        Ok((
+            Self { commands },
+            vec![decoder_handle, feed_handle, drain_handle],
+        ))
+    }
+
+    pub fn load(&self, track: Arc<Track>) {
+        self.send(PlayerCommand::Load(track));
+    }
+
+    /// Replaces the running pipeline. Audio already in the old one plays
+    /// out first, so nothing is dropped or reordered. Returns once the new
+    /// one is running, or with why it couldn't be, in which case the old one
+    /// carries on.
+    pub fn set_pipeline(&self, pipeline: AudioPipeline) -> Result<(), String> {
+        let (sender, reciever) = channel();
+        self.send(PlayerCommand::SetPipeline(pipeline, sender));
+        return match reciever.recv() {
+            Ok(result) => result,
+            Err(_) => Err("player has stopped".to_string()),
+        };
+    }
+
+    pub fn seek(&self, position: Duration) {
+        self.send(PlayerCommand::Seek(position));
+    }
+
+    pub fn play(&self) {
+        self.send(PlayerCommand::Play);
+    }
+
+    pub fn pause(&self) {
+        self.send(PlayerCommand::Pause);
+    }
+
+    pub fn stop(&self) {
+        self.send(PlayerCommand::Stop);
+    }
+
+    fn send(&self, command: PlayerCommand) {
+        // the decoder only goes away after a stop or a pipeline error, and
+        // either way there's nothing left to control
+        let _ = self.commands.send(command);
+    }
+}
+
+struct PlayerDecoder {
+    channels: u16,
+    sample_rate: u32,
+    clock: Arc<PlaybackClock>,
+    to: SyncSender<PipeMessage>,
+    drain_sender: SyncSender<PipeMessage>,
+    flush: Arc<FlushControl>,
+    stage_handles: Vec<PipeHandle>,
+    reader: Option<AudioReader>,
+    converter: FormatConverter,
+    sample_buffer: Option<SampleBuffer<Sample>>,
+    playing: bool,
+    discard_frames: u64,
+}
+
+impl PlayerDecoder {
+    const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn run<F>(mut self, commands: Receiver<PlayerCommand>, on_track_end: F) -> Result<(), String>
+    where
+        F: Fn(TrackEnd),
+    {
+        loop {
+            // only block on commands while there's nothing to decode
+            let command = match self.playing && self.reader.is_some() {
+                true => match commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                },
+                false => match commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return Ok(()),
+                },
+            };
+
+            match command {
+                Some(PlayerCommand::Load(track)) => {
+                    if let Err(e) = self.load(&track) {
+                        eprintln!("error loading track {:x}: {e}", track.id);
+                        on_track_end(TrackEnd::Failed);
+                    }
+                }
+                Some(PlayerCommand::SetPipeline(pipeline, reply)) => {
+                    // whoever asked may have given up waiting
+                    let _ = reply.send(self.set_pipeline(pipeline));
+                }
+                Some(PlayerCommand::Seek(position)) => self.seek(position),
+                Some(PlayerCommand::Play) => {
+                    self.playing = true;
+                    self.clock.set_paused(false);
+                }
+                Some(PlayerCommand::Pause) => {
+                    self.playing = false;
+                    self.clock.set_paused(true);
+                }
+                Some(PlayerCommand::Stop) => return Ok(()),
+                None => {
+                    if !self.decode_next()? {
+                        self.reader = None;
+                        on_track_end(TrackEnd::Finished);
+                    }
+                }
+            }
+        }
+    }
+
+    fn load(&mut self, track: &Track) -> Result<(), String> {
+        // a track that was decoded to the end is still playing out and the
+        // next one should follow it; one that's cut short is dropped
+        let follows_on = self.reader.take().is_none();
+        if !follows_on {
+            self.flush()?;
+        }
+        let reader = AudioReader::open(&track.path)?;
+
+        let signal_spec = reader.signal_spec();
+        self.converter = FormatConverter::new(
+            signal_spec.rate,
+            signal_spec.channels.count() as u16,
+            self.sample_rate,
+            self.channels,
+        );
+        let n_frames = self.n_frames(&reader);
+        match follows_on {
+            true => self.clock.queue_track(self.sample_rate, n_frames),
+            false => self.clock.start_track(self.sample_rate, 0, n_frames),
+        }
+        self.discard_frames = 0;
+        self.reader = Some(reader);
+        Ok(())
+    }
+
+    fn set_pipeline(&mut self, pipeline: AudioPipeline) -> Result<(), String> {
+        let ctx = pipeline.ctx();
+        if ctx.sample_rate != self.sample_rate || ctx.channels != self.channels {
+            return Err(format!(
+                "pipeline is {} channels at {} Hz, drain is {} channels at {} Hz",
+                ctx.channels, ctx.sample_rate, self.channels, self.sample_rate
+            ));
+        }
+
+        let (sender, reciever) = sync_channel(ctx.fitting_buffer);
+        let stage_handles =
+            pipeline.pipe(reciever, self.drain_sender.clone(), self.flush.clone())?;
+
+        // closing the old pipeline's input lets it flush into the drain;
+        // waiting for it keeps its tail ahead of the new pipeline's output
+        drop(mem::replace(&mut self.to, sender));
+        for handle in mem::replace(&mut self.stage_handles, stage_handles) {
+            match handle.join() {
+                Ok(Err(e)) => eprintln!("previous pipeline failed: {e}"),
+                Err(_) => eprintln!("previous pipeline panicked"),
+                Ok(Ok(())) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Throws away everything between the decoder and the output, returning
+    /// once the pipeline is empty.
+    fn flush(&mut self) -> Result<(), String> {
+        let token = self.flush.begin();
+        if let Err(e) = self.to.send(PipeMessage::Flush(token)) {
+            return Err(format!("error sending flush barrier: {e}"));
+        }
+        if !self.flush.wait(token, Self::FLUSH_TIMEOUT) {
+            eprintln!("pipeline flush timed out");
+        }
+
+        Ok(())
+    }
+
+    fn seek(&mut self, position: Duration) {
+        if self.reader.is_none() {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            eprintln!("error flushing before seek: {e}");
+        }
+
+        let reader = self.reader.as_mut().unwrap();
+        match reader.seek(position) {
+            Ok(discard_frames) => {
+                self.discard_frames = discard_frames;
+                self.converter.reset();
+                let start_frame = self.clock.duration_to_frames(position);
+                let n_frames = self.n_frames(self.reader.as_ref().unwrap());
+                self.clock
+                    .start_track(self.sample_rate, start_frame, n_frames);
+            }
+            Err(e) => eprintln!("error seeking to {position:?}: {e}"),
+        }
+    }
+
+    /// Decodes and sends one packet. Returns false at the end of the track.
+    fn decode_next(&mut self) -> Result<bool, String> {
+        let reader = self.reader.as_mut().unwrap();
+        let sample_buffer = &mut self.sample_buffer;
+        let consume_result = reader.consume_next(|buffer| {
+            let spec = *buffer.spec();
+            let samples = buffer.capacity() * spec.channels.count();
+            if sample_buffer
+                .as_ref()
+                .is_none_or(|b| b.capacity() < samples)
+            {
+                *sample_buffer = Some(SampleBuffer::new(buffer.capacity() as u64, spec));
+            }
+            sample_buffer.as_mut().unwrap().copy_interleaved_ref(buffer);
+            Ok(())
+        });
+        match consume_result {
+            Ok(_) => (),
+            Err(e) if e == "EOF" => {
+                let tail = self.converter.finish();
+                send_samples(&self.clock, &self.to, tail, self.channels as usize)?;
+                return Ok(false);
+            }
+            Err(e) => return Err(format!("error decoding track: {e}")),
+        }
+
+        let channels = self.converter.from_channels();
+        let samples = self.sample_buffer.as_ref().unwrap().samples();
+        let discard = (self.discard_frames as usize).min(samples.len() / channels);
+        self.discard_frames -= discard as u64;
+
+        let samples = self.converter.convert(&samples[(discard * channels)..]);
+        send_samples(&self.clock, &self.to, samples, self.channels as usize)?;
+
+        Ok(true)
+    }
+
+    /// The track's length once converted, in the pipeline's frames.
+    fn n_frames(&self, reader: &AudioReader) -> Option<u64> {
+        reader
+            .n_frames()
+            .map(|frames| self.converter.output_frames(frames))
+    }
+}
+
+fn send_samples(
+    clock: &PlaybackClock,
+    to: &SyncSender<PipeMessage>,
+    samples: &[Sample],
+    channels: usize,
+) -> Result<(), String> {
+    clock.queue((samples.len() / channels) as u64);
+    for sample in samples {
+        if let Err(e) = to.send(PipeMessage::Sample(*sample)) {
+            return Err(format!("error sending sample: {e}"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::pipeline::TransformCtx, null_drain::NullDrain};
+    use hound::{WavSpec, WavWriter};
+    use std::{path::PathBuf, process};
+
+    fn pipeline(sample_rate: u32) -> AudioPipeline {
+        let ctx = TransformCtx {
+            window_size: 256,
+            fitting_buffer: 1024,
+            sample_rate,
+            channels: 2,
+        };
+        AudioPipeline::new(ctx, &[])
+    }
+
+    #[test]
+    fn set_pipeline_reports_whether_it_took() {
+        let clock = Arc::new(PlaybackClock::new());
+        let (player, handles) =
+            Player::spawn(pipeline(44100), Arc::new(NullDrain::new()), clock, |_| ()).unwrap();
+
+        assert!(player.set_pipeline(pipeline(48000)).is_err());
+        assert!(player.set_pipeline(pipeline(44100)).is_ok());
+
+        player.stop();
+        drop(player);
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    /// Half a second of 48 kHz mono.
+    fn mono_48k(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("player-{}-{name}.wav", process::id()));
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for i in 0..24000 {
+            writer.write_sample((i % 100) as i16 * 100).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    #[test]
+    fn other_formats_are_converted_and_missing_files_fail() {
+        let clock = Arc::new(PlaybackClock::new());
+        let (ends, ended) = channel();
+        let (player, handles) = Player::spawn(
+            pipeline(44100),
+            Arc::new(NullDrain::new()),
+            clock.clone(),
+            move |end| ends.send(end).unwrap(),
+        )
+        .unwrap();
+
+        let path = mono_48k("convert");
+        player.load(Arc::new(Track {
+            path: path.clone(),
+            ..Default::default()
+        }));
+        player.play();
+        assert_eq!(ended.recv().unwrap(), TrackEnd::Finished);
+        // the same half second, in the pipeline's frames
+        assert_eq!(clock.sample_rate(), 44100);
+        assert_eq!(clock.duration(), Some(Duration::from_millis(500)));
+
+        player.load(Arc::new(Track {
+            path: PathBuf::from("/nowhere/at/all.wav"),
+            ..Default::default()
+        }));
+        assert_eq!(ended.recv().unwrap(), TrackEnd::Failed);
+
+        player.stop();
+        drop(player);
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
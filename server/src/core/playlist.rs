@@ -0,0 +1,742 @@
+use super::{provider::ProviderObject, smart_playlist::SmartRules};
+use crate::{
+    library::{hex_id, hex_ids, Library, Track},
+    scanner::stable_id,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// An ordered list of library tracks, stored through the audio provider
+/// alongside the audio itself.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Playlist {
+    #[serde(with = "hex_id")]
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    /// URL of the playlist's cover image.
+    pub cover: Option<String>,
+    /// Tracks removed from the library since they were added stay here, and
+    /// are skipped when the playlist is resolved. Smart playlists have
+    /// these worked out from their rules.
+    #[serde(with = "hex_ids")]
+    pub tracks: Vec<u64>,
+    #[serde(default)]
+    pub rules: Option<SmartRules>,
+    /// Unix time in milliseconds.
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl ProviderObject for Playlist {}
+
+impl Playlist {
+    pub fn new(name: String) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            // hashing in the time keeps playlists of the same name apart
+            id: stable_id(&format!("{name}\u{0}{}", now.as_nanos())),
+            name,
+            description: None,
+            cover: None,
+            tracks: Vec::new(),
+            rules: None,
+            created_at: now.as_millis() as u64,
+            updated_at: now.as_millis() as u64,
+        }
+    }
+
+    pub fn touch(&mut self) {
+        self.updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(self.updated_at, |since| since.as_millis() as u64);
+    }
+
+    /// Works a smart playlist's tracks out again. Returns whether they
+    /// changed, which they never do for a playlist that isn't smart.
+    pub fn refresh(&mut self, library: &Library) -> bool {
+        let tracks = match self.rules.as_ref() {
+            Some(rules) => rules.evaluate(library, SystemTime::now()),
+            None => return false,
+        };
+
+        let changed = tracks != self.tracks;
+        self.tracks = tracks;
+        changed
+    }
+
+    /// The playlist's tracks that are in the library, in order. Smart
+    /// playlists are worked out as of now.
+    pub fn resolve(&self, library: &Library) -> Vec<Arc<Track>> {
+        let ids = match self.rules.as_ref() {
+            Some(rules) => rules.evaluate(library, SystemTime::now()),
+            None => self.tracks.clone(),
+        };
+        ids.iter()
+            .filter_map(|id| library.get_track(*id).ok())
+            .collect()
+    }
+}
+
+/// Edits to a playlist, as a client sends them. Fields left out stay as
+/// they are, and `description`, `cover` and `rules` are cleared by sending
+/// null. Clearing the rules leaves a plain list of whatever they matched.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlaylistChanges {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub cover: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present_ids")]
+    pub tracks: Option<Vec<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub rules: Option<Option<SmartRules>>,
+}
+
+impl PlaylistChanges {
+    pub fn apply(self, playlist: &mut Playlist) {
+        if let Some(name) = self.name {
+            playlist.name = name;
+        }
+        if let Some(description) = self.description {
+            playlist.description = description;
+        }
+        if let Some(cover) = self.cover {
+            playlist.cover = cover;
+        }
+        if let Some(tracks) = self.tracks {
+            playlist.tracks = tracks;
+        }
+        if let Some(rules) = self.rules {
+            playlist.rules = rules;
+        }
+        playlist.touch();
+    }
+}
+
+/// Tells a field sent as null, which is Some(None), apart from one left
+/// out, which is None by default.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+fn present_ids<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u64>>, D::Error> {
+    hex_ids::deserialize(deserializer).map(Some)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// Extended M3U in Latin-1, as older players expect.
+    M3u,
+    /// Extended M3U in UTF-8.
+    M3u8,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "audio/x-mpegurl",
+            PlaylistFormat::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            PlaylistFormat::Xspf => "application/xspf+xml",
+        }
+    }
+
+    /// Tells XSPF from M3U for files that come without saying which.
+    pub fn guess(contents: &[u8]) -> Self {
+        let start = String::from_utf8_lossy(&contents[..contents.len().min(256)]).to_lowercase();
+        if start.contains("<playlist") || start.trim_start().starts_with("<?xml") {
+            return PlaylistFormat::Xspf;
+        }
+        match std::str::from_utf8(contents) {
+            Ok(_) => PlaylistFormat::M3u8,
+            Err(_) => PlaylistFormat::M3u,
+        }
+    }
+}
+
+/// A playlist made from another player's, with whatever in it couldn't be
+/// found in the library.
+#[derive(Clone, Debug, Serialize)]
+pub struct PlaylistImport {
+    pub playlist: Playlist,
+    pub unmatched: Vec<String>,
+}
+
+/// A track as another player's playlist describes it, matched up against
+/// the library on import.
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistEntry {
+    pub location: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+/// What was read from an imported playlist file.
+#[derive(Clone, Debug, Default)]
+pub struct ParsedPlaylist {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+pub fn export(playlist: &Playlist, tracks: &[Arc<Track>], format: PlaylistFormat) -> Vec<u8> {
+    return match format {
+        PlaylistFormat::M3u => export_m3u(playlist, tracks)
+            .chars()
+            // Latin-1 is the first 256 code points, anything past that has
+            // no way of being written
+            .map(|c| match (c as u32) < 256 {
+                true => c as u8,
+                false => b'?',
+            })
+            .collect(),
+        PlaylistFormat::M3u8 => export_m3u(playlist, tracks).into_bytes(),
+        PlaylistFormat::Xspf => export_xspf(playlist, tracks).into_bytes(),
+    };
+}
+
+fn export_m3u(playlist: &Playlist, tracks: &[Arc<Track>]) -> String {
+    let mut m3u = format!("#EXTM3U\n#PLAYLIST:{}\n", one_line(&playlist.name));
+    for track in tracks {
+        let seconds = track
+            .duration_ms
+            .map_or(-1, |duration| (duration / 1000) as i64);
+        let label = match track.artist.as_ref() {
+            Some(artist) => format!("{} - {}", artist, track.title),
+            None => track.title.clone(),
+        };
+        m3u.push_str(&format!(
+            "#EXTINF:{seconds},{}\n{}\n",
+            one_line(&label),
+            track.path.display()
+        ));
+    }
+    m3u
+}
+
+fn one_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+fn export_xspf(playlist: &Playlist, tracks: &[Arc<Track>]) -> String {
+    let mut xspf = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    xspf.push_str(&format!(
+        "  <title>{}</title>\n",
+        escape_xml(&playlist.name)
+    ));
+    if let Some(description) = playlist.description.as_ref() {
+        xspf.push_str(&format!(
+            "  <annotation>{}</annotation>\n",
+            escape_xml(description)
+        ));
+    }
+    if let Some(cover) = playlist.cover.as_ref() {
+        xspf.push_str(&format!("  <image>{}</image>\n", escape_xml(cover)));
+    }
+
+    xspf.push_str("  <trackList>\n");
+    for track in tracks {
+        xspf.push_str("    <track>\n");
+        let fields = [
+            ("location", Some(file_uri(&track.path))),
+            ("title", Some(track.title.clone())),
+            ("creator", track.artist.clone()),
+            ("album", track.album.clone()),
+            ("trackNum", track.track_number.map(|n| n.to_string())),
+            ("duration", track.duration_ms.map(|ms| ms.to_string())),
+        ];
+        for (element, value) in fields {
+            if let Some(value) = value {
+                xspf.push_str(&format!(
+                    "      <{element}>{}</{element}>\n",
+                    escape_xml(&value)
+                ));
+            }
+        }
+        xspf.push_str("    </track>\n");
+    }
+    xspf.push_str("  </trackList>\n</playlist>\n");
+    xspf
+}
+
+pub fn parse(contents: &[u8], format: PlaylistFormat) -> Result<ParsedPlaylist, String> {
+    let text = match (format, std::str::from_utf8(contents)) {
+        (_, Ok(text)) => text.to_string(),
+        // plain M3U is Latin-1, which maps byte for byte onto code points
+        (PlaylistFormat::M3u, Err(_)) => contents.iter().map(|b| *b as char).collect(),
+        (_, Err(_)) => return Err("playlist isn't valid UTF-8".to_string()),
+    };
+    let text = text.trim_start_matches('\u{feff}');
+
+    return match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => Ok(parse_m3u(text)),
+        PlaylistFormat::Xspf => parse_xspf(text),
+    };
+}
+
+fn parse_m3u(text: &str) -> ParsedPlaylist {
+    let mut playlist = ParsedPlaylist::default();
+    let mut info: Option<PlaylistEntry> = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix("#PLAYLIST:") {
+            playlist.name = Some(name.trim().to_string());
+        } else if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            // "#EXTINF:123,Artist - Title", the duration we go by the file for
+            let label = extinf.split_once(',').map_or("", |(_, label)| label.trim());
+            info = Some(match label.split_once(" - ") {
+                Some((artist, title)) => PlaylistEntry {
+                    artist: Some(artist.trim().to_string()),
+                    title: Some(title.trim().to_string()),
+                    ..Default::default()
+                },
+                None => PlaylistEntry {
+                    title: Some(label.to_string()).filter(|title| !title.is_empty()),
+                    ..Default::default()
+                },
+            });
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let mut entry = info.take().unwrap_or_default();
+            entry.location = Some(line.to_string());
+            playlist.entries.push(entry);
+        }
+    }
+    playlist
+}
+
+fn parse_xspf(text: &str) -> Result<ParsedPlaylist, String> {
+    let (head, track_list) = match text.find("<trackList") {
+        Some(start) => text.split_at(start),
+        None => return Err("no trackList in XSPF playlist".to_string()),
+    };
+
+    let mut playlist = ParsedPlaylist {
+        name: element_text(head, "title"),
+        description: element_text(head, "annotation"),
+        entries: Vec::new(),
+    };
+    let mut rest = track_list;
+    while let Some(start) = find_tag(rest, "track") {
+        let end = match rest[start..].find("</track>") {
+            Some(end) => start + end,
+            None => return Err("unclosed track in XSPF playlist".to_string()),
+        };
+        let track = &rest[start..end];
+        playlist.entries.push(PlaylistEntry {
+            location: element_text(track, "location"),
+            title: element_text(track, "title"),
+            artist: element_text(track, "creator"),
+            album: element_text(track, "album"),
+        });
+        rest = &rest[end..];
+    }
+
+    Ok(playlist)
+}
+
+/// Where `<tag>` or `<tag ...>` opens in `xml`, not counting tags that
+/// only start with the same name.
+fn find_tag(xml: &str, tag: &str) -> Option<usize> {
+    let open = format!("<{tag}");
+    let mut from = 0;
+    while let Some(found) = xml[from..].find(&open) {
+        let start = from + found;
+        match xml[start + open.len()..].chars().next() {
+            Some('>') | Some(' ') | Some('\t') | Some('\n') | Some('\r') => return Some(start),
+            _ => from = start + open.len(),
+        }
+    }
+    None
+}
+
+/// The unescaped text of the first `tag` element in `xml`.
+fn element_text(xml: &str, tag: &str) -> Option<String> {
+    let start = find_tag(xml, tag)?;
+    let content = start + xml[start..].find('>')? + 1;
+    let end = content + xml[content..].find(&format!("</{tag}>"))?;
+    let text = unescape_xml(xml[content..end].trim());
+    Some(text).filter(|text| !text.is_empty())
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    let cdata = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|text| text.strip_suffix("]]>"));
+    if let Some(cdata) = cdata {
+        return cdata.to_string();
+    }
+
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let decoded = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+            }
+            .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    uri
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => text
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Finds the library track for each entry, going by its file where that's
+/// in the library and by its artist and title where not. Returns the ids
+/// found, and the entries that weren't, as they were written.
+pub fn resolve_entries(entries: &[PlaylistEntry], library: &Library) -> (Vec<u64>, Vec<String>) {
+    let tracks: Vec<&Arc<Track>> = library.tracks().collect();
+    let by_path: HashMap<&Path, u64> = tracks
+        .iter()
+        .map(|track| (track.path.as_path(), track.id))
+        .collect();
+    let by_label: HashMap<(String, String), u64> = tracks
+        .iter()
+        .filter_map(|track| {
+            let artist = track.artist.as_ref()?.to_lowercase();
+            Some(((artist, track.title.to_lowercase()), track.id))
+        })
+        .collect();
+
+    let mut ids = Vec::new();
+    let mut unmatched = Vec::new();
+    for entry in entries {
+        let path = entry.location.as_deref().map(entry_path);
+        let by_location = path
+            .as_ref()
+            .and_then(|path| match by_path.get(path.as_path()) {
+                Some(id) => Some(*id),
+                None => suffix_match(&tracks, path),
+            });
+        let by_label = match (entry.artist.as_ref(), entry.title.as_ref()) {
+            (Some(artist), Some(title)) => by_label
+                .get(&(artist.to_lowercase(), title.to_lowercase()))
+                .copied(),
+            _ => None,
+        };
+
+        match by_location.or(by_label) {
+            Some(id) => ids.push(id),
+            None => unmatched.push(
+                entry
+                    .location
+                    .clone()
+                    .or_else(|| entry.title.clone())
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+
+    (ids, unmatched)
+}
+
+/// The track whose path ends with the most of `path`'s folders and file
+/// name, for playlists written on another machine or relative to wherever
+/// they were kept. A file name alone is too common to go by, unless that's
+/// all there is.
+fn suffix_match(tracks: &[&Arc<Track>], path: &Path) -> Option<u64> {
+    let wanted: Vec<Component> = path
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    let needed = wanted.len().min(2);
+    if needed == 0 {
+        return None;
+    }
+
+    let mut best: Option<(usize, u64)> = None;
+    for track in tracks {
+        let shared = track
+            .path
+            .components()
+            .rev()
+            .zip(wanted.iter().rev())
+            .take_while(|(a, b)| a == *b)
+            .count();
+        if shared >= needed && best.is_none_or(|(most, _)| shared > most) {
+            best = Some((shared, track.id));
+        }
+    }
+    best.map(|(_, id)| id)
+}
+
+/// A playlist location as a path, with `file://` URIs decoded and Windows
+/// separators turned around.
+fn entry_path(location: &str) -> PathBuf {
+    let location = match location.strip_prefix("file://") {
+        Some(path) => percent_decode(path),
+        None => location.to_string(),
+    };
+    PathBuf::from(location.replace('\\', "/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> Library {
+        let mut library = Library::open_in_memory().unwrap();
+        let tracks = [
+            (
+                1,
+                "Fish & Chips",
+                "Ben <B> O'Neil",
+                "/music/Ben/Snacks/01 fish.flac",
+            ),
+            (2, "Café Olé", "Zoë", "/music/Zoë/Ünïcode/02 café.mp3"),
+            (3, "Plain", "Someone", "/other/Someone/Album/03 plain.ogg"),
+        ];
+        for (id, title, artist, path) in tracks {
+            library
+                .insert(Track {
+                    id,
+                    title: title.to_string(),
+                    artist: Some(artist.to_string()),
+                    album: Some("Album".to_string()),
+                    duration_ms: Some(181_500),
+                    path: PathBuf::from(path),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        library
+    }
+
+    fn playlist() -> Playlist {
+        Playlist {
+            description: Some("for \"road\" trips & <more>".to_string()),
+            ..Playlist::new("Mine & Yours".to_string())
+        }
+    }
+
+    #[test]
+    fn exports_import_back_as_the_same_tracks() {
+        let library = library();
+        let mut tracks: Vec<Arc<Track>> = library.tracks().cloned().collect();
+        tracks.sort_by_key(|track| std::cmp::Reverse(track.id));
+        let ids: Vec<u64> = tracks.iter().map(|track| track.id).collect();
+
+        for format in [
+            PlaylistFormat::M3u,
+            PlaylistFormat::M3u8,
+            PlaylistFormat::Xspf,
+        ] {
+            let contents = export(&playlist(), &tracks, format);
+            let parsed = parse(&contents, format).unwrap();
+            assert_eq!(parsed.name.as_deref(), Some("Mine & Yours"), "{format:?}");
+            assert_eq!(
+                resolve_entries(&parsed.entries, &library),
+                (ids.clone(), vec![]),
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn xspf_escapes_what_xml_needs_it_to() {
+        let library = library();
+        let tracks: Vec<Arc<Track>> = library.tracks().cloned().collect();
+        let xspf = String::from_utf8(export(&playlist(), &tracks, PlaylistFormat::Xspf)).unwrap();
+        assert!(xspf.contains("<title>Mine &amp; Yours</title>"));
+        assert!(xspf.contains("<creator>Ben &lt;B&gt; O&apos;Neil</creator>"));
+        assert!(xspf.contains("%C3%A9"));
+
+        let parsed = parse(xspf.as_bytes(), PlaylistFormat::Xspf).unwrap();
+        assert_eq!(
+            parsed.description.as_deref(),
+            Some("for \"road\" trips & <more>")
+        );
+        let titles: Vec<String> = parsed
+            .entries
+            .iter()
+            .filter_map(|entry| entry.title.clone())
+            .collect();
+        assert!(titles.contains(&"Fish & Chips".to_string()));
+        assert!(titles.contains(&"Café Olé".to_string()));
+    }
+
+    #[test]
+    fn xspf_reads_cdata_and_character_references() {
+        let xspf = "<playlist><title><![CDATA[a <b> & c]]></title><trackList>\
+            <track><title>&#233;t&#xE9; &amp; &bogus; &</title></track>\
+            <trackNum>1</trackNum></trackList></playlist>";
+        let parsed = parse(xspf.as_bytes(), PlaylistFormat::Xspf).unwrap();
+        assert_eq!(parsed.name.as_deref(), Some("a <b> & c"));
+        assert_eq!(parsed.entries.len(), 1);
+        assert_eq!(parsed.entries[0].title.as_deref(), Some("été & &bogus; &"));
+    }
+
+    #[test]
+    fn m3u_is_latin_1_and_m3u8_is_utf_8() {
+        let track = Arc::new(Track {
+            title: "Café ☕".to_string(),
+            artist: Some("Zoë".to_string()),
+            duration_ms: Some(61_900),
+            path: PathBuf::from("/music/café.flac"),
+            ..Default::default()
+        });
+        let m3u = export(
+            &playlist(),
+            std::slice::from_ref(&track),
+            PlaylistFormat::M3u,
+        );
+        assert!(std::str::from_utf8(&m3u).is_err());
+        assert_eq!(PlaylistFormat::guess(&m3u), PlaylistFormat::M3u);
+        let parsed = parse(&m3u, PlaylistFormat::M3u).unwrap();
+        assert_eq!(parsed.entries[0].title.as_deref(), Some("Café ?"));
+        assert_eq!(
+            parsed.entries[0].location.as_deref(),
+            Some("/music/café.flac")
+        );
+
+        let m3u8 = export(&playlist(), &[track], PlaylistFormat::M3u8);
+        let text = String::from_utf8(m3u8.clone()).unwrap();
+        assert!(text.contains("#EXTINF:61,Zoë - Café ☕\n/music/café.flac\n"));
+        assert_eq!(PlaylistFormat::guess(&m3u8), PlaylistFormat::M3u8);
+        let parsed = parse(&m3u8, PlaylistFormat::M3u8).unwrap();
+        assert_eq!(parsed.entries[0].artist.as_deref(), Some("Zoë"));
+        assert_eq!(parsed.entries[0].title.as_deref(), Some("Café ☕"));
+    }
+
+    #[test]
+    fn extinf_goes_with_the_next_location_only() {
+        let m3u = "\u{feff}#EXTM3U\r\n\
+            #EXTINF:100,Someone - Plain\r\n\
+            nowhere/plain.ogg\r\n\
+            #EXTINF:-1,Just A Title\r\n\
+            # a comment\r\n\
+            a.mp3\r\n\
+            b.mp3\r\n";
+        let parsed = parse(m3u.as_bytes(), PlaylistFormat::M3u8).unwrap();
+        let entries: Vec<(Option<&str>, Option<&str>, Option<&str>)> = parsed
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.location.as_deref(),
+                    entry.artist.as_deref(),
+                    entry.title.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (Some("nowhere/plain.ogg"), Some("Someone"), Some("Plain")),
+                (Some("a.mp3"), None, Some("Just A Title")),
+                (Some("b.mp3"), None, None),
+            ]
+        );
+
+        // the file isn't in the library, but its artist and title are
+        let (ids, unmatched) = resolve_entries(&parsed.entries, &library());
+        assert_eq!(ids, vec![3]);
+        assert_eq!(unmatched, vec!["a.mp3", "b.mp3"]);
+    }
+
+    #[test]
+    fn relative_and_foreign_paths_match_on_their_ends() {
+        let entries: Vec<PlaylistEntry> = [
+            "Snacks/01 fish.flac",
+            "..\\Zoë\\Ünïcode\\02 café.mp3",
+            "file:///mnt/old/Someone/Album/03%20plain.ogg",
+            // a file name alone only goes if there's nothing else to it
+            "03 plain.ogg",
+            "Elsewhere/03 plain.ogg",
+        ]
+        .into_iter()
+        .map(|location| PlaylistEntry {
+            location: Some(location.to_string()),
+            ..Default::default()
+        })
+        .collect();
+
+        let (ids, unmatched) = resolve_entries(&entries, &library());
+        assert_eq!(ids, vec![1, 2, 3, 3]);
+        assert_eq!(unmatched, vec!["Elsewhere/03 plain.ogg"]);
+    }
+}
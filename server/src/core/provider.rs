@@ -2,6 +2,7 @@ pub trait ProviderObject {}
 
 #[derive(Debug)]
 pub enum ProviderError {
+    NotFound,
     Other(&'static str),
 }
 
@@ -0,0 +1,247 @@
+use super::{
+    params::SmoothedParam,
+    pipeline::{AudioFilter, Sample, TransformCtx},
+};
+use std::sync::Mutex;
+
+/// Reduces samples to a given bit depth, optionally with TPDF dither so the
+/// quantization error turns into benign noise instead of distortion
+/// correlated with the signal.
+pub struct Quantizer {
+    max_level: f32,
+    dither: bool,
+    rng_state: u32,
+}
+
+impl Quantizer {
+    pub fn new(bits: u32, dither: bool) -> Self {
+        let bits = bits.clamp(1, 32);
+        Self {
+            max_level: ((1u64 << (bits - 1)) - 1).max(1) as f32,
+            dither,
+            rng_state: 0x9e37_79b9,
+        }
+    }
+
+    /// Quantizes to an integer sample at the configured bit depth.
+    pub fn quantize_to_int(&mut self, sample: Sample) -> i32 {
+        let mut scaled = sample * self.max_level;
+        if self.dither {
+            // the sum of two uniform variables spans +/-1 LSB with a
+            // triangular distribution
+            scaled += self.next_uniform() + self.next_uniform();
+        }
+
+        scaled.round().clamp(-self.max_level - 1.0, self.max_level) as i32
+    }
+
+    /// Quantizes but stays in floating point, for effects.
+    pub fn quantize(&mut self, sample: Sample) -> Sample {
+        self.quantize_to_int(sample) as Sample / self.max_level
+    }
+
+    /// Uniform in [-0.5, 0.5), from a xorshift generator since this runs
+    /// per sample on the audio thread.
+    fn next_uniform(&mut self) -> f32 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+
+        (x >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+    }
+}
+
+/// Lo-fi effect: quantizes to a reduced bit depth and holds samples to
+/// emulate a reduced sample rate.
+pub struct BitCrusher {
+    reduced_rate: Option<u32>,
+    state: Mutex<BitCrusherState>,
+}
+
+struct BitCrusherState {
+    quantizer: Quantizer,
+    phase: f32,
+    held: Vec<Sample>,
+    channel: usize,
+    mix: Option<SmoothedParam>,
+    wet: f32,
+}
+
+impl BitCrusher {
+    /// `reduced_rate` of `None` leaves the sample rate alone.
+    pub fn new(bits: u32, reduced_rate: Option<u32>, dither: bool) -> Self {
+        Self {
+            reduced_rate,
+            state: Mutex::new(BitCrusherState {
+                quantizer: Quantizer::new(bits, dither),
+                // start due, so the first frame is always taken
+                phase: 1.0,
+                held: Vec::new(),
+                channel: 0,
+                mix: None,
+                wet: 1.0,
+            }),
+        }
+    }
+
+    /// Blends the crushed signal with the original, 0 being fully dry.
+    pub fn with_mix(self, mix: SmoothedParam) -> Self {
+        self.state.lock().unwrap().mix = Some(mix);
+        self
+    }
+}
+
+impl AudioFilter for BitCrusher {
+    fn transform(&self, ctx: &TransformCtx, data: &mut [Sample]) -> Result<(), String> {
+        let channels = ctx.channels.max(1) as usize;
+        let step = match self.reduced_rate {
+            Some(rate) => (rate as f32 / ctx.sample_rate as f32).min(1.0),
+            None => 1.0,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.held.resize(channels, 0.0);
+
+        for sample in data.iter_mut() {
+            if state.channel == 0 {
+                if let Some(mix) = state.mix.as_mut() {
+                    state.wet = mix.next_value();
+                }
+            }
+
+            // sample and hold: a new frame is only taken each time the phase
+            // of the reduced rate wraps
+            if state.phase >= 1.0 {
+                state.held[state.channel] = state.quantizer.quantize(*sample);
+            }
+            *sample += (state.held[state.channel] - *sample) * state.wet;
+
+            state.channel += 1;
+            if state.channel == channels {
+                state.channel = 0;
+                if state.phase >= 1.0 {
+                    state.phase -= 1.0;
+                }
+                state.phase += step;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn purge(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.phase = 1.0;
+        state.held.fill(0.0);
+        state.channel = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn ctx(channels: u16) -> TransformCtx {
+        TransformCtx {
+            window_size: 64,
+            fitting_buffer: 64,
+            sample_rate: 48_000,
+            channels,
+        }
+    }
+
+    /// Evenly spaced from -1 to 1.
+    fn ramp(len: usize) -> Vec<Sample> {
+        (0..len)
+            .map(|i| i as Sample / (len - 1) as Sample * 2.0 - 1.0)
+            .collect()
+    }
+
+    #[test]
+    fn n_bits_gives_two_to_the_n_levels() {
+        for bits in [2, 3, 8] {
+            let mut quantizer = Quantizer::new(bits, false);
+            let max = (1i32 << (bits - 1)) - 1;
+            // full scale reaches as far down as up, the extra negative
+            // level is only for samples past it
+            let levels: BTreeSet<i32> = ramp(10_000)
+                .into_iter()
+                .map(|sample| quantizer.quantize_to_int(sample))
+                .collect();
+            assert_eq!(levels, (-max..=max).collect(), "{bits} bits");
+            assert_eq!(quantizer.quantize_to_int(-1.5), -max - 1);
+        }
+
+        let mut quantizer = Quantizer::new(3, false);
+        assert_eq!(quantizer.quantize(1.0), 1.0);
+        assert_eq!(quantizer.quantize(0.5), 2.0 / 3.0);
+        assert_eq!(quantizer.quantize(0.1), 0.0);
+        assert_eq!(quantizer.quantize(-2.0), -4.0 / 3.0);
+    }
+
+    #[test]
+    fn dither_stays_within_one_lsb() {
+        let mut quantizer = Quantizer::new(8, true);
+        let mut seen = BTreeSet::new();
+        for level in [-100, 0, 57] {
+            let sample = level as Sample / 127.0;
+            let mut sum = 0i64;
+            for _ in 0..10_000 {
+                let quantized = quantizer.quantize_to_int(sample);
+                assert!((quantized - level).abs() <= 1, "{quantized} from {level}");
+                seen.insert(quantized - level);
+                sum += quantized as i64;
+            }
+            // triangular about the level, so it averages out to it
+            let mean = sum as f64 / 10_000.0;
+            assert!((mean - level as f64).abs() < 0.02, "{mean} from {level}");
+        }
+        assert_eq!(seen, [-1, 0, 1].into());
+    }
+
+    #[test]
+    fn reduced_rate_holds_each_frame() {
+        let crusher = BitCrusher::new(16, Some(8_000), false);
+        let mut data = ramp(110);
+        let input = data.clone();
+        crusher.transform(&ctx(2), &mut data).unwrap();
+
+        // 8 kHz out of 48 kHz takes every sixth frame, in both channels
+        for (frame, samples) in data.chunks(2).enumerate() {
+            let held = frame / 6 * 6;
+            for channel in 0..2 {
+                let expected = input[held * 2 + channel];
+                assert!((samples[channel] - expected).abs() <= 1.0 / 32767.0);
+            }
+        }
+
+        // carrying on in a second buffer keeps to the same rhythm
+        let mut more = vec![0.5; 8];
+        crusher.transform(&ctx(2), &mut more).unwrap();
+        for (i, sample) in more.iter().enumerate() {
+            assert_eq!(*sample, data[108 + i % 2]);
+        }
+
+        crusher.purge();
+        let mut after = vec![0.25, -0.25];
+        crusher.transform(&ctx(2), &mut after).unwrap();
+        assert!((after[0] - 0.25).abs() <= 1.0 / 32767.0);
+        assert!((after[1] + 0.25).abs() <= 1.0 / 32767.0);
+    }
+
+    #[test]
+    fn full_rate_changes_nothing_but_the_bits() {
+        let crusher = BitCrusher::new(4, None, false);
+        let mut data = ramp(64);
+        crusher.transform(&ctx(1), &mut data).unwrap();
+
+        let mut quantizer = Quantizer::new(4, false);
+        for (crushed, sample) in data.iter().zip(ramp(64)) {
+            assert_eq!(*crushed, quantizer.quantize(sample));
+        }
+    }
+}
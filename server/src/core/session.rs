@@ -0,0 +1,236 @@
+use super::volume::ReplayGainMode;
+use crate::library::hex_ids;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    All,
+}
+
+/// Snapshot of everything needed to pick a playback session back up after a
+/// restart. Tracks are stored by id only and resolved against the library on
+/// restore.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlaybackSession {
+    #[serde(with = "hex_ids")]
+    pub track_ids: Vec<u64>,
+    pub current_track: usize,
+    pub position_ms: u64,
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
+    #[serde(default = "PlaybackSession::default_volume")]
+    pub volume: f32,
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub replay_gain: ReplayGainMode,
+}
+
+impl Default for PlaybackSession {
+    fn default() -> Self {
+        Self {
+            track_ids: Vec::new(),
+            current_track: 0,
+            position_ms: 0,
+            repeat: RepeatMode::Off,
+            shuffle: false,
+            volume: Self::default_volume(),
+            muted: false,
+            replay_gain: ReplayGainMode::Off,
+        }
+    }
+}
+
+impl PlaybackSession {
+    fn default_volume() -> f32 {
+        1.0
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("error reading session file: {e}")),
+        };
+
+        return match serde_json::from_slice(&contents) {
+            Ok(session) => Ok(Some(session)),
+            Err(e) => Err(format!("error parsing session file: {e}")),
+        };
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let contents = match serde_json::to_vec(self) {
+            Ok(contents) => contents,
+            Err(e) => return Err(format!("error serializing session: {e}")),
+        };
+
+        // write next to the target and rename over it so a crash mid-write
+        // never leaves a truncated session behind
+        let tmp_path = path.with_extension("tmp");
+        if let Err(e) = fs::write(&tmp_path, contents) {
+            return Err(format!("error writing session file: {e}"));
+        }
+        if let Err(e) = fs::rename(&tmp_path, path) {
+            return Err(format!("error replacing session file: {e}"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes sessions to disk on a background thread, collapsing bursts of
+/// changes into a single write once no new snapshot has arrived for the
+/// debounce period.
+pub struct SessionPersister {
+    sender: Sender<PlaybackSession>,
+    handle: JoinHandle<()>,
+}
+
+impl SessionPersister {
+    pub fn spawn(path: PathBuf, debounce: Duration) -> Self {
+        let (sender, reciever) = channel::<PlaybackSession>();
+        let handle = thread::spawn(move || {
+            while let Ok(mut session) = reciever.recv() {
+                let mut disconnected = false;
+                loop {
+                    match reciever.recv_timeout(debounce) {
+                        Ok(next) => session = next,
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            disconnected = true;
+                            break;
+                        }
+                    }
+                }
+
+                if let Err(e) = session.save(&path) {
+                    eprintln!("error persisting playback session: {e}");
+                }
+                if disconnected {
+                    return;
+                }
+            }
+        });
+
+        Self { sender, handle }
+    }
+
+    pub fn listener(&self) -> impl Fn(&PlaybackSession) + Send + 'static {
+        let sender = self.sender.clone();
+        move |session| {
+            // the persister only goes away on shutdown, at which point
+            // there is nothing left worth saving
+            let _ = sender.send(session.clone());
+        }
+    }
+
+    /// Flushes any pending snapshot and waits for the writer to finish. Only
+    /// returns once every listener handed out has been dropped as well.
+    pub fn close(self) {
+        drop(self.sender);
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process, time::Instant};
+
+    fn session_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("session-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("session.json")
+    }
+
+    fn at(position_ms: u64) -> PlaybackSession {
+        PlaybackSession {
+            track_ids: vec![1, 2],
+            position_ms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sessions_round_trip_through_the_file() {
+        let path = session_path("round-trip");
+        assert!(PlaybackSession::load(&path).unwrap().is_none());
+
+        let session = PlaybackSession {
+            current_track: 1,
+            repeat: RepeatMode::One,
+            shuffle: true,
+            volume: 0.25,
+            muted: true,
+            replay_gain: ReplayGainMode::Album,
+            ..at(1234)
+        };
+        session.save(&path).unwrap();
+        let loaded = PlaybackSession::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.track_ids, vec![1, 2]);
+        assert_eq!(loaded.current_track, 1);
+        assert_eq!(loaded.position_ms, 1234);
+        assert_eq!(loaded.repeat, RepeatMode::One);
+        assert!(loaded.shuffle && loaded.muted);
+        assert_eq!(loaded.volume, 0.25);
+        assert_eq!(loaded.replay_gain, ReplayGainMode::Album);
+        assert!(!path.with_extension("tmp").exists());
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn persister_writes_a_burst_once() {
+        let path = session_path("burst");
+        let debounce = Duration::from_millis(200);
+        let persister = SessionPersister::spawn(path.clone(), debounce);
+        let listener = persister.listener();
+
+        let started = Instant::now();
+        for position_ms in 0..50 {
+            listener(&at(position_ms));
+            thread::sleep(Duration::from_millis(2));
+        }
+        // nothing's written while changes keep coming
+        if started.elapsed() < debounce {
+            assert!(!path.exists());
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !path.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let written = PlaybackSession::load(&path).unwrap().unwrap();
+        assert_eq!(written.position_ms, 49);
+
+        // and nothing after, once the last of it is down
+        fs::remove_file(&path).unwrap();
+        thread::sleep(debounce * 3);
+        assert!(!path.exists());
+
+        // closing writes what's pending without waiting it out
+        listener(&at(500));
+        drop(listener);
+        persister.close();
+        assert_eq!(
+            PlaybackSession::load(&path).unwrap().unwrap().position_ms,
+            500
+        );
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
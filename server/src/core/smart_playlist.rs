@@ -0,0 +1,562 @@
+use super::provider::WriteableProvider;
+use crate::{
+    fs_provider::FsAudioProvider,
+    library::{Library, LibraryEvent, Track},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// What makes a playlist smart: the rules a track has to meet to be on it,
+/// and how many of those, in what order. For example:
+///
+/// ```json
+/// {
+///     "match": "all",
+///     "rules": [
+///         { "field": "genre", "op": "is", "value": "jazz" },
+///         { "field": "added_at", "op": "in_last_days", "value": 30 },
+///         { "field": "play_count", "op": "lt", "value": 3 }
+///     ],
+///     "sort": { "field": "added_at", "order": "desc" },
+///     "limit": 50
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmartRules {
+    #[serde(default, rename = "match")]
+    pub match_: RuleMatch,
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub sort: Option<SmartSort>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleMatch {
+    #[default]
+    All,
+    Any,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub field: RuleField,
+    pub op: RuleOp,
+    pub value: RuleValue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleField {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Year,
+    TrackNumber,
+    DiscNumber,
+    DurationMs,
+    PlayCount,
+    /// Unix time in milliseconds, as are the other times.
+    LastPlayed,
+    AddedAt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleOp {
+    Is,
+    IsNot,
+    Contains,
+    NotContains,
+    StartsWith,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    /// Within this many days of now, for times.
+    InLastDays,
+    NotInLastDays,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RuleValue {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmartSort {
+    pub field: RuleField,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// A track's value for a field.
+enum FieldValue<'a> {
+    Text(Option<&'a str>),
+    Number(Option<f64>),
+}
+
+impl FieldValue<'_> {
+    fn is_missing(&self) -> bool {
+        matches!(self, FieldValue::Text(None) | FieldValue::Number(None))
+    }
+}
+
+impl RuleField {
+    fn is_text(&self) -> bool {
+        matches!(
+            self,
+            RuleField::Title
+                | RuleField::Artist
+                | RuleField::Album
+                | RuleField::AlbumArtist
+                | RuleField::Genre
+        )
+    }
+
+    fn is_time(&self) -> bool {
+        matches!(self, RuleField::LastPlayed | RuleField::AddedAt)
+    }
+
+    fn value<'a>(&self, track: &'a Track) -> FieldValue<'a> {
+        return match self {
+            RuleField::Title => FieldValue::Text(Some(&track.title)),
+            RuleField::Artist => FieldValue::Text(track.artist.as_deref()),
+            RuleField::Album => FieldValue::Text(track.album.as_deref()),
+            RuleField::AlbumArtist => FieldValue::Text(track.album_artist.as_deref()),
+            RuleField::Genre => FieldValue::Text(track.genre.as_deref()),
+            RuleField::Year => FieldValue::Number(track.year.map(f64::from)),
+            RuleField::TrackNumber => FieldValue::Number(track.track_number.map(f64::from)),
+            RuleField::DiscNumber => FieldValue::Number(track.disc_number.map(f64::from)),
+            RuleField::DurationMs => FieldValue::Number(track.duration_ms.map(|d| d as f64)),
+            RuleField::PlayCount => FieldValue::Number(Some(track.play_count as f64)),
+            RuleField::LastPlayed => FieldValue::Number(track.last_played.map(|t| t as f64)),
+            RuleField::AddedAt => FieldValue::Number(track.added_at.map(|t| t as f64)),
+        };
+    }
+}
+
+impl Rule {
+    fn validate(&self) -> Result<(), String> {
+        let text_field = self.field.is_text();
+        let valid = match (self.op, &self.value) {
+            (RuleOp::Is | RuleOp::IsNot, RuleValue::Text(_)) => text_field,
+            (RuleOp::Is | RuleOp::IsNot, RuleValue::Number(_)) => !text_field,
+            (RuleOp::Contains | RuleOp::NotContains | RuleOp::StartsWith, value) => {
+                text_field && matches!(value, RuleValue::Text(_))
+            }
+            (RuleOp::Lt | RuleOp::Lte | RuleOp::Gt | RuleOp::Gte, value) => {
+                !text_field && matches!(value, RuleValue::Number(_))
+            }
+            (RuleOp::InLastDays | RuleOp::NotInLastDays, value) => {
+                self.field.is_time() && matches!(value, RuleValue::Number(_))
+            }
+        };
+
+        match valid {
+            true => Ok(()),
+            false => Err(format!(
+                "{:?} {:?} {:?} isn't a rule that can be checked",
+                self.field, self.op, self.value
+            )),
+        }
+    }
+
+    /// Missing values never match, except for the negated operators.
+    fn matches(&self, track: &Track, now_ms: f64) -> bool {
+        return match (self.field.value(track), &self.value) {
+            (FieldValue::Text(value), RuleValue::Text(wanted)) => {
+                let value = value.map(str::to_lowercase);
+                let wanted = wanted.to_lowercase();
+                match (self.op, value) {
+                    (RuleOp::IsNot, value) => value.as_ref() != Some(&wanted),
+                    (RuleOp::NotContains, value) => {
+                        value.is_none_or(|value| !value.contains(&wanted))
+                    }
+                    (_, None) => false,
+                    (RuleOp::Is, Some(value)) => value == wanted,
+                    (RuleOp::Contains, Some(value)) => value.contains(&wanted),
+                    (RuleOp::StartsWith, Some(value)) => value.starts_with(&wanted),
+                    _ => false,
+                }
+            }
+            (FieldValue::Number(value), RuleValue::Number(wanted)) => match (self.op, value) {
+                (RuleOp::IsNot, value) => value != Some(*wanted),
+                (RuleOp::NotInLastDays, value) => {
+                    value.is_none_or(|value| now_ms - value > wanted * DAY_MS)
+                }
+                (_, None) => false,
+                (RuleOp::Is, Some(value)) => value == *wanted,
+                (RuleOp::Lt, Some(value)) => value < *wanted,
+                (RuleOp::Lte, Some(value)) => value <= *wanted,
+                (RuleOp::Gt, Some(value)) => value > *wanted,
+                (RuleOp::Gte, Some(value)) => value >= *wanted,
+                (RuleOp::InLastDays, Some(value)) => now_ms - value <= wanted * DAY_MS,
+                _ => false,
+            },
+            _ => false,
+        };
+    }
+}
+
+const DAY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+
+impl SmartRules {
+    pub fn validate(&self) -> Result<(), String> {
+        self.rules.iter().try_for_each(Rule::validate)
+    }
+
+    /// Ids of the library tracks that meet the rules, sorted and limited.
+    /// Times are relative to `now`, so the same rules find different tracks
+    /// as time goes by.
+    pub fn evaluate(&self, library: &Library, now: SystemTime) -> Vec<u64> {
+        let now_ms = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_millis() as f64);
+        let mut tracks: Vec<&Track> = library
+            .tracks()
+            .map(|track| &**track)
+            .filter(|track| match self.match_ {
+                RuleMatch::All => self.rules.iter().all(|rule| rule.matches(track, now_ms)),
+                RuleMatch::Any => self.rules.iter().any(|rule| rule.matches(track, now_ms)),
+            })
+            .collect();
+
+        // with no sort asked for, the order an album plays in
+        tracks.sort_by(|a, b| {
+            match self.sort {
+                Some(sort) => compare(sort.field.value(a), sort.field.value(b), sort.order),
+                None => Ordering::Equal,
+            }
+            .then_with(|| album_order(a, b))
+        });
+        if let Some(limit) = self.limit {
+            tracks.truncate(limit);
+        }
+
+        tracks.into_iter().map(|track| track.id).collect()
+    }
+}
+
+/// Tracks missing a value go last either way round.
+fn compare(a: FieldValue, b: FieldValue, order: SortOrder) -> Ordering {
+    let ordering = match (a, b) {
+        (FieldValue::Text(Some(a)), FieldValue::Text(Some(b))) => {
+            a.to_lowercase().cmp(&b.to_lowercase())
+        }
+        (FieldValue::Number(Some(a)), FieldValue::Number(Some(b))) => a.total_cmp(&b),
+        (a, b) => return b.is_missing().cmp(&a.is_missing()).reverse(),
+    };
+
+    return match order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    };
+}
+
+fn album_order(a: &Track, b: &Track) -> Ordering {
+    let artist = |track: &Track| {
+        track
+            .album_artist
+            .as_ref()
+            .or(track.artist.as_ref())
+            .map(|artist| artist.to_lowercase())
+    };
+    artist(a)
+        .cmp(&artist(b))
+        .then_with(|| a.album.cmp(&b.album))
+        .then(a.disc_number.cmp(&b.disc_number))
+        .then(a.track_number.cmp(&b.track_number))
+        .then(a.id.cmp(&b.id))
+}
+
+/// Keeps the stored track lists of smart playlists up to date, so clients
+/// following `/library/events` hear when one changes.
+///
+/// It's told when the library changes, and waits for `debounce` of quiet
+/// before going through the playlists. Rules on times go stale without the
+/// library changing at all, so it also goes through them every `interval`.
+pub struct SmartPlaylistRefresher {
+    sender: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl SmartPlaylistRefresher {
+    pub fn spawn<F>(
+        provider: Arc<FsAudioProvider>,
+        library: Arc<RwLock<Library>>,
+        debounce: Duration,
+        interval: Duration,
+        on_event: F,
+    ) -> Self
+    where
+        F: Fn(LibraryEvent) + Send + 'static,
+    {
+        let (sender, reciever) = channel::<()>();
+        let handle = thread::spawn(move || loop {
+            let disconnected = match reciever.recv_timeout(interval) {
+                Ok(_) => loop {
+                    match reciever.recv_timeout(debounce) {
+                        Ok(_) => continue,
+                        Err(RecvTimeoutError::Timeout) => break false,
+                        Err(RecvTimeoutError::Disconnected) => break true,
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+            if disconnected {
+                return;
+            }
+
+            if let Err(e) = refresh_all(&provider, &library, &on_event) {
+                eprintln!("error refreshing smart playlists: {e}");
+            }
+        });
+
+        Self { sender, handle }
+    }
+
+    /// For the library to call whenever it changes.
+    pub fn listener(&self) -> impl Fn() + Send + Sync + 'static {
+        let sender = self.sender.clone();
+        move || {
+            // the refresher only goes away on shutdown
+            let _ = sender.send(());
+        }
+    }
+
+    pub fn close(self) {
+        drop(self.sender);
+        let _ = self.handle.join();
+    }
+}
+
+fn refresh_all<F>(
+    provider: &FsAudioProvider,
+    library: &RwLock<Library>,
+    on_event: &F,
+) -> Result<(), String>
+where
+    F: Fn(LibraryEvent),
+{
+    let playlists = match provider.playlists() {
+        Ok(playlists) => playlists,
+        Err(e) => return Err(format!("{e:?}")),
+    };
+
+    for mut playlist in playlists.into_iter().filter(|p| p.rules.is_some()) {
+        if !playlist.refresh(&library.read().unwrap()) {
+            continue;
+        }
+        playlist.touch();
+        if let Err(e) = provider.set(&format!("{:x}", playlist.id), playlist.clone()) {
+            return Err(format!("{e:?}"));
+        }
+        on_event(LibraryEvent::PlaylistUpdated { playlist });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW_MS: u64 = 1_700_000_000_000;
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(NOW_MS)
+    }
+
+    /// Ids 1 to 6: jazz on odd ids and rock on even, added `id` days ago
+    /// and played `id - 1` times. Track 6 has never been played and has no
+    /// year. In album order they go 6, 3, 4, 1, 5, 2.
+    fn library() -> Library {
+        let mut library = Library::open_in_memory().unwrap();
+        for id in 1..=6u64 {
+            library
+                .insert(Track {
+                    id,
+                    title: format!("Song {id}"),
+                    artist: Some(["Miles", "Nina", "Ozzy"][id as usize % 3].to_string()),
+                    album: Some("Album".to_string()),
+                    track_number: Some(7 - id as u32),
+                    genre: Some(if id % 2 == 1 { "Jazz" } else { "Rock" }.to_string()),
+                    year: Some(2000 + id as u32).filter(|_| id != 6),
+                    play_count: id as u32 - 1,
+                    last_played: Some(NOW_MS - id * DAY).filter(|_| id != 6),
+                    added_at: Some(NOW_MS - id * DAY),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        library
+    }
+
+    fn rules(json: &str) -> SmartRules {
+        let rules: SmartRules = serde_json::from_str(json).unwrap();
+        rules.validate().unwrap();
+        rules
+    }
+
+    fn evaluate(json: &str) -> Vec<u64> {
+        rules(json).evaluate(&library(), now())
+    }
+
+    #[test]
+    fn rules_parse_as_documented() {
+        let parsed = rules(
+            r#"{
+                "match": "all",
+                "rules": [
+                    { "field": "genre", "op": "is", "value": "jazz" },
+                    { "field": "added_at", "op": "in_last_days", "value": 30 },
+                    { "field": "play_count", "op": "lt", "value": 3 }
+                ],
+                "sort": { "field": "added_at", "order": "desc" },
+                "limit": 50
+            }"#,
+        );
+        assert_eq!(
+            parsed.rules[1],
+            Rule {
+                field: RuleField::AddedAt,
+                op: RuleOp::InLastDays,
+                value: RuleValue::Number(30.0),
+            }
+        );
+        assert_eq!(
+            parsed.sort,
+            Some(SmartSort {
+                field: RuleField::AddedAt,
+                order: SortOrder::Desc,
+            })
+        );
+        assert_eq!(parsed.limit, Some(50));
+
+        // matching all and sorting as found are what's left out
+        let defaults = rules(r#"{ "rules": [] }"#);
+        assert_eq!(defaults.match_, RuleMatch::All);
+        assert_eq!(defaults.sort, None);
+    }
+
+    #[test]
+    fn rules_that_cant_be_checked_are_refused() {
+        let invalid = [
+            r#"{ "field": "genre", "op": "lt", "value": "jazz" }"#,
+            r#"{ "field": "play_count", "op": "contains", "value": "1" }"#,
+            r#"{ "field": "year", "op": "in_last_days", "value": 3 }"#,
+            r#"{ "field": "title", "op": "is", "value": 3 }"#,
+        ];
+        for rule in invalid {
+            let rules: SmartRules =
+                serde_json::from_str(&format!(r#"{{ "rules": [{rule}] }}"#)).unwrap();
+            assert!(rules.validate().is_err(), "{rule}");
+        }
+
+        let unknown = r#"{ "rules": [], "limt": 3 }"#;
+        assert!(serde_json::from_str::<SmartRules>(unknown).is_err());
+    }
+
+    #[test]
+    fn all_and_any_combine_rules() {
+        let all = evaluate(
+            r#"{ "match": "all", "rules": [
+                { "field": "genre", "op": "is", "value": "JAZZ" },
+                { "field": "play_count", "op": "lt", "value": 3 }
+            ] }"#,
+        );
+        assert_eq!(all, vec![3, 1]);
+
+        let any = evaluate(
+            r#"{ "match": "any", "rules": [
+                { "field": "genre", "op": "is", "value": "JAZZ" },
+                { "field": "play_count", "op": "lt", "value": 3 }
+            ] }"#,
+        );
+        assert_eq!(any, vec![3, 1, 5, 2]);
+    }
+
+    #[test]
+    fn days_count_back_from_now() {
+        let added =
+            evaluate(r#"{ "rules": [{ "field": "added_at", "op": "in_last_days", "value": 3 }] }"#);
+        assert_eq!(added, vec![3, 1, 2]);
+
+        // never played counts as not played lately
+        let not_played = evaluate(
+            r#"{ "rules": [{ "field": "last_played", "op": "not_in_last_days", "value": 4 }] }"#,
+        );
+        assert_eq!(not_played, vec![6, 5]);
+
+        let later =
+            rules(r#"{ "rules": [{ "field": "added_at", "op": "in_last_days", "value": 3 }] }"#)
+                .evaluate(&library(), now() + Duration::from_millis(2 * DAY));
+        assert_eq!(later, vec![1]);
+    }
+
+    #[test]
+    fn missing_values_only_match_negated_rules() {
+        let year = evaluate(r#"{ "rules": [{ "field": "year", "op": "lt", "value": 3000 }] }"#);
+        assert_eq!(year, vec![3, 4, 1, 5, 2]);
+
+        let not_year =
+            evaluate(r#"{ "rules": [{ "field": "year", "op": "is_not", "value": 2001 }] }"#);
+        assert_eq!(not_year, vec![6, 3, 4, 5, 2]);
+
+        let not_contains = evaluate(
+            r#"{ "rules": [{ "field": "album_artist", "op": "not_contains", "value": "x" }] }"#,
+        );
+        assert_eq!(not_contains.len(), 6);
+    }
+
+    #[test]
+    fn sort_and_limit_pick_the_tracks() {
+        let most_played = evaluate(
+            r#"{ "rules": [], "sort": { "field": "play_count", "order": "desc" }, "limit": 2 }"#,
+        );
+        assert_eq!(most_played, vec![6, 5]);
+
+        let by_title = evaluate(r#"{ "rules": [], "sort": { "field": "title" }, "limit": 3 }"#);
+        assert_eq!(by_title, vec![1, 2, 3]);
+
+        // no year goes last, whichever way round
+        for order in ["asc", "desc"] {
+            let by_year = evaluate(&format!(
+                r#"{{ "rules": [], "sort": {{ "field": "year", "order": "{order}" }} }}"#
+            ));
+            assert_eq!(by_year.last(), Some(&6), "{order}");
+        }
+
+        // ties, and no sort at all, go in album order
+        let by_genre = evaluate(r#"{ "rules": [], "sort": { "field": "genre" } }"#);
+        assert_eq!(by_genre, vec![3, 1, 5, 6, 4, 2]);
+    }
+}
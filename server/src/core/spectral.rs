@@ -0,0 +1,345 @@
+use super::{
+    params::StageParam,
+    pipeline::{AudioFilter, Sample, TransformCtx},
+    volume::db_to_amplitude,
+};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+/// What a spectral processor is told about the frames it's handed.
+pub struct SpectralCtx {
+    pub sample_rate: u32,
+    pub fft_size: usize,
+}
+
+impl SpectralCtx {
+    pub fn bin_frequency(&self, bin: usize) -> f32 {
+        bin as f32 * self.sample_rate as f32 / self.fft_size as f32
+    }
+}
+
+/// Frequency domain processing, run by `StftFilter` once per hop on every
+/// channel's bins, `0..=fft_size / 2`. The mirrored upper half is rebuilt
+/// from these, so only they need changing.
+pub trait SpectralProcessor: Send {
+    fn process(&mut self, ctx: &SpectralCtx, bins: &mut [Vec<Complex<f32>>]);
+
+    /// Forgets any state kept between frames.
+    fn purge(&mut self) {}
+}
+
+/// Short-time Fourier transform with overlap-add resynthesis around a
+/// `SpectralProcessor`. Each channel's frames are a window of
+/// `ctx.window_size` samples, so the FFT size is that in frames, with a hop
+/// of the FFT size over `overlap`.
+///
+/// Uses a square-root Hann window for both analysis and synthesis, so with a
+/// processor that leaves the bins alone the output is the input delayed by
+/// `fft_size - hop` frames, with the last of it pushed out by silence once
+/// the input ends.
+pub struct StftFilter<P: SpectralProcessor> {
+    overlap: usize,
+    state: Mutex<StftState<P>>,
+}
+
+struct StftState<P> {
+    processor: P,
+    plan: Option<StftPlan>,
+}
+
+struct StftPlan {
+    fft_size: usize,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    // scales the overlapped windows back to unity gain
+    normalization: f32,
+    bins: Vec<Vec<Complex<f32>>>,
+    buffer: Vec<Complex<f32>>,
+    // per channel overlap-add accumulator of fft_size frames
+    output: Vec<Vec<f32>>,
+}
+
+impl<P: SpectralProcessor> StftFilter<P> {
+    /// `overlap` is how many windows each frame is part of: 2 or 4 for a
+    /// Hann window.
+    pub fn new(processor: P, overlap: usize) -> Self {
+        Self {
+            overlap: overlap.max(1),
+            state: Mutex::new(StftState {
+                processor,
+                plan: None,
+            }),
+        }
+    }
+
+    fn fft_size(ctx: &TransformCtx) -> usize {
+        ctx.window_size / ctx.channels.max(1) as usize
+    }
+
+    fn hop_frames(&self, ctx: &TransformCtx) -> usize {
+        (Self::fft_size(ctx) / self.overlap).max(1)
+    }
+}
+
+impl StftPlan {
+    fn new(fft_size: usize, hop: usize, channels: usize) -> Self {
+        let mut planner = FftPlanner::new();
+        // periodic rather than symmetric, so shifted copies sum evenly
+        let window: Vec<f32> = (0..fft_size)
+            .map(|n| (0.5 - 0.5 * (2.0 * PI * n as f32 / fft_size as f32).cos()).sqrt())
+            .collect();
+        let window_power: f32 = window.iter().map(|w| w * w).sum();
+
+        Self {
+            fft_size,
+            forward: planner.plan_fft_forward(fft_size),
+            inverse: planner.plan_fft_inverse(fft_size),
+            normalization: hop as f32 / window_power / fft_size as f32,
+            window,
+            bins: vec![vec![Complex::default(); fft_size / 2 + 1]; channels],
+            buffer: vec![Complex::default(); fft_size],
+            output: vec![vec![0.0; fft_size]; channels],
+        }
+    }
+}
+
+impl<P: SpectralProcessor> AudioFilter for StftFilter<P> {
+    fn transform(&self, ctx: &TransformCtx, data: &mut [Sample]) -> Result<(), String> {
+        let channels = ctx.channels.max(1) as usize;
+        let fft_size = Self::fft_size(ctx);
+        let hop = self.hop_frames(ctx);
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if state
+            .plan
+            .as_ref()
+            .is_none_or(|plan| plan.fft_size != fft_size)
+        {
+            state.plan = Some(StftPlan::new(fft_size, hop, channels));
+        }
+        let plan = state.plan.as_mut().unwrap();
+
+        // the window is `fft_size - hop` frames of context and then the new
+        // frames, except at the end of a stream where there can be fewer and
+        // the rest is padded with silence
+        let frames = data.len() / channels;
+        let new_frames = frames.saturating_sub(fft_size - hop);
+
+        for channel in 0..channels {
+            for (n, value) in plan.buffer.iter_mut().enumerate() {
+                let sample = match n < frames {
+                    true => data[n * channels + channel],
+                    false => 0.0,
+                };
+                *value = Complex::new(sample * plan.window[n], 0.0);
+            }
+            plan.forward.process(&mut plan.buffer);
+            plan.bins[channel].copy_from_slice(&plan.buffer[..=fft_size / 2]);
+        }
+
+        let spectral_ctx = SpectralCtx {
+            sample_rate: ctx.sample_rate,
+            fft_size,
+        };
+        state.processor.process(&spectral_ctx, &mut plan.bins);
+
+        for channel in 0..channels {
+            let bins = &plan.bins[channel];
+            plan.buffer[..=fft_size / 2].copy_from_slice(bins);
+            for (bin, value) in bins.iter().enumerate().take(fft_size.div_ceil(2)).skip(1) {
+                plan.buffer[fft_size - bin] = value.conj();
+            }
+            plan.inverse.process(&mut plan.buffer);
+
+            let output = &mut plan.output[channel];
+            for (n, value) in plan.buffer.iter().enumerate() {
+                output[n] += value.re * plan.window[n] * plan.normalization;
+            }
+
+            // the oldest frames have every window they're part of added in
+            for frame in 0..new_frames {
+                data[(frames - new_frames + frame) * channels + channel] = output[frame];
+            }
+            output.copy_within(new_frames.., 0);
+            output[fft_size - new_frames..].fill(0.0);
+        }
+
+        Ok(())
+    }
+
+    fn hop_size(&self, ctx: &TransformCtx) -> usize {
+        self.hop_frames(ctx) * ctx.channels.max(1) as usize
+    }
+
+    fn latency(&self, ctx: &TransformCtx) -> usize {
+        Self::fft_size(ctx) - self.hop_frames(ctx)
+    }
+
+    fn purge(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(plan) = state.plan.as_mut() {
+            plan.output.iter_mut().for_each(|output| output.fill(0.0));
+        }
+        state.processor.purge();
+    }
+}
+
+/// Attenuates bins quieter than a threshold, taking out steady low-level
+/// noise between and underneath louder content.
+pub struct SpectralGate {
+    threshold_db: Arc<StageParam>,
+    reduction_db: Arc<StageParam>,
+    // per channel and bin, so a gate doesn't chatter on a bin hovering
+    // around the threshold
+    gains: Vec<Vec<f32>>,
+}
+
+impl SpectralGate {
+    /// How much of the way to its target a bin's gain moves each hop.
+    const RESPONSE: f32 = 0.5;
+
+    pub fn new(threshold_db: Arc<StageParam>, reduction_db: Arc<StageParam>) -> Self {
+        Self {
+            threshold_db,
+            reduction_db,
+            gains: Vec::new(),
+        }
+    }
+}
+
+impl SpectralProcessor for SpectralGate {
+    fn process(&mut self, ctx: &SpectralCtx, bins: &mut [Vec<Complex<f32>>]) {
+        // bin magnitudes scale with the FFT size, a full scale sine peaks
+        // at roughly a third of it through the square-root Hann window
+        let threshold = db_to_amplitude(self.threshold_db.get()) * ctx.fft_size as f32 / 3.0;
+        let reduction = db_to_amplitude(self.reduction_db.get());
+
+        self.gains.resize(bins.len(), Vec::new());
+        for (channel, bins) in bins.iter_mut().enumerate() {
+            let gains = &mut self.gains[channel];
+            gains.resize(bins.len(), 1.0);
+            for (bin, gain) in bins.iter_mut().zip(gains.iter_mut()) {
+                let target = match bin.norm() < threshold {
+                    true => reduction,
+                    false => 1.0,
+                };
+                *gain += (target - *gain) * Self::RESPONSE;
+                *bin *= *gain;
+            }
+        }
+    }
+
+    fn purge(&mut self) {
+        self.gains.clear();
+    }
+}
+
+/// Applies a gain curve through a set of `(frequency, gain_db)` points,
+/// interpolated on a log frequency scale and held flat past either end.
+pub struct SpectralEq {
+    bands: Vec<(f32, Arc<StageParam>)>,
+    gains: Vec<f32>,
+}
+
+impl SpectralEq {
+    pub fn new(mut bands: Vec<(f32, Arc<StageParam>)>) -> Self {
+        bands.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self {
+            bands,
+            gains: Vec::new(),
+        }
+    }
+
+    fn gain_db(&self, frequency: f32) -> f32 {
+        let (first, last) = match (self.bands.first(), self.bands.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0.0,
+        };
+        if frequency <= first.0 {
+            return first.1.get();
+        }
+        if frequency >= last.0 {
+            return last.1.get();
+        }
+
+        let upper = self
+            .bands
+            .iter()
+            .position(|(f, _)| *f >= frequency)
+            .unwrap();
+        let (low_freq, low_gain) = &self.bands[upper - 1];
+        let (high_freq, high_gain) = &self.bands[upper];
+        let t = (frequency / low_freq).ln() / (high_freq / low_freq).ln();
+        low_gain.get() + (high_gain.get() - low_gain.get()) * t
+    }
+}
+
+impl SpectralProcessor for SpectralEq {
+    fn process(&mut self, ctx: &SpectralCtx, bins: &mut [Vec<Complex<f32>>]) {
+        // worked out once per hop, the same curve applies to every channel
+        let bin_count = bins.first().map_or(0, Vec::len);
+        self.gains.resize(bin_count, 1.0);
+        for bin in 0..bin_count {
+            self.gains[bin] = db_to_amplitude(self.gain_db(ctx.bin_frequency(bin)));
+        }
+
+        for bins in bins.iter_mut() {
+            for (bin, gain) in bins.iter_mut().zip(self.gains.iter()) {
+                *bin *= *gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::pipeline::{AudioPipe, FilterPipe};
+    use std::sync::mpsc::sync_channel;
+
+    struct Identity;
+
+    impl SpectralProcessor for Identity {
+        fn process(&mut self, _ctx: &SpectralCtx, _bins: &mut [Vec<Complex<f32>>]) {}
+    }
+
+    #[test]
+    fn untouched_bins_give_back_the_delayed_input() {
+        let ctx = TransformCtx {
+            window_size: 256,
+            fitting_buffer: 1024,
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let pipe = FilterPipe(Arc::new(StftFilter::new(Identity, 4)));
+        pipe.init(&ctx).unwrap();
+        let latency = pipe.latency(&ctx);
+        assert_eq!(latency, 96);
+
+        // not a whole number of hops, so the last window is a short one
+        let input: Vec<Sample> = (0..1001 * 2)
+            .map(|i| (i as f32 * 0.05).sin() * 0.5)
+            .collect();
+        let (sender, reciever) = sync_channel(input.len());
+        for sample in input.iter() {
+            sender.send(*sample).unwrap();
+        }
+        drop(sender);
+        let (out_sender, out_reciever) = sync_channel(input.len() + 2 * ctx.window_size);
+        pipe.pipe(&ctx, reciever, out_sender).unwrap();
+        let output: Vec<Sample> = out_reciever.iter().collect();
+
+        assert_eq!(output.len(), input.len() + latency * 2);
+        assert!(output[..latency * 2]
+            .iter()
+            .all(|sample| sample.abs() < 1e-4));
+        for (out, expected) in output[latency * 2..].iter().zip(input.iter()) {
+            assert!((out - expected).abs() < 1e-4, "{out} != {expected}");
+        }
+    }
+}
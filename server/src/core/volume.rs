@@ -0,0 +1,279 @@
+use super::pipeline::{AudioPipe, Sample, TransformCtx};
+use crate::library::ReplayGain;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{Receiver, SyncSender},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+}
+
+impl ReplayGainMode {
+    /// Normalization gain in dB for a track, limited so its peak can't clip.
+    /// Album mode falls back to the track gain for tracks without album
+    /// values.
+    pub fn gain_db(&self, replay_gain: Option<&ReplayGain>) -> f32 {
+        let replay_gain = match (self, replay_gain) {
+            (ReplayGainMode::Off, _) | (_, None) => return 0.0,
+            (_, Some(replay_gain)) => replay_gain,
+        };
+
+        let (gain, peak) = match self {
+            ReplayGainMode::Album => (
+                replay_gain.album_gain.unwrap_or(replay_gain.track_gain),
+                replay_gain.album_peak.unwrap_or(replay_gain.track_peak),
+            ),
+            _ => (replay_gain.track_gain, replay_gain.track_peak),
+        };
+
+        match peak > 0.0 {
+            true => gain.min(-amplitude_to_db(peak)),
+            false => gain,
+        }
+    }
+}
+
+/// Volume as the user sees it, from 0 to 1, mapped onto this many dB of
+/// attenuation so equal slider steps sound like equal loudness steps.
+const VOLUME_RANGE_DB: f32 = 60.0;
+
+pub fn volume_to_gain(volume: f32) -> f32 {
+    match volume <= 0.0 {
+        true => 0.0,
+        false => db_to_amplitude((volume.min(1.0) - 1.0) * VOLUME_RANGE_DB),
+    }
+}
+
+pub fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+pub fn amplitude_to_db(amplitude: f32) -> f32 {
+    20.0 * amplitude.log10()
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct VolumeStatus {
+    pub volume: f32,
+    pub muted: bool,
+    pub replay_gain: ReplayGainMode,
+    pub normalization_db: f32,
+}
+
+/// Volume settings shared between the playback state, which changes them,
+/// and the mixer stage, which reads them on the audio thread.
+pub struct VolumeControl {
+    volume: AtomicU32,
+    muted: AtomicBool,
+    normalization_db: AtomicU32,
+}
+
+impl VolumeControl {
+    pub fn new(volume: f32) -> Self {
+        Self {
+            volume: AtomicU32::new(volume.to_bits()),
+            muted: AtomicBool::new(false),
+            normalization_db: AtomicU32::new(0f32.to_bits()),
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.volume
+            .store(volume.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn normalization_db(&self) -> f32 {
+        f32::from_bits(self.normalization_db.load(Ordering::Relaxed))
+    }
+
+    pub fn set_normalization_db(&self, db: f32) {
+        self.normalization_db.store(db.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Linear gain the mixer should be heading towards.
+    pub fn target_gain(&self) -> f32 {
+        match self.muted() {
+            true => 0.0,
+            false => volume_to_gain(self.volume()) * db_to_amplitude(self.normalization_db()),
+        }
+    }
+}
+
+/// Applies volume, mute and ReplayGain normalization. Gain changes are
+/// smoothed over `ramp` so moving the slider doesn't zipper.
+pub struct SoftMixer {
+    control: Arc<VolumeControl>,
+    ramp: Duration,
+}
+
+impl SoftMixer {
+    /// Frames between reads of the shared control.
+    const CONTROL_INTERVAL: usize = 64;
+    const SNAP_THRESHOLD: f32 = 1e-5;
+
+    pub fn new(control: Arc<VolumeControl>, ramp: Duration) -> Self {
+        Self { control, ramp }
+    }
+}
+
+impl AudioPipe for SoftMixer {
+    fn pipe(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        to: SyncSender<Sample>,
+    ) -> Result<(), String> {
+        let channels = ctx.channels.max(1) as usize;
+        let ramp_frames = (self.ramp.as_secs_f32() * ctx.sample_rate as f32).max(1.0);
+        let coefficient = 1.0 - (-1.0 / ramp_frames).exp();
+
+        let mut gain = self.control.target_gain();
+        let mut target = gain;
+        let mut channel = 0;
+        let mut frame = 0;
+
+        for sample in from {
+            if channel == 0 {
+                if frame == 0 {
+                    target = self.control.target_gain();
+                }
+                frame = (frame + 1) % Self::CONTROL_INTERVAL;
+
+                gain += (target - gain) * coefficient;
+                if (target - gain).abs() < Self::SNAP_THRESHOLD {
+                    gain = target;
+                }
+            }
+            channel = (channel + 1) % channels;
+
+            if let Err(err) = to.send(sample * gain) {
+                return Err(format!("error sending sample: {err}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc::sync_channel, thread};
+
+    #[test]
+    fn volume_spans_sixty_db() {
+        assert_eq!(volume_to_gain(1.0), 1.0);
+        assert_eq!(volume_to_gain(2.0), 1.0);
+        assert_eq!(volume_to_gain(0.0), 0.0);
+        assert_eq!(volume_to_gain(-1.0), 0.0);
+
+        // equal steps of volume are equal steps of dB
+        for (volume, db) in [(0.5, -30.0), (0.25, -45.0), (1e-6, -60.0)] {
+            let gain = amplitude_to_db(volume_to_gain(volume));
+            assert!((gain - db).abs() < 1e-3, "{volume} at {gain} dB");
+        }
+    }
+
+    #[test]
+    fn muting_keeps_the_volume() {
+        let control = VolumeControl::new(0.5);
+        control.set_normalization_db(-6.0);
+        let unmuted = control.target_gain();
+        assert!((amplitude_to_db(unmuted) + 36.0).abs() < 1e-3);
+
+        control.set_muted(true);
+        assert_eq!(control.target_gain(), 0.0);
+        assert_eq!(control.volume(), 0.5);
+        control.set_muted(false);
+        assert_eq!(control.target_gain(), unmuted);
+
+        control.set_volume(3.0);
+        assert_eq!(control.volume(), 1.0);
+    }
+
+    #[test]
+    fn replay_gain_stops_short_of_clipping() {
+        let replay_gain = ReplayGain {
+            track_gain: 8.0,
+            track_peak: 0.5,
+            album_gain: Some(-3.0),
+            album_peak: None,
+        };
+        assert_eq!(ReplayGainMode::Off.gain_db(Some(&replay_gain)), 0.0);
+        assert_eq!(ReplayGainMode::Track.gain_db(None), 0.0);
+        assert!((ReplayGainMode::Track.gain_db(Some(&replay_gain)) - 6.0206).abs() < 1e-3);
+        assert_eq!(ReplayGainMode::Album.gain_db(Some(&replay_gain)), -3.0);
+    }
+
+    #[test]
+    fn mixer_ramps_between_gains_without_a_step() {
+        let control = Arc::new(VolumeControl::new(1.0));
+        let mixer = SoftMixer::new(control.clone(), Duration::from_millis(10));
+        let ctx = TransformCtx {
+            window_size: 64,
+            fitting_buffer: 64,
+            sample_rate: 8000,
+            channels: 2,
+        };
+
+        let (sender, from) = sync_channel(0);
+        let (to, receiver) = sync_channel(1024);
+        let handle = thread::spawn(move || mixer.pipe(&ctx, from, to));
+        let play = |frames: usize| -> Vec<Sample> {
+            (0..frames * 2)
+                .map(|_| {
+                    sender.send(1.0).unwrap();
+                    receiver.recv().unwrap()
+                })
+                .collect()
+        };
+
+        // a whole control interval, so the change is read with the next
+        let before = play(SoftMixer::CONTROL_INTERVAL);
+        assert!(before.iter().all(|sample| *sample == 1.0));
+
+        control.set_muted(true);
+        let after = play(1000);
+        let mut previous = 1.0;
+        for frame in after.chunks(2) {
+            assert_eq!(frame[0], frame[1]);
+            assert!(frame[0] <= previous);
+            // 10 ms at 8 kHz is 80 frames, none of which drop more than
+            // one of them's share
+            assert!(
+                previous - frame[0] < 1.0 / 80.0,
+                "{previous} to {}",
+                frame[0]
+            );
+            previous = frame[0];
+        }
+        assert!(after[1] > 0.9);
+        assert_eq!(previous, 0.0);
+
+        drop(sender);
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+use super::{audio::AudioReader, pipeline::Sample};
+use serde::Serialize;
+use symphonia::core::audio::SampleBuffer;
+
+/// The extremes and loudness of a run of frames, across every channel.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct WaveformPoint {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
+
+/// Downsampled peak data for drawing a track's waveform.
+///
+/// What's cached is a fixed number of frames per point, fine enough for any
+/// reasonable display, and `resample` brings that down to however many points
+/// a client asks for.
+#[derive(Clone, Debug, Serialize)]
+pub struct Waveform {
+    pub sample_rate: u32,
+    pub frames_per_point: u32,
+    pub points: Vec<WaveformPoint>,
+}
+
+impl Waveform {
+    /// Cached and served as: magic, version, sample rate, frames per point,
+    /// point count, then each point as min, max and rms in 16-bit fixed point.
+    /// Every field is little endian.
+    const MAGIC: &[u8; 4] = b"WFRM";
+    const VERSION: u16 = 1;
+    const HEADER_SIZE: usize = 18;
+    const POINT_SIZE: usize = 6;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(Self::HEADER_SIZE + self.points.len() * Self::POINT_SIZE);
+        bytes.extend_from_slice(Self::MAGIC);
+        bytes.extend_from_slice(&Self::VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.frames_per_point.to_le_bytes());
+        bytes.extend_from_slice(&(self.points.len() as u32).to_le_bytes());
+        for point in self.points.iter() {
+            for value in [point.min, point.max, point.rms] {
+                let fixed = (value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                bytes.extend_from_slice(&fixed.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < Self::HEADER_SIZE || &bytes[..4] != Self::MAGIC {
+            return Err("not waveform data".to_string());
+        }
+
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let version = u16_at(4);
+        if version != Self::VERSION {
+            return Err(format!("unsupported waveform version {version}"));
+        }
+
+        let count = u32_at(14) as usize;
+        let data = &bytes[Self::HEADER_SIZE..];
+        if data.len() != count * Self::POINT_SIZE {
+            return Err("truncated waveform data".to_string());
+        }
+
+        let points = data
+            .chunks_exact(Self::POINT_SIZE)
+            .map(|point| {
+                let value = |i: usize| {
+                    i16::from_le_bytes([point[i], point[i + 1]]) as f32 / i16::MAX as f32
+                };
+                WaveformPoint {
+                    min: value(0),
+                    max: value(2),
+                    rms: value(4),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            sample_rate: u32_at(6),
+            frames_per_point: u32_at(10),
+            points,
+        })
+    }
+
+    /// Merges runs of points down to at most `points` of them. Asking for
+    /// more than there are gives back what there is.
+    pub fn resample(&self, points: usize) -> Self {
+        if points == 0 || points >= self.points.len() {
+            return self.clone();
+        }
+
+        let resampled = (0..points)
+            .map(|i| {
+                // spread any remainder evenly rather than piling it on the end
+                let start = i * self.points.len() / points;
+                let end = (i + 1) * self.points.len() / points;
+                let run = &self.points[start..end];
+                let squares: f32 = run.iter().map(|point| point.rms * point.rms).sum();
+                WaveformPoint {
+                    min: run.iter().map(|point| point.min).fold(0.0, f32::min),
+                    max: run.iter().map(|point| point.max).fold(0.0, f32::max),
+                    rms: (squares / run.len() as f32).sqrt(),
+                }
+            })
+            .collect();
+
+        Self {
+            sample_rate: self.sample_rate,
+            frames_per_point: (self.frames_per_point as usize * self.points.len() / points) as u32,
+            points: resampled,
+        }
+    }
+}
+
+/// Builds a `Waveform` from interleaved samples as they go by.
+pub struct WaveformBuilder {
+    sample_rate: u32,
+    channels: usize,
+    frames_per_point: usize,
+    points: Vec<WaveformPoint>,
+    current: WaveformPoint,
+    squares: f64,
+    frames: usize,
+    channel: usize,
+}
+
+impl WaveformBuilder {
+    /// Around 5 ms at 44.1 kHz, so a four minute track is under 300 KiB.
+    pub const FRAMES_PER_POINT: usize = 256;
+
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels: channels.max(1) as usize,
+            frames_per_point: Self::FRAMES_PER_POINT,
+            points: Vec::new(),
+            current: Self::empty_point(),
+            squares: 0.0,
+            frames: 0,
+            channel: 0,
+        }
+    }
+
+    fn empty_point() -> WaveformPoint {
+        WaveformPoint {
+            min: 0.0,
+            max: 0.0,
+            rms: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, samples: &[Sample]) {
+        for sample in samples {
+            self.current.min = self.current.min.min(*sample);
+            self.current.max = self.current.max.max(*sample);
+            self.squares += (*sample as f64) * (*sample as f64);
+
+            self.channel += 1;
+            if self.channel == self.channels {
+                self.channel = 0;
+                self.frames += 1;
+                if self.frames == self.frames_per_point {
+                    self.push_point();
+                }
+            }
+        }
+    }
+
+    fn push_point(&mut self) {
+        let count = (self.frames * self.channels) as f64;
+        self.current.rms = (self.squares / count).sqrt() as f32;
+        self.points.push(self.current);
+
+        self.current = Self::empty_point();
+        self.squares = 0.0;
+        self.frames = 0;
+    }
+
+    pub fn finish(mut self) -> Waveform {
+        if self.frames > 0 {
+            self.push_point();
+        }
+
+        Waveform {
+            sample_rate: self.sample_rate,
+            frames_per_point: self.frames_per_point as u32,
+            points: self.points,
+        }
+    }
+
+    /// Decodes all of what's left in `reader`.
+    pub fn read(reader: &mut AudioReader) -> Result<Waveform, String> {
+        let spec = reader.signal_spec();
+        let mut builder = Self::new(spec.rate, spec.channels.count() as u16);
+
+        let mut sample_buffer = SampleBuffer::new(64 * 1024, spec);
+        loop {
+            match reader.read_next_as_samples::<Sample>(&mut sample_buffer) {
+                Ok(_) => builder.add(sample_buffer.samples()),
+                Err(e) if e == "EOF" => break,
+                Err(e) => return Err(format!("error reading samples: {e}")),
+            }
+        }
+
+        Ok(builder.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(min: f32, max: f32, rms: f32) -> WaveformPoint {
+        WaveformPoint { min, max, rms }
+    }
+
+    #[test]
+    fn bytes_round_trip_to_within_fixed_point() {
+        let waveform = Waveform {
+            sample_rate: 48000,
+            frames_per_point: 256,
+            points: vec![
+                point(-1.0, 1.0, 0.7),
+                point(-0.25, 0.5, 0.3),
+                point(0.0, 0.0, 0.0),
+                // clamped into range on the way out
+                point(-1.5, 2.0, 1.2),
+            ],
+        };
+        let bytes = waveform.to_bytes();
+        assert_eq!(
+            bytes.len(),
+            Waveform::HEADER_SIZE + 4 * Waveform::POINT_SIZE
+        );
+
+        let read = Waveform::from_bytes(&bytes).unwrap();
+        assert_eq!(read.sample_rate, 48000);
+        assert_eq!(read.frames_per_point, 256);
+        assert_eq!(read.points.len(), 4);
+        for (read, written) in read.points.iter().zip(waveform.points.iter()) {
+            for (a, b) in [
+                (read.min, written.min.clamp(-1.0, 1.0)),
+                (read.max, written.max.clamp(-1.0, 1.0)),
+                (read.rms, written.rms.clamp(-1.0, 1.0)),
+            ] {
+                assert!((a - b).abs() <= 0.5 / i16::MAX as f32, "{a} for {b}");
+            }
+        }
+
+        assert!(Waveform::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Waveform::from_bytes(b"RIFF").is_err());
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(Waveform::from_bytes(&newer).is_err());
+    }
+
+    #[test]
+    fn resampling_keeps_extremes_and_rms() {
+        let waveform = Waveform {
+            sample_rate: 44100,
+            frames_per_point: 256,
+            points: vec![
+                point(-0.1, 0.2, 0.1),
+                point(-0.8, 0.3, 0.5),
+                point(-0.2, 0.9, 0.4),
+                point(-0.3, 0.1, 0.2),
+                point(-0.05, 0.05, 0.03),
+            ],
+        };
+
+        let resampled = waveform.resample(2);
+        assert_eq!(resampled.frames_per_point, 640);
+        let [first, second] = resampled.points[..] else {
+            panic!("{:?}", resampled.points);
+        };
+        assert_eq!((first.min, first.max), (-0.8, 0.3));
+        assert!((first.rms - ((0.01 + 0.25) / 2f32).sqrt()).abs() < 1e-6);
+        assert_eq!((second.min, second.max), (-0.3, 0.9));
+        assert!((second.rms - ((0.16 + 0.04 + 0.0009) / 3f32).sqrt()).abs() < 1e-6);
+
+        assert_eq!(waveform.resample(10).points.len(), 5);
+    }
+
+    #[test]
+    fn resampling_matches_building_with_longer_points() {
+        let samples: Vec<Sample> = (0..4096 * 2)
+            .map(|i| (i as f32 * 0.013).sin() * (i as f32 * 0.0007).cos())
+            .collect();
+
+        let mut builder = WaveformBuilder::new(44100, 2);
+        builder.add(&samples);
+        let resampled = builder.finish().resample(8);
+
+        let mut builder = WaveformBuilder::new(44100, 2);
+        builder.frames_per_point = 512;
+        builder.add(&samples);
+        let built = builder.finish();
+
+        assert_eq!(resampled.frames_per_point, built.frames_per_point);
+        for (a, b) in resampled.points.iter().zip(built.points.iter()) {
+            assert_eq!((a.min, a.max), (b.min, b.max));
+            assert!((a.rms - b.rms).abs() < 1e-5, "{} and {}", a.rms, b.rms);
+        }
+    }
+
+    #[test]
+    fn builder_keeps_the_last_partial_point() {
+        let frames = WaveformBuilder::FRAMES_PER_POINT;
+        let mut builder = WaveformBuilder::new(8000, 2);
+        // a full point of quiet, then a short one of louder, handed over in
+        // pieces that split frames between calls
+        let mut samples = vec![0.1; frames * 2];
+        samples.extend([0.5, -0.5].repeat(10));
+        for piece in samples.chunks(7) {
+            builder.add(piece);
+        }
+        let waveform = builder.finish();
+
+        assert_eq!(waveform.points.len(), 2);
+        let full = waveform.points[0];
+        assert_eq!((full.min, full.max), (0.0, 0.1));
+        assert!((full.rms - 0.1).abs() < 1e-6);
+
+        // the partial point's rms is over its own ten frames alone
+        let partial = waveform.points[1];
+        assert_eq!((partial.min, partial.max), (-0.5, 0.5));
+        assert!((partial.rms - 0.5).abs() < 1e-6, "{}", partial.rms);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::core::{
+    clock::PlaybackClock,
+    drain::{Drain, DrainMetrics, DrainStats},
+    pipeline::{Sample, TransformCtx},
+};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize, Device, OutputCallbackInfo, SampleFormat, SampleRate, StreamConfig,
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Receiver,
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+/// Plays the pipeline output on a local sound device through cpal (ALSA on
+/// Linux).
+pub struct DeviceDrain {
+    device_name: Option<String>,
+    buffer: Duration,
+    metrics: Arc<DrainMetrics>,
+    // only there while draining, for purge to clear
+    queue: Mutex<Option<Arc<OutputQueue>>>,
+}
+
+/// Samples waiting for the output callback, shared between it and the
+/// drain thread.
+struct OutputQueue {
+    samples: Mutex<VecDeque<Sample>>,
+    changed: Condvar,
+    flowing: AtomicBool,
+}
+
+impl DeviceDrain {
+    const CHUNK: usize = 1024;
+
+    /// Uses the named output device, or the host default when `None`.
+    /// `buffer` is how much audio is queued ahead of the device.
+    pub fn new(device_name: Option<String>, buffer: Duration) -> Self {
+        Self {
+            device_name,
+            buffer,
+            metrics: Arc::new(DrainMetrics::new()),
+            queue: Mutex::new(None),
+        }
+    }
+
+    fn find_device(&self) -> Result<Device, String> {
+        let host = cpal::default_host();
+        let name = match &self.device_name {
+            Some(name) => name,
+            None => {
+                return host
+                    .default_output_device()
+                    .ok_or("no default output device".to_string())
+            }
+        };
+
+        let mut devices = match host.output_devices() {
+            Ok(devices) => devices,
+            Err(e) => return Err(format!("error listing output devices: {e}")),
+        };
+        return match devices.find(|device| device.name().is_ok_and(|n| n == *name)) {
+            Some(device) => Ok(device),
+            None => Err(format!("output device not found: {name}")),
+        };
+    }
+
+    fn stream_config(ctx: &TransformCtx) -> StreamConfig {
+        StreamConfig {
+            channels: ctx.channels,
+            sample_rate: SampleRate(ctx.sample_rate),
+            buffer_size: BufferSize::Default,
+        }
+    }
+}
+
+impl Drain for DeviceDrain {
+    fn init(&self, ctx: &TransformCtx) -> Result<(), String> {
+        let device = self.find_device()?;
+        let mut configs = match device.supported_output_configs() {
+            Ok(configs) => configs,
+            Err(e) => return Err(format!("error querying output configs: {e}")),
+        };
+
+        let rate = SampleRate(ctx.sample_rate);
+        let supported = configs.any(|config| {
+            config.channels() == ctx.channels
+                && config.sample_format() == SampleFormat::F32
+                && config.min_sample_rate() <= rate
+                && rate <= config.max_sample_rate()
+        });
+        if !supported {
+            return Err(format!(
+                "output device does not support {} channels of f32 at {} Hz",
+                ctx.channels, ctx.sample_rate
+            ));
+        }
+
+        self.metrics.reset();
+        Ok(())
+    }
+
+    fn drain(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        clock: Arc<PlaybackClock>,
+    ) -> Result<(), String> {
+        let device = self.find_device()?;
+        let config = Self::stream_config(ctx);
+        let channels = ctx.channels.max(1) as usize;
+        let samples_per_sec = ctx.sample_rate as f64 * channels as f64;
+        let capacity = ((self.buffer.as_secs_f64() * samples_per_sec) as usize).max(Self::CHUNK);
+
+        let queue = Arc::new(OutputQueue {
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+            changed: Condvar::new(),
+            flowing: AtomicBool::new(false),
+        });
+
+        *self.queue.lock().unwrap() = Some(queue.clone());
+        let callback_queue = queue.clone();
+        let metrics = self.metrics.clone();
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [Sample], info: &OutputCallbackInfo| {
+                let mut samples = callback_queue.samples.lock().unwrap();
+                let available = samples.len().min(data.len());
+                for (dst, sample) in data.iter_mut().zip(samples.drain(..available)) {
+                    *dst = sample;
+                }
+                if available < data.len() {
+                    data[available..].fill(0.0);
+                    if callback_queue.flowing.load(Ordering::Relaxed) && !clock.is_paused() {
+                        metrics.add_underrun();
+                    }
+                }
+                metrics.add_frames((available / channels) as u64);
+                clock.advance((available / channels) as u64);
+
+                let timestamp = info.timestamp();
+                let device_latency = timestamp
+                    .playback
+                    .duration_since(&timestamp.callback)
+                    .unwrap_or_default();
+                let queued = Duration::from_secs_f64(samples.len() as f64 / samples_per_sec);
+                metrics.set_latency(device_latency + queued);
+
+                drop(samples);
+                callback_queue.changed.notify_all();
+            },
+            |err| eprintln!("output stream error: {err}"),
+            None,
+        );
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => return Err(format!("error building output stream: {e}")),
+        };
+        if let Err(e) = stream.play() {
+            return Err(format!("error starting output stream: {e}"));
+        }
+
+        let mut chunk = Vec::with_capacity(Self::CHUNK);
+        while let Ok(sample) = from.recv() {
+            chunk.push(sample);
+            chunk.extend(from.try_iter().take(Self::CHUNK - 1));
+
+            let mut samples = queue.samples.lock().unwrap();
+            while samples.len() + chunk.len() > capacity {
+                samples = queue.changed.wait(samples).unwrap();
+            }
+            samples.extend(chunk.drain(..));
+
+            // running dry only counts as an underrun once the buffer has
+            // been primed, otherwise startup would always show up as one
+            if samples.len() >= capacity / 2 {
+                queue.flowing.store(true, Ordering::Relaxed);
+            }
+        }
+
+        // input is done; play out what's queued without counting the
+        // final partial buffer as an underrun
+        queue.flowing.store(false, Ordering::Relaxed);
+        let mut samples = queue.samples.lock().unwrap();
+        while !samples.is_empty() {
+            let (next, timeout) = queue
+                .changed
+                .wait_timeout(samples, self.buffer + Duration::from_secs(1))
+                .unwrap();
+            if timeout.timed_out() {
+                return Err("output device stopped consuming samples".to_string());
+            }
+            samples = next;
+        }
+        drop(samples);
+        drop(stream);
+        *self.queue.lock().unwrap() = None;
+
+        Ok(())
+    }
+
+    fn purge(&self) -> Result<(), String> {
+        if let Some(queue) = self.queue.lock().unwrap().as_ref() {
+            queue.samples.lock().unwrap().clear();
+            // refilling from empty isn't an underrun
+            queue.flowing.store(false, Ordering::Relaxed);
+            queue.changed.notify_all();
+        }
+
+        Ok(())
+    }
+
+    fn stats(&self) -> DrainStats {
+        self.metrics.snapshot()
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    core::fingerprint::Fingerprint,
+    library::{Library, LibraryError, Track},
+};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Library tracks that sound like the same recording.
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateGroup {
+    /// Of the least alike pair that put the group together.
+    pub similarity: f32,
+    pub tracks: Vec<Track>,
+}
+
+/// What new audio turned out to be a duplicate of.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DuplicateOf {
+    Track {
+        track: Box<Track>,
+    },
+    /// Audio stored through the provider, by its id there.
+    Audio {
+        id: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateMatch {
+    #[serde(flatten)]
+    pub of: DuplicateOf,
+    pub similarity: f32,
+}
+
+/// Whether two lengths could be the same recording, give or take encoder
+/// padding and trimmed silence. Unknown lengths could be anything.
+fn close_durations(a: Option<u64>, b: Option<u64>) -> bool {
+    return match (a, b) {
+        (Some(a), Some(b)) => a.abs_diff(b) <= (a.max(b) / 20).max(3000),
+        _ => true,
+    };
+}
+
+/// Groups of library tracks at least `threshold` alike. Only tracks of
+/// about the same length are compared, which keeps this well short of
+/// comparing every pair in a big library.
+pub fn groups(library: &Library, threshold: f32) -> Result<Vec<DuplicateGroup>, LibraryError> {
+    let mut entries: Vec<(Option<u64>, u64, Fingerprint)> = library
+        .fingerprints()?
+        .into_iter()
+        .filter_map(|(id, fingerprint)| {
+            let track = library.get_track(id).ok()?;
+            Some((track.duration_ms, id, fingerprint))
+        })
+        .collect();
+    // unknown lengths first, they're compared with everything
+    entries.sort_by_key(|(duration, id, _)| (*duration, *id));
+
+    let mut parents: Vec<usize> = (0..entries.len()).collect();
+    let mut similarities: HashMap<usize, f32> = HashMap::new();
+    for i in 0..entries.len() {
+        for j in i + 1..entries.len() {
+            let (a, b) = (&entries[i], &entries[j]);
+            // sorted, so everything after is longer still
+            if !close_durations(a.0, b.0) {
+                break;
+            }
+
+            let similarity = a.2.similarity(&b.2);
+            if similarity < threshold {
+                continue;
+            }
+            let (a_root, b_root) = (root(&mut parents, i), root(&mut parents, j));
+            let lowest = [
+                similarity,
+                similarities.remove(&a_root).unwrap_or(1.0),
+                similarities.remove(&b_root).unwrap_or(1.0),
+            ]
+            .into_iter()
+            .fold(1.0, f32::min);
+            parents[b_root] = a_root;
+            similarities.insert(a_root, lowest);
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<Track>> = HashMap::new();
+    for (i, (_, id, _)) in entries.iter().enumerate() {
+        let root = root(&mut parents, i);
+        if let Ok(track) = library.get_track(*id) {
+            members.entry(root).or_default().push((*track).clone());
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = members
+        .into_iter()
+        .filter(|(_, tracks)| tracks.len() > 1)
+        .map(|(root, mut tracks)| {
+            tracks.sort_by(|a, b| a.path.cmp(&b.path));
+            DuplicateGroup {
+                similarity: similarities.get(&root).copied().unwrap_or(1.0),
+                tracks,
+            }
+        })
+        .collect();
+    groups.sort_by(|a, b| {
+        b.similarity
+            .total_cmp(&a.similarity)
+            .then_with(|| a.tracks[0].path.cmp(&b.tracks[0].path))
+    });
+
+    Ok(groups)
+}
+
+fn root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// Library tracks and stored audio at least `threshold` like `fingerprint`,
+/// most alike first.
+pub fn matches(
+    fingerprint: &Fingerprint,
+    duration_ms: Option<u64>,
+    library: &Library,
+    stored: &[(String, Fingerprint)],
+    threshold: f32,
+) -> Result<Vec<DuplicateMatch>, LibraryError> {
+    let mut matches = Vec::new();
+    for (id, other) in library.fingerprints()? {
+        let track = match library.get_track(id) {
+            Ok(track) if close_durations(duration_ms, track.duration_ms) => track,
+            _ => continue,
+        };
+        let similarity = fingerprint.similarity(&other);
+        if similarity >= threshold {
+            matches.push(DuplicateMatch {
+                of: DuplicateOf::Track {
+                    track: Box::new((*track).clone()),
+                },
+                similarity,
+            });
+        }
+    }
+    for (id, other) in stored {
+        let similarity = fingerprint.similarity(other);
+        if similarity >= threshold {
+            matches.push(DuplicateMatch {
+                of: DuplicateOf::Audio { id: id.clone() },
+                similarity,
+            });
+        }
+    }
+
+    matches.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::LibraryBatch;
+
+    /// `words` with the bits in `flipped` flipped in every word, so
+    /// fingerprints can be made exactly so alike.
+    fn fingerprint(words: &[u32], flipped: std::ops::Range<u32>) -> Fingerprint {
+        let mask = flipped.fold(0u32, |mask, bit| mask | 1 << bit);
+        let bytes: Vec<u8> = words
+            .iter()
+            .flat_map(|word| (word ^ mask).to_le_bytes())
+            .collect();
+        Fingerprint::from_bytes(&bytes).unwrap()
+    }
+
+    fn words() -> Vec<u32> {
+        let mut state = 0x2545_f491u32;
+        (0..200)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    fn library(tracks: &[(u64, u64, Fingerprint)]) -> Library {
+        let mut library = Library::open_in_memory().unwrap();
+        library
+            .apply(LibraryBatch {
+                tracks: tracks
+                    .iter()
+                    .map(|(id, duration_ms, _)| Track {
+                        id: *id,
+                        title: format!("{id}"),
+                        duration_ms: Some(*duration_ms),
+                        path: format!("/music/{id}.flac").into(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                fingerprints: tracks
+                    .iter()
+                    .map(|(id, _, fingerprint)| (*id, fingerprint.clone()))
+                    .collect(),
+                ..Default::default()
+            })
+            .unwrap();
+        library
+    }
+
+    #[test]
+    fn groups_are_transitive() {
+        let words = words();
+        // a and c are only 62.5% alike, but both are 81.25% like b
+        let a = fingerprint(&words, 0..0);
+        let b = fingerprint(&words, 0..6);
+        let c = fingerprint(&words, 0..12);
+        assert!(a.similarity(&c) < Fingerprint::DUPLICATE_SIMILARITY);
+        let library = library(&[(1, 200_000, a), (2, 201_000, b), (3, 199_000, c)]);
+
+        let groups = groups(&library, Fingerprint::DUPLICATE_SIMILARITY).unwrap();
+        assert_eq!(groups.len(), 1);
+        let mut ids: Vec<u64> = groups[0].tracks.iter().map(|track| track.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(groups[0].similarity, 1.0 - 6.0 / 32.0);
+    }
+
+    #[test]
+    fn different_lengths_are_not_compared() {
+        let words = words();
+        let library = library(&[
+            (1, 200_000, fingerprint(&words, 0..0)),
+            (2, 400_000, fingerprint(&words, 0..0)),
+        ]);
+
+        assert!(groups(&library, Fingerprint::DUPLICATE_SIMILARITY)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn matches_leave_out_other_lengths() {
+        let words = words();
+        let library = library(&[
+            (1, 200_000, fingerprint(&words, 0..0)),
+            (2, 400_000, fingerprint(&words, 0..0)),
+        ]);
+        let stored = vec![("upload".to_string(), fingerprint(&words, 0..2))];
+
+        let matches = matches(
+            &fingerprint(&words, 0..0),
+            Some(201_000),
+            &library,
+            &stored,
+            Fingerprint::DUPLICATE_SIMILARITY,
+        )
+        .unwrap();
+        assert_eq!(matches.len(), 2);
+        assert!(matches!(&matches[0].of, DuplicateOf::Track { track } if track.id == 1));
+        assert!(matches!(&matches[1].of, DuplicateOf::Audio { id } if id == "upload"));
+    }
+}
@@ -1,61 +1,553 @@
-use crate::core::{audio::AudioReader, provider::{ReadableProvider, ProviderError, WriteableProvider}};
-use std::{
-    fs::{File, self},
-    path::PathBuf,
+use crate::core::{
+    audio::AudioReader,
+    fingerprint::{Fingerprint, FingerprintBuilder},
+    loudness::{LoudnessAnalysis, LoudnessAnalyzer},
+    playlist::Playlist,
+    provider::{ProviderError, ReadableProvider, WriteableProvider},
+    waveform::{Waveform, WaveformBuilder},
 };
 use hound::{WavSpec, WavWriter};
-use symphonia::{core::{
-    io::MediaSourceStream, meta::MetadataOptions, formats::FormatOptions, probe::Hint, audio::SampleBuffer,
-}, default::{get_probe, get_codecs}};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use symphonia::core::audio::SampleBuffer;
 
+/// Audio is stored by the hash of its contents, in `audio/blobs`, with an
+/// index from ids to hashes. Identical audio under two ids is stored once,
+/// and only goes once neither id refers to it.
 pub struct FsAudioProvider {
     path: PathBuf,
+    // id to content hash, held while the blobs and the index are changed so
+    // reference counts can't go stale under a concurrent set or delete
+    index: Mutex<HashMap<String, String>>,
+    temp_count: AtomicU64,
+}
+
+/// What `FsAudioProvider::check` found wrong with stored audio.
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    pub checked: usize,
+    /// Hashes of blobs whose contents no longer hash to their name.
+    pub corrupt: Vec<String>,
+    /// Ids in the index whose blob is gone.
+    pub missing: Vec<String>,
+    /// Hashes of blobs no id refers to.
+    pub orphaned: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty() && self.missing.is_empty() && self.orphaned.is_empty()
+    }
 }
 
 impl FsAudioProvider {
     const AUDIO_DIR: &str = "audio";
+    const BLOB_DIR: &str = "blobs";
+    const AUDIO_INDEX_FILE: &str = "index.json";
+    const PLAYLIST_DIR: &str = "playlists";
+    const TRACK_WAVEFORM_DIR: &str = "waveforms";
+    const SESSION_FILE: &str = "session.json";
+    const PIPELINE_CONFIG_FILE: &str = "pipeline.toml";
+    const LIBRARY_CONFIG_FILE: &str = "library.toml";
+    const LIBRARY_DB_FILE: &str = "library.db";
+    const SPOTIFY_CONFIG_FILE: &str = "spotify.toml";
+    const SCROBBLE_LOG_FILE: &str = "scrobbles.jsonl";
 
     pub fn new(path: &str) -> Self {
         Self {
             path: PathBuf::from(path),
+            index: Mutex::new(HashMap::new()),
+            temp_count: AtomicU64::new(0),
         }
     }
 
+    /// Creates what's missing, loads the index and tidies up after a crash
+    /// or an older version, ready to store audio.
     pub fn init(&mut self) -> Result<(), String> {
-        if let Err(e) = fs::create_dir_all(self.path.join(Self::AUDIO_DIR)) {
+        if let Err(e) = fs::create_dir_all(self.blob_dir()) {
             return Err(format!("error creating audio dir: {e}"));
         }
+        if let Err(e) = fs::create_dir_all(self.path.join(Self::PLAYLIST_DIR)) {
+            return Err(format!("error creating playlist dir: {e}"));
+        }
+        if let Err(e) = fs::create_dir_all(self.path.join(Self::TRACK_WAVEFORM_DIR)) {
+            return Err(format!("error creating waveform dir: {e}"));
+        }
+        self.load_index()?;
+
+        // whatever a crash left half written
+        let entries = match fs::read_dir(self.blob_dir()) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("error reading blob dir: {e}")),
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+
+        self.migrate_audio()
+    }
+
+    /// Loads the index without changing anything on disk, which is all
+    /// reading audio needs, e.g. to `check` it.
+    pub fn load_index(&mut self) -> Result<(), String> {
+        let index = match fs::read(self.audio_index_path()) {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(index) => index,
+                Err(e) => return Err(format!("error parsing audio index: {e}")),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("error reading audio index: {e}")),
+        };
+        *self.index.get_mut().unwrap() = index;
+
+        Ok(())
+    }
+
+    /// Moves audio stored as `{id}.wav`, from before it was stored by
+    /// hash, into blobs.
+    ///
+    /// Each id goes into the index before its audio moves, so stopping
+    /// partway through never leaves a blob nothing points to. The legacy
+    /// file is still there for the next `init` to pick up again.
+    fn migrate_audio(&mut self) -> Result<(), String> {
+        let entries = match fs::read_dir(self.path.join(Self::AUDIO_DIR)) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("error reading audio dir: {e}")),
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "wav") {
+                continue;
+            }
+            let id = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let hash = match hash_file(&path) {
+                Ok(hash) => hash,
+                Err(e) => return Err(format!("error hashing {}: {e}", path.display())),
+            };
+
+            let index = self.index.get_mut().unwrap();
+            if index.get(&id) != Some(&hash) {
+                index.insert(id.clone(), hash.clone());
+                if let Err(e) = self.write_index(&self.index.lock().unwrap()) {
+                    return Err(format!("error writing audio index: {e:?}"));
+                }
+            }
+
+            let legacy_path = |ext| path.with_file_name(format!("{id}.{ext}"));
+            let moves = [
+                (legacy_path("waveform"), self.waveform_path(&hash)),
+                (legacy_path("fingerprint"), self.fingerprint_path(&hash)),
+                (legacy_path("loudness.json"), self.loudness_path(&hash)),
+                (path.clone(), self.blob_path(&hash)),
+            ];
+            for (old_path, new_path) in moves {
+                let result = match new_path.exists() {
+                    true => fs::remove_file(&old_path),
+                    false => fs::rename(&old_path, &new_path),
+                };
+                match result {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == ErrorKind::NotFound => (),
+                    Err(e) => return Err(format!("error moving {}: {e}", old_path.display())),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn session_path(&self) -> PathBuf {
+        self.path.join(Self::SESSION_FILE)
+    }
+
+    pub fn pipeline_config_path(&self) -> PathBuf {
+        self.path.join(Self::PIPELINE_CONFIG_FILE)
+    }
+
+    pub fn library_config_path(&self) -> PathBuf {
+        self.path.join(Self::LIBRARY_CONFIG_FILE)
+    }
+
+    pub fn spotify_config_path(&self) -> PathBuf {
+        self.path.join(Self::SPOTIFY_CONFIG_FILE)
+    }
+
+    pub fn library_db_path(&self) -> PathBuf {
+        self.path.join(Self::LIBRARY_DB_FILE)
+    }
+
+    /// Where the local scrobbler writes, see `LocalScrobbler`.
+    pub fn scrobble_log_path(&self) -> PathBuf {
+        self.path.join(Self::SCROBBLE_LOG_FILE)
+    }
+
+    fn blob_dir(&self) -> PathBuf {
+        self.path.join(Self::AUDIO_DIR).join(Self::BLOB_DIR)
+    }
+
+    fn audio_index_path(&self) -> PathBuf {
+        self.path.join(Self::AUDIO_DIR).join(Self::AUDIO_INDEX_FILE)
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.blob_dir().join(format!("{hash}.wav"))
+    }
+
+    fn loudness_path(&self, hash: &str) -> PathBuf {
+        self.blob_dir().join(format!("{hash}.loudness.json"))
+    }
+
+    fn waveform_path(&self, hash: &str) -> PathBuf {
+        self.blob_dir().join(format!("{hash}.waveform"))
+    }
+
+    fn track_waveform_path(&self, id: u64) -> PathBuf {
+        self.path
+            .join(Self::TRACK_WAVEFORM_DIR)
+            .join(format!("{id:016x}.waveform"))
+    }
+
+    fn fingerprint_path(&self, hash: &str) -> PathBuf {
+        self.blob_dir().join(format!("{hash}.fingerprint"))
+    }
+
+    /// Somewhere in the blob dir to write to before renaming into place, on
+    /// the same filesystem as everything else stored and where `init` clears
+    /// out what a crash leaves behind.
+    fn temp_path(&self) -> PathBuf {
+        let count = self.temp_count.fetch_add(1, Ordering::Relaxed);
+        self.blob_dir()
+            .join(format!("{}-{count}.tmp", process::id()))
+    }
+
+    /// Writes by way of a temp file renamed into place, so a crash
+    /// mid-write can't leave anything truncated.
+    fn write_atomic(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let temp_path = self.temp_path();
+        if let Err(e) = fs::write(&temp_path, contents) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        fs::rename(&temp_path, path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp_path);
+        })
+    }
+
+    fn write_index(&self, index: &HashMap<String, String>) -> Result<(), ProviderError> {
+        let contents = match serde_json::to_vec_pretty(index) {
+            Ok(contents) => contents,
+            Err(_) => return Err(ProviderError::Other("error serializing audio index")),
+        };
+        return match self.write_atomic(&self.audio_index_path(), &contents) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ProviderError::Other("error writing audio index")),
+        };
+    }
+
+    fn hash_of(&self, id: &str) -> Result<String, ProviderError> {
+        return match self.index.lock().unwrap().get(id) {
+            Some(hash) => Ok(hash.clone()),
+            None => Err(ProviderError::NotFound),
+        };
+    }
+
+    /// Whether there's audio stored under `id`.
+    pub fn contains_audio(&self, id: &str) -> bool {
+        self.index.lock().unwrap().contains_key(id)
+    }
+
+    /// Removes the blob and what was worked out from it once nothing refers
+    /// to it any more. Called with the index held.
+    fn release(&self, index: &HashMap<String, String>, hash: &str) -> Result<(), ProviderError> {
+        if index.values().any(|other| other == hash) {
+            return Ok(());
+        }
+
+        for path in [
+            self.blob_path(hash),
+            self.waveform_path(hash),
+            self.fingerprint_path(hash),
+            self.loudness_path(hash),
+        ] {
+            match fs::remove_file(path) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(_) => return Err(ProviderError::Other("error deleting audio file")),
+            }
+        }
 
         Ok(())
     }
+
+    /// Stops `id` referring to its audio, which is deleted if nothing else
+    /// refers to the same.
+    pub fn delete_audio(&self, id: &str) -> Result<(), ProviderError> {
+        let mut index = self.index.lock().unwrap();
+        let hash = match index.remove(id) {
+            Some(hash) => hash,
+            None => return Err(ProviderError::NotFound),
+        };
+        // the index first, a blob left behind is only wasted space
+        if let Err(e) = self.write_index(&index) {
+            index.insert(id.to_string(), hash);
+            return Err(e);
+        }
+
+        self.release(&index, &hash)
+    }
+
+    /// Re-hashes every blob, and cross checks them with the index.
+    pub fn check(&self) -> Result<IntegrityReport, ProviderError> {
+        let index = self.index.lock().unwrap();
+        let entries = match fs::read_dir(self.blob_dir()) {
+            Ok(entries) => entries,
+            Err(_) => return Err(ProviderError::Other("error reading blob dir")),
+        };
+
+        let mut report = IntegrityReport::default();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "wav") {
+                continue;
+            }
+            let hash = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            report.checked += 1;
+            match hash_file(&path) {
+                Ok(actual) if actual == hash => (),
+                Ok(_) => report.corrupt.push(hash.clone()),
+                Err(_) => return Err(ProviderError::Other("error reading blob")),
+            }
+            if !index.values().any(|other| *other == hash) {
+                report.orphaned.push(hash);
+            }
+        }
+        for (id, hash) in index.iter() {
+            if !self.blob_path(hash).exists() {
+                report.missing.push(id.clone());
+            }
+        }
+
+        report.corrupt.sort();
+        report.missing.sort();
+        report.orphaned.sort();
+        Ok(report)
+    }
+
+    /// The stored audio as it is on disk, a 32 bit float wav.
+    pub fn audio_bytes(&self, id: &str) -> Result<Vec<u8>, ProviderError> {
+        return match fs::read(self.blob_path(&self.hash_of(id)?)) {
+            Ok(contents) => Ok(contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(ProviderError::NotFound),
+            Err(_) => Err(ProviderError::Other("error reading audio file")),
+        };
+    }
+
+    /// The loudness measured when the audio was stored, if it was.
+    pub fn loudness(&self, id: &str) -> Result<Option<LoudnessAnalysis>, ProviderError> {
+        let hash = match self.hash_of(id) {
+            Ok(hash) => hash,
+            Err(ProviderError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let contents = match fs::read(self.loudness_path(&hash)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(_) => return Err(ProviderError::Other("error reading loudness file")),
+        };
+
+        return match serde_json::from_slice(&contents) {
+            Ok(analysis) => Ok(Some(analysis)),
+            Err(_) => Err(ProviderError::Other("error parsing loudness file")),
+        };
+    }
+
+    /// The peak data cached when the audio was stored. Audio stored before
+    /// there was a cache has it worked out and cached now instead.
+    pub fn waveform(&self, id: &str) -> Result<Waveform, ProviderError> {
+        let hash = self.hash_of(id)?;
+        match fs::read(self.waveform_path(&hash)) {
+            Ok(contents) => {
+                return match Waveform::from_bytes(&contents) {
+                    Ok(waveform) => Ok(waveform),
+                    Err(_) => Err(ProviderError::Other("error parsing waveform file")),
+                };
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(_) => return Err(ProviderError::Other("error reading waveform file")),
+        }
+
+        let waveform = match WaveformBuilder::read(&mut self.get(id)?) {
+            Ok(waveform) => waveform,
+            Err(_) => return Err(ProviderError::Other("error generating waveform")),
+        };
+        return match self.write_atomic(&self.waveform_path(&hash), &waveform.to_bytes()) {
+            Ok(_) => Ok(waveform),
+            Err(_) => Err(ProviderError::Other("error writing waveform file")),
+        };
+    }
+
+    /// The peak data for a library track, worked out from its file the first
+    /// time it's asked for and again whenever the file changes after that.
+    pub fn track_waveform(&self, id: u64, path: &Path) -> Result<Waveform, ProviderError> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let track_modified = match modified(path) {
+            Some(modified) => modified,
+            None => return Err(ProviderError::NotFound),
+        };
+
+        let cache_path = self.track_waveform_path(id);
+        if modified(&cache_path).is_some_and(|cached| cached >= track_modified) {
+            // anything unreadable is worked out again and replaced
+            if let Ok(Ok(waveform)) = fs::read(&cache_path).map(|c| Waveform::from_bytes(&c)) {
+                return Ok(waveform);
+            }
+        }
+
+        let mut reader = match AudioReader::open(path) {
+            Ok(reader) => reader,
+            Err(_) => return Err(ProviderError::Other("error opening track")),
+        };
+        let waveform = match WaveformBuilder::read(&mut reader) {
+            Ok(waveform) => waveform,
+            Err(_) => return Err(ProviderError::Other("error generating waveform")),
+        };
+        return match self.write_atomic(&cache_path, &waveform.to_bytes()) {
+            Ok(_) => Ok(waveform),
+            Err(_) => Err(ProviderError::Other("error writing waveform file")),
+        };
+    }
+
+    /// The fingerprints of all the stored audio, by id.
+    pub fn fingerprints(&self) -> Result<Vec<(String, Fingerprint)>, ProviderError> {
+        let index = self.index.lock().unwrap().clone();
+
+        let mut fingerprints = Vec::new();
+        for (id, hash) in index {
+            let contents = match fs::read(self.fingerprint_path(&hash)) {
+                Ok(contents) => contents,
+                // stored before there were fingerprints
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(_) => return Err(ProviderError::Other("error reading fingerprint file")),
+            };
+            let fingerprint = match Fingerprint::from_bytes(&contents) {
+                Ok(fingerprint) => fingerprint,
+                Err(_) => return Err(ProviderError::Other("error parsing fingerprint file")),
+            };
+            fingerprints.push((id, fingerprint));
+        }
+
+        Ok(fingerprints)
+    }
+
+    fn playlist_path(&self, id: &str) -> PathBuf {
+        self.path
+            .join(Self::PLAYLIST_DIR)
+            .join(format!("{id}.json"))
+    }
+
+    pub fn playlists(&self) -> Result<Vec<Playlist>, ProviderError> {
+        let entries = match fs::read_dir(self.path.join(Self::PLAYLIST_DIR)) {
+            Ok(entries) => entries,
+            Err(_) => return Err(ProviderError::Other("error reading playlist dir")),
+        };
+
+        let mut playlists = Vec::new();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let id = path.file_stem().unwrap_or_default().to_string_lossy();
+            playlists.push(self.get(&id)?);
+        }
+
+        Ok(playlists)
+    }
+
+    pub fn delete_playlist(&self, id: &str) -> Result<(), ProviderError> {
+        return match fs::remove_file(self.playlist_path(id)) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(ProviderError::NotFound),
+            Err(_) => Err(ProviderError::Other("error deleting playlist file")),
+        };
+    }
 }
 
-impl ReadableProvider<AudioReader> for FsAudioProvider {
-    fn get(&self, id: &str) -> Result<AudioReader, ProviderError> {
-        let source_path = self.path.join(Self::AUDIO_DIR).join(format!("{id}.wav"));
-        let source_file = match File::open(source_path) {
-            Ok(file) => file,
-            Err(e) => return Err(ProviderError::Other("error reading source file")),
+impl ReadableProvider<Playlist> for FsAudioProvider {
+    fn get(&self, id: &str) -> Result<Playlist, ProviderError> {
+        let contents = match fs::read(self.playlist_path(id)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(ProviderError::NotFound),
+            Err(_) => return Err(ProviderError::Other("error reading playlist file")),
+        };
+
+        return match serde_json::from_slice(&contents) {
+            Ok(playlist) => Ok(playlist),
+            Err(_) => Err(ProviderError::Other("error parsing playlist file")),
+        };
+    }
+}
+
+impl WriteableProvider<Playlist> for FsAudioProvider {
+    fn set(&self, id: &str, playlist: Playlist) -> Result<(), ProviderError> {
+        let contents = match serde_json::to_vec(&playlist) {
+            Ok(contents) => contents,
+            Err(_) => return Err(ProviderError::Other("error serializing playlist")),
         };
 
-        let media = MediaSourceStream::new(Box::new(source_file), Default::default());
-        let meta_opts: MetadataOptions = Default::default();
-        let fmt_opts: FormatOptions = Default::default();
-        let hint = Hint::new();
-        let format_reader =  match get_probe().format(&hint, media, &fmt_opts, &meta_opts) {
-            Err(e) => return Err(ProviderError::Other("error formatting audio")),
-            Ok(result) => result.format
+        return match self.write_atomic(&self.playlist_path(id), &contents) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ProviderError::Other("error writing playlist file")),
         };
+    }
+}
 
-        return match AudioReader::new(format_reader, get_codecs(), &Default::default()) {
+impl ReadableProvider<AudioReader> for FsAudioProvider {
+    fn get(&self, id: &str) -> Result<AudioReader, ProviderError> {
+        let source_path = self.blob_path(&self.hash_of(id)?);
+        return match AudioReader::open(&source_path) {
             Ok(reader) => Ok(reader),
-            Err(_) => Err(ProviderError::Other("error constructing reader")),
+            Err(_) => Err(ProviderError::Other("error opening source file")),
         };
     }
 }
 
 impl WriteableProvider<AudioReader> for FsAudioProvider {
-    fn set(&self, id: &str, mut audio: AudioReader) -> Result<(), ProviderError> {
+    /// Stores the audio under `id`, in place of whatever was there before.
+    fn set(&self, id: &str, audio: AudioReader) -> Result<(), ProviderError> {
+        self.set_checked(id, audio, |_| Ok(()))
+    }
+}
+
+impl FsAudioProvider {
+    /// Like `set`, but `accept` gets to look at the audio's fingerprint once
+    /// it's decoded, and can turn it away before anything is stored.
+    pub fn set_checked<E, F>(&self, id: &str, mut audio: AudioReader, accept: F) -> Result<(), E>
+    where
+        E: From<ProviderError>,
+        F: FnOnce(&Fingerprint) -> Result<(), E>,
+    {
         let signal_spec = audio.signal_spec();
         let wav_spec = WavSpec {
             channels: signal_spec.channels.count() as u16,
@@ -64,36 +556,358 @@ impl WriteableProvider<AudioReader> for FsAudioProvider {
             sample_format: hound::SampleFormat::Float,
         };
 
-        let file_path = self.path.join(Self::AUDIO_DIR).join(format!("{id}.wav"));
-        let mut writer = WavWriter::create(file_path, wav_spec).unwrap();
-        let mut sample_buffer = SampleBuffer::new(64 * 1024, signal_spec);
+        // written under a temp name, as what it's called isn't known until
+        // it's all been written and hashed
+        let temp_path = self.temp_path();
+        let result = self
+            .write_wav(&temp_path, wav_spec, &mut audio)
+            .map_err(E::from)
+            .and_then(|(analysis, waveform, fingerprint)| {
+                accept(&fingerprint)?;
+                let hash = match hash_file(&temp_path) {
+                    Ok(hash) => hash,
+                    Err(_) => return Err(ProviderError::Other("error hashing audio").into()),
+                };
+                self.store(id, &hash, &temp_path, analysis, waveform, fingerprint)
+                    .map_err(E::from)
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /// Decodes `audio` into a wav at `path`, working out the loudness,
+    /// waveform and fingerprint on the way.
+    fn write_wav(
+        &self,
+        path: &Path,
+        wav_spec: WavSpec,
+        audio: &mut AudioReader,
+    ) -> Result<(LoudnessAnalysis, Waveform, Fingerprint), ProviderError> {
+        let mut writer = match WavWriter::create(path, wav_spec) {
+            Ok(writer) => writer,
+            Err(_) => return Err(ProviderError::Other("error creating audio file")),
+        };
+        let mut sample_buffer = SampleBuffer::new(64 * 1024, audio.signal_spec());
+        let mut analyzer = LoudnessAnalyzer::new(wav_spec.sample_rate, wav_spec.channels);
+        let mut waveform = WaveformBuilder::new(wav_spec.sample_rate, wav_spec.channels);
+        let mut fingerprint = FingerprintBuilder::new(wav_spec.sample_rate, wav_spec.channels);
 
         loop {
             match audio.read_next_as_samples::<f32>(&mut sample_buffer) {
                 Ok(_) => (),
                 Err(e) if e == "EOF" => break,
-                Err(e) => return Err(ProviderError::Other("error reading samples")),
+                Err(_) => return Err(ProviderError::Other("error reading samples")),
             }
 
+            analyzer.add(sample_buffer.samples());
+            waveform.add(sample_buffer.samples());
+            fingerprint.add(sample_buffer.samples());
             for sample in sample_buffer.samples() {
-                if let Err(e) = writer.write_sample(*sample) {
+                if writer.write_sample(*sample).is_err() {
                     return Err(ProviderError::Other("error writing sample"));
                 }
             }
         }
 
-        return match writer.finalize() {
-            Ok(_) => Ok(()),
-            Err(_) => Err(ProviderError::Other("error closing file"))
+        if writer.finalize().is_err() {
+            return Err(ProviderError::Other("error closing file"));
+        }
+
+        // measured on the way in so it's ready before anything plays it
+        Ok((analyzer.finish(), waveform.finish(), fingerprint.finish()))
+    }
+
+    /// Moves the written wav into place as the blob for `hash`, unless
+    /// there already is one, and points `id` at it.
+    fn store(
+        &self,
+        id: &str,
+        hash: &str,
+        temp_path: &Path,
+        analysis: LoudnessAnalysis,
+        waveform: Waveform,
+        fingerprint: Fingerprint,
+    ) -> Result<(), ProviderError> {
+        let analysis = match serde_json::to_vec(&analysis) {
+            Ok(analysis) => analysis,
+            Err(_) => return Err(ProviderError::Other("error serializing loudness")),
         };
+
+        let mut index = self.index.lock().unwrap();
+        if self.blob_path(hash).exists() {
+            let _ = fs::remove_file(temp_path);
+        } else {
+            // alongside the blob before it, so the blob being there means
+            // they are too
+            let derived = [
+                (
+                    self.waveform_path(hash),
+                    waveform.to_bytes(),
+                    "error writing waveform file",
+                ),
+                (
+                    self.fingerprint_path(hash),
+                    fingerprint.to_bytes(),
+                    "error writing fingerprint file",
+                ),
+                (
+                    self.loudness_path(hash),
+                    analysis,
+                    "error writing loudness file",
+                ),
+            ];
+            for (path, contents, error) in derived {
+                if self.write_atomic(&path, &contents).is_err() {
+                    return Err(ProviderError::Other(error));
+                }
+            }
+            if fs::rename(temp_path, self.blob_path(hash)).is_err() {
+                return Err(ProviderError::Other("error moving audio file into place"));
+            }
+        }
+
+        let previous = index.insert(id.to_string(), hash.to_string());
+        if let Err(e) = self.write_index(&index) {
+            match previous {
+                Some(previous) => index.insert(id.to_string(), previous),
+                None => index.remove(id),
+            };
+            let _ = self.release(&index, hash);
+            return Err(e);
+        }
+
+        return match previous {
+            Some(previous) if previous != hash => self.release(&index, &previous),
+            _ => Ok(()),
+        };
+    }
+}
+
+/// Hex SHA-256 of a file's contents.
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::WavWriter;
+    use std::io::Cursor;
+
+    /// An empty provider in its own temp dir, ready to store audio.
+    fn provider(name: &str) -> FsAudioProvider {
+        let path = std::env::temp_dir().join(format!("fs-provider-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let mut provider = FsAudioProvider::new(path.to_str().unwrap());
+        provider.init().unwrap();
+        provider
+    }
+
+    /// A short 16 bit wav, a different tone for each `pitch`.
+    fn wav(pitch: u32) -> Vec<u8> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut bytes = Vec::new();
+        let mut writer = WavWriter::new(Cursor::new(&mut bytes), spec).unwrap();
+        for i in 0..4000 {
+            let phase = i as f32 * pitch as f32 * 100.0 / 8000.0;
+            writer
+                .write_sample(((phase * std::f32::consts::TAU).sin() * 8000.0) as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        bytes
+    }
+
+    fn set(provider: &FsAudioProvider, id: &str, pitch: u32) {
+        provider
+            .set(id, AudioReader::from_bytes(wav(pitch)).unwrap())
+            .unwrap();
+    }
+
+    fn blobs(provider: &FsAudioProvider) -> Vec<String> {
+        let mut blobs: Vec<String> = fs::read_dir(provider.blob_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wav"))
+            .map(|path| path.file_stem().unwrap().to_string_lossy().to_string())
+            .collect();
+        blobs.sort();
+        blobs
+    }
+
+    #[test]
+    fn same_audio_is_stored_once() {
+        let provider = provider("dedup");
+        set(&provider, "a", 1);
+        set(&provider, "b", 1);
+
+        let hash = provider.hash_of("a").unwrap();
+        assert_eq!(provider.hash_of("b").unwrap(), hash);
+        assert_eq!(blobs(&provider), vec![hash]);
+        let _ = fs::remove_dir_all(&provider.path);
+    }
+
+    #[test]
+    fn blob_goes_with_the_last_id() {
+        let provider = provider("refcount");
+        set(&provider, "a", 1);
+        set(&provider, "b", 1);
+        let hash = provider.hash_of("a").unwrap();
+
+        provider.delete_audio("a").unwrap();
+        assert!(provider.blob_path(&hash).exists());
+        assert!(ReadableProvider::<AudioReader>::get(&provider, "b").is_ok());
+
+        provider.delete_audio("b").unwrap();
+        assert!(blobs(&provider).is_empty());
+        assert!(!provider.fingerprint_path(&hash).exists());
+        assert!(!provider.loudness_path(&hash).exists());
+        assert!(matches!(
+            provider.delete_audio("b"),
+            Err(ProviderError::NotFound)
+        ));
+        let _ = fs::remove_dir_all(&provider.path);
+    }
+
+    #[test]
+    fn replacing_audio_releases_the_old_blob() {
+        let provider = provider("replace");
+        set(&provider, "a", 1);
+        let old_hash = provider.hash_of("a").unwrap();
+
+        set(&provider, "a", 2);
+        let new_hash = provider.hash_of("a").unwrap();
+        assert_ne!(new_hash, old_hash);
+        assert_eq!(blobs(&provider), vec![new_hash]);
+        let _ = fs::remove_dir_all(&provider.path);
+    }
+
+    #[test]
+    fn init_clears_out_temp_files() {
+        let mut provider = provider("temp");
+        let temp_path = provider.temp_path();
+        fs::write(&temp_path, b"half written").unwrap();
+
+        provider.init().unwrap();
+        assert!(!temp_path.exists());
+        let _ = fs::remove_dir_all(&provider.path);
+    }
+
+    #[test]
+    fn check_finds_corrupt_missing_and_orphaned_audio() {
+        let provider = provider("check");
+        set(&provider, "corrupt", 1);
+        set(&provider, "missing", 2);
+        set(&provider, "orphaned", 3);
+        set(&provider, "fine", 4);
+        assert!(provider.check().unwrap().is_ok());
+
+        let corrupt = provider.hash_of("corrupt").unwrap();
+        fs::write(provider.blob_path(&corrupt), b"not what it was").unwrap();
+        fs::remove_file(provider.blob_path(&provider.hash_of("missing").unwrap())).unwrap();
+        let orphaned = provider.index.lock().unwrap().remove("orphaned").unwrap();
+
+        let report = provider.check().unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.corrupt, vec![corrupt]);
+        assert_eq!(report.missing, vec!["missing".to_string()]);
+        assert_eq!(report.orphaned, vec![orphaned]);
+        let _ = fs::remove_dir_all(&provider.path);
+    }
+
+    #[test]
+    fn legacy_audio_is_migrated_into_blobs() {
+        let mut provider = provider("migrate");
+        let audio_dir = provider.path.join(FsAudioProvider::AUDIO_DIR);
+        fs::write(audio_dir.join("old.wav"), wav(1)).unwrap();
+        fs::write(audio_dir.join("old.waveform"), b"peaks").unwrap();
+
+        provider.init().unwrap();
+        let hash = provider.hash_of("old").unwrap();
+        assert_eq!(blobs(&provider), vec![hash.clone()]);
+        assert_eq!(fs::read(provider.blob_path(&hash)).unwrap(), wav(1));
+        assert_eq!(fs::read(provider.waveform_path(&hash)).unwrap(), b"peaks");
+        assert!(!audio_dir.join("old.wav").exists());
+        assert!(!audio_dir.join("old.waveform").exists());
+
+        // and the index it was moved into is what's loaded next time
+        let mut reopened = FsAudioProvider::new(provider.path.to_str().unwrap());
+        reopened.init().unwrap();
+        assert_eq!(reopened.hash_of("old").unwrap(), hash);
+        let _ = fs::remove_dir_all(&provider.path);
+    }
+
+    #[test]
+    fn track_waveforms_are_cached_until_the_file_changes() {
+        let provider = provider("track-waveform");
+        let track_path = provider.path.join("track.wav");
+        fs::write(&track_path, wav(1)).unwrap();
+
+        let waveform = provider.track_waveform(0xabc, &track_path).unwrap();
+        assert_eq!(waveform.points.len(), 4000usize.div_ceil(256));
+        let cache_path = provider.track_waveform_path(0xabc);
+        assert_eq!(fs::read(&cache_path).unwrap(), waveform.to_bytes());
+
+        // what's cached is what's served
+        let cached = Waveform {
+            points: waveform.points[..1].to_vec(),
+            ..waveform.clone()
+        };
+        fs::write(&cache_path, cached.to_bytes()).unwrap();
+        let served = provider.track_waveform(0xabc, &track_path).unwrap();
+        assert_eq!(served.points.len(), 1);
+
+        // until the track is newer than it
+        let cache_modified = fs::metadata(&cache_path).unwrap().modified().unwrap();
+        let track = File::options().write(true).open(&track_path).unwrap();
+        track
+            .set_modified(cache_modified + std::time::Duration::from_secs(1))
+            .unwrap();
+        let served = provider.track_waveform(0xabc, &track_path).unwrap();
+        assert_eq!(served.points.len(), waveform.points.len());
+
+        assert!(matches!(
+            provider.track_waveform(0xdef, &provider.path.join("gone.wav")),
+            Err(ProviderError::NotFound)
+        ));
+        let _ = fs::remove_dir_all(&provider.path);
     }
 
-    // fn delete_audio(&self, id: &str) -> Result<(), String> {
-    //     let file_path = self.path.join(format!("./audio/{id}.wav"));
-    //     if let Err(e) = fs::remove_file(file_path) {
-    //         return Err(ProviderError::Other(format!("error deleting audio file: {e}").as_str()));
-    //     }
+    #[test]
+    fn failed_migrations_leave_every_blob_indexed() {
+        let mut provider = provider("migrate-fail");
+        let audio_dir = provider.path.join(FsAudioProvider::AUDIO_DIR);
+        for pitch in 1..=8 {
+            fs::write(audio_dir.join(format!("{pitch}.wav")), wav(pitch)).unwrap();
+        }
+        // a wav that can't be read, wherever it comes in the dir
+        fs::create_dir(audio_dir.join("broken.wav")).unwrap();
 
-    //     Ok(())
-    // }
+        assert!(provider.init().is_err());
+        let mut reopened = FsAudioProvider::new(provider.path.to_str().unwrap());
+        reopened.load_index().unwrap();
+        for blob in blobs(&reopened) {
+            let index = reopened.index.lock().unwrap();
+            assert!(index.values().any(|hash| *hash == blob), "{blob} lost");
+        }
+
+        fs::remove_dir(audio_dir.join("broken.wav")).unwrap();
+        reopened.init().unwrap();
+        for pitch in 1..=8 {
+            let hash = reopened.hash_of(&pitch.to_string()).unwrap();
+            assert_eq!(fs::read(reopened.blob_path(&hash)).unwrap(), wav(pitch));
+        }
+        let _ = fs::remove_dir_all(&provider.path);
+    }
 }
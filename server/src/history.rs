@@ -0,0 +1,332 @@
+use crate::{
+    library::{hex_id, Library, Track},
+    scrobble::ScrobbleSink,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// What a track was queued from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlaySource {
+    /// On its own, or from a session restored after a restart.
+    #[default]
+    Queue,
+    Playlist {
+        #[serde(with = "hex_id")]
+        id: u64,
+    },
+}
+
+/// One play of a track, however far it got. Title and artist are kept as
+/// they were, so history outlives tracks leaving the library.
+///
+/// A play counts, towards a track's play count and in stats alike, when it
+/// scrobbled. Whether it was skipped only says how it ended, so a track
+/// skipped three quarters of the way through still counts, and a short one
+/// played to the end may not.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Play {
+    #[serde(with = "hex_id")]
+    pub track_id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Unix time in milliseconds the play started.
+    pub played_at: u64,
+    pub duration_ms: u64,
+    /// Moved on from before the end.
+    pub skipped: bool,
+    /// Whether it was played for long enough to count, see `Play::new`.
+    pub scrobbled: bool,
+    pub source: PlaySource,
+}
+
+impl Play {
+    /// Plays count the way scrobblers count them, as listened to once
+    /// they've gone on for half the track or four minutes, whichever comes
+    /// first. Anything under 30 seconds long never counts.
+    pub fn new(track: &Track, played: Duration, skipped: bool, source: PlaySource) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let length = track.duration_ms.map(Duration::from_millis);
+        let scrobbled = match length {
+            Some(length) if length < Duration::from_secs(30) => false,
+            Some(length) => played >= (length / 2).min(Duration::from_secs(4 * 60)),
+            None => played >= Duration::from_secs(4 * 60),
+        };
+
+        Self {
+            track_id: track.id,
+            title: track.title.clone(),
+            artist: track.artist.clone(),
+            album: track.album.clone(),
+            played_at: now.saturating_sub(played).as_millis() as u64,
+            duration_ms: played.as_millis() as u64,
+            skipped,
+            scrobbled,
+            source,
+        }
+    }
+}
+
+/// How far back stats go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+    Year,
+    All,
+}
+
+impl Period {
+    pub fn parse(period: &str) -> Option<Self> {
+        return match period {
+            "day" => Some(Period::Day),
+            "week" => Some(Period::Week),
+            "month" => Some(Period::Month),
+            "year" => Some(Period::Year),
+            "all" => Some(Period::All),
+            _ => None,
+        };
+    }
+
+    /// Unix time in milliseconds the period started, None for all time.
+    pub fn since(&self, now: SystemTime) -> Option<u64> {
+        let days = match self {
+            Period::Day => 1,
+            Period::Week => 7,
+            Period::Month => 30,
+            Period::Year => 365,
+            Period::All => return None,
+        };
+        let since = now.checked_sub(Duration::from_secs(days * 24 * 60 * 60))?;
+        since
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_millis() as u64)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TrackCount {
+    #[serde(with = "hex_id")]
+    pub track_id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub plays: u64,
+    /// Gone if it's left the library since.
+    pub track: Option<Track>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ArtistCount {
+    pub artist: String,
+    pub plays: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PlaySummary {
+    pub plays: u64,
+    pub scrobbles: u64,
+    pub skips: u64,
+    pub skip_rate: f64,
+    pub listened_ms: u64,
+}
+
+/// Writes plays to the library's history and hands the ones that count on
+/// to the scrobble sinks, off the playback path since either can be slow.
+pub struct PlayRecorder {
+    sender: Sender<Play>,
+    handle: JoinHandle<()>,
+}
+
+impl PlayRecorder {
+    pub fn spawn(library: Arc<RwLock<Library>>, sinks: Vec<Box<dyn ScrobbleSink>>) -> Self {
+        let (sender, reciever) = channel::<Play>();
+        let handle = thread::spawn(move || {
+            for play in reciever {
+                if let Err(e) = library.write().unwrap().record_play(&play) {
+                    eprintln!("error recording play: {e}");
+                }
+                if !play.scrobbled {
+                    continue;
+                }
+                for sink in sinks.iter() {
+                    if let Err(e) = sink.scrobble(&play) {
+                        eprintln!("error scrobbling to {}: {e}", sink.name());
+                    }
+                }
+            }
+        });
+
+        Self { sender, handle }
+    }
+
+    pub fn listener(&self) -> impl Fn(&Play) + Send + 'static {
+        let sender = self.sender.clone();
+        move |play| {
+            // the recorder only goes away on shutdown
+            let _ = sender.send(play.clone());
+        }
+    }
+
+    /// Records anything pending and waits for the recorder to finish. Only
+    /// returns once every listener handed out has been dropped as well.
+    pub fn close(self) {
+        drop(self.sender);
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrobble::LocalScrobbler;
+    use std::fs;
+
+    fn track(id: u64, artist: &str) -> Track {
+        Track {
+            id,
+            title: format!("track {id}"),
+            artist: Some(artist.to_string()),
+            duration_ms: Some(200_000),
+            ..Default::default()
+        }
+    }
+
+    fn play(track: &Track, seconds: u64, skipped: bool) -> Play {
+        Play::new(
+            track,
+            Duration::from_secs(seconds),
+            skipped,
+            PlaySource::Queue,
+        )
+    }
+
+    #[test]
+    fn plays_count_at_half_the_track() {
+        let track = track(1, "a");
+        assert!(play(&track, 100, true).scrobbled);
+        assert!(!play(&track, 99, true).scrobbled);
+
+        let short = Track {
+            duration_ms: Some(20_000),
+            ..track.clone()
+        };
+        assert!(!play(&short, 20, false).scrobbled);
+    }
+
+    #[test]
+    fn recorder_only_scrobbles_plays_that_count() {
+        let library = Arc::new(RwLock::new(Library::open_in_memory().unwrap()));
+        let short = Track {
+            duration_ms: Some(20_000),
+            ..track(2, "a")
+        };
+        let short = library.write().unwrap().insert(short).unwrap();
+        let track = library.write().unwrap().insert(track(1, "a")).unwrap();
+
+        let path = std::env::temp_dir().join(format!("recorder-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let recorder =
+            PlayRecorder::spawn(library.clone(), vec![Box::new(LocalScrobbler::new(&path))]);
+
+        let listener = recorder.listener();
+        listener(&play(&track, 200, false));
+        // far enough in to count, even though it didn't finish
+        listener(&play(&track, 150, true));
+        listener(&play(&track, 10, true));
+        // finished, but too short to count
+        listener(&play(&short, 20, false));
+        drop(listener);
+        recorder.close();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2, "{contents}");
+        for (line, (duration_ms, skipped)) in lines.iter().zip([(200_000, false), (150_000, true)])
+        {
+            assert_eq!(line["track_id"], "1");
+            assert_eq!(line["title"], "track 1");
+            assert_eq!(line["artist"], "a");
+            assert_eq!(line["duration_ms"], duration_ms);
+            assert_eq!(line["skipped"], skipped);
+            assert_eq!(line["scrobbled"], true);
+            assert_eq!(line["source"]["type"], "queue");
+        }
+
+        // and the play count goes by the same rule
+        let library = library.read().unwrap();
+        assert_eq!(library.plays(None, 10).unwrap().len(), 4);
+        assert_eq!(library.get_track(1).unwrap().play_count, 2);
+        assert!(library.get_track(1).unwrap().last_played.is_some());
+        assert_eq!(library.get_track(2).unwrap().play_count, 0);
+        assert!(library.get_track(2).unwrap().last_played.is_none());
+    }
+
+    #[test]
+    fn stats_count_scrobbles() {
+        let mut library = Library::open_in_memory().unwrap();
+        let (one, two, three) = (track(1, "a"), track(2, "b"), track(3, "B"));
+        for (track, seconds, skipped) in [
+            (&one, 200, false),
+            (&two, 200, false),
+            (&two, 150, true),
+            (&three, 200, false),
+            (&three, 5, true),
+        ] {
+            library.record_play(&play(track, seconds, skipped)).unwrap();
+        }
+
+        let top_tracks = library.top_tracks(Period::Day, 10).unwrap();
+        let counts: Vec<_> = top_tracks.iter().map(|c| (c.track_id, c.plays)).collect();
+        assert_eq!(counts[0], (2, 2));
+        assert_eq!(counts.len(), 3);
+
+        let top_artists = library.top_artists(Period::All, 10).unwrap();
+        assert_eq!(top_artists.len(), 2);
+        assert_eq!(top_artists[0].artist.to_lowercase(), "b");
+        assert_eq!(top_artists[0].plays, 3);
+
+        let summary = library.play_summary(Period::Week).unwrap();
+        assert_eq!(summary.plays, 5);
+        assert_eq!(summary.scrobbles, 4);
+        assert_eq!(summary.skips, 2);
+        assert_eq!(summary.skip_rate, 0.4);
+        assert_eq!(summary.listened_ms, 755_000);
+    }
+
+    #[test]
+    fn local_scrobbler_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("scrobbles-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let scrobbler = LocalScrobbler::new(&path);
+        let track = track(0xabc, "a");
+        scrobbler.scrobble(&play(&track, 200, false)).unwrap();
+        scrobbler.scrobble(&play(&track, 150, true)).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let plays: Vec<Play> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(plays.len(), 2);
+        assert_eq!(plays[0].track_id, 0xabc);
+        assert!(plays[1].skipped);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{
+    core::{
+        fingerprint::Fingerprint,
+        loudness::{Loudness, LoudnessAnalysis},
+        playlist::Playlist,
+    },
+    history::{ArtistCount, Period, Play, PlaySummary, TrackCount},
+    library_db::LibraryDb,
+    search::{SearchIndex, SearchResults},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Track {
+    #[serde(with = "hex_id")]
+    pub id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub duration_ms: Option<u64>,
+    pub replay_gain: Option<ReplayGain>,
+    pub loudness: Option<Loudness>,
+    /// Plays that scrobbled, see `Play`.
+    #[serde(default)]
+    pub play_count: u32,
+    /// Unix time in milliseconds of the last play that scrobbled.
+    pub last_played: Option<u64>,
+    /// Unix time in milliseconds the track was first indexed.
+    pub added_at: Option<u64>,
+    /// Where to fetch the track's cover image, if it has one.
+    pub art_url: Option<String>,
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+/// ReplayGain values in dB, with peaks as linear sample amplitudes.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ReplayGain {
+    pub track_gain: f32,
+    pub track_peak: f32,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+/// Changes to the library as they happen, for clients that want to stay in
+/// sync with it.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LibraryEvent {
+    Added {
+        track: Track,
+    },
+    Updated {
+        track: Track,
+    },
+    Removed {
+        #[serde(with = "hex_id")]
+        id: u64,
+    },
+    /// A smart playlist's tracks changed along with the library.
+    PlaylistUpdated {
+        playlist: Playlist,
+    },
+}
+
+#[derive(Debug)]
+pub enum LibraryError {
+    NotFound,
+    Database(String),
+    Other(&'static str),
+}
+
+impl fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LibraryError::NotFound => write!(f, "no such track"),
+            LibraryError::Database(message) => write!(f, "{message}"),
+            LibraryError::Other(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// A file as it was when the scanner last read it, to tell whether it
+/// changed since.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStamp {
+    pub modified: SystemTime,
+    pub size: u64,
+}
+
+/// Unset `track_id` is a file that isn't audio, remembered so it isn't
+/// probed again on every scan.
+#[derive(Clone, Copy, Debug)]
+pub struct IndexedFile {
+    pub stamp: FileStamp,
+    pub track_id: Option<u64>,
+}
+
+/// Changes to make to the library all at once, or not at all.
+#[derive(Default)]
+pub struct LibraryBatch {
+    pub tracks: Vec<Track>,
+    pub removed: Vec<u64>,
+    pub files: Vec<(PathBuf, IndexedFile)>,
+    pub removed_files: Vec<PathBuf>,
+    pub fingerprints: Vec<(u64, Fingerprint)>,
+}
+
+impl LibraryBatch {
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+            + self.removed.len()
+            + self.files.len()
+            + self.removed_files.len()
+            + self.fingerprints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Unset fields match anything.
+#[derive(Clone, Debug, Default)]
+pub struct TrackQuery {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl Track {
+    /// Takes on a loudness analysis, along with the ReplayGain track values
+    /// derived from it. Album values already there are kept.
+    pub fn set_loudness(&mut self, analysis: &LoudnessAnalysis) {
+        let mut replay_gain = analysis.replay_gain();
+        if let Some(previous) = self.replay_gain {
+            replay_gain.album_gain = previous.album_gain;
+            replay_gain.album_peak = previous.album_peak;
+        }
+        self.replay_gain = Some(replay_gain);
+        self.loudness = Some(analysis.loudness);
+    }
+}
+
+/// The track catalog, kept in a SQLite database and mirrored in memory so
+/// lookups on the playback path never touch the disk.
+pub struct Library {
+    tracks: HashMap<u64, Arc<Track>>,
+    search: SearchIndex,
+    db: LibraryDb,
+    change_listeners: Vec<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl Library {
+    pub fn open(path: &Path) -> Result<Self, LibraryError> {
+        Self::load(LibraryDb::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, LibraryError> {
+        Self::load(LibraryDb::open_in_memory()?)
+    }
+
+    fn load(db: LibraryDb) -> Result<Self, LibraryError> {
+        let mut search = SearchIndex::new();
+        let tracks = db
+            .tracks()?
+            .into_iter()
+            .map(|track| {
+                search.insert(&track);
+                (track.id, Arc::new(track))
+            })
+            .collect();
+
+        Ok(Self {
+            tracks,
+            search,
+            db,
+            change_listeners: Vec::new(),
+        })
+    }
+
+    /// Called after anything about the tracks changes, plays included.
+    pub fn on_change<F: Fn() + Send + Sync + 'static>(&mut self, listener: F) {
+        self.change_listeners.push(Box::new(listener));
+    }
+
+    fn changed(&self) {
+        for listener in self.change_listeners.iter() {
+            listener();
+        }
+    }
+
+    pub fn insert(&mut self, track: Track) -> Result<Arc<Track>, LibraryError> {
+        let id = track.id;
+        self.apply(LibraryBatch {
+            tracks: vec![track],
+            ..Default::default()
+        })?;
+        self.get_track(id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Result<Option<Arc<Track>>, LibraryError> {
+        let track = self.tracks.get(&id).cloned();
+        self.apply(LibraryBatch {
+            removed: vec![id],
+            ..Default::default()
+        })?;
+        Ok(track)
+    }
+
+    /// Stores every change in `batch` in one transaction. Play statistics
+    /// and when they were added of tracks already there are kept, those
+    /// only change by playing.
+    pub fn apply(&mut self, mut batch: LibraryBatch) -> Result<(), LibraryError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let now = unix_millis(SystemTime::now());
+        for track in batch.tracks.iter_mut() {
+            match self.tracks.get(&track.id) {
+                Some(previous) => {
+                    track.play_count = previous.play_count;
+                    track.last_played = previous.last_played;
+                    track.added_at = previous.added_at;
+                }
+                None => track.added_at = track.added_at.or(now),
+            }
+        }
+        self.db.apply(&batch)?;
+
+        for track in batch.tracks {
+            self.search.insert(&track);
+            self.tracks.insert(track.id, Arc::new(track));
+        }
+        for id in batch.removed {
+            self.search.remove(id);
+            self.tracks.remove(&id);
+        }
+        self.changed();
+
+        Ok(())
+    }
+
+    pub fn tracks(&self) -> impl Iterator<Item = &Arc<Track>> {
+        self.tracks.values()
+    }
+
+    pub fn get_track(&self, id: u64) -> Result<Arc<Track>, LibraryError> {
+        return match self.tracks.get(&id) {
+            Some(track) => Ok(track.clone()),
+            None => Err(LibraryError::NotFound),
+        };
+    }
+
+    pub fn query(&self, query: &TrackQuery) -> Result<Vec<Arc<Track>>, LibraryError> {
+        Ok(self
+            .db
+            .query(query)?
+            .into_iter()
+            .filter_map(|id| self.tracks.get(&id).cloned())
+            .collect())
+    }
+
+    /// Tracks, albums and artists matching `query`, up to `limit` of each.
+    pub fn search(&self, query: &str, limit: usize) -> SearchResults {
+        let (ids, albums, artists) = self.search.search(query, limit);
+        SearchResults {
+            tracks: ids
+                .into_iter()
+                .filter_map(|id| self.tracks.get(&id).map(|track| (**track).clone()))
+                .collect(),
+            albums,
+            artists,
+        }
+    }
+
+    /// Every track's fingerprint, for those that have one. These aren't
+    /// kept in memory, they're only wanted for finding duplicates.
+    pub fn fingerprints(&self) -> Result<Vec<(u64, Fingerprint)>, LibraryError> {
+        self.db.fingerprints()
+    }
+
+    /// Ids of the tracks that have a fingerprint.
+    pub fn fingerprinted(&self) -> Result<HashSet<u64>, LibraryError> {
+        self.db.fingerprinted()
+    }
+
+    /// What the scanner knew about each file when it last ran.
+    pub fn files(&self) -> Result<Vec<(PathBuf, IndexedFile)>, LibraryError> {
+        self.db.files()
+    }
+
+    /// Adds `play` to the history, and counts it on the track if it
+    /// scrobbled.
+    pub fn record_play(&mut self, play: &Play) -> Result<(), LibraryError> {
+        let now = SystemTime::now();
+        self.db.record_play(play, now)?;
+
+        if let (true, Some(track)) = (play.scrobbled, self.tracks.get(&play.track_id)) {
+            let mut track = (**track).clone();
+            track.play_count += 1;
+            track.last_played = unix_millis(now);
+            self.tracks.insert(track.id, Arc::new(track));
+        }
+        self.changed();
+
+        Ok(())
+    }
+
+    /// Play history from before `before`, newest first.
+    pub fn plays(&self, before: Option<u64>, limit: usize) -> Result<Vec<Play>, LibraryError> {
+        self.db.plays(before, limit)
+    }
+
+    pub fn top_tracks(
+        &self,
+        period: Period,
+        limit: usize,
+    ) -> Result<Vec<TrackCount>, LibraryError> {
+        let mut counts = self.db.top_tracks(period.since(SystemTime::now()), limit)?;
+        for count in counts.iter_mut() {
+            count.track = self
+                .tracks
+                .get(&count.track_id)
+                .map(|track| (**track).clone());
+        }
+        Ok(counts)
+    }
+
+    pub fn top_artists(
+        &self,
+        period: Period,
+        limit: usize,
+    ) -> Result<Vec<ArtistCount>, LibraryError> {
+        self.db.top_artists(period.since(SystemTime::now()), limit)
+    }
+
+    pub fn play_summary(&self, period: Period) -> Result<PlaySummary, LibraryError> {
+        self.db.play_summary(period.since(SystemTime::now()))
+    }
+
+    /// Stores a track's loudness analysis, see `Track::set_loudness`.
+    pub fn set_loudness(
+        &mut self,
+        id: u64,
+        analysis: &LoudnessAnalysis,
+    ) -> Result<Arc<Track>, LibraryError> {
+        let mut track = (*self.get_track(id)?).clone();
+        track.set_loudness(analysis);
+        self.insert(track)
+    }
+
+    /// Works out ReplayGain album values from the analyses of every track
+    /// on an album and stores them on each of those tracks.
+    pub fn set_album_gain(
+        &mut self,
+        album: &[(u64, &LoudnessAnalysis)],
+    ) -> Result<(), LibraryError> {
+        let analyses: Vec<_> = album.iter().map(|(_, analysis)| *analysis).collect();
+        let (album_gain, album_peak) = LoudnessAnalysis::album_gain(&analyses);
+
+        let mut batch = LibraryBatch::default();
+        for (id, analysis) in album.iter() {
+            let mut track = match self.tracks.get(id) {
+                Some(track) => (**track).clone(),
+                None => continue,
+            };
+            let mut replay_gain = track.replay_gain.unwrap_or_else(|| analysis.replay_gain());
+            replay_gain.album_gain = Some(album_gain);
+            replay_gain.album_peak = Some(album_peak);
+            track.replay_gain = Some(replay_gain);
+            batch.tracks.push(track);
+        }
+
+        self.apply(batch)
+    }
+
+    pub fn get_track_source(&self, id: u64) -> Result<Vec<u8>, LibraryError> {
+        let track = self.get_track(id)?;
+        return match fs::read(&track.path) {
+            Ok(source) => Ok(source),
+            Err(_) => Err(LibraryError::Other("error reading track source")),
+        };
+    }
+}
+
+fn unix_millis(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|since| since.as_millis() as u64)
+}
+
+/// Track ids are 64-bit hashes, which don't survive a round trip through a
+/// JS number, so they go over the wire as the same hex strings the gateway
+/// accepts in its `id` query parameters.
+pub mod hex_id {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{id:x}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let id_str = String::deserialize(deserializer)?;
+        u64::from_str_radix(&id_str, 16).map_err(D::Error::custom)
+    }
+}
+
+pub mod hex_ids {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(ids: &[u64], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(ids.iter().map(|id| format!("{id:x}")))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u64>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|id_str| u64::from_str_radix(id_str, 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
@@ -0,0 +1,698 @@
+use crate::{
+    art::art_url,
+    core::{fingerprint::Fingerprint, loudness::Loudness},
+    history::{ArtistCount, Play, PlaySource, PlaySummary, TrackCount},
+    library::{FileStamp, IndexedFile, LibraryBatch, LibraryError, ReplayGain, Track, TrackQuery},
+};
+use rusqlite::{params, Connection, Row, Transaction};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Schema changes in the order they were made. A database records how many
+/// it has had applied in `user_version`, and on opening gets whichever it's
+/// missing. Only ever add to the end.
+const MIGRATIONS: &[&str] = &[
+    // 1: tracks, and the file stamps the scanner goes by
+    "CREATE TABLE tracks (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL,
+        title TEXT NOT NULL,
+        artist TEXT,
+        album TEXT,
+        album_artist TEXT,
+        track_number INTEGER,
+        disc_number INTEGER,
+        genre TEXT,
+        year INTEGER,
+        duration_ms INTEGER,
+        track_gain REAL,
+        track_peak REAL,
+        album_gain REAL,
+        album_peak REAL,
+        integrated_lufs REAL,
+        range_lu REAL,
+        true_peak_dbtp REAL,
+        play_count INTEGER NOT NULL DEFAULT 0,
+        last_played INTEGER
+    );
+    CREATE INDEX tracks_artist ON tracks (artist COLLATE NOCASE);
+    CREATE INDEX tracks_album ON tracks (album COLLATE NOCASE);
+    CREATE INDEX tracks_genre ON tracks (genre COLLATE NOCASE);
+    CREATE TABLE files (
+        path TEXT PRIMARY KEY,
+        modified_ns INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        track_id INTEGER
+    );",
+    // 2: when tracks were added, going by their files for those already
+    // there, and whether they have art, which needs every track read again
+    "ALTER TABLE tracks ADD COLUMN added_at INTEGER;
+    ALTER TABLE tracks ADD COLUMN has_art INTEGER NOT NULL DEFAULT 0;
+    UPDATE tracks SET added_at =
+        (SELECT modified_ns / 1000000 FROM files WHERE files.track_id = tracks.id);
+    UPDATE files SET modified_ns = 0 WHERE track_id IS NOT NULL;",
+    // 3: every play, kept apart from the tracks so it outlives them
+    "CREATE TABLE plays (
+        id INTEGER PRIMARY KEY,
+        track_id INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        artist TEXT,
+        album TEXT,
+        played_at INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        skipped INTEGER NOT NULL,
+        scrobbled INTEGER NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE INDEX plays_played_at ON plays (played_at);",
+    // 4: acoustic fingerprints, apart from the tracks since they're only
+    // read to look for duplicates
+    "CREATE TABLE fingerprints (
+        track_id INTEGER PRIMARY KEY,
+        data BLOB NOT NULL
+    );",
+];
+
+const TRACK_COLUMNS: &str = "id, path, title, artist, album, album_artist, track_number, \
+    disc_number, genre, year, duration_ms, track_gain, track_peak, album_gain, album_peak, \
+    integrated_lufs, range_lu, true_peak_dbtp, play_count, last_played, added_at, has_art";
+
+/// The library's on-disk catalog. Track ids are u64 hashes, stored as the
+/// i64 with the same bits since that's what SQLite has.
+pub struct LibraryDb {
+    // rusqlite connections can move between threads but not be shared
+    conn: Mutex<Connection>,
+}
+
+impl LibraryDb {
+    pub fn open(path: &Path) -> Result<Self, LibraryError> {
+        match Connection::open(path) {
+            Ok(conn) => Self::init(conn),
+            Err(e) => Err(db_error("error opening library database", e)),
+        }
+    }
+
+    pub fn open_in_memory() -> Result<Self, LibraryError> {
+        match Connection::open_in_memory() {
+            Ok(conn) => Self::init(conn),
+            Err(e) => Err(db_error("error opening library database", e)),
+        }
+    }
+
+    fn init(mut conn: Connection) -> Result<Self, LibraryError> {
+        // a rescan rewrites most of the catalog, WAL keeps readers going
+        // through that
+        if let Err(e) = conn.pragma_update(None, "journal_mode", "WAL") {
+            return Err(db_error("error configuring library database", e));
+        }
+
+        let version: usize = match conn.pragma_query_value(None, "user_version", |row| row.get(0)) {
+            Ok(version) => version,
+            Err(e) => return Err(db_error("error reading schema version", e)),
+        };
+        if version > MIGRATIONS.len() {
+            return Err(LibraryError::Database(format!(
+                "library database is schema version {version}, newer than this server's {}",
+                MIGRATIONS.len()
+            )));
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let result = conn.transaction().and_then(|tx| {
+                tx.execute_batch(migration)?;
+                tx.pragma_update(None, "user_version", index + 1)?;
+                tx.commit()
+            });
+            if let Err(e) = result {
+                return Err(db_error(
+                    &format!("error applying migration {}", index + 1),
+                    e,
+                ));
+            }
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn tracks(&self) -> Result<Vec<Track>, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .prepare(&format!("SELECT {TRACK_COLUMNS} FROM tracks"))
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], track_from_row)?
+                    .collect::<Result<Vec<_>, _>>()
+            });
+
+        result.map_err(|e| db_error("error loading tracks", e))
+    }
+
+    pub fn fingerprints(&self) -> Result<Vec<(u64, Fingerprint)>, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .prepare("SELECT track_id, data FROM fingerprints")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| {
+                        Ok((row.get::<_, i64>(0)? as u64, row.get::<_, Vec<u8>>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            });
+
+        let rows = result.map_err(|e| db_error("error loading fingerprints", e))?;
+        rows.into_iter()
+            .map(|(id, data)| match Fingerprint::from_bytes(&data) {
+                Ok(fingerprint) => Ok((id, fingerprint)),
+                Err(e) => Err(LibraryError::Database(format!(
+                    "error parsing fingerprint: {e}"
+                ))),
+            })
+            .collect()
+    }
+
+    pub fn fingerprinted(&self) -> Result<HashSet<u64>, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .prepare("SELECT track_id FROM fingerprints")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| row.get::<_, i64>(0).map(|id| id as u64))?
+                    .collect::<Result<HashSet<_>, _>>()
+            });
+
+        result.map_err(|e| db_error("error loading fingerprinted tracks", e))
+    }
+
+    /// The scanner's record of every file it's looked at.
+    pub fn files(&self) -> Result<Vec<(PathBuf, IndexedFile)>, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .prepare("SELECT path, modified_ns, size, track_id FROM files")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| {
+                        let modified_ns: i64 = row.get(1)?;
+                        let size: i64 = row.get(2)?;
+                        let track_id: Option<i64> = row.get(3)?;
+                        Ok((
+                            PathBuf::from(row.get::<_, String>(0)?),
+                            IndexedFile {
+                                stamp: FileStamp {
+                                    modified: UNIX_EPOCH + Duration::from_nanos(modified_ns as u64),
+                                    size: size as u64,
+                                },
+                                track_id: track_id.map(|id| id as u64),
+                            },
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            });
+
+        result.map_err(|e| db_error("error loading scanned files", e))
+    }
+
+    /// Writes all of `batch` or, if anything fails, none of it.
+    pub fn apply(&self, batch: &LibraryBatch) -> Result<(), LibraryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let result = conn.transaction().and_then(|tx| {
+            for track in batch.tracks.iter() {
+                upsert_track(&tx, track)?;
+            }
+            for id in batch.removed.iter() {
+                tx.execute("DELETE FROM tracks WHERE id = ?1", [*id as i64])?;
+                tx.execute("DELETE FROM fingerprints WHERE track_id = ?1", [*id as i64])?;
+            }
+            for (id, fingerprint) in batch.fingerprints.iter() {
+                tx.execute(
+                    "INSERT INTO fingerprints (track_id, data) VALUES (?1, ?2)
+                    ON CONFLICT (track_id) DO UPDATE SET data = excluded.data",
+                    params![*id as i64, fingerprint.to_bytes()],
+                )?;
+            }
+            for (path, file) in batch.files.iter() {
+                tx.execute(
+                    "INSERT INTO files (path, modified_ns, size, track_id)
+                    VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT (path) DO UPDATE SET
+                        modified_ns = excluded.modified_ns,
+                        size = excluded.size,
+                        track_id = excluded.track_id",
+                    params![
+                        path.to_string_lossy(),
+                        unix_nanos(file.stamp.modified),
+                        file.stamp.size as i64,
+                        file.track_id.map(|id| id as i64),
+                    ],
+                )?;
+            }
+            for path in batch.removed_files.iter() {
+                tx.execute(
+                    "DELETE FROM files WHERE path = ?1",
+                    [path.to_string_lossy()],
+                )?;
+            }
+            tx.commit()
+        });
+
+        result.map_err(|e| db_error("error updating library", e))
+    }
+
+    /// Adds `play` to the history. Plays that scrobbled count towards the
+    /// track's play count, if it's still there.
+    pub fn record_play(&self, play: &Play, at: SystemTime) -> Result<(), LibraryError> {
+        let source = match serde_json::to_string(&play.source) {
+            Ok(source) => source,
+            Err(e) => {
+                return Err(LibraryError::Database(format!(
+                    "error serializing play source: {e}"
+                )))
+            }
+        };
+
+        let mut conn = self.conn.lock().unwrap();
+        let result = conn.transaction().and_then(|tx| {
+            tx.execute(
+                "INSERT INTO plays (
+                    track_id, title, artist, album, played_at, duration_ms, skipped, scrobbled,
+                    source
+                )
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    play.track_id as i64,
+                    play.title,
+                    play.artist,
+                    play.album,
+                    play.played_at as i64,
+                    play.duration_ms as i64,
+                    play.skipped,
+                    play.scrobbled,
+                    source,
+                ],
+            )?;
+            if play.scrobbled {
+                tx.execute(
+                    "UPDATE tracks SET play_count = play_count + 1, last_played = ?2 WHERE id = ?1",
+                    params![play.track_id as i64, unix_millis(at)],
+                )?;
+            }
+            tx.commit()
+        });
+
+        result.map_err(|e| db_error("error recording play", e))
+    }
+
+    /// The most recent plays from before `before`, newest first.
+    pub fn plays(&self, before: Option<u64>, limit: usize) -> Result<Vec<Play>, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .prepare(
+                "SELECT track_id, title, artist, album, played_at, duration_ms, skipped,
+                    scrobbled, source
+                FROM plays
+                WHERE ?1 IS NULL OR played_at < ?1
+                ORDER BY played_at DESC
+                LIMIT ?2",
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_map(
+                        params![before.map(|before| before as i64), limit as i64],
+                        play_from_row,
+                    )?
+                    .collect::<Result<Vec<_>, _>>()
+            });
+
+        result.map_err(|e| db_error("error loading plays", e))
+    }
+
+    /// The tracks played most since `since`, going by plays that count.
+    /// Ties go to whichever was played last.
+    pub fn top_tracks(
+        &self,
+        since: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<TrackCount>, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .prepare(
+                // title and artist come from the latest play, the one max()
+                // picks
+                "SELECT track_id, title, artist, count(*) AS plays, max(played_at)
+                FROM plays
+                WHERE scrobbled AND (?1 IS NULL OR played_at >= ?1)
+                GROUP BY track_id
+                ORDER BY plays DESC, max(played_at) DESC
+                LIMIT ?2",
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_map(
+                        params![since.map(|since| since as i64), limit as i64],
+                        |row| {
+                            Ok(TrackCount {
+                                track_id: row.get::<_, i64>(0)? as u64,
+                                title: row.get(1)?,
+                                artist: row.get(2)?,
+                                plays: row.get::<_, i64>(3)? as u64,
+                                track: None,
+                            })
+                        },
+                    )?
+                    .collect::<Result<Vec<_>, _>>()
+            });
+
+        result.map_err(|e| db_error("error counting track plays", e))
+    }
+
+    /// Like `top_tracks`, for artists.
+    pub fn top_artists(
+        &self,
+        since: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<ArtistCount>, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .prepare(
+                "SELECT artist, count(*) AS plays, max(played_at)
+                FROM plays
+                WHERE scrobbled AND artist IS NOT NULL AND (?1 IS NULL OR played_at >= ?1)
+                GROUP BY artist COLLATE NOCASE
+                ORDER BY plays DESC, max(played_at) DESC
+                LIMIT ?2",
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_map(
+                        params![since.map(|since| since as i64), limit as i64],
+                        |row| {
+                            Ok(ArtistCount {
+                                artist: row.get(0)?,
+                                plays: row.get::<_, i64>(1)? as u64,
+                            })
+                        },
+                    )?
+                    .collect::<Result<Vec<_>, _>>()
+            });
+
+        result.map_err(|e| db_error("error counting artist plays", e))
+    }
+
+    pub fn play_summary(&self, since: Option<u64>) -> Result<PlaySummary, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT count(*), coalesce(sum(scrobbled), 0), coalesce(sum(skipped), 0),
+                coalesce(sum(duration_ms), 0)
+            FROM plays
+            WHERE ?1 IS NULL OR played_at >= ?1",
+            [since.map(|since| since as i64)],
+            |row| {
+                let plays = row.get::<_, i64>(0)? as u64;
+                let skips = row.get::<_, i64>(2)? as u64;
+                Ok(PlaySummary {
+                    plays,
+                    scrobbles: row.get::<_, i64>(1)? as u64,
+                    skips,
+                    skip_rate: match plays {
+                        0 => 0.0,
+                        plays => skips as f64 / plays as f64,
+                    },
+                    listened_ms: row.get::<_, i64>(3)? as u64,
+                })
+            },
+        );
+
+        result.map_err(|e| db_error("error summarizing plays", e))
+    }
+
+    /// Ids of the tracks matching `query`, in album order.
+    pub fn query(&self, query: &TrackQuery) -> Result<Vec<u64>, LibraryError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .prepare(
+                "SELECT id FROM tracks
+                WHERE (?1 IS NULL OR artist = ?1 COLLATE NOCASE OR album_artist = ?1 COLLATE NOCASE)
+                    AND (?2 IS NULL OR album = ?2 COLLATE NOCASE)
+                    AND (?3 IS NULL OR genre = ?3 COLLATE NOCASE)
+                ORDER BY
+                    coalesce(album_artist, artist) COLLATE NOCASE,
+                    album COLLATE NOCASE,
+                    disc_number,
+                    track_number,
+                    title COLLATE NOCASE
+                LIMIT ?4 OFFSET ?5",
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_map(
+                        params![
+                            query.artist,
+                            query.album,
+                            query.genre,
+                            // a negative limit is no limit
+                            query.limit.map_or(-1, |limit| limit as i64),
+                            query.offset as i64,
+                        ],
+                        |row| row.get::<_, i64>(0),
+                    )?
+                    .map(|id| id.map(|id| id as u64))
+                    .collect::<Result<Vec<_>, _>>()
+            });
+
+        result.map_err(|e| db_error("error querying tracks", e))
+    }
+}
+
+/// Play statistics are left alone, they only change through `record_play`,
+/// and so is when the track was added.
+fn upsert_track(tx: &Transaction, track: &Track) -> rusqlite::Result<usize> {
+    let replay_gain = track.replay_gain.as_ref();
+    let loudness = track.loudness.as_ref();
+    tx.execute(
+        "INSERT INTO tracks (
+            id, path, title, artist, album, album_artist, track_number, disc_number, genre,
+            year, duration_ms, track_gain, track_peak, album_gain, album_peak,
+            integrated_lufs, range_lu, true_peak_dbtp, added_at, has_art
+        )
+        VALUES (
+            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18,
+            ?19, ?20
+        )
+        ON CONFLICT (id) DO UPDATE SET
+            path = excluded.path,
+            title = excluded.title,
+            artist = excluded.artist,
+            album = excluded.album,
+            album_artist = excluded.album_artist,
+            track_number = excluded.track_number,
+            disc_number = excluded.disc_number,
+            genre = excluded.genre,
+            year = excluded.year,
+            duration_ms = excluded.duration_ms,
+            track_gain = excluded.track_gain,
+            track_peak = excluded.track_peak,
+            album_gain = excluded.album_gain,
+            album_peak = excluded.album_peak,
+            integrated_lufs = excluded.integrated_lufs,
+            range_lu = excluded.range_lu,
+            true_peak_dbtp = excluded.true_peak_dbtp,
+            has_art = excluded.has_art",
+        params![
+            track.id as i64,
+            track.path.to_string_lossy(),
+            track.title,
+            track.artist,
+            track.album,
+            track.album_artist,
+            track.track_number,
+            track.disc_number,
+            track.genre,
+            track.year,
+            track.duration_ms.map(|duration| duration as i64),
+            replay_gain.map(|rg| rg.track_gain),
+            replay_gain.map(|rg| rg.track_peak),
+            replay_gain.and_then(|rg| rg.album_gain),
+            replay_gain.and_then(|rg| rg.album_peak),
+            loudness.map(|l| l.integrated_lufs),
+            loudness.map(|l| l.range_lu),
+            loudness.map(|l| l.true_peak_dbtp),
+            track.added_at.map(|at| at as i64),
+            track.art_url.is_some(),
+        ],
+    )
+}
+
+fn track_from_row(row: &Row) -> rusqlite::Result<Track> {
+    let replay_gain = match (row.get(11)?, row.get(12)?) {
+        (Some(track_gain), Some(track_peak)) => Some(ReplayGain {
+            track_gain,
+            track_peak,
+            album_gain: row.get(13)?,
+            album_peak: row.get(14)?,
+        }),
+        _ => None,
+    };
+    let loudness = match (row.get(15)?, row.get(16)?, row.get(17)?) {
+        (Some(integrated_lufs), Some(range_lu), Some(true_peak_dbtp)) => Some(Loudness {
+            integrated_lufs,
+            range_lu,
+            true_peak_dbtp,
+        }),
+        _ => None,
+    };
+
+    let id = row.get::<_, i64>(0)? as u64;
+    Ok(Track {
+        id,
+        path: PathBuf::from(row.get::<_, String>(1)?),
+        title: row.get(2)?,
+        artist: row.get(3)?,
+        album: row.get(4)?,
+        album_artist: row.get(5)?,
+        track_number: row.get(6)?,
+        disc_number: row.get(7)?,
+        genre: row.get(8)?,
+        year: row.get(9)?,
+        duration_ms: row
+            .get::<_, Option<i64>>(10)?
+            .map(|duration| duration as u64),
+        replay_gain,
+        loudness,
+        play_count: row.get(18)?,
+        last_played: row.get::<_, Option<i64>>(19)?.map(|at| at as u64),
+        added_at: row.get::<_, Option<i64>>(20)?.map(|at| at as u64),
+        art_url: match row.get(21)? {
+            true => Some(art_url(id)),
+            false => None,
+        },
+    })
+}
+
+fn play_from_row(row: &Row) -> rusqlite::Result<Play> {
+    // a source this server doesn't know is from a newer one
+    let source: String = row.get(8)?;
+    Ok(Play {
+        track_id: row.get::<_, i64>(0)? as u64,
+        title: row.get(1)?,
+        artist: row.get(2)?,
+        album: row.get(3)?,
+        played_at: row.get::<_, i64>(4)? as u64,
+        duration_ms: row.get::<_, i64>(5)? as u64,
+        skipped: row.get(6)?,
+        scrobbled: row.get(7)?,
+        source: serde_json::from_str(&source).unwrap_or(PlaySource::Queue),
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as i64)
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+fn db_error(context: &str, e: rusqlite::Error) -> LibraryError {
+    LibraryError::Database(format!("{context}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(db: &LibraryDb) -> usize {
+        let conn = db.conn.lock().unwrap();
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    fn schema(db: &LibraryDb) -> Vec<String> {
+        let conn = db.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare("SELECT sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY name")
+            .unwrap();
+        let rows = statement.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    fn track(id: u64) -> Track {
+        Track {
+            id,
+            title: format!("track {id}"),
+            path: PathBuf::from(format!("/music/{id}.flac")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn migrations_run_once() {
+        let db = LibraryDb::open_in_memory().unwrap();
+        assert_eq!(user_version(&db), MIGRATIONS.len());
+        db.apply(&LibraryBatch {
+            tracks: vec![track(1)],
+            ..Default::default()
+        })
+        .unwrap();
+        let before = schema(&db);
+
+        // opening again finds nothing left to do
+        let db = LibraryDb::init(db.conn.into_inner().unwrap()).unwrap();
+        assert_eq!(user_version(&db), MIGRATIONS.len());
+        assert_eq!(schema(&db), before);
+        assert_eq!(db.tracks().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let db = LibraryDb::open_in_memory().unwrap();
+        let conn = db.conn.into_inner().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+
+        assert!(matches!(
+            LibraryDb::init(conn),
+            Err(LibraryError::Database(_))
+        ));
+    }
+
+    #[test]
+    fn failed_batches_change_nothing() {
+        let db = LibraryDb::open_in_memory().unwrap();
+        db.apply(&LibraryBatch {
+            tracks: vec![track(1)],
+            ..Default::default()
+        })
+        .unwrap();
+        // the files come after the tracks, so those are written first
+        db.conn
+            .lock()
+            .unwrap()
+            .execute_batch("DROP TABLE files")
+            .unwrap();
+
+        let file = IndexedFile {
+            stamp: FileStamp {
+                modified: UNIX_EPOCH,
+                size: 0,
+            },
+            track_id: Some(2),
+        };
+        let result = db.apply(&LibraryBatch {
+            tracks: vec![track(2)],
+            removed: vec![1],
+            files: vec![(PathBuf::from("/music/2.flac"), file)],
+            ..Default::default()
+        });
+        assert!(result.is_err());
+
+        let ids: Vec<u64> = db.tracks().unwrap().iter().map(|track| track.id).collect();
+        assert_eq!(ids, vec![1]);
+    }
+}
@@ -0,0 +1,313 @@
+use crate::core::{
+    clock::PlaybackClock,
+    drain::Drain,
+    flush::FlushControl,
+    pipeline::{AudioPipeline, PipeHandle, PipeMessage, Sample},
+};
+use librespot::{
+    connect::spirc::Spirc,
+    core::{
+        authentication::Credentials,
+        config::{ConnectConfig, SessionConfig},
+        session::Session,
+    },
+    playback::{
+        audio_backend::{Sink, SinkError, SinkResult},
+        config::PlayerConfig,
+        convert::Converter,
+        decoder::AudioPacket,
+        mixer::{softmixer::SoftMixer, Mixer, MixerConfig},
+        player::Player,
+        NUM_CHANNELS, SAMPLE_RATE,
+    },
+};
+use serde::Deserialize;
+use std::{
+    f64::consts::TAU,
+    fs,
+    io::ErrorKind,
+    path::Path,
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Where to log in to Spotify, read from `spotify.toml`:
+///
+/// ```toml
+/// username = "someone"
+/// password = "hunter2"
+/// device_name = "Living room"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpotifyConfig {
+    pub username: String,
+    pub password: String,
+    /// What it shows up as in Spotify's device list.
+    #[serde(default = "SpotifyConfig::default_device_name")]
+    pub device_name: String,
+}
+
+impl SpotifyConfig {
+    fn default_device_name() -> String {
+        "audio_server".to_string()
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("error reading spotify config: {e}")),
+        };
+
+        return match toml::from_str(&contents) {
+            Ok(config) => Ok(Some(config)),
+            Err(e) => Err(format!("error parsing spotify config: {e}")),
+        };
+    }
+}
+
+/// Runs a pipeline fed by librespot, so Spotify Connect playback goes through
+/// the same filters and drains as local tracks.
+pub struct LibrespotBridge {
+    sender: SyncSender<PipeMessage>,
+    clock: Arc<PlaybackClock>,
+}
+
+impl LibrespotBridge {
+    pub fn spawn(
+        pipeline: AudioPipeline,
+        drain: Arc<dyn Drain>,
+        clock: Arc<PlaybackClock>,
+    ) -> Result<(Self, Vec<PipeHandle>), String> {
+        let ctx = pipeline.ctx();
+        if ctx.sample_rate != SAMPLE_RATE || ctx.channels != NUM_CHANNELS as u16 {
+            return Err(format!(
+                "librespot plays {} channels at {} Hz, pipeline is {} channels at {} Hz",
+                NUM_CHANNELS, SAMPLE_RATE, ctx.channels, ctx.sample_rate
+            ));
+        }
+
+        let (sender, reciever) = sync_channel(ctx.fitting_buffer);
+        let flush = Arc::new(FlushControl::new());
+        let handles = pipeline.drain(reciever, drain, clock.clone(), flush)?;
+        clock.start_track(SAMPLE_RATE, 0, None);
+
+        Ok((Self { sender, clock }, handles))
+    }
+
+    /// Logs in and shows up as a Spotify Connect device, playing whatever
+    /// it's sent into the pipeline. Runs until the session ends.
+    pub async fn connect(&self, config: &SpotifyConfig) -> Result<(), String> {
+        let credentials = Credentials::with_password(&config.username, &config.password);
+        let session =
+            match Session::connect(SessionConfig::default(), credentials, None, false).await {
+                Ok((session, _)) => session,
+                Err(e) => return Err(format!("error connecting to spotify: {e}")),
+            };
+
+        // volume from Spotify is applied by librespot, before the pipeline's
+        // own mixer
+        let mixer = SoftMixer::open(MixerConfig::default());
+        let sink = self.sink();
+        let (player, _) = Player::new(
+            PlayerConfig::default(),
+            session.clone(),
+            mixer.get_soft_volume(),
+            move || Box::new(sink),
+        );
+        let connect_config = ConnectConfig {
+            name: config.device_name.clone(),
+            ..Default::default()
+        };
+        let (_spirc, task) = Spirc::new(connect_config, session, player, Box::new(mixer));
+        task.await;
+
+        Ok(())
+    }
+
+    /// A sink to hand to librespot's `Player::new`.
+    pub fn sink(&self) -> PipelineSink {
+        PipelineSink {
+            to: self.sender.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+pub struct PipelineSink {
+    to: SyncSender<PipeMessage>,
+    clock: Arc<PlaybackClock>,
+}
+
+impl Sink for PipelineSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.clock.set_paused(false);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.clock.set_paused(true);
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, _converter: &mut Converter) -> SinkResult<()> {
+        let samples = match packet.samples() {
+            Ok(samples) => samples,
+            Err(e) => return Err(SinkError::InvalidParams(e.to_string())),
+        };
+
+        self.clock
+            .queue((samples.len() / NUM_CHANNELS as usize) as u64);
+        for sample in samples {
+            if let Err(e) = self.to.send(PipeMessage::Sample(*sample as Sample)) {
+                return Err(SinkError::NotConnected(format!("pipeline closed: {e}")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Drives a `Sink` the way librespot's player does, with a generated tone
+/// instead of a Spotify stream, so the bridge can be exercised without an
+/// account.
+pub struct MockSinkDriver {
+    frequency: f64,
+    amplitude: f64,
+    packet_frames: usize,
+    phase: f64,
+}
+
+impl MockSinkDriver {
+    pub fn new(frequency: f64, amplitude: f64) -> Self {
+        Self {
+            frequency,
+            amplitude,
+            // librespot hands over roughly one decoded ogg page at a time
+            packet_frames: 1024,
+            phase: 0.0,
+        }
+    }
+
+    /// Plays `duration` of the tone into `sink` between a start and a stop,
+    /// returning the number of frames written.
+    pub fn play(&mut self, sink: &mut dyn Sink, duration: Duration) -> SinkResult<u64> {
+        let total_frames = (duration.as_secs_f64() * SAMPLE_RATE as f64) as u64;
+        let mut converter = Converter::new(None);
+        let mut written = 0;
+
+        sink.start()?;
+        while written < total_frames {
+            let frames = (total_frames - written).min(self.packet_frames as u64);
+            sink.write(self.next_packet(frames as usize), &mut converter)?;
+            written += frames;
+        }
+        sink.stop()?;
+
+        Ok(written)
+    }
+
+    fn next_packet(&mut self, frames: usize) -> AudioPacket {
+        let step = TAU * self.frequency / SAMPLE_RATE as f64;
+        let mut samples = Vec::with_capacity(frames * NUM_CHANNELS as usize);
+        for _ in 0..frames {
+            let sample = self.amplitude * self.phase.sin();
+            samples.extend((0..NUM_CHANNELS).map(|_| sample));
+            self.phase = (self.phase + step) % TAU;
+        }
+
+        AudioPacket::Samples(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{drain::DrainStats, pipeline::TransformCtx};
+    use std::sync::{mpsc::Receiver, Mutex};
+
+    /// Keeps everything it's given.
+    struct CollectDrain {
+        samples: Mutex<Vec<Sample>>,
+    }
+
+    impl Drain for CollectDrain {
+        fn init(&self, _ctx: &TransformCtx) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn drain(
+            &self,
+            ctx: &TransformCtx,
+            from: Receiver<Sample>,
+            clock: Arc<PlaybackClock>,
+        ) -> Result<(), String> {
+            let mut samples = self.samples.lock().unwrap();
+            for sample in from {
+                samples.push(sample);
+                if samples.len() % ctx.channels as usize == 0 {
+                    clock.advance(1);
+                }
+            }
+            Ok(())
+        }
+
+        fn purge(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn stats(&self) -> DrainStats {
+            DrainStats::default()
+        }
+    }
+
+    #[test]
+    fn sink_feeds_the_pipeline() {
+        let ctx = TransformCtx {
+            window_size: 1024,
+            fitting_buffer: 4096,
+            sample_rate: SAMPLE_RATE,
+            channels: NUM_CHANNELS as u16,
+        };
+        let drain = Arc::new(CollectDrain {
+            samples: Mutex::new(Vec::new()),
+        });
+        let clock = Arc::new(PlaybackClock::new());
+        let (bridge, handles) =
+            LibrespotBridge::spawn(AudioPipeline::new(ctx, &[]), drain.clone(), clock.clone())
+                .unwrap();
+
+        let mut sink = bridge.sink();
+        let frames = MockSinkDriver::new(440.0, 0.5)
+            .play(&mut sink, Duration::from_millis(100))
+            .unwrap();
+        assert!(clock.is_paused());
+        drop(sink);
+        drop(bridge);
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        assert_eq!(frames, SAMPLE_RATE as u64 / 10);
+        assert_eq!(clock.position(), Duration::from_millis(100));
+        let samples = drain.samples.lock().unwrap();
+        assert_eq!(samples.len(), frames as usize * NUM_CHANNELS as usize);
+        // the same tone in both channels, through unchanged
+        let step = TAU * 440.0 / SAMPLE_RATE as f64;
+        for (i, frame) in samples.chunks(NUM_CHANNELS as usize).enumerate() {
+            let expected = (0.5 * (step * i as f64).sin()) as Sample;
+            assert!(frame.iter().all(|sample| (sample - expected).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn config_defaults_the_device_name() {
+        let config: SpotifyConfig =
+            toml::from_str("username = \"someone\"\npassword = \"hunter2\"").unwrap();
+        assert_eq!(config.device_name, "audio_server");
+    }
+}
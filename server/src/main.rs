@@ -1,13 +1,345 @@
+// `return match ...;` and `-> ()` on closures are how this codebase spells
+// things out
+#![allow(clippy::needless_return, clippy::unused_unit)]
+
+pub mod art;
+pub mod browse;
 pub mod core;
+#[cfg(feature = "cpal")]
+pub mod device_drain;
+pub mod duplicates;
 pub mod fs_provider;
+pub mod history;
+pub mod library;
+pub mod library_db;
+#[cfg(feature = "librespot")]
+pub mod librespot_bridge;
+pub mod null_drain;
+pub mod scanner;
+pub mod scrobble;
+pub mod search;
+pub mod watcher;
+pub mod wav_drain;
 
-use crate::core::provider::{ReadableProvider, WriteableProvider};
+use crate::core::{
+    drain::Drain,
+    gateway::HttpGateway,
+    meter::MeterTap,
+    params::{PipelineControls, StageControls},
+    pipeline::{AudioPipe, AudioPipeline},
+    pipeline_config::{watch_config, ConfigError, PipelineConfig, StageRegistry},
+    playback::ObservablePlaybackState,
+    player::{Player, TrackEnd},
+    session::{PlaybackSession, SessionPersister},
+    smart_playlist::SmartPlaylistRefresher,
+    volume::SoftMixer,
+};
 use fs_provider::FsAudioProvider;
+use history::PlayRecorder;
+use library::Library;
+use scanner::{LibraryConfig, LibraryScanner};
+use scrobble::LocalScrobbler;
+use std::{
+    env, process,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use watcher::LibraryWatcher;
+
+const PORT: u16 = 8000;
+const SESSION_DEBOUNCE: Duration = Duration::from_millis(500);
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+const VOLUME_RAMP: Duration = Duration::from_millis(20);
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const METER_RATE: f32 = 30.0;
+const METER_BANDS: usize = 32;
+const LIBRARY_DEBOUNCE: Duration = Duration::from_secs(2);
+const SMART_PLAYLIST_DEBOUNCE: Duration = Duration::from_secs(2);
+const SMART_PLAYLIST_INTERVAL: Duration = Duration::from_secs(60);
+
+#[cfg(feature = "cpal")]
+fn output_drain() -> Arc<dyn Drain> {
+    Arc::new(device_drain::DeviceDrain::new(
+        None,
+        Duration::from_millis(200),
+    ))
+}
+
+#[cfg(not(feature = "cpal"))]
+fn output_drain() -> Arc<dyn Drain> {
+    Arc::new(null_drain::NullDrain::paced())
+}
+
+/// The configured stages, with the mixer after them so volume applies to
+/// whatever they produce, and then the meter so it shows what's heard.
+fn build_pipeline(
+    registry: &StageRegistry,
+    config: &PipelineConfig,
+    mixer: &Arc<dyn AudioPipe>,
+    meter: &Arc<dyn AudioPipe>,
+) -> Result<(AudioPipeline, Vec<StageControls>), ConfigError> {
+    let mut pipes = Vec::new();
+    let mut controls = Vec::new();
+    for stage in registry.build_stages(config)? {
+        pipes.push(stage.pipe);
+        controls.extend(stage.controls);
+    }
+    pipes.push(mixer.clone());
+    pipes.push(meter.clone());
+    let pipe_refs: Vec<_> = pipes.iter().collect();
+
+    Ok((
+        AudioPipeline::new(config.transform_ctx(), &pipe_refs),
+        controls,
+    ))
+}
+
+/// Plays as a Spotify Connect device when there's a `spotify.toml`,
+/// through its own run of the configured stages and its own output. Volume
+/// comes from the Spotify app rather than the mixer, and config reloads
+/// don't reach it.
+#[cfg(feature = "librespot")]
+fn spawn_spotify(
+    registry: &StageRegistry,
+    config: &PipelineConfig,
+    spotify_config_path: &std::path::Path,
+) -> Result<(), String> {
+    let spotify_config = match librespot_bridge::SpotifyConfig::load(spotify_config_path)? {
+        Some(spotify_config) => spotify_config,
+        None => return Ok(()),
+    };
+
+    let pipes: Vec<_> = match registry.build_stages(config) {
+        Ok(stages) => stages.into_iter().map(|stage| stage.pipe).collect(),
+        Err(e) => return Err(e.to_string()),
+    };
+    let pipe_refs: Vec<_> = pipes.iter().collect();
+    let pipeline = AudioPipeline::new(config.transform_ctx(), &pipe_refs);
+    let (bridge, _handles) = librespot_bridge::LibrespotBridge::spawn(
+        pipeline,
+        output_drain(),
+        Arc::new(core::clock::PlaybackClock::new()),
+    )?;
+
+    tokio::spawn(async move {
+        if let Err(e) = bridge.connect(&spotify_config).await {
+            eprintln!("{e}");
+        }
+    });
+    Ok(())
+}
 
-fn main() {
+/// Re-hashes stored audio, for `audio_server check`. Exits non-zero if
+/// anything's wrong, so it can be run from cron or the like.
+fn check_storage(provider: &FsAudioProvider) -> i32 {
+    let report = match provider.check() {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("error checking audio: {e:?}");
+            return 2;
+        }
+    };
+
+    for hash in report.corrupt.iter() {
+        println!("corrupt: {hash}");
+    }
+    for id in report.missing.iter() {
+        println!("missing: {id}");
+    }
+    for hash in report.orphaned.iter() {
+        println!("orphaned: {hash}");
+    }
+    println!(
+        "checked {} blobs: {} corrupt, {} missing, {} orphaned",
+        report.checked,
+        report.corrupt.len(),
+        report.missing.len(),
+        report.orphaned.len()
+    );
+
+    return match report.is_ok() {
+        true => 0,
+        false => 1,
+    };
+}
+
+#[tokio::main]
+async fn main() {
     let mut fs_provider = FsAudioProvider::new("./public");
+    // checking only reads, so it leaves temp files and unmigrated audio be
+    if env::args().nth(1).as_deref() == Some("check") {
+        fs_provider.load_index().unwrap();
+        process::exit(check_storage(&fs_provider));
+    }
     fs_provider.init().unwrap();
 
-    let audio_reader = fs_provider.get("sine").unwrap();
-    fs_provider.set("sine_copy", audio_reader).unwrap();
+    let library = Arc::new(RwLock::new(
+        Library::open(&fs_provider.library_db_path()).unwrap(),
+    ));
+    // before the session is restored, so its tracks are there to find
+    let scanner = LibraryConfig::load(&fs_provider.library_config_path())
+        .unwrap()
+        .map(|config| {
+            let mut scanner = LibraryScanner::new(&config, &library.read().unwrap()).unwrap();
+            match scanner.scan(&library) {
+                Ok(summary) => println!("scanned {}: {summary:?}", scanner.root().display()),
+                Err(e) => eprintln!("error scanning library: {e}"),
+            }
+            scanner
+        });
+    let playback = Arc::new(Mutex::new(ObservablePlaybackState::new()));
+
+    let config_path = fs_provider.pipeline_config_path();
+    // a broken config is reported and left for the next reload to fix,
+    // same as one broken while running
+    let mut config = match config_path.exists() {
+        true => PipelineConfig::load(&config_path).unwrap_or_else(|e| {
+            eprintln!("not using pipeline config: {e}");
+            PipelineConfig::default()
+        }),
+        false => PipelineConfig::default(),
+    };
+    let registry = StageRegistry::new();
+    let mixer: Arc<dyn AudioPipe> = Arc::new(SoftMixer::new(
+        playback.lock().unwrap().volume_control(),
+        VOLUME_RAMP,
+    ));
+    let meter_playback = playback.clone();
+    let meter: Arc<dyn AudioPipe> =
+        Arc::new(MeterTap::new(METER_RATE, METER_BANDS, move |reading| {
+            // a dropped reading just means one less visualizer frame, which beats
+            // holding up the audio thread
+            if let Ok(playback) = meter_playback.try_lock() {
+                playback.publish_meter(reading);
+            }
+        }));
+    let pipeline_controls = Arc::new(PipelineControls::new());
+    let (pipeline, controls) = match build_pipeline(&registry, &config, &mixer, &meter) {
+        Ok(built) => built,
+        Err(e) => {
+            eprintln!("not using pipeline config: {e}");
+            config = PipelineConfig::default();
+            match build_pipeline(&registry, &config, &mixer, &meter) {
+                Ok(built) => built,
+                Err(e) => {
+                    eprintln!("error building default pipeline: {e}");
+                    process::exit(1);
+                }
+            }
+        }
+    };
+    #[cfg(feature = "librespot")]
+    if let Err(e) = spawn_spotify(&registry, &config, &fs_provider.spotify_config_path()) {
+        eprintln!("not playing spotify: {e}");
+    }
+    pipeline_controls.replace(controls);
+    let clock = playback.lock().unwrap().clock();
+    let track_end_playback = playback.clone();
+    let (player, _handles) = Player::spawn(pipeline, output_drain(), clock, move |end| {
+        let mut playback = track_end_playback.lock().unwrap();
+        match end {
+            TrackEnd::Finished => playback.track_finished(),
+            TrackEnd::Failed => playback.track_failed(),
+        }
+    })
+    .unwrap();
+
+    let reload_player = player.clone();
+    let reload_controls = pipeline_controls.clone();
+    watch_config(config_path, CONFIG_POLL_INTERVAL, move |config| {
+        match build_pipeline(&registry, &config, &mixer, &meter) {
+            Ok((pipeline, controls)) => {
+                reload_controls.carry_over(&controls);
+                // the running stages keep their controls if it's turned down
+                match reload_player.set_pipeline(pipeline) {
+                    Ok(_) => reload_controls.replace(controls),
+                    Err(e) => eprintln!("not reloading pipeline: {e}"),
+                }
+            }
+            Err(e) => eprintln!("not reloading pipeline: {e}"),
+        }
+    });
+
+    let persister = SessionPersister::spawn(fs_provider.session_path(), SESSION_DEBOUNCE);
+    let recorder = PlayRecorder::spawn(
+        library.clone(),
+        vec![Box::new(LocalScrobbler::new(
+            &fs_provider.scrobble_log_path(),
+        ))],
+    );
+    {
+        let mut playback = playback.lock().unwrap();
+        playback.on_play_ended(recorder.listener());
+
+        let track_player = player.clone();
+        playback.on_current_track_changed(move |track| {
+            if let Some(track) = track {
+                track_player.load(track.clone());
+            }
+        });
+        let playing_player = player.clone();
+        playback.on_is_playing_changed(move |playing| match playing {
+            true => playing_player.play(),
+            false => playing_player.pause(),
+        });
+        let seek_player = player.clone();
+        playback.on_seek(move |position| seek_player.seek(*position));
+
+        match PlaybackSession::load(&fs_provider.session_path()) {
+            Ok(Some(session)) => playback.restore(&session, &library.read().unwrap()),
+            Ok(None) => (),
+            Err(e) => eprintln!("discarding playback session: {e}"),
+        }
+        playback.on_session_changed(persister.listener());
+    }
+
+    // the position moves without notifying anyone, so save it periodically
+    // while something is playing
+    let save_session = persister.listener();
+    let session_playback = playback.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SESSION_SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let session = {
+                let playback = session_playback.lock().unwrap();
+                if !playback.is_playing() {
+                    continue;
+                }
+                playback.snapshot()
+            };
+            save_session(&session);
+        }
+    });
+
+    let provider = Arc::new(fs_provider);
+    let gateway = HttpGateway::new(
+        provider.clone(),
+        library.clone(),
+        playback,
+        pipeline_controls,
+    );
+    let smart_playlists = SmartPlaylistRefresher::spawn(
+        provider,
+        library.clone(),
+        SMART_PLAYLIST_DEBOUNCE,
+        SMART_PLAYLIST_INTERVAL,
+        gateway.library_listener(),
+    );
+    library
+        .write()
+        .unwrap()
+        .on_change(smart_playlists.listener());
+    let _watcher = scanner.and_then(|scanner| {
+        LibraryWatcher::spawn(
+            scanner,
+            library,
+            LIBRARY_DEBOUNCE,
+            gateway.library_listener(),
+        )
+        .map_err(|e| eprintln!("not watching library: {e}"))
+        .ok()
+    });
+
+    gateway.serve(PORT).await;
 }
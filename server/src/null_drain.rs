@@ -0,0 +1,175 @@
+use crate::core::{
+    clock::PlaybackClock,
+    drain::{Drain, DrainMetrics, DrainStats},
+    pipeline::{Sample, TransformCtx},
+};
+use std::{
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Discards everything it recieves. When paced, it consumes samples at the
+/// rate a sound device would, so headless machines still see realistic
+/// backpressure, underruns and latency.
+pub struct NullDrain {
+    paced: bool,
+    metrics: DrainMetrics,
+}
+
+impl Default for NullDrain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NullDrain {
+    const PERIOD: Duration = Duration::from_millis(10);
+
+    pub fn new() -> Self {
+        Self {
+            paced: false,
+            metrics: DrainMetrics::new(),
+        }
+    }
+
+    pub fn paced() -> Self {
+        Self {
+            paced: true,
+            metrics: DrainMetrics::new(),
+        }
+    }
+
+    fn drain_unpaced(&self, ctx: &TransformCtx, from: Receiver<Sample>, clock: &PlaybackClock) {
+        let channels = ctx.channels.max(1) as u64;
+        let mut samples = 0;
+        for _ in from {
+            samples += 1;
+            if samples == channels {
+                self.metrics.add_frames(1);
+                clock.advance(1);
+                samples = 0;
+            }
+        }
+    }
+
+    fn drain_paced(&self, ctx: &TransformCtx, from: Receiver<Sample>, clock: &PlaybackClock) {
+        let channels = ctx.channels.max(1) as usize;
+        let period_frames = (ctx.sample_rate as u128 * Self::PERIOD.as_millis() / 1000) as usize;
+        let period_samples = period_frames.max(1) * channels;
+        self.metrics.set_latency(Self::PERIOD);
+
+        let mut deadline = Instant::now();
+        let mut flowing = false;
+        loop {
+            deadline += Self::PERIOD;
+
+            let mut recieved = 0;
+            while recieved < period_samples {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match from.recv_timeout(timeout) {
+                    Ok(_) => recieved += 1,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        self.metrics.add_frames((recieved / channels) as u64);
+                        clock.advance((recieved / channels) as u64);
+                        return;
+                    }
+                }
+            }
+
+            // a short period only counts once audio has started flowing,
+            // otherwise pipeline startup would always show as an underrun.
+            // running dry while paused is expected.
+            if recieved < period_samples && flowing && !clock.is_paused() {
+                self.metrics.add_underrun();
+            }
+            flowing |= recieved > 0;
+            self.metrics.add_frames((recieved / channels) as u64);
+            clock.advance((recieved / channels) as u64);
+
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+impl Drain for NullDrain {
+    fn init(&self, _ctx: &TransformCtx) -> Result<(), String> {
+        self.metrics.reset();
+        Ok(())
+    }
+
+    fn drain(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        clock: Arc<PlaybackClock>,
+    ) -> Result<(), String> {
+        match self.paced {
+            true => self.drain_paced(ctx, from, &clock),
+            false => self.drain_unpaced(ctx, from, &clock),
+        }
+        Ok(())
+    }
+
+    fn purge(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn stats(&self) -> DrainStats {
+        self.metrics.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    fn ctx() -> TransformCtx {
+        TransformCtx {
+            window_size: 64,
+            fitting_buffer: 256,
+            sample_rate: 1000,
+            channels: 2,
+        }
+    }
+
+    fn run(drain: NullDrain, samples: usize) -> (NullDrain, Arc<PlaybackClock>) {
+        let ctx = ctx();
+        let clock = Arc::new(PlaybackClock::new());
+        clock.start_track(ctx.sample_rate, 0, None);
+        drain.init(&ctx).unwrap();
+
+        let (sender, reciever) = sync_channel(samples);
+        for _ in 0..samples {
+            sender.send(0.0).unwrap();
+        }
+        drop(sender);
+        drain.drain(&ctx, reciever, clock.clone()).unwrap();
+        (drain, clock)
+    }
+
+    #[test]
+    fn advances_the_clock_by_whole_frames() {
+        // the odd sample out is half a frame, never played
+        let (drain, clock) = run(NullDrain::new(), 201);
+        assert_eq!(clock.position_frames(), 100);
+        assert_eq!(clock.position(), Duration::from_millis(100));
+        assert_eq!(drain.stats().frames, 100);
+    }
+
+    #[test]
+    fn paced_drain_takes_as_long_as_playing_would() {
+        let started = Instant::now();
+        let (drain, clock) = run(NullDrain::paced(), 100);
+        assert_eq!(clock.position_frames(), 50);
+        assert_eq!(drain.stats().frames, 50);
+        assert_eq!(drain.stats().latency, NullDrain::PERIOD);
+        // ten frames a period, and the last partial one returns early
+        assert!(started.elapsed() >= NullDrain::PERIOD * 4);
+    }
+}
@@ -0,0 +1,853 @@
+use crate::{
+    art::{self, art_url},
+    core::{
+        audio::AudioReader,
+        fingerprint::{Fingerprint, FingerprintBuilder},
+        loudness::{LoudnessAnalysis, LoudnessAnalyzer},
+        pipeline::Sample,
+    },
+    library::{FileStamp, IndexedFile, Library, LibraryBatch, Track},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, Metadata},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+use symphonia::{
+    core::{
+        audio::SampleBuffer,
+        formats::FormatOptions,
+        io::MediaSourceStream,
+        meta::{MetadataOptions, StandardTagKey, Tag},
+        probe::Hint,
+    },
+    default::get_probe,
+};
+
+/// Where the music to index lives, as written in `library.toml`:
+///
+/// ```toml
+/// root = "/home/me/Music"
+/// analyze_loudness = true
+/// fingerprint = true
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LibraryConfig {
+    pub root: PathBuf,
+    /// Decodes every new or changed file to measure its loudness, which is
+    /// slow on a first scan of a big collection.
+    #[serde(default)]
+    pub analyze_loudness: bool,
+    /// Decodes the start of every file to fingerprint it for finding
+    /// duplicates, including files already indexed without one.
+    #[serde(default)]
+    pub fingerprint: bool,
+}
+
+impl LibraryConfig {
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("error reading library config: {e}")),
+        };
+
+        return match toml::from_str(&contents) {
+            Ok(config) => Ok(Some(config)),
+            Err(e) => Err(format!("error parsing library config: {e}")),
+        };
+    }
+}
+
+/// What a scan changed.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct ScanSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    /// Files that aren't audio symphonia can read.
+    pub skipped: usize,
+}
+
+/// Indexes the audio files under a directory into the library, in place.
+///
+/// Ids are hashed from each file's path relative to the root, so they stay
+/// the same across rescans and restarts, and survive the root moving. Only
+/// files that are new or whose modification time or size changed since the
+/// last scan are read again, going by what's stored in the library.
+///
+/// Changes are written to the library in batches, each in one transaction.
+pub struct LibraryScanner {
+    root: PathBuf,
+    analyze_loudness: bool,
+    fingerprint: bool,
+    // tracks with a fingerprint, while fingerprinting
+    fingerprinted: HashSet<u64>,
+    files: HashMap<PathBuf, IndexedFile>,
+    pending: LibraryBatch,
+    pending_changes: Vec<LibraryChange>,
+    changes: Vec<LibraryChange>,
+}
+
+/// A track the scanner added, re-read or dropped.
+#[derive(Clone, Copy, Debug)]
+pub enum LibraryChange {
+    Added(u64),
+    Updated(u64),
+    Removed(u64),
+}
+
+impl LibraryScanner {
+    /// Files read before writing what was found to the library.
+    const BATCH_SIZE: usize = 256;
+
+    pub fn new(config: &LibraryConfig, library: &Library) -> Result<Self, String> {
+        let files = match library.files() {
+            Ok(files) => files.into_iter().collect(),
+            Err(e) => return Err(format!("error loading scanned files: {e}")),
+        };
+        let fingerprinted = match config.fingerprint {
+            true => match library.fingerprinted() {
+                Ok(fingerprinted) => fingerprinted,
+                Err(e) => return Err(format!("error loading fingerprinted tracks: {e}")),
+            },
+            false => HashSet::new(),
+        };
+
+        Ok(Self {
+            root: config.root.clone(),
+            analyze_loudness: config.analyze_loudness,
+            fingerprint: config.fingerprint,
+            fingerprinted,
+            files,
+            pending: LibraryBatch::default(),
+            pending_changes: Vec::new(),
+            changes: Vec::new(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whatever changed in the library since this was last called.
+    pub fn take_changes(&mut self) -> Vec<LibraryChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Walks the whole root, bringing the library up to date with it.
+    pub fn scan(&mut self, library: &RwLock<Library>) -> Result<ScanSummary, String> {
+        let mut found = Vec::new();
+        if let Err(e) = walk(&self.root, &mut found) {
+            return Err(format!("error walking {}: {e}", self.root.display()));
+        }
+
+        let mut summary = ScanSummary::default();
+        let mut seen = HashSet::with_capacity(found.len());
+        let mut read = HashSet::new();
+        let mut art_dirs = HashSet::new();
+        let mut analyses = Vec::new();
+        for (path, metadata) in found {
+            seen.insert(path.clone());
+            let stamp = match metadata.modified() {
+                Ok(modified) => FileStamp {
+                    modified,
+                    size: metadata.len(),
+                },
+                Err(_) => continue,
+            };
+            match self.files.get(&path) {
+                // read again just to be fingerprinted
+                Some(IndexedFile {
+                    track_id: Some(id), ..
+                }) if self.fingerprint && !self.fingerprinted.contains(id) => (),
+                Some(file) if file.stamp == stamp => {
+                    match file.track_id {
+                        Some(_) => summary.unchanged += 1,
+                        None => summary.skipped += 1,
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            if art::is_cover_file(&path) {
+                art_dirs.extend(path.parent().map(Path::to_path_buf));
+            }
+            read.insert(path.clone());
+
+            let indexed = self.is_indexed(&path);
+            match self.read_file(&path, stamp, true, library) {
+                Some((track, analysis)) => {
+                    match indexed {
+                        true => summary.updated += 1,
+                        false => summary.added += 1,
+                    }
+                    if let Some(analysis) = analysis {
+                        analyses.push((track, analysis));
+                    }
+                }
+                None => {
+                    // it was audio before, so it's gone from the library now
+                    summary.removed += indexed as usize;
+                    summary.skipped += 1;
+                }
+            }
+
+            if self.pending.len() >= Self::BATCH_SIZE {
+                self.commit(library)?;
+            }
+        }
+
+        let removed: Vec<PathBuf> = self
+            .files
+            .keys()
+            .filter(|path| !seen.contains(*path))
+            .cloned()
+            .collect();
+        for path in removed {
+            if art::is_cover_file(&path) {
+                art_dirs.extend(path.parent().map(Path::to_path_buf));
+            }
+            summary.removed += self.forget(&path) as usize;
+        }
+        summary.updated += self.refresh_art(&art_dirs, &read, library)?;
+        self.commit(library)?;
+
+        self.set_album_gains(&analyses, library)?;
+        Ok(summary)
+    }
+
+    /// Reads one file into the library, e.g. after a change under the root.
+    /// Returns whether it's audio that's now indexed.
+    pub fn scan_file(&mut self, path: &Path, library: &RwLock<Library>) -> bool {
+        // skipped the same as they are when walking
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        if relative
+            .iter()
+            .any(|name| name.to_string_lossy().starts_with('.'))
+        {
+            return false;
+        }
+
+        let stamp = match fs::metadata(path).and_then(|m| Ok((m.modified()?, m.len()))) {
+            Ok((modified, size)) => FileStamp { modified, size },
+            Err(_) => return false,
+        };
+        let indexed = match self.files.get(path) {
+            Some(file) if file.stamp == stamp => return file.track_id.is_some(),
+            _ => self.read_file(path, stamp, true, library).is_some(),
+        };
+
+        let refreshed = match art::is_cover_file(path) {
+            true => self.refresh_art_of(path, library),
+            false => Ok(0),
+        };
+        return match refreshed.and_then(|_| self.commit(library)) {
+            Ok(_) => indexed,
+            Err(e) => {
+                eprintln!("{e}");
+                false
+            }
+        };
+    }
+
+    /// Forgets a file that's no longer there. Returns whether it was indexed.
+    pub fn remove(&mut self, path: &Path, library: &RwLock<Library>) -> bool {
+        let removed = self.forget(path);
+        let refreshed = match art::is_cover_file(path) {
+            true => self.refresh_art_of(path, library),
+            false => Ok(0),
+        };
+        return match refreshed.and_then(|_| self.commit(library)) {
+            Ok(_) => removed,
+            Err(e) => {
+                eprintln!("{e}");
+                false
+            }
+        };
+    }
+
+    /// Whether `dir` is a folder the scanner has seen files in.
+    pub fn is_known_dir(&self, dir: &Path) -> bool {
+        self.files
+            .keys()
+            .any(|path| path != dir && path.starts_with(dir))
+    }
+
+    /// Re-reads the tracks in folders whose cover file came, went or
+    /// changed, other than those in `read` already, so their art is up to
+    /// date. Returns how many were re-read.
+    fn refresh_art(
+        &mut self,
+        dirs: &HashSet<PathBuf>,
+        read: &HashSet<PathBuf>,
+        library: &RwLock<Library>,
+    ) -> Result<usize, String> {
+        let stale: Vec<(PathBuf, FileStamp)> = self
+            .files
+            .iter()
+            .filter(|(path, file)| file.track_id.is_some() && !read.contains(*path))
+            .filter(|(path, _)| path.parent().is_some_and(|dir| dirs.contains(dir)))
+            .map(|(path, file)| (path.clone(), file.stamp))
+            .collect();
+
+        let mut refreshed = 0;
+        for (path, stamp) in stale {
+            // only the art can have changed, so no need to decode again
+            refreshed += self.read_file(&path, stamp, false, library).is_some() as usize;
+            if self.pending.len() >= Self::BATCH_SIZE {
+                self.commit(library)?;
+            }
+        }
+
+        Ok(refreshed)
+    }
+
+    fn refresh_art_of(&mut self, cover: &Path, library: &RwLock<Library>) -> Result<usize, String> {
+        let dirs = cover.parent().map(Path::to_path_buf).into_iter().collect();
+        self.refresh_art(&dirs, &HashSet::new(), library)
+    }
+
+    fn is_indexed(&self, path: &Path) -> bool {
+        self.files
+            .get(path)
+            .is_some_and(|file| file.track_id.is_some())
+    }
+
+    /// Queues removing a file and its track, if it was one.
+    fn forget(&mut self, path: &Path) -> bool {
+        let file = match self.files.remove(path) {
+            Some(file) => file,
+            None => return false,
+        };
+        self.pending.removed_files.push(path.to_path_buf());
+
+        return match file.track_id {
+            Some(id) => {
+                self.pending.removed.push(id);
+                self.pending_changes.push(LibraryChange::Removed(id));
+                true
+            }
+            None => false,
+        };
+    }
+
+    /// Writes out the pending batch. If that fails the files in it are
+    /// forgotten as well, so the next scan tries them again.
+    fn commit(&mut self, library: &RwLock<Library>) -> Result<(), String> {
+        let batch = std::mem::take(&mut self.pending);
+        let changes = std::mem::take(&mut self.pending_changes);
+        let paths: Vec<PathBuf> = batch.files.iter().map(|(path, _)| path.clone()).collect();
+
+        return match library.write().unwrap().apply(batch) {
+            Ok(_) => {
+                self.changes.extend(changes);
+                Ok(())
+            }
+            Err(e) => {
+                for path in paths {
+                    self.files.remove(&path);
+                }
+                Err(format!("error saving scanned files: {e}"))
+            }
+        };
+    }
+
+    /// With `decode`, also measures and fingerprints the audio, if the
+    /// scanner does those.
+    fn read_file(
+        &mut self,
+        path: &Path,
+        stamp: FileStamp,
+        decode: bool,
+        library: &RwLock<Library>,
+    ) -> Option<(Track, Option<LoudnessAnalysis>)> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let mut track = match read_track(path, relative) {
+            Ok(track) => track,
+            Err(_) => {
+                self.forget(path);
+                let file = IndexedFile {
+                    stamp,
+                    track_id: None,
+                };
+                self.files.insert(path.to_path_buf(), file);
+                self.pending.files.push((path.to_path_buf(), file));
+                return None;
+            }
+        };
+
+        // keep what was measured before unless it's being measured again
+        let previous = library.read().unwrap().get_track(track.id).ok();
+        if let Some(previous) = previous {
+            track.replay_gain = previous.replay_gain;
+            track.loudness = previous.loudness;
+        }
+
+        let (analysis, fingerprint) = match decode {
+            true => match analyze(path, self.analyze_loudness, self.fingerprint) {
+                Ok(analyzed) => analyzed,
+                Err(e) => {
+                    eprintln!("not analyzing {}: {e}", path.display());
+                    (None, None)
+                }
+            },
+            false => (None, None),
+        };
+        if let Some(analysis) = analysis.as_ref() {
+            track.set_loudness(analysis);
+        }
+        if let Some(fingerprint) = fingerprint {
+            self.fingerprinted.insert(track.id);
+            self.pending.fingerprints.push((track.id, fingerprint));
+        }
+
+        self.pending_changes.push(match self.is_indexed(path) {
+            true => LibraryChange::Updated(track.id),
+            false => LibraryChange::Added(track.id),
+        });
+        let file = IndexedFile {
+            stamp,
+            track_id: Some(track.id),
+        };
+        self.files.insert(path.to_path_buf(), file);
+        self.pending.files.push((path.to_path_buf(), file));
+        self.pending.tracks.push(track.clone());
+
+        Some((track, analysis))
+    }
+
+    /// Album gain needs every track of the album measured, so it's only
+    /// worked out for albums that were measured in full by the same scan,
+    /// which a first scan of a library always does.
+    fn set_album_gains(
+        &mut self,
+        analyses: &[(Track, LoudnessAnalysis)],
+        library: &RwLock<Library>,
+    ) -> Result<(), String> {
+        let mut albums: HashMap<AlbumKey, Vec<(u64, &LoudnessAnalysis)>> = HashMap::new();
+        for (track, analysis) in analyses.iter() {
+            if let Some(key) = album_key(track) {
+                albums.entry(key).or_default().push((track.id, analysis));
+            }
+        }
+
+        let mut library = library.write().unwrap();
+        for (key, measured) in albums {
+            let album_tracks = library
+                .tracks()
+                .filter(|track| album_key(track).as_ref() == Some(&key))
+                .count();
+            if album_tracks != measured.len() {
+                continue;
+            }
+            if let Err(e) = library.set_album_gain(&measured) {
+                return Err(format!("error saving album gain: {e}"));
+            }
+            self.changes
+                .extend(measured.iter().map(|(id, _)| LibraryChange::Updated(*id)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Album artist, or artist if there isn't one, and album title.
+type AlbumKey = (Option<String>, String);
+
+fn album_key(track: &Track) -> Option<AlbumKey> {
+    let artist = track.album_artist.clone().or_else(|| track.artist.clone());
+    track.album.clone().map(|album| (artist, album))
+}
+
+/// Every regular file under `dir`, skipping hidden entries. Symlinks aren't
+/// followed, so a link back up the tree can't loop forever.
+fn walk(dir: &Path, found: &mut Vec<(PathBuf, Metadata)>) -> Result<(), std::io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            // one unreadable folder shouldn't stop the rest being indexed
+            if let Err(e) = walk(&entry.path(), found) {
+                eprintln!("skipping {}: {e}", entry.path().display());
+            }
+        } else if file_type.is_file() {
+            found.push((entry.path(), entry.metadata()?));
+        }
+    }
+
+    Ok(())
+}
+
+/// A 64-bit FNV-1a hash, which unlike the std hasher is the same from one
+/// build to the next.
+pub fn stable_id(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Decodes as much of a file as the analyses asked for need, all of it to
+/// measure loudness, just the start to fingerprint.
+fn analyze(
+    path: &Path,
+    loudness: bool,
+    fingerprint: bool,
+) -> Result<(Option<LoudnessAnalysis>, Option<Fingerprint>), String> {
+    if !loudness && !fingerprint {
+        return Ok((None, None));
+    }
+
+    let mut reader = AudioReader::open(path)?;
+    let spec = reader.signal_spec();
+    let channels = spec.channels.count() as u16;
+    let mut analyzer = loudness.then(|| LoudnessAnalyzer::new(spec.rate, channels));
+    let mut fingerprinter = fingerprint.then(|| FingerprintBuilder::new(spec.rate, channels));
+
+    let mut sample_buffer: Option<SampleBuffer<Sample>> = None;
+    while analyzer.is_some() || fingerprinter.as_ref().is_some_and(|f| !f.is_full()) {
+        let result = reader.consume_next(|buffer| {
+            let spec = *buffer.spec();
+            let samples = buffer.capacity() * spec.channels.count();
+            if sample_buffer
+                .as_ref()
+                .is_none_or(|b| b.capacity() < samples)
+            {
+                sample_buffer = Some(SampleBuffer::new(buffer.capacity() as u64, spec));
+            }
+            sample_buffer.as_mut().unwrap().copy_interleaved_ref(buffer);
+            Ok(())
+        });
+        match result {
+            Ok(_) => (),
+            Err(e) if e == "EOF" => break,
+            Err(e) => return Err(format!("error decoding: {e}")),
+        }
+
+        let samples = sample_buffer.as_ref().unwrap().samples();
+        if let Some(analyzer) = analyzer.as_mut() {
+            analyzer.add(samples);
+        }
+        if let Some(fingerprinter) = fingerprinter.as_mut() {
+            fingerprinter.add(samples);
+        }
+    }
+
+    Ok((
+        analyzer.map(LoudnessAnalyzer::finish),
+        fingerprinter.map(FingerprintBuilder::finish),
+    ))
+}
+
+/// Probes `path` and reads its tags, falling back on the
+/// `Artist/Album/NN Title.ext` layout for whatever isn't tagged.
+pub fn read_track(path: &Path, relative: &Path) -> Result<Track, String> {
+    let source_file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("error opening audio file: {e}")),
+    };
+
+    let media = MediaSourceStream::new(Box::new(source_file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut probed = match get_probe().format(
+        &hint,
+        media,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) {
+        Ok(probed) => probed,
+        Err(e) => return Err(format!("error probing audio format: {e}")),
+    };
+
+    // tags ahead of the container, like ID3v2, and then the container's own,
+    // which win where both have a value
+    let mut tags = TrackTags::default();
+    let mut has_art = false;
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        tags.read(revision.tags());
+        has_art |= !revision.visuals().is_empty();
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.read(revision.tags());
+        has_art |= !revision.visuals().is_empty();
+    }
+    has_art = has_art || path.parent().and_then(art::cover_file).is_some();
+
+    let duration_ms = match probed.format.default_track() {
+        Some(track) => match (track.codec_params.n_frames, track.codec_params.sample_rate) {
+            (Some(frames), Some(rate)) => Some(frames * 1000 / rate as u64),
+            _ => None,
+        },
+        None => return Err("no audio track".to_string()),
+    };
+
+    let relative_key = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let mut folders = relative
+        .parent()
+        .into_iter()
+        .flat_map(|parent| parent.iter().rev())
+        .map(|name| name.to_string_lossy().to_string());
+    let folder_album = folders.next();
+    let folder_artist = folders.next();
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (stem_number, stem_title) = split_track_number(&stem);
+
+    let id = stable_id(&relative_key);
+    Ok(Track {
+        id,
+        title: tags.title.unwrap_or(stem_title),
+        artist: tags.artist.or_else(|| folder_artist.clone()),
+        album: tags.album.or(folder_album),
+        album_artist: tags.album_artist,
+        track_number: tags.track_number.or(stem_number),
+        disc_number: tags.disc_number,
+        genre: tags.genre,
+        year: tags.year,
+        duration_ms,
+        replay_gain: None,
+        loudness: None,
+        play_count: 0,
+        last_played: None,
+        added_at: None,
+        art_url: match has_art {
+            true => Some(art_url(id)),
+            false => None,
+        },
+        path: path.to_path_buf(),
+    })
+}
+
+#[derive(Default)]
+struct TrackTags {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    genre: Option<String>,
+    year: Option<u32>,
+}
+
+impl TrackTags {
+    fn read(&mut self, tags: &[Tag]) {
+        for tag in tags {
+            let value = tag.value.to_string().trim().to_string();
+            if value.is_empty() {
+                continue;
+            }
+
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => self.title = Some(value),
+                Some(StandardTagKey::Artist) => self.artist = Some(value),
+                Some(StandardTagKey::Album) => self.album = Some(value),
+                Some(StandardTagKey::AlbumArtist) => self.album_artist = Some(value),
+                Some(StandardTagKey::Genre) => self.genre = Some(value),
+                // "3/12" style, the total isn't needed
+                Some(StandardTagKey::TrackNumber) => {
+                    self.track_number = leading_number(&value).or(self.track_number)
+                }
+                Some(StandardTagKey::DiscNumber) => {
+                    self.disc_number = leading_number(&value).or(self.disc_number)
+                }
+                Some(StandardTagKey::Date) | Some(StandardTagKey::OriginalDate) => {
+                    self.year = self.year.or(leading_number(&value))
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn leading_number(value: &str) -> Option<u32> {
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// Splits `"03 - Title"` or `"03. Title"` into its number and title.
+fn split_track_number(stem: &str) -> (Option<u32>, String) {
+    let number = match leading_number(stem) {
+        Some(number) => number,
+        None => return (None, stem.to_string()),
+    };
+
+    let title = stem
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '-' || c == '.' || c == '_');
+    match title.is_empty() {
+        true => (None, stem.to_string()),
+        false => (Some(number), title.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::{WavSpec, WavWriter};
+    use std::{
+        process,
+        time::{Duration, SystemTime},
+    };
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("scanner-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("Artist/Album")).unwrap();
+        root
+    }
+
+    fn write_wav(path: &Path, frames: usize) {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(path, spec).unwrap();
+        for i in 0..frames {
+            writer.write_sample((i % 100) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn scanner(root: &Path, library: &RwLock<Library>) -> LibraryScanner {
+        let config = LibraryConfig {
+            root: root.to_path_buf(),
+            analyze_loudness: false,
+            fingerprint: false,
+        };
+        LibraryScanner::new(&config, &library.read().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rescans_skip_unchanged_files() {
+        let root = root("unchanged");
+        write_wav(&root.join("Artist/Album/01 One.wav"), 800);
+        write_wav(&root.join("Artist/Album/02 Two.wav"), 800);
+        fs::write(root.join("Artist/Album/notes.txt"), "not audio").unwrap();
+        let library = RwLock::new(Library::open_in_memory().unwrap());
+
+        let summary = scanner(&root, &library).scan(&library).unwrap();
+        assert_eq!((summary.added, summary.skipped), (2, 1));
+        let track = library
+            .read()
+            .unwrap()
+            .get_track(stable_id("Artist/Album/01 One.wav"))
+            .unwrap();
+        assert_eq!(track.title, "One");
+        assert_eq!(track.album.as_deref(), Some("Album"));
+        assert_eq!(track.duration_ms, Some(100));
+
+        // a new scanner goes by what the library stored
+        let mut scanner = scanner(&root, &library);
+        let summary = scanner.scan(&library).unwrap();
+        assert_eq!(
+            (
+                summary.added,
+                summary.updated,
+                summary.removed,
+                summary.unchanged,
+                summary.skipped
+            ),
+            (0, 0, 0, 2, 1)
+        );
+        assert!(scanner.take_changes().is_empty());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn rescans_pick_up_changed_files() {
+        let root = root("changed");
+        let one = root.join("Artist/Album/01 One.wav");
+        let two = root.join("Artist/Album/02 Two.wav");
+        write_wav(&one, 800);
+        write_wav(&two, 800);
+        let library = RwLock::new(Library::open_in_memory().unwrap());
+        let mut scanner = scanner(&root, &library);
+        scanner.scan(&library).unwrap();
+        scanner.take_changes();
+
+        // a new size, and the same size but touched
+        write_wav(&one, 1600);
+        File::options()
+            .write(true)
+            .open(&two)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        let summary = scanner.scan(&library).unwrap();
+        assert_eq!((summary.updated, summary.unchanged), (2, 0));
+        let id = stable_id("Artist/Album/01 One.wav");
+        assert_eq!(
+            library.read().unwrap().get_track(id).unwrap().duration_ms,
+            Some(200)
+        );
+        assert_eq!(scanner.take_changes().len(), 2);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn rescans_drop_removed_files() {
+        let root = root("removed");
+        write_wav(&root.join("Artist/Album/01 One.wav"), 800);
+        write_wav(&root.join("Artist/Album/02 Two.wav"), 800);
+        let library = RwLock::new(Library::open_in_memory().unwrap());
+        scanner(&root, &library).scan(&library).unwrap();
+
+        fs::remove_file(root.join("Artist/Album/02 Two.wav")).unwrap();
+        let mut scanner = scanner(&root, &library);
+        let summary = scanner.scan(&library).unwrap();
+        assert_eq!((summary.removed, summary.unchanged), (1, 1));
+
+        let id = stable_id("Artist/Album/02 Two.wav");
+        assert!(library.read().unwrap().get_track(id).is_err());
+        assert!(matches!(
+            scanner.take_changes()[..],
+            [LibraryChange::Removed(removed)] if removed == id
+        ));
+        assert!(library
+            .read()
+            .unwrap()
+            .files()
+            .unwrap()
+            .iter()
+            .all(|(path, _)| !path.ends_with("02 Two.wav")));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn track_numbers_come_off_file_names() {
+        assert_eq!(
+            split_track_number("03 - Title"),
+            (Some(3), "Title".to_string())
+        );
+        assert_eq!(
+            split_track_number("03. Title"),
+            (Some(3), "Title".to_string())
+        );
+        assert_eq!(split_track_number("1999"), (None, "1999".to_string()));
+        assert_eq!(split_track_number("Title"), (None, "Title".to_string()));
+    }
+}
@@ -0,0 +1,57 @@
+use crate::history::Play;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Somewhere plays that count get sent on to, like Last.fm or
+/// ListenBrainz. Called from the play recorder's thread, so it's fine to
+/// block on the network.
+pub trait ScrobbleSink: Send {
+    /// For error messages.
+    fn name(&self) -> &str;
+
+    fn scrobble(&self, play: &Play) -> Result<(), String>;
+}
+
+/// Stands in for an external scrobbler by appending each scrobble to a file
+/// as a line of JSON.
+pub struct LocalScrobbler {
+    path: PathBuf,
+}
+
+impl LocalScrobbler {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl ScrobbleSink for LocalScrobbler {
+    fn name(&self) -> &str {
+        "local scrobbler"
+    }
+
+    fn scrobble(&self, play: &Play) -> Result<(), String> {
+        let mut line = match serde_json::to_vec(play) {
+            Ok(line) => line,
+            Err(e) => return Err(format!("error serializing scrobble: {e}")),
+        };
+        line.push(b'\n');
+
+        let mut file = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+        {
+            Ok(file) => file,
+            Err(e) => return Err(format!("error opening scrobble log: {e}")),
+        };
+        return match file.write_all(&line) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("error writing scrobble log: {e}")),
+        };
+    }
+}
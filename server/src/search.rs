@@ -0,0 +1,533 @@
+use crate::library::Track;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+};
+
+/// An album as far as search is concerned: its name and whoever it's by,
+/// which is the album artist if it's tagged and the track artist if not.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct AlbumKey {
+    pub artist: Option<String>,
+    pub album: String,
+}
+
+impl AlbumKey {
+    pub fn of(track: &Track) -> Option<Self> {
+        track.album.clone().map(|album| Self {
+            artist: track.album_artist.clone().or_else(|| track.artist.clone()),
+            album,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AlbumResult {
+    #[serde(flatten)]
+    pub key: AlbumKey,
+    pub track_count: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ArtistResult {
+    pub name: String,
+    pub track_count: usize,
+}
+
+/// Best matches first in each group.
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchResults {
+    pub tracks: Vec<Track>,
+    pub albums: Vec<AlbumResult>,
+    pub artists: Vec<ArtistResult>,
+}
+
+/// Search over tracks, and the albums and artists they make up, kept up to
+/// date one track at a time as the library changes.
+pub struct SearchIndex {
+    tracks: TextIndex<u64>,
+    albums: TextIndex<AlbumKey>,
+    artists: TextIndex<String>,
+    // what each indexed track contributed, to take back out when it changes
+    contributions: HashMap<u64, (Option<AlbumKey>, Vec<String>)>,
+    album_counts: HashMap<AlbumKey, usize>,
+    artist_counts: HashMap<String, usize>,
+}
+
+impl SearchIndex {
+    const TITLE_WEIGHT: f32 = 3.0;
+    const NAME_WEIGHT: f32 = 2.0;
+    const TAG_WEIGHT: f32 = 1.0;
+
+    pub fn new() -> Self {
+        Self {
+            tracks: TextIndex::new(),
+            albums: TextIndex::new(),
+            artists: TextIndex::new(),
+            contributions: HashMap::new(),
+            album_counts: HashMap::new(),
+            artist_counts: HashMap::new(),
+        }
+    }
+
+    /// Adds a track, or re-indexes it if it's already there.
+    pub fn insert(&mut self, track: &Track) {
+        self.remove(track.id);
+
+        let year = track.year.map(|year| year.to_string());
+        let fields = [
+            (Some(&track.title), Self::TITLE_WEIGHT),
+            (track.artist.as_ref(), Self::NAME_WEIGHT),
+            (track.album_artist.as_ref(), Self::NAME_WEIGHT),
+            (track.album.as_ref(), Self::NAME_WEIGHT),
+            (track.genre.as_ref(), Self::TAG_WEIGHT),
+            (year.as_ref(), Self::TAG_WEIGHT),
+        ];
+        self.tracks.insert(
+            track.id,
+            fields
+                .iter()
+                .filter_map(|(text, weight)| text.map(|text| (text.as_str(), *weight))),
+        );
+
+        let album = AlbumKey::of(track);
+        if let Some(album) = album.as_ref() {
+            let count = self.album_counts.entry(album.clone()).or_insert(0);
+            if *count == 0 {
+                let mut fields = vec![(album.album.as_str(), Self::TITLE_WEIGHT)];
+                if let Some(artist) = album.artist.as_ref() {
+                    fields.push((artist.as_str(), Self::NAME_WEIGHT));
+                }
+                self.albums.insert(album.clone(), fields.into_iter());
+            }
+            *count += 1;
+        }
+
+        let mut artists: Vec<String> = [&track.artist, &track.album_artist]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        artists.dedup();
+        for artist in artists.iter() {
+            let count = self.artist_counts.entry(artist.clone()).or_insert(0);
+            if *count == 0 {
+                self.artists.insert(
+                    artist.clone(),
+                    [(artist.as_str(), Self::TITLE_WEIGHT)].into_iter(),
+                );
+            }
+            *count += 1;
+        }
+
+        self.contributions.insert(track.id, (album, artists));
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        let (album, artists) = match self.contributions.remove(&id) {
+            Some(contribution) => contribution,
+            None => return,
+        };
+        self.tracks.remove(&id);
+
+        if let Some(album) = album {
+            if release(&mut self.album_counts, &album) {
+                self.albums.remove(&album);
+            }
+        }
+        for artist in artists {
+            if release(&mut self.artist_counts, &artist) {
+                self.artists.remove(&artist);
+            }
+        }
+    }
+
+    /// Up to `limit` of each of the best matching track ids, albums and
+    /// artists.
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+    ) -> (Vec<u64>, Vec<AlbumResult>, Vec<ArtistResult>) {
+        let tokens = tokenize(query);
+        if tokens.is_empty() {
+            return (Vec::new(), Vec::new(), Vec::new());
+        }
+
+        let tracks = self.tracks.search(&tokens, limit);
+        let albums = self
+            .albums
+            .search(&tokens, limit)
+            .into_iter()
+            .map(|key| AlbumResult {
+                track_count: self.album_counts.get(&key).copied().unwrap_or(0),
+                key,
+            })
+            .collect();
+        let artists = self
+            .artists
+            .search(&tokens, limit)
+            .into_iter()
+            .map(|name| ArtistResult {
+                track_count: self.artist_counts.get(&name).copied().unwrap_or(0),
+                name,
+            })
+            .collect();
+
+        (tracks, albums, artists)
+    }
+}
+
+impl Default for SearchIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drops a reference, returning whether it was the last.
+fn release<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: &K) -> bool {
+    let count = match counts.get_mut(key) {
+        Some(count) => count,
+        None => return false,
+    };
+    *count -= 1;
+    if *count > 0 {
+        return false;
+    }
+
+    counts.remove(key);
+    true
+}
+
+/// An inverted index from terms to the documents they appear in, weighted by
+/// the most important field each appears in for that document.
+struct TextIndex<K> {
+    // sorted, so every term starting with a prefix is one range
+    terms: BTreeMap<String, HashMap<K, f32>>,
+    // the same terms by length in chars, so typos are only looked for among
+    // terms they could be within reach of
+    lengths: HashMap<usize, HashSet<String>>,
+    docs: HashMap<K, Vec<String>>,
+}
+
+impl<K: Clone + Eq + Hash + Ord> TextIndex<K> {
+    const PREFIX_QUALITY: f32 = 0.7;
+    const TYPO_QUALITY: f32 = 0.4;
+
+    fn new() -> Self {
+        Self {
+            terms: BTreeMap::new(),
+            lengths: HashMap::new(),
+            docs: HashMap::new(),
+        }
+    }
+
+    fn insert<'a>(&mut self, key: K, fields: impl Iterator<Item = (&'a str, f32)>) {
+        self.remove(&key);
+
+        let mut weights: HashMap<String, f32> = HashMap::new();
+        for (text, weight) in fields {
+            for token in tokenize(text) {
+                let best = weights.entry(token).or_insert(0.0);
+                *best = best.max(weight);
+            }
+        }
+
+        for (term, weight) in weights.iter() {
+            if !self.terms.contains_key(term) {
+                self.lengths
+                    .entry(term.chars().count())
+                    .or_default()
+                    .insert(term.clone());
+            }
+            self.terms
+                .entry(term.clone())
+                .or_default()
+                .insert(key.clone(), *weight);
+        }
+        self.docs.insert(key, weights.into_keys().collect());
+    }
+
+    fn remove(&mut self, key: &K) {
+        for term in self.docs.remove(key).into_iter().flatten() {
+            if let Some(postings) = self.terms.get_mut(&term) {
+                postings.remove(key);
+                if postings.is_empty() {
+                    self.terms.remove(&term);
+                    self.forget_length(&term);
+                }
+            }
+        }
+    }
+
+    fn forget_length(&mut self, term: &str) {
+        let length = term.chars().count();
+        if let Some(terms) = self.lengths.get_mut(&length) {
+            terms.remove(term);
+            if terms.is_empty() {
+                self.lengths.remove(&length);
+            }
+        }
+    }
+
+    /// Documents matching every token, by whole word, by prefix, or within
+    /// a typo or two, ranked by how well and where they matched.
+    fn search(&self, tokens: &[String], limit: usize) -> Vec<K> {
+        let mut scores: Option<HashMap<K, f32>> = None;
+        for token in tokens {
+            let matches = self.match_token(token);
+            scores = Some(match scores {
+                None => matches,
+                Some(scores) => scores
+                    .into_iter()
+                    .filter_map(|(key, score)| matches.get(&key).map(|s| (key, score + s)))
+                    .collect(),
+            });
+        }
+
+        let mut ranked: Vec<(K, f32)> = scores.unwrap_or_default().into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked.truncate(limit);
+        ranked.into_iter().map(|(key, _)| key).collect()
+    }
+
+    /// Each document's best score for one query token.
+    fn match_token(&self, token: &str) -> HashMap<K, f32> {
+        let mut scores: HashMap<K, f32> = HashMap::new();
+        let mut add = |term: &str, quality: f32| {
+            let postings = &self.terms[term];
+            // rarer terms say more about what's being looked for
+            let idf = (1.0 + self.docs.len() as f32 / postings.len() as f32).ln();
+            for (key, weight) in postings.iter() {
+                let score = scores.entry(key.clone()).or_insert(0.0);
+                *score = score.max(quality * weight * idf);
+            }
+        };
+
+        for (term, _) in self.terms.range(token.to_string()..) {
+            if !term.starts_with(token) {
+                break;
+            }
+            let quality = match term.len() == token.len() {
+                true => 1.0,
+                false => Self::PREFIX_QUALITY * token.len() as f32 / term.len() as f32,
+            };
+            add(term, quality);
+        }
+
+        let max_distance = match token.chars().count() {
+            0..=2 => return scores,
+            3..=6 => 1,
+            _ => 2,
+        };
+        let token_chars: Vec<char> = token.chars().collect();
+        let lengths =
+            token_chars.len().saturating_sub(max_distance)..=token_chars.len() + max_distance;
+        let terms = lengths
+            .filter_map(|length| self.lengths.get(&length))
+            .flatten();
+        for term in terms {
+            if term.starts_with(token) {
+                continue;
+            }
+            let term_chars: Vec<char> = term.chars().collect();
+            if let Some(distance) = edit_distance(&token_chars, &term_chars, max_distance) {
+                add(term, Self::TYPO_QUALITY / distance as f32);
+            }
+        }
+
+        scores
+    }
+}
+
+/// Lowercased words, with apostrophes dropped so "don't" is one word.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .replace(['\'', '\u{2019}'], "")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Optimal string alignment distance, counting a swap of neighbouring
+/// letters as one typo. None if it's over `max`.
+fn edit_distance(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+
+    // row i lives at i % 3, keeping the two before it for transpositions
+    let width = b.len() + 1;
+    let mut rows = vec![vec![0usize; width]; 3];
+    rows[0] = (0..width).collect();
+    for i in 1..=a.len() {
+        let (current, previous, before) = (i % 3, (i - 1) % 3, (i + 1) % 3);
+        rows[current][0] = i;
+        let mut row_min = i;
+        for j in 1..width {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            let mut distance = (rows[previous][j] + 1)
+                .min(rows[current][j - 1] + 1)
+                .min(rows[previous][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[before][j - 2] + 1);
+            }
+            rows[current][j] = distance;
+            row_min = row_min.min(distance);
+        }
+        if row_min > max {
+            return None;
+        }
+    }
+
+    let distance = rows[a.len() % 3][b.len()];
+    match distance <= max {
+        true => Some(distance),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: u64, title: &str, artist: &str, album: &str) -> Track {
+        Track {
+            id,
+            title: title.to_string(),
+            artist: Some(artist.to_string()),
+            album: Some(album.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex::new();
+        for track in [
+            track(1, "Blue in Green", "Miles Davis", "Kind of Blue"),
+            track(2, "So What", "Miles Davis", "Kind of Blue"),
+            track(3, "Blues for Alice", "Charlie Parker", "Swedish Schnapps"),
+            track(4, "Green Onions", "Booker T", "Green Onions"),
+            track(5, "Don't Know Why", "Norah Jones", "Come Away with Me"),
+        ] {
+            index.insert(&track);
+        }
+        index
+    }
+
+    fn tracks(index: &SearchIndex, query: &str) -> Vec<u64> {
+        index.search(query, 10).0
+    }
+
+    #[test]
+    fn titles_rank_above_other_fields() {
+        let index = index();
+        // "blue" is the title of one, and only the album of the other
+        let blue = tracks(&index, "blue");
+        assert_eq!(blue[0], 1);
+        assert!(blue.contains(&2));
+        // both have it in the title, so it comes down to id
+        assert_eq!(tracks(&index, "green"), vec![1, 4]);
+        assert_eq!(tracks(&index, "miles green"), vec![1]);
+    }
+
+    #[test]
+    fn whole_words_rank_above_prefixes() {
+        let mut index = index();
+        index.insert(&track(6, "Lovely Day", "Bill Withers", "Menagerie"));
+        index.insert(&track(7, "Love Me Do", "The Beatles", "Please Please Me"));
+        assert_eq!(tracks(&index, "love"), vec![7, 6]);
+        assert_eq!(tracks(&index, "lov"), vec![7, 6]);
+
+        // "blues" only starts with "blue"
+        assert_eq!(tracks(&index, "blu").len(), 3);
+        assert_eq!(tracks(&index, "sch"), vec![3]);
+        assert_eq!(tracks(&index, "dont"), vec![5]);
+    }
+
+    #[test]
+    fn typos_are_forgiven_by_word_length() {
+        let index = index();
+        assert_eq!(tracks(&index, "onoins"), vec![4]);
+        assert_eq!(tracks(&index, "parkr"), vec![3]);
+        assert_eq!(tracks(&index, "schanpps"), vec![3]);
+        // too short to guess at, and too far off
+        assert!(tracks(&index, "sp").is_empty());
+        assert!(tracks(&index, "onxxns").is_empty());
+    }
+
+    #[test]
+    fn typos_only_look_at_terms_near_in_length() {
+        let mut index = TextIndex::new();
+        index.insert(1, [("schnapps", 1.0)].into_iter());
+        index.insert(2, [("schnappsy parkers", 1.0)].into_iter());
+        assert_eq!(index.lengths[&8], HashSet::from(["schnapps".to_string()]));
+        assert_eq!(index.lengths[&9].len(), 1);
+        assert_eq!(index.lengths[&7].len(), 1);
+
+        // a letter short or over is still in reach of both
+        assert_eq!(index.search(&tokenize("schnaps"), 10), vec![1, 2]);
+        assert_eq!(index.search(&tokenize("parkerss"), 10), vec![2]);
+
+        // and terms leave their length as they leave the index
+        index.remove(&2);
+        assert_eq!(index.lengths.len(), 1);
+        index.remove(&1);
+        assert!(index.lengths.is_empty() && index.terms.is_empty());
+    }
+
+    #[test]
+    fn limit_applies_to_each_group() {
+        let (tracks, albums, artists) = index().search("blue green", 1);
+        assert_eq!(tracks, vec![1]);
+        assert_eq!(albums.len(), 0);
+        assert_eq!(artists.len(), 0);
+
+        let (tracks, albums, _) = index().search("kind", 1);
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(albums.len(), 1);
+    }
+
+    #[test]
+    fn counts_follow_tracks_in_and_out() {
+        let mut index = index();
+        let counts = |index: &SearchIndex| {
+            let (_, albums, artists) = index.search("miles kind", 10);
+            let (_, _, miles) = index.search("miles", 10);
+            (
+                albums.first().map(|album| album.track_count),
+                miles.first().map(|artist| artist.track_count),
+                artists.len(),
+            )
+        };
+        assert_eq!(counts(&index), (Some(2), Some(2), 0));
+
+        index.remove(1);
+        assert_eq!(counts(&index), (Some(1), Some(1), 0));
+        // removing twice takes nothing more away
+        index.remove(1);
+        assert_eq!(counts(&index), (Some(1), Some(1), 0));
+
+        // re-inserting re-indexes rather than counting twice
+        let so_what = track(2, "So What", "Miles Davis", "Kind of Blue");
+        index.insert(&so_what);
+        index.insert(&so_what);
+        assert_eq!(counts(&index), (Some(1), Some(1), 0));
+
+        index.insert(&track(2, "So What", "Bill Evans", "Portrait"));
+        assert_eq!(counts(&index), (None, None, 0));
+        assert!(index.search("kind", 10).1.is_empty());
+        assert_eq!(index.search("evans", 10).2[0].track_count, 1);
+    }
+
+    #[test]
+    fn transposed_letters_are_one_typo() {
+        let chars = |text: &str| text.chars().collect::<Vec<char>>();
+        assert_eq!(edit_distance(&chars("blue"), &chars("bleu"), 2), Some(1));
+        assert_eq!(edit_distance(&chars("blue"), &chars("glue"), 2), Some(1));
+        assert_eq!(edit_distance(&chars("blue"), &chars("bl"), 2), Some(2));
+        assert_eq!(edit_distance(&chars("blue"), &chars("b"), 2), None);
+    }
+}
@@ -0,0 +1,335 @@
+use crate::{
+    library::{Library, LibraryEvent},
+    scanner::{LibraryChange, LibraryScanner},
+};
+use notify::{recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, RecvTimeoutError},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Keeps the library in step with the scanner's root as files come and go.
+///
+/// Changes are collected until nothing has happened for `debounce`, so a
+/// file being copied in is read once it's done rather than on every write,
+/// and then applied through the scanner, which only re-reads what changed.
+pub struct LibraryWatcher {
+    // stops watching when dropped
+    watcher: RecommendedWatcher,
+    handle: JoinHandle<()>,
+}
+
+/// What the watcher has been told, reduced to what to do about it.
+enum WatchMessage {
+    Changed(Vec<PathBuf>),
+    Rescan,
+}
+
+impl LibraryWatcher {
+    pub fn spawn<F>(
+        mut scanner: LibraryScanner,
+        library: Arc<RwLock<Library>>,
+        debounce: Duration,
+        on_event: F,
+    ) -> Result<Self, String>
+    where
+        F: Fn(LibraryEvent) + Send + 'static,
+    {
+        let (sender, reciever) = channel();
+        let mut watcher = match recommended_watcher(move |event: notify::Result<Event>| {
+            let message = match event {
+                // the OS dropped events, so there's no telling what changed
+                Ok(event) if event.need_rescan() => WatchMessage::Rescan,
+                Ok(event) => match event.kind {
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
+                        WatchMessage::Changed(event.paths)
+                    }
+                    _ => return,
+                },
+                Err(e) => {
+                    eprintln!("error watching library: {e}");
+                    WatchMessage::Rescan
+                }
+            };
+            // the receiving end only goes away with the watcher
+            let _ = sender.send(message);
+        }) {
+            Ok(watcher) => watcher,
+            Err(e) => return Err(format!("error creating library watcher: {e}")),
+        };
+        if let Err(e) = watcher.watch(scanner.root(), RecursiveMode::Recursive) {
+            return Err(format!("error watching {}: {e}", scanner.root().display()));
+        }
+
+        let handle = thread::spawn(move || {
+            // the initial scan is what clients load the library from, they
+            // only need to hear about what changes after it
+            scanner.take_changes();
+
+            while let Ok(message) = reciever.recv() {
+                let mut paths = HashSet::new();
+                let mut rescan = false;
+                let mut disconnected = false;
+                let mut next = Some(message);
+                while let Some(message) = next.take() {
+                    match message {
+                        WatchMessage::Changed(changed) => paths.extend(changed),
+                        WatchMessage::Rescan => rescan = true,
+                    }
+                    match reciever.recv_timeout(debounce) {
+                        Ok(message) => next = Some(message),
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            disconnected = true;
+                            break;
+                        }
+                    }
+                }
+
+                for path in paths {
+                    if rescan {
+                        break;
+                    }
+                    if path.is_file() {
+                        scanner.scan_file(&path, &library);
+                    } else if path.is_dir() {
+                        // a folder created or moved in, which a scan works
+                        // through for us
+                        rescan = true;
+                    } else if !scanner.remove(&path, &library) {
+                        // a folder moved or deleted takes everything in it
+                        // along, anything else gone was never indexed
+                        rescan = scanner.is_known_dir(&path);
+                    }
+                }
+                if rescan {
+                    if let Err(e) = scanner.scan(&library) {
+                        eprintln!("error rescanning library: {e}");
+                    }
+                }
+
+                publish(&mut scanner, &library, &on_event);
+                if disconnected {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self { watcher, handle })
+    }
+
+    /// Stops watching and waits for changes already seen to be applied.
+    pub fn close(self) {
+        drop(self.watcher);
+        let _ = self.handle.join();
+    }
+}
+
+fn publish<F>(scanner: &mut LibraryScanner, library: &RwLock<Library>, on_event: &F)
+where
+    F: Fn(LibraryEvent),
+{
+    for change in scanner.take_changes() {
+        let event = match change {
+            LibraryChange::Removed(id) => LibraryEvent::Removed { id },
+            LibraryChange::Added(id) | LibraryChange::Updated(id) => {
+                // removed again later in the same batch
+                let track = match library.read().unwrap().get_track(id) {
+                    Ok(track) => (*track).clone(),
+                    Err(_) => continue,
+                };
+                match change {
+                    LibraryChange::Added(_) => LibraryEvent::Added { track },
+                    _ => LibraryEvent::Updated { track },
+                }
+            }
+        };
+        on_event(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        library::Track,
+        scanner::{stable_id, LibraryConfig},
+    };
+    use hound::{WavSpec, WavWriter};
+    use std::{fs, path::Path, process, sync::Mutex, time::Instant};
+
+    const DEBOUNCE: Duration = Duration::from_millis(200);
+
+    fn write_wav(path: &Path, frames: usize) {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(path, spec).unwrap();
+        for i in 0..frames {
+            writer.write_sample((i % 100) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    /// A scanned root with one album of two tracks, and the events a
+    /// watcher on it has sent so far.
+    struct Watched {
+        root: PathBuf,
+        library: Arc<RwLock<Library>>,
+        watcher: LibraryWatcher,
+        events: Arc<Mutex<Vec<LibraryEvent>>>,
+    }
+
+    impl Watched {
+        fn new(name: &str, before_watching: impl FnOnce(&Path)) -> Self {
+            let root = std::env::temp_dir().join(format!("watcher-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("Artist/Album")).unwrap();
+            write_wav(&root.join("Artist/Album/01 One.wav"), 800);
+            write_wav(&root.join("Artist/Album/02 Two.wav"), 800);
+
+            let library = Arc::new(RwLock::new(Library::open_in_memory().unwrap()));
+            let config = LibraryConfig {
+                root: root.clone(),
+                analyze_loudness: false,
+                fingerprint: false,
+            };
+            let mut scanner = LibraryScanner::new(&config, &library.read().unwrap()).unwrap();
+            scanner.scan(&library).unwrap();
+            before_watching(&root);
+
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let sent = events.clone();
+            let watcher = LibraryWatcher::spawn(scanner, library.clone(), DEBOUNCE, move |e| {
+                sent.lock().unwrap().push(e)
+            })
+            .unwrap();
+            Self {
+                root,
+                library,
+                watcher,
+                events,
+            }
+        }
+
+        /// Waits for `count` events, then long enough to be sure no more
+        /// are coming, and takes them.
+        fn take_events(&self, count: usize) -> Vec<LibraryEvent> {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while self.events.lock().unwrap().len() < count && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            thread::sleep(DEBOUNCE * 3);
+            std::mem::take(&mut *self.events.lock().unwrap())
+        }
+
+        fn has_track(&self, relative: &str) -> bool {
+            self.library
+                .read()
+                .unwrap()
+                .get_track(stable_id(relative))
+                .is_ok()
+        }
+
+        fn close(self) {
+            self.watcher.close();
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn added(event: &LibraryEvent) -> Option<&Track> {
+        match event {
+            LibraryEvent::Added { track } => Some(track),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn file_changes_reach_the_library_once_settled() {
+        let watched = Watched::new("files", |_| ());
+        let album = watched.root.join("Artist/Album");
+
+        // written twice in quick succession, read once it's settled
+        write_wav(&album.join("03 Three.wav"), 800);
+        thread::sleep(DEBOUNCE / 4);
+        write_wav(&album.join("03 Three.wav"), 1600);
+        let events = watched.take_events(1);
+        let [LibraryEvent::Added { track }] = &events[..] else {
+            panic!("{events:?}");
+        };
+        assert_eq!(track.title, "Three");
+        assert_eq!(track.duration_ms, Some(200));
+
+        write_wav(&album.join("01 One.wav"), 2400);
+        let events = watched.take_events(1);
+        let [LibraryEvent::Updated { track }] = &events[..] else {
+            panic!("{events:?}");
+        };
+        assert_eq!(track.duration_ms, Some(300));
+
+        fs::rename(album.join("02 Two.wav"), album.join("02 Deux.wav")).unwrap();
+        let events = watched.take_events(2);
+        assert_eq!(events.len(), 2, "{events:?}");
+        let two = stable_id("Artist/Album/02 Two.wav");
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, LibraryEvent::Removed { id } if *id == two)));
+        assert_eq!(
+            events.iter().find_map(added).map(|t| t.title.as_str()),
+            Some("Deux")
+        );
+
+        fs::remove_file(album.join("01 One.wav")).unwrap();
+        let events = watched.take_events(1);
+        let one = stable_id("Artist/Album/01 One.wav");
+        assert!(
+            matches!(events[..], [LibraryEvent::Removed { id }] if id == one),
+            "{events:?}"
+        );
+        assert!(!watched.has_track("Artist/Album/01 One.wav"));
+        assert!(watched.has_track("Artist/Album/02 Deux.wav"));
+        assert!(watched.has_track("Artist/Album/03 Three.wav"));
+        watched.close();
+    }
+
+    #[test]
+    fn only_known_folders_going_rescan() {
+        // a file the watcher never hears about, which only a rescan finds
+        let watched = Watched::new("folders", |root| {
+            write_wav(&root.join("Unseen.wav"), 800);
+        });
+
+        // gone before it's looked at, and never indexed
+        fs::write(watched.root.join("scratch.txt"), "partial").unwrap();
+        fs::remove_file(watched.root.join("scratch.txt")).unwrap();
+        assert!(watched.take_events(0).is_empty());
+        assert!(!watched.has_track("Unseen.wav"));
+
+        // moved out whole, so there's only the folder to go by
+        let moved = watched.root.with_extension("moved");
+        let _ = fs::remove_dir_all(&moved);
+        fs::rename(watched.root.join("Artist"), &moved).unwrap();
+        let events = watched.take_events(3);
+        let removed = events
+            .iter()
+            .filter(|e| matches!(e, LibraryEvent::Removed { .. }))
+            .count();
+        assert_eq!(removed, 2, "{events:?}");
+        assert_eq!(
+            events.iter().find_map(added).map(|t| t.title.as_str()),
+            Some("Unseen")
+        );
+        assert!(!watched.has_track("Artist/Album/01 One.wav"));
+        let _ = fs::remove_dir_all(&moved);
+        watched.close();
+    }
+}
@@ -0,0 +1,185 @@
+use crate::core::{
+    clock::PlaybackClock,
+    drain::{Drain, DrainMetrics, DrainStats},
+    pipeline::{Sample, TransformCtx},
+    quantize::Quantizer,
+};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::{
+    path::PathBuf,
+    sync::{mpsc::Receiver, Arc},
+};
+
+/// Writes the pipeline output to a WAV file, for headless machines that
+/// need to check what would have been played.
+pub struct WavDrain {
+    path: PathBuf,
+    bits_per_sample: u16,
+    metrics: DrainMetrics,
+}
+
+impl WavDrain {
+    /// `bits_per_sample` of 32 writes float samples, 16 or 24 write dithered
+    /// integer PCM.
+    pub fn new(path: PathBuf, bits_per_sample: u16) -> Self {
+        Self {
+            path,
+            bits_per_sample,
+            metrics: DrainMetrics::new(),
+        }
+    }
+
+    fn wav_spec(&self, ctx: &TransformCtx) -> WavSpec {
+        WavSpec {
+            channels: ctx.channels,
+            sample_rate: ctx.sample_rate,
+            bits_per_sample: self.bits_per_sample,
+            sample_format: match self.bits_per_sample {
+                32 => SampleFormat::Float,
+                _ => SampleFormat::Int,
+            },
+        }
+    }
+}
+
+impl Drain for WavDrain {
+    fn init(&self, _ctx: &TransformCtx) -> Result<(), String> {
+        if !matches!(self.bits_per_sample, 16 | 24 | 32) {
+            return Err(format!(
+                "unsupported bits per sample: {}",
+                self.bits_per_sample
+            ));
+        }
+
+        self.metrics.reset();
+        Ok(())
+    }
+
+    fn drain(
+        &self,
+        ctx: &TransformCtx,
+        from: Receiver<Sample>,
+        clock: Arc<PlaybackClock>,
+    ) -> Result<(), String> {
+        let wav_spec = self.wav_spec(ctx);
+        let mut writer = match WavWriter::create(&self.path, wav_spec) {
+            Ok(writer) => writer,
+            Err(e) => return Err(format!("error creating wav file: {e}")),
+        };
+
+        let channels = ctx.channels.max(1) as u64;
+        let mut quantizer = Quantizer::new(self.bits_per_sample as u32, true);
+        let mut samples = 0;
+        for sample in from {
+            let write_result = match wav_spec.sample_format {
+                SampleFormat::Float => writer.write_sample(sample),
+                SampleFormat::Int => writer.write_sample(quantizer.quantize_to_int(sample)),
+            };
+            if let Err(e) = write_result {
+                return Err(format!("error writing sample: {e}"));
+            }
+
+            samples += 1;
+            if samples == channels {
+                self.metrics.add_frames(1);
+                clock.advance(1);
+                samples = 0;
+            }
+        }
+
+        return match writer.finalize() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("error closing wav file: {e}")),
+        };
+    }
+
+    fn purge(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn stats(&self) -> DrainStats {
+        self.metrics.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::WavReader;
+    use std::{process, sync::mpsc::sync_channel};
+
+    fn ctx() -> TransformCtx {
+        TransformCtx {
+            window_size: 64,
+            fitting_buffer: 256,
+            sample_rate: 8000,
+            channels: 2,
+        }
+    }
+
+    /// A stereo ramp, out of phase between the channels.
+    fn input() -> Vec<Sample> {
+        (0..400)
+            .map(|i| match i % 2 {
+                0 => (i as Sample / 400.0) - 0.5,
+                _ => 0.5 - (i as Sample / 400.0),
+            })
+            .collect()
+    }
+
+    fn write(bits_per_sample: u16) -> (PathBuf, Arc<PlaybackClock>) {
+        let path =
+            std::env::temp_dir().join(format!("wav-drain-{}-{bits_per_sample}.wav", process::id()));
+        let ctx = ctx();
+        let drain = WavDrain::new(path.clone(), bits_per_sample);
+        drain.init(&ctx).unwrap();
+
+        let input = input();
+        let (sender, reciever) = sync_channel(input.len());
+        for sample in input {
+            sender.send(sample).unwrap();
+        }
+        drop(sender);
+        let clock = Arc::new(PlaybackClock::new());
+        drain.drain(&ctx, reciever, clock.clone()).unwrap();
+        assert_eq!(drain.stats().frames, 200);
+        (path, clock)
+    }
+
+    #[test]
+    fn float_samples_round_trip_exactly() {
+        let (path, clock) = write(32);
+        assert_eq!(clock.position_frames(), 200);
+
+        let mut reader = WavReader::open(&path).unwrap();
+        assert_eq!(
+            reader.spec(),
+            WavDrain::new(path.clone(), 32).wav_spec(&ctx())
+        );
+        let output: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+        assert_eq!(output, input());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn integer_samples_round_trip_within_the_dither() {
+        let (path, _) = write(16);
+
+        let mut reader = WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().sample_format, SampleFormat::Int);
+        let output: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+        assert_eq!(output.len(), input().len());
+        for (written, sample) in output.iter().zip(input()) {
+            let expected = sample * i16::MAX as Sample;
+            assert!((*written as Sample - expected).abs() <= 2.0);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unsupported_bit_depths_are_refused() {
+        assert!(WavDrain::new(PathBuf::from("unused.wav"), 8)
+            .init(&ctx())
+            .is_err());
+    }
+}